@@ -1,5 +1,5 @@
 use ais_common::common::{
-    AppName, AppStatus, GeneralMessage, MessageType, QueryMessage,
+    AppName, AppStatus, ComponentStatus, GeneralMessage, MessageType, QueryMessage,
     QueryResponse, QueryType, Status,
 };
 use ais_common::log::{log, Names};
@@ -7,13 +7,14 @@ use ais_common::mailing::{Email, EmailSecure};
 use ais_common::messages::{receive_message, send_acknowledge, send_message};
 use ais_common::socket::get_socket_path;
 use ais_common::system::{current_timestamp, get_machine_id};
+use ais_common::systemd::ProcessInfo;
 use ais_common::version::Version;
 use dusa_collection_utils::errors::{ErrorArray, ErrorArrayItem, UnifiedResult, WarningArray};
 use dusa_collection_utils::rwarc::LockWithTimeout;
 use dusa_collection_utils::stringy::Stringy;
 use dusa_collection_utils::types::PathType;
 use simple_pretty::warn;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
 use tokio::net::{UnixListener, UnixStream as TokioUnixStream};
 use tokio::sync::RwLock;
@@ -26,6 +27,7 @@ pub type SharedState = Arc<RwLock<HashMap<AppName, Status>>>;
 async fn main() {
     let state: LockWithTimeout<HashMap<AppName, Status>> = LockWithTimeout::new(HashMap::new());
     let state_clone: LockWithTimeout<HashMap<AppName, Status>> = state.clone();
+    let services: LockWithTimeout<Vec<ProcessInfo>> = LockWithTimeout::new(Vec::new());
     let errors: ErrorArray = ErrorArray::new_container();
     let warnings: WarningArray = WarningArray::new_container();
     let socket_path_result: UnifiedResult<dusa_collection_utils::errors::OkWarning<PathType>> =
@@ -69,10 +71,11 @@ async fn main() {
         };
 
         let new_state: LockWithTimeout<HashMap<AppName, Status>> = state.clone();
+        let new_services: LockWithTimeout<Vec<ProcessInfo>> = services.clone();
 
         tokio::spawn(async move {
             let message: GeneralMessage = receive_message(&mut stream).await.unwrap();
-            handle_message(&new_state, message, &mut stream).await;
+            handle_message(&new_state, &new_services, message, &mut stream).await;
         });
     }
 }
@@ -80,6 +83,7 @@ async fn main() {
 /// Handles incoming general messages and updates the shared state if it's a status update.
 pub async fn handle_message(
     state: &LockWithTimeout<HashMap<AppName, Status>>,
+    services: &LockWithTimeout<Vec<ProcessInfo>>,
     message: GeneralMessage,
     stream: &mut TokioUnixStream,
 ) {
@@ -97,6 +101,14 @@ pub async fn handle_message(
                         send_acknowledge(stream).await;
                     }
                 }
+                MessageType::ServicesUpdate => {
+                    if let Ok(service_info) =
+                        serde_json::from_value::<Vec<ProcessInfo>>(message.payload)
+                    {
+                        handle_services_update(services.clone(), service_info).await;
+                        send_acknowledge(stream).await;
+                    }
+                }
                 MessageType::Acknowledgment => {
                     let email: Email = Email { subject: format!("Connection dropped Erroneous communication").into(), 
                 body: format!("Machine: {} has dropped a connection due to non standard communication", get_machine_id()).into() };
@@ -107,7 +119,7 @@ pub async fn handle_message(
                 }
                 MessageType::Query => {
                     if let Ok(query) = serde_json::from_value::<QueryMessage>(message.payload) {
-                        handle_query(state.clone(), query, stream).await;
+                        handle_query(state.clone(), services.clone(), query, stream).await;
                     }
                 }
             }
@@ -124,6 +136,18 @@ pub async fn handle_status_update(
     if let Ok(mut our_state_locked) =
         LockWithTimeout::try_write_with_timeout(&our_state, Some(Duration::from_secs(2))).await
     {
+        // Applications reporting components get their status from them
+        let mut new_state = new_state;
+        new_state.derive_from_components();
+
+        if let Some(registered_app) = our_state_locked.get(&new_state.app_name) {
+            log_component_changes(
+                &new_state.app_name,
+                registered_app.components.as_ref(),
+                new_state.components.as_ref(),
+            );
+        }
+
         let new_state_clone: Status = new_state.clone();
         let app_name: AppName = new_state_clone.app_name;
         let app_status: AppStatus = new_state_clone.app_status;
@@ -175,7 +199,7 @@ pub async fn handle_status_update(
                             Names::AisAggregator,
                         )
                     }
-                    AppStatus::Warning => {
+                    AppStatus::Warning | AppStatus::AuthFailure | AppStatus::Untrusted => {
                         notify_status(&app_name, &app_status)?;
                         log(
                             format!(
@@ -199,8 +223,59 @@ pub async fn handle_status_update(
     Ok(())
 }
 
+/// Logs every component that changed status, appeared or disappeared.
+fn log_component_changes(
+    app_name: &AppName,
+    old: Option<&BTreeMap<String, ComponentStatus>>,
+    new: Option<&BTreeMap<String, ComponentStatus>>,
+) {
+    let empty = BTreeMap::new();
+    let old = old.unwrap_or(&empty);
+    let new = new.unwrap_or(&empty);
+
+    for (name, component) in new {
+        match old.get(name) {
+            Some(previous) if previous.app_status == component.app_status => {}
+            Some(previous) => log(
+                format!(
+                    "{:?}/{} status changed from {:?}, to {:?}",
+                    app_name, name, previous.app_status, component.app_status
+                ),
+                Names::AisAggregator,
+            ),
+            None => log(
+                format!(
+                    "New component registered: {:?}/{} ({:?})",
+                    app_name, name, component.app_status
+                ),
+                Names::AisAggregator,
+            ),
+        }
+    }
+
+    for name in old.keys().filter(|name| !new.contains_key(*name)) {
+        log(
+            format!("Component removed: {:?}/{}", app_name, name),
+            Names::AisAggregator,
+        );
+    }
+}
+
+/// Replaces the stored service details with the latest report from ais_services.
+pub async fn handle_services_update(
+    our_services: LockWithTimeout<Vec<ProcessInfo>>,
+    new_services: Vec<ProcessInfo>,
+) {
+    if let Ok(mut services_locked) =
+        LockWithTimeout::try_write_with_timeout(&our_services, Some(Duration::from_secs(2))).await
+    {
+        *services_locked = new_services;
+    }
+}
+
 pub async fn handle_query(
     state: LockWithTimeout<HashMap<AppName, Status>>,
+    services: LockWithTimeout<Vec<ProcessInfo>>,
     query: QueryMessage,
     stream: &mut TokioUnixStream,
 ) {
@@ -216,13 +291,24 @@ pub async fn handle_query(
                     version: Version::get(),
                     app_status,
                     all_statuses: None,
+                    services: None,
                 }
             }
             QueryType::AllStatuses => QueryResponse {
                 version: Version::get(),
                 app_status: None,
                 all_statuses: Some(state_lock.clone()),
+                services: None,
             },
+            QueryType::Services => {
+                let services_lock = LockWithTimeout::try_read(&services).await.unwrap();
+                QueryResponse {
+                    version: Version::get(),
+                    app_status: None,
+                    all_statuses: None,
+                    services: Some(services_lock.clone()),
+                }
+            }
         }
     };
 
@@ -254,6 +340,8 @@ pub async fn check_for_timeouts(state: LockWithTimeout<HashMap<AppName, Status>>
                     app_status: AppStatus::TimedOut,
                     timestamp: current_timestamp(),
                     version: status.version.clone(),
+                    message: None,
+                    components: None,
                 };
                 let email = Email {
                     subject: format!("Application timed out").into(),
@@ -298,6 +386,8 @@ mod tests {
             app_status: AppStatus::Running,
             timestamp: current_timestamp(),
             version: Version::get(),
+            message: None,
+            components: None,
         };
 
         let _ = handle_status_update(state.clone(), status.clone()).await;
@@ -307,6 +397,34 @@ mod tests {
         assert_eq!(state_guard.get(&AppName::Github), Some(&status));
     }
 
+    #[tokio::test]
+    async fn test_status_derived_from_components() {
+        let state: LockWithTimeout<HashMap<AppName, Status>> = LockWithTimeout::new(HashMap::new());
+        let component = |app_status: AppStatus| ComponentStatus {
+            app_status,
+            timestamp: current_timestamp(),
+            message: None,
+        };
+        let status: Status = Status {
+            app_name: AppName::Github,
+            app_status: AppStatus::Running,
+            timestamp: current_timestamp(),
+            version: Version::get(),
+            message: None,
+            components: Some(BTreeMap::from([
+                (String::from("site"), component(AppStatus::Running)),
+                (String::from("shop"), component(AppStatus::Warning)),
+            ])),
+        };
+
+        let _ = handle_status_update(state.clone(), status).await;
+
+        let state_guard = LockWithTimeout::try_read(&state).await.unwrap();
+        let stored = state_guard.get(&AppName::Github).unwrap();
+        assert_eq!(stored.app_status, AppStatus::Warning);
+        assert_eq!(stored.components.as_ref().map(|c| c.len()), Some(2));
+    }
+
     #[tokio::test]
     async fn test_check_for_timeouts() {
         let state: LockWithTimeout<HashMap<AppName, Status>> = LockWithTimeout::new(HashMap::new());
@@ -315,6 +433,8 @@ mod tests {
             app_status: AppStatus::Running,
             timestamp: current_timestamp() - 120, // Simulating a timeout
             version: Version::get(),
+            message: None,
+            components: None,
         };
 
         {
@@ -332,6 +452,8 @@ mod tests {
                 app_status: AppStatus::TimedOut,
                 timestamp: state_guard.get(&AppName::Apache).unwrap().timestamp,
                 version: Version::get(),
+                message: None,
+                components: None,
             })
         );
     }
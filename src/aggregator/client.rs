@@ -38,6 +38,8 @@ async fn send_status_update() -> Result<(), ErrorArrayItem> {
         app_status: AppStatus::Running,
         timestamp: current_timestamp(),
         version: Version::get(),
+        message: None,
+        components: None,
     };
 
     let message = GeneralMessage {
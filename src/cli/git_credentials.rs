@@ -1,8 +1,152 @@
+use std::fs;
 use std::io::{self, Write};
+use std::path::Path;
 
-use ais_common::git_data::{GitAuth, GitCredentials};
+use ais_common::config_bundle::{import_bundle, write_sealed, ConfigBundle, ImportMode};
+use ais_common::constants::{ARTISANCF, ORPHAN_GRACE_PERIOD};
+use ais_common::deploy_keys::DeployKey;
+use ais_common::git_data::{
+    ConflictPolicy, GitAuth, GitCredentials, GitPin, GitProvider, SignaturePolicy,
+};
+use ais_common::remote_check::{check_remotes, RemoteCheck};
+use ais_common::system::{format_unix_timestamp, prompt_yes_no};
+use dusa_collection_utils::errors::{ErrorArrayItem, Errors};
 use dusa_collection_utils::stringy::Stringy;
-use simple_pretty::{halt, pass};
+use serde_json::Value;
+use simple_pretty::{halt, notice, pass, warn};
+
+// Settings `add` and `update` take as `--<field> <value>`, see `GitAuth::set_field`
+const FIELDS: [&str; 12] = [
+    "user",
+    "repo",
+    "branch",
+    "token",
+    "provider",
+    "pin",
+    "webhook-secret",
+    "poll-interval",
+    "conflict-policy",
+    "signatures",
+    "submodules",
+    "lfs",
+];
+// Options of the other commands that take a value
+const VALUES: [&str; 2] = ["output", "passphrase"];
+const SWITCHES: [&str; 7] = [
+    "json",
+    "show-token",
+    "delete-key",
+    "dry-run",
+    "no-verify",
+    "remote",
+    "replace",
+];
+
+fn usage() {
+    halt(
+        "Usage: ais_credentials [keys | list | show <repository> | add | update <repository> | remove <repository> [--delete-key] | validate [--remote] | export [<repository>...] --output <file> | import <file> [--replace]]
+  Without a command new entries are created interactively.
+  <repository> is an id, owner/repo or owner/repo@branch.
+  add and update take --user, --repo, --branch, --token, --provider <kind[:location]>,
+  --pin <none|tag:name|commit:sha|pattern:glob>, --webhook-secret, --poll-interval <seconds|default>,
+  --conflict-policy <refuse|stash|reset>, --signatures <off|commit|tag>, --submodules <yes|no>, --lfs <yes|no>.
+  A token or webhook secret of - is read from stdin.
+  add and update check the remote, branch and pinned tag can be read before saving,
+  --dry-run only runs the checks and --no-verify saves without them.
+  validate --remote runs the same checks for every entry.
+  export writes the given repositories, or all of them, with their secrets, deploy keys and projects
+  to a passphrase encrypted bundle. import merges a bundle, keeping entries that are already configured
+  unless --replace, and takes --dry-run. Both ask for the passphrase unless given --passphrase -.
+  list, show and validate take --json, list and show only print secrets with --show-token.",
+    );
+}
+
+/// Arguments after the command.
+#[derive(Default)]
+struct Options {
+    positional: Vec<String>,
+    fields: Vec<(String, String)>,
+    values: Vec<(String, String)>,
+    switches: Vec<String>,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Self, ErrorArrayItem> {
+        let mut options = Options::default();
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            let name = match arg.strip_prefix("--") {
+                Some(name) => name,
+                None => {
+                    options.positional.push(arg.clone());
+                    continue;
+                }
+            };
+
+            if SWITCHES.contains(&name) {
+                options.switches.push(name.to_owned());
+            } else if FIELDS.contains(&name) || VALUES.contains(&name) {
+                let value = args.next().ok_or_else(|| {
+                    ErrorArrayItem::new(Errors::GeneralError, format!("--{} needs a value", name))
+                })?;
+                let value = (name.to_owned(), read_secret(name, value)?);
+                match FIELDS.contains(&name) {
+                    true => options.fields.push(value),
+                    false => options.values.push(value),
+                }
+            } else {
+                return Err(ErrorArrayItem::new(
+                    Errors::GeneralError,
+                    format!("Unknown option --{}", name),
+                ));
+            }
+        }
+
+        Ok(options)
+    }
+
+    fn switch(&self, name: &str) -> bool {
+        self.switches.iter().any(|switch| switch == name)
+    }
+
+    fn field(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn value(&self, name: &str) -> Option<&str> {
+        self.values
+            .iter()
+            .find(|(option, _)| option == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    // The single repository selector commands like show and remove take
+    fn repository(&self) -> Result<&str, ErrorArrayItem> {
+        match self.positional.as_slice() {
+            [repository] => Ok(repository),
+            _ => Err(ErrorArrayItem::new(
+                Errors::GeneralError,
+                String::from("Expected exactly one repository"),
+            )),
+        }
+    }
+}
+
+// A secret given as - is read from stdin, keeping it out of the process list
+// and shell history
+fn read_secret(name: &str, value: &str) -> Result<String, ErrorArrayItem> {
+    if value != "-" || !matches!(name, "token" | "webhook-secret" | "passphrase") {
+        return Ok(value.to_owned());
+    }
+
+    let mut secret = String::new();
+    io::stdin().read_line(&mut secret)?;
+    Ok(secret.trim().to_owned())
+}
 
 fn prompt_input(prompt: &str) -> Stringy {
     print!("{}", prompt);
@@ -13,28 +157,512 @@ fn prompt_input(prompt: &str) -> Stringy {
     input.trim().into()
 }
 
-fn main() {
+// Prints the public deploy key for each entry so it can be added to the forge
+fn print_deploy_keys(auth_items: &[GitAuth]) {
+    for auth in auth_items {
+        match DeployKey::public_key(auth) {
+            Ok(public_key) => println!(
+                "Deploy key for {}/{} ({}):\n{}\n",
+                auth.user, auth.repo, auth.branch, public_key
+            ),
+            Err(e) => warn(&format!(
+                "Failed to load the deploy key for {}/{}: {}",
+                auth.user, auth.repo, e
+            )),
+        }
+    }
+}
+
+// Checks entries against their remotes, printing the outcome of each
+async fn verify(auth_items: &[GitAuth]) -> Vec<RemoteCheck> {
+    let checks = check_remotes(auth_items).await;
+    for check in &checks {
+        match check.passed() {
+            true => pass(&check.to_string()),
+            false => warn(&check.to_string()),
+        }
+    }
+    checks
+}
+
+// Runs the remote checks `add` and `update` do before saving. Returns whether
+// the entry should be saved.
+async fn verify_before_saving(auth: &GitAuth, options: &Options) -> Result<bool, ErrorArrayItem> {
+    if options.switch("no-verify") {
+        return Ok(!options.switch("dry-run"));
+    }
+
+    let checks = verify(std::slice::from_ref(auth)).await;
+    if checks.iter().any(|check| !check.passed()) {
+        // A new deploy key can't have been added to the remote yet
+        if matches!(auth.provider, GitProvider::Ssh { .. }) {
+            print_deploy_keys(std::slice::from_ref(auth));
+        }
+        return Err(ErrorArrayItem::new(
+            Errors::GeneralError,
+            String::from("The remote checks failed, nothing was saved (--no-verify skips them)"),
+        ));
+    }
+
+    if options.switch("dry-run") {
+        notice("Dry run, nothing was saved");
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = match args.first() {
+        Some(command) => command.as_str(),
+        None => return interactive().await,
+    };
+
+    let result = match Options::parse(&args[1..]) {
+        Err(e) => Err(e),
+        Ok(options) => match command {
+            // Lists the deploy keys without changing anything
+            "keys" => {
+                GitCredentials::new().map(|git_creds| print_deploy_keys(&git_creds.auth_items))
+            }
+            "list" => list(&options),
+            "show" => show(&options),
+            "add" => add(&options).await,
+            "update" => update(&options).await,
+            "remove" => remove(&options),
+            "validate" => validate(&options).await,
+            "export" => export(&options).await,
+            "import" => import(&options).await,
+            _ => {
+                usage();
+                std::process::exit(1);
+            }
+        },
+    };
+
+    if let Err(e) = result {
+        halt(&format!("{}", e));
+        std::process::exit(1);
+    }
+}
+
+fn list(options: &Options) -> Result<(), ErrorArrayItem> {
+    let git_creds = GitCredentials::new()?;
+    let show_secrets = options.switch("show-token");
+
+    if options.switch("json") {
+        let entries = git_creds
+            .auth_items
+            .iter()
+            .map(|auth| to_json(auth, show_secrets))
+            .collect::<Result<Vec<Value>, ErrorArrayItem>>()?;
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+        return Ok(());
+    }
+
+    if git_creds.auth_items.is_empty() {
+        notice("No repositories are configured");
+        return Ok(());
+    }
+
+    for auth in &git_creds.auth_items {
+        println!(
+            "{} {}/{}@{} {} pin: {} token: {}",
+            auth.id(),
+            auth.user,
+            auth.repo,
+            auth.branch,
+            auth.provider,
+            pin_state(auth),
+            secret_state(auth.access_token(), show_secrets)
+        );
+    }
+    Ok(())
+}
+
+fn show(options: &Options) -> Result<(), ErrorArrayItem> {
+    let git_creds = GitCredentials::new()?;
+    let auth = &git_creds.auth_items[git_creds.position(options.repository()?)?];
+    let show_secrets = options.switch("show-token");
+
+    if options.switch("json") {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&to_json(auth, show_secrets)?)?
+        );
+        return Ok(());
+    }
+
+    println!("Id: {}", auth.id());
+    println!("Repository: {}/{}@{}", auth.user, auth.repo, auth.branch);
+    println!("Remote: {} ({})", auth.remote_url(), auth.provider);
+    println!("Pin: {}", pin_state(auth));
+    println!("Token: {}", secret_state(auth.access_token(), show_secrets));
+    println!(
+        "Webhook secret: {}",
+        secret_state(auth.webhook_secret(), show_secrets)
+    );
+    println!(
+        "Poll interval: {}",
+        auth.poll_interval
+            .map(|seconds| format!("{}s", seconds))
+            .unwrap_or_else(|| String::from("default"))
+    );
+    println!("On conflict: {}", auth.conflict_policy);
+    println!("Signatures: {}", auth.signature_policy);
+    println!("Submodules: {}", yes_no(auth.submodules));
+    println!("LFS: {}", yes_no(auth.lfs));
+    Ok(())
+}
+
+async fn add(options: &Options) -> Result<(), ErrorArrayItem> {
+    for required in ["user", "repo", "branch"] {
+        if options.field(required).is_none() {
+            return Err(ErrorArrayItem::new(
+                Errors::GeneralError,
+                format!("add needs --{}", required),
+            ));
+        }
+    }
+
+    let mut auth = GitAuth::new("", "", "");
+    for (field, value) in &options.fields {
+        auth.set_field(field, value)?;
+    }
+
+    let mut git_creds = GitCredentials::bootstrap_git_credentials()?;
+    if git_creds
+        .auth_items
+        .iter()
+        .any(|other| *other.id() == *auth.id())
+    {
+        return Err(ErrorArrayItem::new(
+            Errors::GeneralError,
+            format!(
+                "{}/{}@{} is already configured, use update to change it",
+                auth.user, auth.repo, auth.branch
+            ),
+        ));
+    }
+
+    if !verify_before_saving(&auth, options).await? {
+        return Ok(());
+    }
+
+    git_creds.add_auth(auth.clone())?;
+    git_creds.save(ARTISANCF)?;
+    pass(&format!(
+        "Added {}/{}@{} as {}",
+        auth.user,
+        auth.repo,
+        auth.branch,
+        auth.id()
+    ));
+    report_problems(&auth);
+
+    if matches!(auth.provider, GitProvider::Ssh { .. }) {
+        print_deploy_keys(&[auth]);
+    }
+    Ok(())
+}
+
+async fn update(options: &Options) -> Result<(), ErrorArrayItem> {
+    if options.fields.is_empty() {
+        return Err(ErrorArrayItem::new(
+            Errors::GeneralError,
+            String::from("update needs at least one setting to change"),
+        ));
+    }
+
+    let mut git_creds = GitCredentials::new()?;
+    let index = git_creds.position(options.repository()?)?;
+    let mut auth = git_creds.auth_items[index].clone();
+    for (field, value) in &options.fields {
+        auth.set_field(field, value)?;
+    }
+
+    let old_id = git_creds.auth_items[index].id();
+    if *auth.id() != *old_id {
+        if git_creds
+            .auth_items
+            .iter()
+            .any(|other| *other.id() == *auth.id())
+        {
+            return Err(ErrorArrayItem::new(
+                Errors::GeneralError,
+                format!(
+                    "{}/{}@{} is already configured",
+                    auth.user, auth.repo, auth.branch
+                ),
+            ));
+        }
+        // Projects and deploy keys are tied to the id
+        warn(&format!(
+            "The id changes from {} to {}, the git monitor deploys it as a new project",
+            old_id,
+            auth.id()
+        ));
+    }
+
+    if !verify_before_saving(&auth, options).await? {
+        return Ok(());
+    }
+
+    git_creds.auth_items[index] = auth.clone();
+    git_creds.save(ARTISANCF)?;
+    pass(&format!(
+        "Updated {}/{}@{}",
+        auth.user, auth.repo, auth.branch
+    ));
+    report_problems(&auth);
+    Ok(())
+}
+
+fn remove(options: &Options) -> Result<(), ErrorArrayItem> {
+    let mut git_creds = GitCredentials::new()?;
+    let index = git_creds.position(options.repository()?)?;
+    let auth = git_creds.auth_items.remove(index);
+    git_creds.save(ARTISANCF)?;
+
+    pass(&format!(
+        "Removed {}/{}@{}",
+        auth.user, auth.repo, auth.branch
+    ));
+    if options.switch("delete-key") {
+        DeployKey::remove(&auth)?;
+        pass("Deleted its deploy key");
+    }
+    notice(&format!(
+        "The git monitor tears its project down after {} hours, ais_releases teardown does it now",
+        ORPHAN_GRACE_PERIOD / 3600
+    ));
+    Ok(())
+}
+
+async fn validate(options: &Options) -> Result<(), ErrorArrayItem> {
+    let git_creds = GitCredentials::new()?;
+    let mut problems = git_creds.validate();
+
+    if options.switch("remote") {
+        for check in check_remotes(&git_creds.auth_items).await {
+            for problem in &check.problems {
+                problems.push((check.repository.clone(), problem.clone()));
+            }
+        }
+    }
+
+    if options.switch("json") {
+        let problems: Vec<Value> = problems
+            .iter()
+            .map(|(repository, problem)| {
+                serde_json::json!({ "repository": repository, "problem": problem })
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&problems)?);
+    } else if problems.is_empty() {
+        pass("No problems found");
+    } else {
+        for (repository, problem) in &problems {
+            warn(&format!("{}: {}", repository, problem));
+        }
+    }
+
+    // Lets provisioning scripts stop on a bad configuration
+    if !problems.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+// The bundle passphrase from --passphrase or the terminal, asked twice when
+// a new bundle is written
+fn passphrase(options: &Options, confirm: bool) -> Result<String, ErrorArrayItem> {
+    let passphrase = match options.value("passphrase") {
+        Some(passphrase) => passphrase.to_owned(),
+        None => {
+            let passphrase = prompt_input("Bundle passphrase: ").to_string();
+            if confirm && *prompt_input("Repeat the passphrase: ") != *passphrase {
+                return Err(ErrorArrayItem::new(
+                    Errors::GeneralError,
+                    String::from("The passphrases don't match"),
+                ));
+            }
+            passphrase
+        }
+    };
+
+    match passphrase.is_empty() {
+        true => Err(ErrorArrayItem::new(
+            Errors::GeneralError,
+            String::from("The passphrase must not be empty"),
+        )),
+        false => Ok(passphrase),
+    }
+}
+
+async fn export(options: &Options) -> Result<(), ErrorArrayItem> {
+    let output = options.value("output").ok_or_else(|| {
+        ErrorArrayItem::new(Errors::GeneralError, String::from("export needs --output"))
+    })?;
+
+    let bundle = ConfigBundle::collect(&options.positional).await?;
+    if bundle.repositories.is_empty() {
+        return Err(ErrorArrayItem::new(
+            Errors::GeneralError,
+            String::from("No repositories are configured"),
+        ));
+    }
+
+    let sealed = bundle.seal(&passphrase(options, true)?)?;
+    write_sealed(Path::new(output), &sealed)?;
+    pass(&format!(
+        "Exported {} repositories to {}",
+        bundle.repositories.len(),
+        output
+    ));
+    notice("The bundle holds tokens and deploy keys, keep the passphrase apart from it");
+    Ok(())
+}
+
+async fn import(options: &Options) -> Result<(), ErrorArrayItem> {
+    let file = match options.positional.as_slice() {
+        [file] => file,
+        _ => {
+            return Err(ErrorArrayItem::new(
+                Errors::GeneralError,
+                String::from("Expected exactly one bundle"),
+            ))
+        }
+    };
+
+    let bundle = ConfigBundle::open(&fs::read_to_string(file)?, &passphrase(options, false)?)?;
+    notice(&format!(
+        "Bundle of {} repositories exported on {} at {}",
+        bundle.repositories.len(),
+        bundle.host,
+        format_unix_timestamp(bundle.created)
+    ));
+
+    let mode = match options.switch("replace") {
+        true => ImportMode::Replace,
+        false => ImportMode::Merge,
+    };
+    let dry_run = options.switch("dry-run");
+    let report = import_bundle(&bundle, mode, dry_run).await?;
+
+    let verb = match dry_run {
+        true => "Would import",
+        false => "Imported",
+    };
+    for auth in &report.imported {
+        pass(&format!(
+            "{} {}/{}@{}",
+            verb, auth.user, auth.repo, auth.branch
+        ));
+    }
+    for conflict in &report.conflicts {
+        warn(conflict);
+    }
+    if dry_run {
+        notice("Dry run, nothing was saved");
+    }
+    Ok(())
+}
+
+fn report_problems(auth: &GitAuth) {
+    for problem in auth.problems() {
+        warn(&problem);
+    }
+}
+
+// An entry as json with its id, secrets masked unless asked for
+fn to_json(auth: &GitAuth, show_secrets: bool) -> Result<Value, ErrorArrayItem> {
+    let shown = match show_secrets {
+        true => auth.clone(),
+        false => auth.masked(),
+    };
+
+    let mut value = serde_json::to_value(&shown)?;
+    value["id"] = Value::from(auth.id().to_string());
+    Ok(value)
+}
+
+fn pin_state(auth: &GitAuth) -> String {
+    match &auth.pin {
+        Some(pin) => pin.to_string(),
+        None => String::from("none"),
+    }
+}
+
+fn secret_state(secret: Option<&Stringy>, show_secrets: bool) -> String {
+    match (secret, show_secrets) {
+        (Some(secret), true) => secret.to_string(),
+        (Some(_), false) => String::from("set"),
+        (None, _) => String::from("none"),
+    }
+}
+
+fn yes_no(enabled: bool) -> &'static str {
+    match enabled {
+        true => "yes",
+        false => "no",
+    }
+}
+
+async fn interactive() {
     let mut git_creds = GitCredentials::bootstrap_git_credentials().unwrap();
 
     let num_instances: usize = prompt_input("Enter the number of GitAuth instances to create: ")
         .parse()
         .expect("Invalid input");
 
+    let mut new_items: Vec<GitAuth> = Vec::new();
+
     for i in 0..num_instances {
         println!("Enter details for GitAuth instance {}", i + 1);
 
         let user: Stringy = prompt_input("User: ");
         let repo: Stringy = prompt_input("Repo: ");
         let branch: Stringy = prompt_input("Branch: ");
+        let provider: GitProvider = GitProvider::prompt();
+        let pin: Option<GitPin> = GitPin::prompt();
+        let token: Stringy = GitAuth::prompt_token();
+        let webhook_secret: Option<Stringy> = GitAuth::prompt_webhook_secret();
+        let poll_interval: Option<u64> = GitAuth::prompt_poll_interval();
+        let conflict_policy: ConflictPolicy = ConflictPolicy::prompt();
+        let (submodules, lfs): (bool, bool) = GitAuth::prompt_checkout_extras();
+        let signature_policy: SignaturePolicy = SignaturePolicy::prompt();
 
         let auth = GitAuth {
             user,
             repo,
             branch,
-            token: Stringy::new("******"),
+            token,
+            provider,
+            pin,
+            webhook_secret,
+            poll_interval,
+            conflict_policy,
+            submodules,
+            lfs,
+            signature_policy,
         };
 
-        git_creds.add_auth(auth);
+        if let Err(e) = git_creds.add_auth(auth.clone()) {
+            warn(&format!("Skipped it: {}", e));
+            continue;
+        }
+        new_items.push(auth);
+    }
+
+    let checks = verify(&new_items).await;
+    if checks.iter().any(|check| !check.passed()) {
+        print_deploy_keys(&new_items);
+        if !prompt_yes_no("Some remotes can't be read, save anyway? [y/N]: ") {
+            halt("Nothing was saved");
+            std::process::exit(1);
+        }
     }
 
     match git_creds.save("/etc/artisan.cf") {
@@ -44,4 +672,6 @@ fn main() {
             &e.to_string()
         )),
     }
+
+    print_deploy_keys(&new_items);
 }
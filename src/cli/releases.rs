@@ -0,0 +1,254 @@
+use ais_common::git_data::GitCredentials;
+use ais_common::project_env::{reload_project, ProjectEnv};
+use ais_common::projects::{resolve_project, ProjectRegistry};
+use ais_common::release::{rollback_project, ReleaseLayout};
+use ais_common::teardown::{resolve_teardown, teardown_project, unregistered_projects, Disposal};
+use dusa_collection_utils::stringy::Stringy;
+use simple_pretty::{halt, notice, pass, warn};
+use std::io;
+
+fn usage() {
+    halt("Usage: ais_releases list <project> | rollback <project> [commit] | projects | name <project> [display name] | orphans | teardown <project> [--delete] | env <project> [set NAME=value... | unset NAME...]");
+    notice("A value of - is read from stdin");
+}
+
+#[tokio::main]
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    match (args.first().map(String::as_str), args.get(1)) {
+        (Some("list"), Some(project)) => {
+            let releases = match ReleaseLayout::for_project(&resolve_project(project)).releases() {
+                Ok(releases) => releases,
+                Err(e) => {
+                    halt(&format!("Failed to list releases of {}: {}", project, e));
+                    return;
+                }
+            };
+
+            if releases.is_empty() {
+                notice(&format!("{} has no releases", project));
+                return;
+            }
+
+            for release in releases {
+                let marker = if release.current { "*" } else { " " };
+                println!("{} {} {}", marker, release.commit, release.created);
+            }
+        }
+        (Some("rollback"), Some(project)) => {
+            match rollback_project(project, args.get(2).map(String::as_str)) {
+                Ok(live) => pass(&format!("{} is now running {}", project, live)),
+                Err(e) => halt(&format!("Failed to roll back {}: {}", project, e)),
+            }
+        }
+        (Some("projects"), None) => {
+            let registry = match ProjectRegistry::load() {
+                Ok(registry) => registry,
+                Err(e) => {
+                    halt(&format!("Failed to load the project registry: {}", e));
+                    return;
+                }
+            };
+
+            if registry.projects.is_empty() {
+                notice("No projects have been registered");
+                return;
+            }
+
+            for project in registry.projects {
+                println!(
+                    "{} {} [{}]",
+                    project.slug,
+                    project.name(),
+                    project.legacy_id
+                );
+            }
+        }
+        (Some("name"), Some(project)) => {
+            // Everything after the project is the name, no name clears it
+            let display_name = args[2..].join(" ");
+            let display_name = match display_name.trim() {
+                "" => None,
+                name => Some(Stringy::new(name)),
+            };
+
+            let result = ProjectRegistry::load().and_then(|mut registry| {
+                registry.set_display_name(project, display_name)?;
+                registry.save()
+            });
+            match result {
+                Ok(()) => pass(&format!("Updated the display name of {}", project)),
+                Err(e) => halt(&format!("Failed to name {}: {}", project, e)),
+            }
+        }
+        (Some("orphans"), None) => {
+            let registry = match ProjectRegistry::load() {
+                Ok(registry) => registry,
+                Err(e) => {
+                    halt(&format!("Failed to load the project registry: {}", e));
+                    return;
+                }
+            };
+
+            let mut found = false;
+            for project in &registry.projects {
+                if let Some(since) = project.orphaned_since {
+                    println!(
+                        "{} {} orphaned since {}",
+                        project.slug,
+                        project.name(),
+                        since
+                    );
+                    found = true;
+                }
+            }
+
+            match unregistered_projects(&registry) {
+                Ok(names) => {
+                    for name in names {
+                        println!("{} unregistered", name);
+                        found = true;
+                    }
+                }
+                Err(e) => warn(&format!("Failed to scan the project directory: {}", e)),
+            }
+
+            if !found {
+                notice("No orphaned projects");
+            }
+        }
+        (Some("teardown"), Some(project)) => teardown(project, &args[2..]).await,
+        (Some("env"), Some(project)) => env(project, &args[2..]).await,
+        _ => usage(),
+    }
+}
+
+// Tear a project down now instead of waiting for its grace period. Projects of
+// configured repositories are refused, the git monitor would just redeploy them.
+async fn teardown(project: &str, flags: &[String]) {
+    let disposal = match flags {
+        [] => Disposal::Archive,
+        [flag] if flag == "--delete" => Disposal::Delete,
+        _ => return usage(),
+    };
+
+    let mut registry = match ProjectRegistry::load() {
+        Ok(registry) => registry,
+        Err(e) => {
+            halt(&format!("Failed to load the project registry: {}", e));
+            return;
+        }
+    };
+    let name = match resolve_teardown(&registry, project) {
+        Ok(name) => name,
+        Err(e) => {
+            halt(&format!("Can't tear down {}: {}", project, e));
+            return;
+        }
+    };
+
+    // Unreadable credentials could still list the project, so never guess
+    let auths = match GitCredentials::new_vec() {
+        Ok(auths) => auths,
+        Err(e) => {
+            halt(&format!("Failed to load the credentials: {}", e));
+            return;
+        }
+    };
+    if auths.iter().any(|auth| *registry.slug(auth) == *name) {
+        halt(&format!(
+            "{} is still configured, remove its repository from the credentials first",
+            name
+        ));
+        return;
+    }
+
+    let mut steps = Vec::new();
+    let result = teardown_project(&name, disposal, &mut steps).await;
+    for step in steps {
+        notice(&step);
+    }
+
+    match result {
+        Ok(()) => {
+            registry.remove(&name);
+            if let Err(e) = registry.save() {
+                warn(&format!("Failed to update the project registry: {}", e));
+            }
+            pass(&format!("Tore down {}", name));
+        }
+        Err(e) => halt(&format!("Failed to tear down {}: {}", name, e)),
+    }
+}
+
+// Lists, sets or unsets a project's environment variables. Values are never
+// printed, and a changed project is restarted so they take effect.
+async fn env(project: &str, args: &[String]) {
+    let project = resolve_project(project);
+    let mut env = match ProjectEnv::load(&project) {
+        Ok(env) => env,
+        Err(e) => {
+            halt(&format!(
+                "Failed to load the variables of {}: {}",
+                project, e
+            ));
+            return;
+        }
+    };
+
+    let result = match args.split_first() {
+        None => {
+            if env.is_empty() {
+                notice(&format!("{} has no variables", project));
+            }
+            for (name, masked) in env.masked() {
+                println!("{}={}", name, masked);
+            }
+            return;
+        }
+        Some((action, names)) if action == "set" && !names.is_empty() => {
+            names.iter().try_for_each(|assignment| {
+                let (name, value) = assignment.split_once('=').unwrap_or((assignment, "-"));
+                match value {
+                    "-" => {
+                        let mut value = String::new();
+                        io::stdin().read_line(&mut value)?;
+                        env.set(name, value.trim_end_matches(['\r', '\n']))
+                    }
+                    value => env.set(name, value),
+                }
+            })
+        }
+        Some((action, names)) if action == "unset" && !names.is_empty() => {
+            for name in names {
+                if !env.unset(name) {
+                    warn(&format!("{} has no variable {}", project, name));
+                }
+            }
+            Ok(())
+        }
+        _ => return usage(),
+    };
+
+    if let Err(e) = result.and_then(|()| env.save()) {
+        halt(&format!(
+            "Failed to update the variables of {}: {}",
+            project, e
+        ));
+        return;
+    }
+
+    match reload_project(&project).await {
+        Ok(steps) => {
+            for step in steps {
+                notice(&step);
+            }
+            pass(&format!("Updated the variables of {}", project));
+        }
+        Err(e) => warn(&format!(
+            "Updated the variables of {}, but failed to restart it: {}",
+            project, e
+        )),
+    }
+}
@@ -1,13 +1,22 @@
 use crate::constants::{WEBSERVER_CONFIG_DIR, WEBSERVER_PORTS_CONFIG};
-use crate::directive::{parse_directive, scan_directories, Directive};
+use crate::directive::{parse_directive, project_id, scan_directories, Directive};
+use crate::project_env::apache_include;
 use crate::systemd::Services;
 use dusa_collection_utils::errors::ErrorArrayItem;
 use std::error::Error;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+/// PHP versions with an fpm pool to hand requests to.
+pub const SUPPORTED_PHP_VERSIONS: &[&str] = &["7.4", "8.1", "8.2"];
+
+/// Where the vhost of `directive` is written.
+pub fn apache_config_path(directive: &Directive) -> PathBuf {
+    Path::new(WEBSERVER_CONFIG_DIR).join(format!("{}.conf", directive.url))
+}
 
 fn read_existing_apache_config(directive: &Directive) -> Option<String> {
-    let config_path = Path::new(WEBSERVER_CONFIG_DIR).join(format!("{}.conf", directive.url));
+    let config_path = apache_config_path(directive);
     if config_path.exists() {
         if let Ok(content) = fs::read_to_string(config_path) {
             return Some(content);
@@ -20,20 +29,39 @@ pub fn create_apache_config(
     directive: &Directive,
     base_path: &Path,
 ) -> Result<bool, ErrorArrayItem> {
-    let php_fpm_config = match &directive.php_fpm_version {
-        Some(version) if version == "7.4" => {
-            r#"SetHandler "proxy:unix:/var/run/php/php7.4-fpm.sock|fcgi://localhost/""#
-        }
-        Some(version) if version == "8.1" => {
-            r#"SetHandler "proxy:unix:/var/run/php/php8.1-fpm.sock|fcgi://localhost/""#
-        }
-        Some(version) if version == "8.2" => {
-            r#"SetHandler "proxy:unix:/var/run/php/php8.2-fpm.sock|fcgi://localhost/""#
+    let config_content = render_apache_config(directive, base_path);
+    let config_path = apache_config_path(directive);
+
+    // Check if existing config matches the new config
+    if let Some(existing_config) = read_existing_apache_config(directive) {
+        if existing_config == config_content {
+            println!("Config for {} is up to date", directive.url);
+            return Ok(false); // No change made
         }
-        _ => "", // No PHP-FPM handler if version is not specified or not recognized
+    }
+
+    // Write the new config file if it doesn't match the existing one
+    fs::write(config_path, config_content)?;
+    Ok(true) // Configuration changed
+}
+
+/// The vhost serving the project at `base_path`.
+pub fn render_apache_config(directive: &Directive, base_path: &Path) -> String {
+    let php_fpm_config = match &directive.php_fpm_version {
+        Some(version) if SUPPORTED_PHP_VERSIONS.contains(&version.as_str()) => format!(
+            r#"SetHandler "proxy:unix:/var/run/php/php{}-fpm.sock|fcgi://localhost/""#,
+            version
+        ),
+        _ => String::new(), // No PHP-FPM handler if version is not specified or not recognized
     };
 
-    let config_content = format!(
+    // The project's own variables, kept out of this world readable file
+    let project_env = match project_id(base_path) {
+        Some(id) => format!("IncludeOptional {}", apache_include(&id).display()),
+        None => String::new(),
+    };
+
+    format!(
         r#"<VirtualHost *:{}>
     ServerName {}
     DocumentRoot {}
@@ -48,6 +76,7 @@ pub fn create_apache_config(
     <FilesMatch \.php$>
         {}
     </FilesMatch>
+    {}
     ErrorLog ${{APACHE_LOG_DIR}}/error.log
     CustomLog ${{APACHE_LOG_DIR}}/access.log combined
 </VirtualHost>
@@ -57,21 +86,8 @@ pub fn create_apache_config(
         base_path.display(),
         base_path.display(),
         php_fpm_config,
-    );
-
-    let config_path = Path::new(WEBSERVER_CONFIG_DIR).join(format!("{}.conf", directive.url));
-
-    // Check if existing config matches the new config
-    if let Some(existing_config) = read_existing_apache_config(directive) {
-        if existing_config == config_content {
-            println!("Config for {} is up to date", directive.url);
-            return Ok(false); // No change made
-        }
-    }
-
-    // Write the new config file if it doesn't match the existing one
-    fs::write(config_path, config_content)?;
-    Ok(true) // Configuration changed
+        project_env,
+    )
 }
 
 async fn check_apache_ports(directive: &Directive) -> Result<(), Box<dyn Error>> {
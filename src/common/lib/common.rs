@@ -1,12 +1,15 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use dusa_collection_utils::stringy::Stringy;
 use serde::{Deserialize, Serialize};
 
+use crate::systemd::ProcessInfo;
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum QueryType {
     Status,
     AllStatuses,
+    Services,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -20,11 +23,14 @@ pub struct QueryResponse {
     pub version: Stringy,
     pub app_status: Option<Status>,
     pub all_statuses: Option<HashMap<AppName, Status>>, // New field for all statuses
+    #[serde(default)]
+    pub services: Option<Vec<ProcessInfo>>, // Detailed service info reported by ais_services
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum MessageType {
     StatusUpdate,
+    ServicesUpdate,
     Acknowledgment,
     Query,
 }
@@ -45,6 +51,25 @@ pub enum AppStatus {
     Stopped,
     TimedOut,
     Warning,
+    /// A remote rejected the configured credentials, the token or key needs replacing.
+    AuthFailure,
+    /// A revision was refused for lacking a trusted signature, someone may be
+    /// pushing code that shouldn't run here.
+    Untrusted,
+}
+
+impl AppStatus {
+    /// Ranks statuses so the worst of several can be picked, higher is worse.
+    pub fn severity(&self) -> u8 {
+        match self {
+            AppStatus::Running => 0,
+            AppStatus::Warning => 1,
+            AppStatus::TimedOut => 2,
+            AppStatus::Stopped => 3,
+            AppStatus::AuthFailure => 4,
+            AppStatus::Untrusted => 5,
+        }
+    }
 }
 
 /// Enum representing the name of an application.
@@ -66,4 +91,89 @@ pub struct Status {
     pub app_status: AppStatus,
     pub timestamp: u64,
     pub version: Stringy, // Add version field
+    /// Details for the status, such as the output of a failed deploy hook.
+    #[serde(default)]
+    pub message: Option<Stringy>,
+    /// Statuses of the parts of the application keyed by name, such as one per
+    /// repository for `Github`. Every report carries all of them.
+    #[serde(default)]
+    pub components: Option<BTreeMap<String, ComponentStatus>>,
+}
+
+/// The status of one part of an application, shown as `<app>/<name>`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ComponentStatus {
+    pub app_status: AppStatus,
+    pub timestamp: u64,
+    pub message: Option<Stringy>,
+}
+
+impl Status {
+    /// Sets `app_status` to the worst of the component statuses and, if the
+    /// status has no message of its own, names the unhealthy components.
+    pub fn derive_from_components(&mut self) {
+        let components = match &self.components {
+            Some(components) if !components.is_empty() => components,
+            _ => return,
+        };
+
+        self.app_status = components
+            .values()
+            .map(|component| component.app_status.clone())
+            .max_by_key(AppStatus::severity)
+            .unwrap_or(AppStatus::Running);
+
+        if self.message.is_none() && self.app_status != AppStatus::Running {
+            let unhealthy: Vec<&str> = components
+                .iter()
+                .filter(|(_, component)| component.app_status != AppStatus::Running)
+                .map(|(name, _)| name.as_str())
+                .collect();
+            self.message = Some(Stringy::from(format!(
+                "{} of {} unhealthy: {}",
+                unhealthy.len(),
+                components.len(),
+                unhealthy.join(", ")
+            )));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn component(app_status: AppStatus) -> ComponentStatus {
+        ComponentStatus {
+            app_status,
+            timestamp: 0,
+            message: None,
+        }
+    }
+
+    #[test]
+    fn test_derive_from_components() {
+        let mut status = Status {
+            app_name: AppName::Github,
+            app_status: AppStatus::Running,
+            timestamp: 0,
+            version: Stringy::new("test"),
+            message: None,
+            components: Some(BTreeMap::from([
+                (String::from("aaa"), component(AppStatus::Running)),
+                (String::from("bbb"), component(AppStatus::Warning)),
+            ])),
+        };
+
+        status.derive_from_components();
+        assert_eq!(status.app_status, AppStatus::Warning);
+        assert_eq!(status.message.as_deref(), Some("1 of 2 unhealthy: bbb"));
+
+        status.components = Some(BTreeMap::from([
+            (String::from("aaa"), component(AppStatus::AuthFailure)),
+            (String::from("bbb"), component(AppStatus::Warning)),
+        ]));
+        status.derive_from_components();
+        assert_eq!(status.app_status, AppStatus::AuthFailure);
+    }
 }
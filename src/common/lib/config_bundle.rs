@@ -0,0 +1,578 @@
+// Passphrase encrypted bundles of platform configuration, used to move sites
+// between hosts. A bundle carries the credential entries of the exported
+// repositories with their secrets and deploy keys, their registered projects
+// and the directives of their live releases.
+//
+// The passphrase is stretched with Argon2id and the bundle sealed with
+// ChaCha20-Poly1305, so it can be copied around like any other file. The cost
+// parameters and salt travel in the clear next to the ciphertext.
+
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    stringy::Stringy,
+};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    constants::{ARTISANCF, PROJECT_BASE_DIR},
+    credential_schema::{self, CREDENTIALS_VERSION},
+    deploy_keys::DeployKey,
+    directive::{parse_directive, project_id, scan_directories, Directive},
+    git_data::{GitAuth, GitCredentials, GitProvider},
+    projects::{ProjectEntry, ProjectRegistry},
+    release::ReleaseLayout,
+    system::current_timestamp,
+};
+
+const BUNDLE_FORMAT: &str = "ais-bundle";
+const BUNDLE_VERSION: u64 = 1;
+
+/// Bundles asking for more memory than this to derive their key are refused.
+const MAX_MEMORY_KIB: u32 = 1024 * 1024;
+
+/// Everything exported for a set of repositories.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConfigBundle {
+    /// Hostname of the machine the bundle was exported on.
+    pub host: String,
+    /// Unix timestamp of the export.
+    pub created: u64,
+    /// The credential file version the entries were written in.
+    pub credentials_version: u64,
+    pub repositories: Vec<BundledRepository>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BundledRepository {
+    /// The credential entry, secrets included.
+    pub auth: GitAuth,
+    /// Its registered project, `None` if it was never deployed.
+    pub project: Option<ProjectEntry>,
+    /// The directive of the live release.
+    pub directive: Option<Directive>,
+    /// The public and private half of the deploy key of ssh remotes.
+    pub deploy_key: Option<(Stringy, Stringy)>,
+}
+
+/// How entries already configured on the importing host are treated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportMode {
+    /// Local entries are kept and the bundled ones skipped.
+    Merge,
+    /// Bundled entries overwrite local ones.
+    Replace,
+}
+
+/// The outcome of merging a bundle.
+#[derive(Debug, Default)]
+pub struct ImportReport {
+    /// Entries added or replaced.
+    pub imported: Vec<GitAuth>,
+    /// Everything that didn't carry over as it was on the exporting host.
+    pub conflicts: Vec<String>,
+}
+
+// The file written to disk, only the ciphertext is secret
+#[derive(Serialize, Deserialize)]
+struct SealedBundle {
+    format: String,
+    version: u64,
+    kdf: KdfParams,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Argon2id costs and salt the bundle key is derived with.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct KdfParams {
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+    salt: String,
+}
+
+impl KdfParams {
+    fn new(memory_kib: u32, iterations: u32) -> Self {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        Self {
+            memory_kib,
+            iterations,
+            parallelism: 1,
+            salt: base64::encode(salt),
+        }
+    }
+
+    fn derive_key(&self, passphrase: &str) -> Result<[u8; 32], ErrorArrayItem> {
+        if self.memory_kib > MAX_MEMORY_KIB {
+            return Err(ErrorArrayItem::new(
+                Errors::InvalidFile,
+                format!(
+                    "The bundle asks for {} KiB to derive its key",
+                    self.memory_kib
+                ),
+            ));
+        }
+
+        let salt = decode(&self.salt)?;
+        let params = Params::new(self.memory_kib, self.iterations, self.parallelism, Some(32))
+            .map_err(kdf_error)?;
+        let mut key = [0u8; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(kdf_error)?;
+        Ok(key)
+    }
+}
+
+impl Default for KdfParams {
+    // 64 MiB and three passes, a second or so on a small server
+    fn default() -> Self {
+        Self::new(64 * 1024, 3)
+    }
+}
+
+impl ConfigBundle {
+    /// Collects the repositories named by `selectors`, every configured one if
+    /// there are none. Selectors are the ones `GitCredentials::position` takes.
+    pub async fn collect(selectors: &[String]) -> Result<Self, ErrorArrayItem> {
+        let git_creds = GitCredentials::new()?;
+        let registry = ProjectRegistry::load()?;
+
+        let mut indices = match selectors.is_empty() {
+            true => (0..git_creds.auth_items.len()).collect(),
+            false => selectors
+                .iter()
+                .map(|selector| git_creds.position(selector))
+                .collect::<Result<Vec<usize>, ErrorArrayItem>>()?,
+        };
+        indices.sort_unstable();
+        indices.dedup();
+
+        let mut repositories = Vec::with_capacity(indices.len());
+        for index in indices {
+            let auth = git_creds.auth_items[index].clone();
+            let project = registry.get(&auth).cloned();
+
+            let directive = match &project {
+                Some(project) => live_directive(&project.slug).await,
+                None => None,
+            };
+            // Keys are only generated for ssh remotes
+            let deploy_key = match auth.provider {
+                GitProvider::Ssh { .. } => DeployKey::load(&auth)
+                    .ok()
+                    .map(|key| (key.public_key.clone(), key.private_key().clone())),
+                _ => None,
+            };
+
+            repositories.push(BundledRepository {
+                auth,
+                project,
+                directive,
+                deploy_key,
+            });
+        }
+
+        Ok(Self {
+            host: gethostname::gethostname().to_string_lossy().into_owned(),
+            created: current_timestamp(),
+            credentials_version: CREDENTIALS_VERSION,
+            repositories,
+        })
+    }
+
+    /// Encrypts the bundle with `passphrase`.
+    pub fn seal(&self, passphrase: &str) -> Result<String, ErrorArrayItem> {
+        self.seal_with(passphrase, KdfParams::default())
+    }
+
+    fn seal_with(&self, passphrase: &str, kdf: KdfParams) -> Result<String, ErrorArrayItem> {
+        let key = kdf.derive_key(passphrase)?;
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut nonce);
+
+        let plaintext = serde_json::to_vec(self)?;
+        let ciphertext = ChaCha20Poly1305::new(Key::from_slice(&key))
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &plaintext,
+                    aad: BUNDLE_FORMAT.as_bytes(),
+                },
+            )
+            .map_err(|_| {
+                ErrorArrayItem::new(
+                    Errors::GeneralError,
+                    String::from("Failed to encrypt the bundle"),
+                )
+            })?;
+
+        let sealed = SealedBundle {
+            format: BUNDLE_FORMAT.to_owned(),
+            version: BUNDLE_VERSION,
+            kdf,
+            nonce: base64::encode(nonce),
+            ciphertext: base64::encode(ciphertext),
+        };
+        Ok(serde_json::to_string_pretty(&sealed)?)
+    }
+
+    /// Decrypts a sealed bundle. Credential entries written by an older
+    /// version are migrated like the credential file is.
+    pub fn open(sealed: &str, passphrase: &str) -> Result<Self, ErrorArrayItem> {
+        let sealed: SealedBundle = serde_json::from_str(sealed).map_err(|_| {
+            ErrorArrayItem::new(
+                Errors::InvalidFile,
+                String::from("Not a configuration bundle"),
+            )
+        })?;
+        if sealed.format != BUNDLE_FORMAT || sealed.version > BUNDLE_VERSION {
+            return Err(ErrorArrayItem::new(
+                Errors::InvalidFile,
+                format!(
+                    "Unsupported bundle {} version {}",
+                    sealed.format, sealed.version
+                ),
+            ));
+        }
+
+        let key = sealed.kdf.derive_key(passphrase)?;
+        let nonce = decode(&sealed.nonce)?;
+        if nonce.len() != 12 {
+            return Err(ErrorArrayItem::new(
+                Errors::InvalidFile,
+                String::from("The bundle nonce is damaged"),
+            ));
+        }
+        let plaintext = ChaCha20Poly1305::new(Key::from_slice(&key))
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &decode(&sealed.ciphertext)?,
+                    aad: BUNDLE_FORMAT.as_bytes(),
+                },
+            )
+            .map_err(|_| {
+                ErrorArrayItem::new(
+                    Errors::GeneralError,
+                    String::from("The bundle can't be decrypted, the passphrase is wrong or the file is damaged"),
+                )
+            })?;
+
+        let mut document: Value = serde_json::from_slice(&plaintext)?;
+        migrate_credentials(&mut document)?;
+        Ok(serde_json::from_value(document)?)
+    }
+
+    /// Merges the bundle into the local configuration. `ports_in_use` holds
+    /// the ports the directives of local projects listen on, by project.
+    pub fn merge_into(
+        &self,
+        mode: ImportMode,
+        git_creds: &mut GitCredentials,
+        registry: &mut ProjectRegistry,
+        ports_in_use: &[(String, u16)],
+    ) -> ImportReport {
+        let mut report = ImportReport::default();
+
+        for repository in &self.repositories {
+            let auth = &repository.auth;
+            let name = format!("{}/{}@{}", auth.user, auth.repo, auth.branch);
+
+            let local = git_creds
+                .auth_items
+                .iter()
+                .position(|local| *local.id() == *auth.id());
+            match (local, mode) {
+                (Some(_), ImportMode::Merge) => {
+                    report.conflicts.push(format!(
+                        "{} is already configured, kept the local entry",
+                        name
+                    ));
+                    continue;
+                }
+                (Some(index), ImportMode::Replace) => {
+                    git_creds.auth_items[index] = auth.clone();
+                    report
+                        .conflicts
+                        .push(format!("{} was already configured, replaced it", name));
+                }
+                (None, _) => {
+                    if let Err(e) = git_creds.add_auth(auth.clone()) {
+                        report.conflicts.push(format!("{}: {}", name, e));
+                        continue;
+                    }
+                }
+            }
+            report.imported.push(auth.clone());
+
+            let project = match &repository.project {
+                Some(project) => project,
+                None => continue,
+            };
+            let slug = registry.import(project, mode == ImportMode::Replace);
+            if *slug != *project.slug {
+                report.conflicts.push(format!(
+                    "{}: the project name {} is taken, it is deployed as {}",
+                    name, project.slug, slug
+                ));
+            }
+
+            if let Some(directive) = &repository.directive {
+                for (other, port) in ports_in_use {
+                    if *port == directive.port && *other != *slug {
+                        report.conflicts.push(format!(
+                            "{}: port {} is already used by {}",
+                            name, port, other
+                        ));
+                    }
+                }
+            }
+        }
+
+        report
+    }
+
+    fn deploy_key(&self, auth: &GitAuth) -> Option<DeployKey> {
+        self.repositories
+            .iter()
+            .find(|repository| *repository.auth.id() == *auth.id())
+            .and_then(|repository| repository.deploy_key.clone())
+            .map(|(public_key, private_key)| DeployKey::from_parts(public_key, private_key))
+    }
+}
+
+/// Merges `bundle` into this host's configuration and, unless `dry_run`,
+/// saves the credentials, the project registry and the deploy keys.
+pub async fn import_bundle(
+    bundle: &ConfigBundle,
+    mode: ImportMode,
+    dry_run: bool,
+) -> Result<ImportReport, ErrorArrayItem> {
+    let mut git_creds = GitCredentials::bootstrap_git_credentials()?;
+    let mut registry = ProjectRegistry::load()?;
+    let report = bundle.merge_into(mode, &mut git_creds, &mut registry, &ports_in_use().await);
+
+    if dry_run {
+        return Ok(report);
+    }
+
+    for auth in &report.imported {
+        if let Some(key) = bundle.deploy_key(auth) {
+            key.save(auth)?;
+        }
+    }
+    // Projects first, the git monitor picks new credentials up right away
+    registry.save()?;
+    git_creds.save(ARTISANCF)?;
+    Ok(report)
+}
+
+/// Writes a sealed bundle readable by root only. Existing files are never
+/// overwritten.
+pub fn write_sealed(path: &Path, sealed: &str) -> Result<(), ErrorArrayItem> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(sealed.as_bytes())?;
+    Ok(())
+}
+
+// Bundles keep the credential entries in the version they were exported in
+fn migrate_credentials(document: &mut Value) -> Result<(), ErrorArrayItem> {
+    let version = document["credentials_version"].as_u64().unwrap_or(0);
+    let repositories = match document["repositories"].as_array_mut() {
+        Some(repositories) => repositories,
+        None => return Ok(()),
+    };
+
+    let auth_items: Vec<Value> = repositories
+        .iter_mut()
+        .map(|repository| repository["auth"].take())
+        .collect();
+    let mut credentials = serde_json::json!({ "version": version, "auth_items": auth_items });
+    credential_schema::migrate(&mut credentials)?;
+
+    let migrated = credentials["auth_items"].as_array_mut();
+    for (repository, auth) in repositories.iter_mut().zip(migrated.into_iter().flatten()) {
+        repository["auth"] = auth.take();
+    }
+    document["credentials_version"] = Value::from(CREDENTIALS_VERSION);
+    Ok(())
+}
+
+async fn live_directive(slug: &str) -> Option<Directive> {
+    let path: PathBuf = ReleaseLayout::for_project(slug)
+        .current_dir()
+        .join("directive.ais");
+    match path.exists() {
+        true => parse_directive(&path).await.ok(),
+        false => None,
+    }
+}
+
+// The ports the directives of the projects deployed here listen on
+async fn ports_in_use() -> Vec<(String, u16)> {
+    let mut ports = Vec::new();
+    for path in scan_directories(PROJECT_BASE_DIR).await.unwrap_or_default() {
+        if let (Some(project), Ok(directive)) = (project_id(&path), parse_directive(&path).await) {
+            ports.push((project, directive.port));
+        }
+    }
+    ports
+}
+
+fn decode(data: &str) -> Result<Vec<u8>, ErrorArrayItem> {
+    base64::decode(data).map_err(|e| {
+        ErrorArrayItem::new(Errors::InvalidFile, format!("The bundle is damaged: {}", e))
+    })
+}
+
+fn kdf_error(err: argon2::Error) -> ErrorArrayItem {
+    ErrorArrayItem::new(
+        Errors::GeneralError,
+        format!("Failed to derive the bundle key: {}", err),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hooks::DeployHooks;
+
+    fn auth(repo: &str) -> GitAuth {
+        let mut auth = GitAuth::new("owner", repo, "main");
+        auth.token = Stringy::new(&format!("ghp_{}", repo));
+        auth
+    }
+
+    fn project(repo: &str, slug: &str) -> ProjectEntry {
+        ProjectEntry {
+            slug: Stringy::new(slug),
+            display_name: Some(Stringy::new("Exported")),
+            user: Stringy::new("owner"),
+            repo: Stringy::new(repo),
+            branch: Stringy::new("main"),
+            legacy_id: auth(repo).id(),
+            orphaned_since: None,
+        }
+    }
+
+    fn bundle() -> ConfigBundle {
+        let directive = Directive {
+            url: String::from("site.example.com"),
+            apache: true,
+            port: 8080,
+            php_fpm_version: None,
+            nodejs_bool: false,
+            nodejs_version: None,
+            directive_executed: false,
+            hooks: DeployHooks::default(),
+        };
+
+        ConfigBundle {
+            host: String::from("old-host"),
+            created: 0,
+            credentials_version: CREDENTIALS_VERSION,
+            repositories: vec![
+                BundledRepository {
+                    auth: auth("site"),
+                    project: Some(project("site", "site")),
+                    directive: Some(directive),
+                    deploy_key: None,
+                },
+                BundledRepository {
+                    auth: auth("api"),
+                    project: Some(project("api", "api")),
+                    directive: None,
+                    deploy_key: None,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let bundle = bundle();
+        let sealed = bundle
+            .seal_with("correct horse", KdfParams::new(64, 1))
+            .unwrap();
+        assert!(!sealed.contains("ghp_site"));
+
+        let opened = ConfigBundle::open(&sealed, "correct horse").unwrap();
+        assert_eq!(opened.repositories.len(), 2);
+        assert_eq!(&*opened.repositories[0].auth.token, "ghp_site");
+        assert_eq!(
+            opened.repositories[0].directive.as_ref().unwrap().port,
+            8080
+        );
+
+        assert!(ConfigBundle::open(&sealed, "wrong horse").is_err());
+        let mut tampered: Value = serde_json::from_str(&sealed).unwrap();
+        tampered["ciphertext"] = Value::from(base64::encode(b"not the bundle"));
+        assert!(ConfigBundle::open(&tampered.to_string(), "correct horse").is_err());
+    }
+
+    #[test]
+    fn test_merge_and_replace() {
+        let bundle = bundle();
+        let mut local_site = auth("site");
+        local_site.token = Stringy::new("ghp_local");
+        let local = GitCredentials {
+            auth_items: vec![local_site],
+        };
+        // Another repository already owns the slug `api` here
+        let registry = ProjectRegistry {
+            projects: vec![project("other", "api")],
+        };
+        let ports = [(String::from("api"), 8080)];
+
+        let (mut git_creds, mut projects) = (local.clone(), registry.clone());
+        let report = bundle.merge_into(ImportMode::Merge, &mut git_creds, &mut projects, &ports);
+        assert_eq!(report.imported.len(), 1);
+        assert_eq!(&*git_creds.auth_items[0].token, "ghp_local");
+        assert_eq!(git_creds.auth_items.len(), 2);
+        let imported = projects.get(&auth("api")).unwrap();
+        assert_ne!(&*imported.slug, "api");
+        // The kept entry and the renamed project
+        assert_eq!(report.conflicts.len(), 2, "{:?}", report.conflicts);
+
+        let (mut git_creds, mut projects) = (local, registry);
+        let report = bundle.merge_into(ImportMode::Replace, &mut git_creds, &mut projects, &ports);
+        assert_eq!(report.imported.len(), 2);
+        assert_eq!(&*git_creds.auth_items[0].token, "ghp_site");
+        assert_eq!(&*projects.get(&auth("site")).unwrap().slug, "site");
+        // The replaced entry, the port clash and the renamed project
+        assert_eq!(report.conflicts.len(), 3, "{:?}", report.conflicts);
+    }
+
+    #[test]
+    fn test_older_credentials_are_migrated() {
+        let mut document = serde_json::to_value(bundle()).unwrap();
+        document["credentials_version"] = Value::from(0);
+        document["repositories"][1]["auth"] =
+            serde_json::json!({ "user": "owner", "repo": "api", "branch": "main", "token": "" });
+
+        migrate_credentials(&mut document).unwrap();
+        let bundle: ConfigBundle = serde_json::from_value(document).unwrap();
+        assert_eq!(bundle.credentials_version, CREDENTIALS_VERSION);
+        assert!(bundle.repositories[1].auth.access_token().is_none());
+    }
+}
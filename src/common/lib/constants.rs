@@ -1,10 +1,47 @@
 // Github credential file
 pub const ARTISANCF: &str = "/etc/artisan.cf";
 
+// Per repository ssh deploy keys, private halves encrypted with the secret store
+pub const DEPLOY_KEY_DIR: &str = "/etc/ais/deploy_keys";
+
+// Deployed projects and the number of releases kept per project for rollbacks
+pub const PROJECT_BASE_DIR: &str = "/var/www/ais";
+pub const RELEASES_TO_KEEP: usize = 5;
+
+// Choice of the backend encrypting secrets at rest, and the key of the local one
+pub const SECRETS_CONFIG: &str = "/etc/ais/secrets.json";
+pub const SECRET_KEY_FILE: &str = "/etc/ais/secret.key";
+
+// Environment variables of deployed projects, encrypted, and the root-only
+// files rendered from them for the services
+pub const PROJECT_ENV_DIR: &str = "/etc/ais/env";
+
+// Readable slugs and display names of deployed projects, keyed by repository
+pub const PROJECT_REGISTRY: &str = "/etc/ais/projects.json";
+
+// Signers trusted to sign deployed revisions, ssh keys in git's allowed signers
+// format and gpg keys imported into a keyring of their own
+pub const ALLOWED_SIGNERS: &str = "/etc/ais/allowed_signers";
+pub const SIGNING_KEYRING: &str = "/etc/ais/gnupg";
+
+// Projects whose repository was removed from the credentials are torn down after
+// this many seconds, their directory is archived here first
+pub const ORPHAN_GRACE_PERIOD: u64 = 24 * 60 * 60;
+pub const PROJECT_ARCHIVE_DIR: &str = "/var/backups/ais";
+
+// Every deploy hook run is appended here as a line of json
+pub const DEPLOY_AUDIT_LOG: &str = "/var/log/ais_deploy.log";
+
 // Server address for the ais manager server and tui
 pub const SERVERADDRESS: &str = "0.0.0.0:8640";
 pub const SERVERPORT: &str = "8640";
 
+// Listener for push webhooks, only started when a repository has a webhook secret
+pub const WEBHOOK_ADDRESS: &str = "0.0.0.0:8641";
+
+// Unit files of the services set up for projects
+pub const SYSTEMD_UNIT_DIR: &str = "/etc/systemd/system";
+
 // Webserver configuration constants
 pub const WEBSERVER_CONFIG_DIR: &str = "/etc/apache2/sites-available";
 pub const WEBSERVER_PORTS_CONFIG: &str = "/etc/apache2/ports.conf";
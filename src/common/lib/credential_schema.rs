@@ -0,0 +1,265 @@
+// Versioning of the credential file. The decrypted document carries a top level
+// `version`, files written before versioning have none and count as version 0.
+// Documents are migrated forward one version at a time on the raw json, so an
+// old file never has to deserialize into the current `GitAuth`.
+//
+// Changing the stored format means appending a step to `MIGRATIONS`, which
+// raises `CREDENTIALS_VERSION`, and adding a fixture of the old format.
+
+use std::{
+    fs::OpenOptions,
+    io::{ErrorKind, Write},
+    os::unix::fs::OpenOptionsExt,
+};
+
+use dusa_collection_utils::errors::{ErrorArrayItem, Errors};
+use serde_json::{Map, Value};
+
+type Migration = fn(&mut Map<String, Value>) -> Result<(), ErrorArrayItem>;
+
+/// `MIGRATIONS[n]` takes a version `n` document to version `n + 1`.
+const MIGRATIONS: &[Migration] = &[v0_to_v1];
+
+/// The version written by this build.
+pub const CREDENTIALS_VERSION: u64 = MIGRATIONS.len() as u64;
+
+/// Brings a decrypted credential document up to `CREDENTIALS_VERSION`.
+/// Returns the version it was migrated from, `None` if it was current.
+pub fn migrate(document: &mut Value) -> Result<Option<u64>, ErrorArrayItem> {
+    let document = document.as_object_mut().ok_or_else(|| {
+        ErrorArrayItem::new(
+            Errors::InvalidFile,
+            String::from("The credential file is not a json object"),
+        )
+    })?;
+
+    let version = match document.get("version") {
+        None => 0,
+        Some(version) => version.as_u64().ok_or_else(|| {
+            ErrorArrayItem::new(
+                Errors::InvalidFile,
+                format!("Invalid credential file version: {}", version),
+            )
+        })?,
+    };
+
+    // Saving a newer file would drop the settings this build doesn't know
+    if version > CREDENTIALS_VERSION {
+        return Err(ErrorArrayItem::new(
+            Errors::InvalidFile,
+            format!(
+                "The credential file is version {}, this build only reads up to version {}",
+                version, CREDENTIALS_VERSION
+            ),
+        ));
+    }
+    if version == CREDENTIALS_VERSION {
+        return Ok(None);
+    }
+
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(document)?;
+    }
+    document.insert(String::from("version"), Value::from(CREDENTIALS_VERSION));
+
+    Ok(Some(version))
+}
+
+/// Keeps the still encrypted contents of a file about to be migrated next to
+/// it as `<file>.v<version>.bak`. An existing backup of the same version is
+/// never overwritten, it holds the oldest copy.
+pub fn backup(file_path: &str, version: u64, contents: &str) -> Result<String, ErrorArrayItem> {
+    let backup = format!("{}.v{}.bak", file_path, version);
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&backup);
+
+    match file {
+        Ok(mut file) => file.write_all(contents.as_bytes())?,
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => (),
+        Err(e) => return Err(ErrorArrayItem::from(e)),
+    }
+    Ok(backup)
+}
+
+// Every setting is written out and unset tokens are stored as the
+// placeholder. Unversioned files come from any point in the history of
+// `GitAuth` and only ever lack fields added later.
+fn v0_to_v1(document: &mut Map<String, Value>) -> Result<(), ErrorArrayItem> {
+    let items = match document.get_mut("auth_items") {
+        Some(Value::Array(items)) => items,
+        _ => {
+            return Err(ErrorArrayItem::new(
+                Errors::InvalidFile,
+                String::from("The credential file has no auth_items"),
+            ))
+        }
+    };
+
+    for (index, item) in items.iter_mut().enumerate() {
+        let item = item.as_object_mut().ok_or_else(|| {
+            ErrorArrayItem::new(
+                Errors::InvalidFile,
+                format!("Credential entry {} is not a json object", index),
+            )
+        })?;
+
+        for required in ["user", "repo", "branch"] {
+            if !matches!(item.get(required), Some(Value::String(_))) {
+                return Err(ErrorArrayItem::new(
+                    Errors::InvalidFile,
+                    format!("Credential entry {} has no {}", index, required),
+                ));
+            }
+        }
+
+        match item.get("token") {
+            Some(Value::String(token)) if !token.trim().is_empty() => (),
+            _ => {
+                item.insert(String::from("token"), Value::from("******"));
+            }
+        }
+
+        // The defaults as they were when version 1 was introduced
+        for (field, default) in [
+            ("provider", Value::from("GitHub")),
+            ("pin", Value::Null),
+            ("webhook_secret", Value::Null),
+            ("poll_interval", Value::Null),
+            ("conflict_policy", Value::from("Refuse")),
+            ("submodules", Value::from(false)),
+            ("lfs", Value::from(false)),
+            ("signature_policy", Value::from("Off")),
+        ] {
+            item.entry(field).or_insert(default);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git_data::{
+        ConflictPolicy, GitCredentials, GitPin, GitProvider, SignaturePolicy, TOKEN_PLACEHOLDER,
+    };
+    use std::fs;
+    use tempfile::TempDir;
+
+    // Every format the credential file has been written in, oldest first
+    const FIXTURES: [(&str, &str); 9] = [
+        (
+            "v0-baseline",
+            include_str!("fixtures/credentials/v0-baseline.json"),
+        ),
+        (
+            "v0-providers",
+            include_str!("fixtures/credentials/v0-providers.json"),
+        ),
+        ("v0-pins", include_str!("fixtures/credentials/v0-pins.json")),
+        (
+            "v0-webhooks",
+            include_str!("fixtures/credentials/v0-webhooks.json"),
+        ),
+        (
+            "v0-poll-intervals",
+            include_str!("fixtures/credentials/v0-poll-intervals.json"),
+        ),
+        (
+            "v0-conflict-policies",
+            include_str!("fixtures/credentials/v0-conflict-policies.json"),
+        ),
+        (
+            "v0-checkout-extras",
+            include_str!("fixtures/credentials/v0-checkout-extras.json"),
+        ),
+        (
+            "v0-signature-policies",
+            include_str!("fixtures/credentials/v0-signature-policies.json"),
+        ),
+        ("v1", include_str!("fixtures/credentials/v1.json")),
+    ];
+
+    fn parse(name: &str) -> (GitCredentials, Option<u64>) {
+        let (_, json) = FIXTURES
+            .iter()
+            .find(|(fixture, _)| *fixture == name)
+            .unwrap();
+        GitCredentials::parse(json).unwrap()
+    }
+
+    #[test]
+    fn test_every_format_migrates() {
+        for (name, json) in FIXTURES {
+            let mut document: Value = serde_json::from_str(json).unwrap();
+            let expected = match name.starts_with("v0") {
+                true => Some(0),
+                false => None,
+            };
+            assert_eq!(migrate(&mut document).unwrap(), expected, "{}", name);
+            assert_eq!(document["version"], Value::from(CREDENTIALS_VERSION));
+            // A migrated document is current
+            assert_eq!(migrate(&mut document).unwrap(), None, "{}", name);
+            serde_json::from_value::<GitCredentials>(document).unwrap();
+        }
+
+        let (baseline, _) = parse("v0-baseline");
+        assert_eq!(&*baseline.auth_items[0].token, TOKEN_PLACEHOLDER);
+        assert_eq!(&*baseline.auth_items[1].token, "ghp_example");
+        assert_eq!(baseline.auth_items[0].provider, GitProvider::GitHub);
+
+        let (providers, _) = parse("v0-providers");
+        assert_eq!(
+            providers.auth_items[1].remote_url().to_string(),
+            "ssh://git@git.example.com/owner/api.git"
+        );
+        let (pins, _) = parse("v0-pins");
+        assert!(matches!(
+            pins.auth_items[0].pin,
+            Some(GitPin::TagPattern(_))
+        ));
+        assert_eq!(pins.auth_items[0].poll_interval, None);
+        let (intervals, _) = parse("v0-poll-intervals");
+        assert_eq!(intervals.auth_items[0].poll_interval, Some(90));
+        let (conflicts, _) = parse("v0-conflict-policies");
+        assert_eq!(
+            conflicts.auth_items[0].conflict_policy,
+            ConflictPolicy::Stash
+        );
+        let (extras, _) = parse("v0-checkout-extras");
+        assert!(extras.auth_items[0].submodules && extras.auth_items[0].lfs);
+        assert_eq!(extras.auth_items[0].signature_policy, SignaturePolicy::Off);
+        let (signatures, _) = parse("v0-signature-policies");
+        assert_eq!(
+            signatures.auth_items[0].signature_policy,
+            SignaturePolicy::Tag
+        );
+    }
+
+    #[test]
+    fn test_unreadable_documents_are_refused() {
+        let mut newer = serde_json::json!({ "version": CREDENTIALS_VERSION + 1, "auth_items": [] });
+        assert!(migrate(&mut newer).is_err());
+
+        let mut incomplete =
+            serde_json::json!({ "auth_items": [{ "user": "owner", "repo": "site" }] });
+        assert!(migrate(&mut incomplete).is_err());
+
+        assert!(migrate(&mut Value::from("credentials")).is_err());
+    }
+
+    #[test]
+    fn test_backup_keeps_the_oldest_copy() {
+        let root = TempDir::new().unwrap();
+        let file = root.path().join("artisan.cf");
+        let file = file.to_str().unwrap();
+
+        let copy = backup(file, 0, "original").unwrap();
+        assert_eq!(copy, format!("{}.v0.bak", file));
+        backup(file, 0, "rewritten").unwrap();
+        assert_eq!(fs::read_to_string(&copy).unwrap(), "original");
+    }
+}
@@ -1,4 +1,9 @@
-use dusa_collection_utils::errors::ErrorArrayItem;
+use crate::constants::PROJECT_BASE_DIR;
+use crate::directive_schema::{check_directive, DirectiveReport};
+use crate::hooks::DeployHooks;
+use crate::node::run_npm_install;
+use crate::release::{RELEASES_DIR, REPO_DIR};
+use dusa_collection_utils::errors::{ErrorArrayItem, Errors};
 use dusa_collection_utils::functions::open_file;
 use dusa_collection_utils::stringy::Stringy;
 use dusa_collection_utils::types::PathType;
@@ -19,12 +24,29 @@ pub struct Directive {
     pub nodejs_version: Option<String>,
     // pub nodejs_exec_command: Option<String>, // This field will change what is written to the service file
     pub directive_executed: bool, // This should never be changed
+    /// Commands run around each deploy of this repository.
+    #[serde(default)]
+    pub hooks: DeployHooks,
 }
 
 pub async fn scan_directories(base_path: &str) -> Result<Vec<PathBuf>, ErrorArrayItem> {
     let mut directive_paths = Vec::new();
 
-    for entry in WalkDir::new(base_path).into_iter().filter_map(|e| e.ok()) {
+    // Projects are only configured from their live `current` release, the git
+    // checkout and the retained releases next to it are skipped
+    let walker = WalkDir::new(base_path)
+        .follow_links(true)
+        .into_iter()
+        .filter_entry(|entry| {
+            !(entry.depth() == 2
+                && entry.file_type().is_dir()
+                && matches!(
+                    entry.file_name().to_str(),
+                    Some(REPO_DIR) | Some(RELEASES_DIR)
+                ))
+        });
+
+    for entry in walker.filter_map(|e| e.ok()) {
         if entry.file_name() == "directive.ais" {
             directive_paths.push(entry.path().to_path_buf());
         }
@@ -33,15 +55,70 @@ pub async fn scan_directories(base_path: &str) -> Result<Vec<PathBuf>, ErrorArra
     Ok(directive_paths)
 }
 
+/// Returns the project id for a path inside a project directory, such as
+/// `/var/www/ais/<id>/current` or a legacy `/var/www/ais/<id>`.
+pub fn project_id(path: &Path) -> Option<String> {
+    path.strip_prefix(PROJECT_BASE_DIR)
+        .ok()?
+        .components()
+        .next()
+        .map(|component| component.as_os_str().to_string_lossy().to_string())
+}
+
+/// Runs the build steps the directive in `release_dir` asks for. Releases are
+/// built before they go live so a failing build never replaces a working one.
+pub async fn run_build_steps(release_dir: &PathType) -> Result<(), ErrorArrayItem> {
+    let directive_path = release_dir.join("directive.ais");
+    if !directive_path.exists() {
+        return Ok(());
+    }
+
+    let directive = parse_directive(&directive_path).await?;
+
+    if directive.nodejs_bool {
+        let status = run_npm_install(release_dir)?;
+        if !status.success() {
+            return Err(ErrorArrayItem::new(
+                Errors::GeneralError,
+                format!("npm install failed in {} with {}", release_dir, status),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the deploy hooks the directive in `release_dir` declares, none if
+/// the release has no directive.
+pub async fn load_hooks(release_dir: &PathType) -> Result<DeployHooks, ErrorArrayItem> {
+    let directive_path = release_dir.join("directive.ais");
+    if !directive_path.exists() {
+        return Ok(DeployHooks::default());
+    }
+
+    Ok(parse_directive(&directive_path).await?.hooks)
+}
+
+/// Reads and validates the directive at `path`, failing with every error found
+/// in it. Warnings never fail it, use `validate_directive` to get them as well.
 pub async fn parse_directive(path: &Path) -> Result<Directive, ErrorArrayItem> {
-    let content = read_json_without_comments(PathType::Path(path.into()))
-        .map_err(|err| ErrorArrayItem::from(err))?;
-    let directive: Directive =
-        serde_json::from_str(&content).map_err(|err| ErrorArrayItem::from(err))?;
-    Ok(directive)
+    match validate_directive(path).await? {
+        (Some(directive), report) if report.is_valid() => Ok(directive),
+        (_, report) => Err(ErrorArrayItem::new(Errors::InvalidFile, report.to_string())),
+    }
+}
+
+/// Checks the directive at `path`, see `directive_schema`. Only failing to read
+/// the file is an error, the directive is returned whenever it could be read.
+pub async fn validate_directive(
+    path: &Path,
+) -> Result<(Option<Directive>, DirectiveReport), ErrorArrayItem> {
+    let content = read_json_without_comments(PathType::Path(path.into()))?;
+    Ok(check_directive(&path.to_string_lossy(), &content))
 }
 
-/// Reads a JSON file and removes lines starting with `#`
+/// Reads a JSON file and blanks lines starting with `#`, keeping the line
+/// numbers of the rest
 fn read_json_without_comments(file_path: PathType) -> Result<Stringy, ErrorArrayItem> {
     let file = open_file(file_path, false)?;
     let reader = io::BufReader::new(file);
@@ -53,9 +130,42 @@ fn read_json_without_comments(file_path: PathType) -> Result<Stringy, ErrorArray
         // Skip lines that start with a `#`
         if !line.trim_start().starts_with('#') {
             json_string.push_str(&line);
-            json_string.push('\n');
         }
+        json_string.push('\n');
     }
 
     Ok(Stringy::new(&json_string))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_warnings_never_stop_a_directive() {
+        let root = TempDir::new().unwrap();
+        let path = root.path().join("directive.ais");
+        std::fs::write(
+            &path,
+            r#"{
+    "url": "app.example.com",
+    "apache": true,
+    "port": 3000,
+    "nodejs_bool": true,
+    "nodejs_version": "22",
+    "directive_executed": true,
+    "proxy": true
+}"#,
+        )
+        .unwrap();
+
+        let (_, report) = validate_directive(&path).await.unwrap();
+        assert!(
+            report.is_valid() && report.warnings().count() == 2,
+            "{}",
+            report
+        );
+        assert!(parse_directive(&path).await.unwrap().nodejs_bool);
+    }
+}
@@ -0,0 +1,336 @@
+// What executing a directive does to the system, worked out before anything is
+// touched. `ais_directive` executes the plan, `ais_directive plan` only prints
+// it, so the preview is always exactly what would happen.
+
+use std::{
+    fmt, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    types::PathType,
+};
+
+use crate::{
+    apache::{apache_config_path, reload_apache, render_apache_config},
+    constants::SYSTEMD_UNIT_DIR,
+    directive::{parse_directive, project_id},
+    monitor::{monitoring_script_path, render_monitoring_script, render_monitoring_service},
+    node::create_node_systemd_service,
+    project_env::environment_file,
+    projects::is_platform_unit,
+    systemd::{enable_now, reload_systemd_daemon},
+};
+
+/// Lines of unchanged context shown around each change of a file.
+const DIFF_CONTEXT: usize = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    /// Writes `contents` to `path`, `current` is what it holds now.
+    WriteFile {
+        path: PathBuf,
+        contents: String,
+        current: Option<String>,
+    },
+    /// Runs a command in `dir`, failing the directive if it fails.
+    Run {
+        program: String,
+        args: Vec<String>,
+        dir: PathBuf,
+    },
+    ReloadApache,
+    ReloadSystemd,
+    /// Enables and starts a unit, which is left alone if it already runs.
+    EnableNow(String),
+}
+
+impl Step {
+    fn write_file(path: PathBuf, contents: String) -> Self {
+        let current = fs::read_to_string(&path).ok();
+        Step::WriteFile {
+            path,
+            contents,
+            current,
+        }
+    }
+
+    fn changes(&self) -> bool {
+        match self {
+            Step::WriteFile {
+                contents, current, ..
+            } => current.as_ref() != Some(contents),
+            _ => true,
+        }
+    }
+
+    async fn execute(&self) -> Result<(), ErrorArrayItem> {
+        if !self.changes() {
+            return Ok(());
+        }
+        match self {
+            Step::WriteFile { path, contents, .. } => {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(path, contents)?;
+            }
+            Step::Run { program, args, dir } => {
+                let status = Command::new(program).args(args).current_dir(dir).status()?;
+                if !status.success() {
+                    return Err(ErrorArrayItem::new(
+                        Errors::GeneralError,
+                        format!("{} failed in {} with {}", self, dir.display(), status),
+                    ));
+                }
+            }
+            Step::ReloadApache => {
+                if !reload_apache().await? {
+                    eprintln!("My god we killed apache, quick email the admin");
+                    eprintln!("The apache config we rolled out most likely killed apache");
+                }
+            }
+            Step::ReloadSystemd => {
+                reload_systemd_daemon()?;
+            }
+            Step::EnableNow(unit) => {
+                enable_now(unit.clone())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Step::WriteFile {
+                path,
+                contents,
+                current,
+            } => {
+                let action = match current {
+                    None => "create",
+                    Some(current) if current == contents => "unchanged",
+                    Some(_) => "update",
+                };
+                write!(f, "{} {}", action, path.display())
+            }
+            Step::Run { program, args, .. } => write!(f, "run {} {}", program, args.join(" ")),
+            Step::ReloadApache => write!(f, "reload apache2.service"),
+            Step::ReloadSystemd => write!(f, "reload the systemd daemon"),
+            Step::EnableNow(unit) => write!(f, "enable and start {}.service", unit),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DirectivePlan {
+    pub project_dir: PathBuf,
+    pub steps: Vec<Step>,
+}
+
+impl DirectivePlan {
+    /// Works out what executing the directive at `directive_path` would do,
+    /// reading but never changing the system.
+    pub async fn new(directive_path: &Path) -> Result<Self, ErrorArrayItem> {
+        let directive = parse_directive(directive_path).await?;
+        let project_dir = directive_path
+            .parent()
+            .unwrap_or_else(|| Path::new("/tmp"))
+            .to_path_buf();
+        let mut steps = Vec::new();
+
+        // Apache is only reloaded when its config changes
+        if directive.apache {
+            let vhost = Step::write_file(
+                apache_config_path(&directive),
+                render_apache_config(&directive, &project_dir),
+            );
+            if vhost.changes() {
+                steps.push(vhost);
+                steps.push(Step::ReloadApache);
+            }
+        }
+
+        if directive.nodejs_bool {
+            let service_id = project_id(&project_dir).ok_or_else(|| {
+                ErrorArrayItem::new(
+                    Errors::GeneralError,
+                    format!(
+                        "{} is not inside a project directory",
+                        project_dir.display()
+                    ),
+                )
+            })?;
+            let working_dir = PathType::Path(project_dir.clone().into_boxed_path());
+
+            // Projects registered before slugs were checked against the system's
+            // units may be named like one of them
+            for unit in [service_id.clone(), format!("{}_monitor", service_id)] {
+                let path = Path::new(SYSTEMD_UNIT_DIR).join(format!("{}.service", unit));
+                if path.exists() && !is_platform_unit(&path) {
+                    return Err(ErrorArrayItem::new(
+                        Errors::InvalidFile,
+                        format!(
+                            "{} belongs to another service, {} can't be deployed under that name",
+                            path.display(),
+                            service_id
+                        ),
+                    ));
+                }
+            }
+
+            steps.push(Step::Run {
+                program: String::from("npm"),
+                args: vec![String::from("install")],
+                dir: project_dir.clone(),
+            });
+
+            // TODO MITOBYTE HAS THE WRONG VERSION of directive.ais, the exec
+            // command isn't read from it yet
+            let unit = create_node_systemd_service(
+                "/usr/bin/npm run dev",
+                &working_dir,
+                &format!("Ais project id {}", project_dir.display()),
+                &environment_file(&service_id),
+            )?;
+            steps.push(Step::write_file(
+                Path::new(SYSTEMD_UNIT_DIR).join(format!("{}.service", service_id)),
+                unit.to_string(),
+            ));
+
+            let script_path = monitoring_script_path(&service_id);
+            steps.push(Step::write_file(
+                PathBuf::from(&script_path),
+                render_monitoring_script(&project_dir.to_string_lossy(), &service_id),
+            ));
+            steps.push(Step::write_file(
+                Path::new(SYSTEMD_UNIT_DIR).join(format!("{}_monitor.service", service_id)),
+                render_monitoring_service(&service_id, &script_path),
+            ));
+
+            steps.push(Step::ReloadSystemd);
+            steps.push(Step::EnableNow(service_id.clone()));
+            steps.push(Step::EnableNow(format!("{}_monitor", service_id)));
+        }
+
+        Ok(Self { project_dir, steps })
+    }
+
+    /// Runs the steps in order, stopping at the first that fails.
+    pub async fn execute(&self) -> Result<(), ErrorArrayItem> {
+        for step in &self.steps {
+            step.execute().await?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for DirectivePlan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Plan for {}", self.project_dir.display())?;
+        if self.steps.is_empty() {
+            return write!(f, "  nothing to change");
+        }
+
+        for step in &self.steps {
+            writeln!(f, "  {}", step)?;
+            if let Step::WriteFile {
+                contents, current, ..
+            } = step
+            {
+                let old = current.as_deref().unwrap_or_default();
+                for line in unified_diff(old, contents, DIFF_CONTEXT).lines() {
+                    writeln!(f, "    {}", line)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A unified diff of two texts, without file headers. Empty if they are equal.
+pub fn unified_diff(old: &str, new: &str, context: usize) -> String {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+
+    // Longest common subsequence, directives render small files
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = match old[i] == new[j] {
+                true => common[i + 1][j + 1] + 1,
+                false => common[i + 1][j].max(common[i][j + 1]),
+            };
+        }
+    }
+
+    // Every line as (marker, old line number, new line number, text)
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            lines.push((' ', i, j, old[i]));
+            i += 1;
+            j += 1;
+        } else if i < old.len() && (j == new.len() || common[i + 1][j] >= common[i][j + 1]) {
+            lines.push(('-', i, j, old[i]));
+            i += 1;
+        } else {
+            lines.push(('+', i, j, new[j]));
+            j += 1;
+        }
+    }
+
+    let changed: Vec<usize> = (0..lines.len())
+        .filter(|index| lines[*index].0 != ' ')
+        .collect();
+    let mut diff = String::new();
+    let mut next = 0;
+    while next < changed.len() {
+        // A hunk runs on while changes are close enough for their context to meet
+        let start = changed[next].saturating_sub(context);
+        let mut end = changed[next];
+        while next < changed.len() && changed[next] <= end + 2 * context {
+            end = changed[next];
+            next += 1;
+        }
+        let end = (end + context).min(lines.len() - 1);
+
+        let hunk = &lines[start..=end];
+        let old_count = hunk.iter().filter(|line| line.0 != '+').count();
+        let new_count = hunk.iter().filter(|line| line.0 != '-').count();
+        diff.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            hunk[0].1 + usize::from(old_count > 0),
+            old_count,
+            hunk[0].2 + usize::from(new_count > 0),
+            new_count
+        ));
+        for (marker, _, _, text) in hunk {
+            diff.push_str(&format!("{}{}\n", marker, text));
+        }
+    }
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unified_diff() {
+        assert_eq!(unified_diff("a\nb\n", "a\nb\n", 3), "");
+        assert_eq!(unified_diff("", "a\nb\n", 3), "@@ -0,0 +1,2 @@\n+a\n+b\n");
+
+        let old = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n";
+        let new = "1\n2\n3\nfour\n5\n6\n7\n8\n9\n10\n11\n";
+        assert_eq!(
+            unified_diff(old, new, 1),
+            "@@ -3,3 +3,3 @@\n 3\n-4\n+four\n 5\n@@ -10,1 +10,2 @@\n 10\n+11\n"
+        );
+    }
+}
@@ -0,0 +1,405 @@
+// Validation of `directive.ais` files. A directive is checked in stages, each
+// only if the previous one passed: the json syntax, the schema version, the
+// types of the known keys and finally the values themselves. Unknown keys are
+// only warned about, they are usually typos of optional settings that would
+// otherwise be silently ignored.
+//
+// Directives carry a top level `version`, files written before versioning have
+// none and count as version 1. Changing the format means raising
+// `DIRECTIVE_VERSION` and keeping the older versions readable.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{apache::SUPPORTED_PHP_VERSIONS, directive::Directive};
+
+/// The newest directive version this build reads.
+pub const DIRECTIVE_VERSION: u64 = 1;
+
+/// Every top level key a directive may have.
+pub const DIRECTIVE_KEYS: &[&str] = &[
+    "version",
+    "url",
+    "apache",
+    "port",
+    "php_fpm_version",
+    "nodejs_bool",
+    "nodejs_version",
+    "directive_executed",
+    "hooks",
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem found in a directive, at the position it was found if known.
+/// Lines and columns start at 1.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub severity: Severity,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => write!(
+                f,
+                "{} at line {}, column {}: {}",
+                severity, line, column, self.message
+            ),
+            (Some(line), None) => write!(f, "{} at line {}: {}", severity, line, self.message),
+            _ => write!(f, "{}: {}", severity, self.message),
+        }
+    }
+}
+
+/// Everything found in one directive.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct DirectiveReport {
+    pub path: String,
+    pub findings: Vec<Finding>,
+}
+
+impl DirectiveReport {
+    /// Whether the directive can be used, warnings don't count.
+    pub fn is_valid(&self) -> bool {
+        self.errors().next().is_none()
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &Finding> {
+        self.findings
+            .iter()
+            .filter(|finding| finding.severity == Severity::Error)
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item = &Finding> {
+        self.findings
+            .iter()
+            .filter(|finding| finding.severity == Severity::Warning)
+    }
+
+    fn push(&mut self, severity: Severity, position: Option<(usize, usize)>, message: String) {
+        self.findings.push(Finding {
+            severity,
+            line: position.map(|(line, _)| line),
+            column: position.map(|(_, column)| column),
+            message,
+        });
+    }
+}
+
+impl fmt::Display for DirectiveReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, finding) in self.findings.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}: {}", self.path, finding)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks the text of the directive at `path`, which has its comment lines
+/// blanked so positions match the file. Returns the directive whenever it
+/// could be read, even with invalid values, check the report before using it.
+pub fn check_directive(path: &str, content: &str) -> (Option<Directive>, DirectiveReport) {
+    let mut report = DirectiveReport {
+        path: path.to_owned(),
+        findings: Vec::new(),
+    };
+
+    let document: Value = match serde_json::from_str(content) {
+        Ok(document) => document,
+        Err(e) => {
+            report.push(
+                Severity::Error,
+                Some((e.line(), e.column())),
+                serde_message(&e),
+            );
+            return (None, report);
+        }
+    };
+    let document = match document.as_object() {
+        Some(document) => document,
+        None => {
+            report.push(
+                Severity::Error,
+                None,
+                String::from("A directive must be a json object"),
+            );
+            return (None, report);
+        }
+    };
+
+    // A newer directive may mean something this build would get wrong
+    if let Some(version) = document.get("version") {
+        let problem = match version.as_u64() {
+            Some(0) | None => Some(format!("Invalid version {}", version)),
+            Some(version) if version > DIRECTIVE_VERSION => Some(format!(
+                "The directive is version {}, this build only reads up to version {}",
+                version, DIRECTIVE_VERSION
+            )),
+            Some(_) => None,
+        };
+        if let Some(problem) = problem {
+            report.push(Severity::Error, locate(content, "version"), problem);
+            return (None, report);
+        }
+    }
+
+    for key in document.keys() {
+        if DIRECTIVE_KEYS.contains(&key.as_str()) {
+            continue;
+        }
+        let message = match suggest(key) {
+            Some(known) => format!("Unknown key {}, did you mean {}?", key, known),
+            None => format!("Unknown key {}, it is ignored", key),
+        };
+        report.push(Severity::Warning, locate(content, key), message);
+    }
+
+    let directive: Directive = match serde_json::from_str(content) {
+        Ok(directive) => directive,
+        Err(e) => {
+            report.push(
+                Severity::Error,
+                Some((e.line(), e.column())),
+                serde_message(&e),
+            );
+            return (None, report);
+        }
+    };
+
+    check_values(&directive, content, &mut report);
+    (Some(directive), report)
+}
+
+fn check_values(directive: &Directive, content: &str, report: &mut DirectiveReport) {
+    if let Some(problem) = url_problem(&directive.url) {
+        report.push(
+            Severity::Error,
+            locate(content, "url"),
+            format!("Invalid url {}: {}", directive.url, problem),
+        );
+    }
+
+    if directive.port == 0 {
+        report.push(
+            Severity::Error,
+            locate(content, "port"),
+            String::from("The port must be between 1 and 65535"),
+        );
+    }
+
+    if let Some(version) = &directive.php_fpm_version {
+        if !SUPPORTED_PHP_VERSIONS.contains(&version.as_str()) {
+            report.push(
+                Severity::Error,
+                locate(content, "php_fpm_version"),
+                format!(
+                    "Unsupported PHP version {}, use one of {}",
+                    version,
+                    SUPPORTED_PHP_VERSIONS.join(", ")
+                ),
+            );
+        }
+        if !directive.apache {
+            report.push(
+                Severity::Error,
+                locate(content, "php_fpm_version"),
+                String::from("php_fpm_version needs apache to be true"),
+            );
+        }
+    }
+
+    if let Some(version) = &directive.nodejs_version {
+        if !directive.nodejs_bool {
+            report.push(
+                Severity::Warning,
+                locate(content, "nodejs_version"),
+                String::from("nodejs_version is ignored unless nodejs_bool is true"),
+            );
+        } else if version.is_empty() || !version.split('.').all(is_number) {
+            report.push(
+                Severity::Error,
+                locate(content, "nodejs_version"),
+                format!("Invalid node version {}, use a version such as 22", version),
+            );
+        }
+    }
+
+    if directive.directive_executed {
+        report.push(
+            Severity::Warning,
+            locate(content, "directive_executed"),
+            String::from("directive_executed is managed by the platform, leave it false"),
+        );
+    }
+}
+
+// The url is the vhost's ServerName and names its config file, so only a
+// plain host name is accepted
+pub(crate) fn url_problem(url: &str) -> Option<&'static str> {
+    if url.contains("://") {
+        return Some("give the host name only, without a scheme");
+    }
+    if url.contains('/') {
+        return Some("give the host name only, without a path");
+    }
+    if url.is_empty() || url.len() > 253 {
+        return Some("a host name is 1 to 253 characters long");
+    }
+
+    let valid_labels = url.split('.').all(|label| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    });
+    match valid_labels {
+        true => None,
+        false => Some("use letters, digits, dashes and dots between them"),
+    }
+}
+
+fn is_number(text: &str) -> bool {
+    !text.is_empty() && text.chars().all(|c| c.is_ascii_digit())
+}
+
+// serde_json appends the position, which is reported separately
+fn serde_message(e: &serde_json::Error) -> String {
+    let message = e.to_string();
+    let suffix = format!(" at line {} column {}", e.line(), e.column());
+    message.strip_suffix(&suffix).unwrap_or(&message).to_owned()
+}
+
+/// Finds where `key` is set, the first time it appears as a key.
+fn locate(content: &str, key: &str) -> Option<(usize, usize)> {
+    let quoted = format!("\"{}\"", key);
+    for (index, line) in content.lines().enumerate() {
+        let mut offset = 0;
+        while let Some(found) = line[offset..].find(&quoted) {
+            let start = offset + found;
+            let rest = &line[start + quoted.len()..];
+            if rest.trim_start().starts_with(':') {
+                return Some((index + 1, line[..start].chars().count() + 1));
+            }
+            offset = start + quoted.len();
+        }
+    }
+    None
+}
+
+// The known key an unknown one is most likely a typo of
+fn suggest(key: &str) -> Option<&'static str> {
+    DIRECTIVE_KEYS
+        .iter()
+        .map(|known| (edit_distance(key, known), *known))
+        .filter(|(distance, known)| *distance <= 2.max(known.len() / 4))
+        .min()
+        .map(|(_, known)| known)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALID: &str = r#"{
+    "url": "shop.example.com",
+    "apache": true,
+    "port": 80,
+    "php_fpm_version": "8.2",
+    "nodejs_bool": false,
+    "directive_executed": false
+}"#;
+
+    #[test]
+    fn test_valid_directive() {
+        let (directive, report) = check_directive("directive.ais", VALID);
+        assert!(report.findings.is_empty(), "{}", report);
+        assert_eq!(directive.unwrap().url, "shop.example.com");
+
+        let versioned = VALID.replacen('{', "{\n    \"version\": 1,", 1);
+        assert!(check_directive("directive.ais", &versioned).0.is_some());
+    }
+
+    #[test]
+    fn test_findings_have_positions() {
+        // Syntax, with the position serde reports
+        let (directive, report) = check_directive("directive.ais", "{\n  \"url\": \"a\",,\n}");
+        assert!(directive.is_none());
+        assert_eq!(report.findings[0].line, Some(2));
+
+        // Types
+        let wrong_type = VALID.replace("\"port\": 80", "\"port\": \"80\"");
+        let (_, report) = check_directive("directive.ais", &wrong_type);
+        assert_eq!(report.errors().next().unwrap().line, Some(4));
+
+        // Values, unknown keys are only warnings
+        let invalid = VALID
+            .replace("8.2", "5.6")
+            .replace("\"port\"", "\"prot\": 1,\n    \"port\"")
+            .replace("shop.example.com", "https://shop.example.com");
+        let (directive, report) = check_directive("directive.ais", &invalid);
+        assert!(directive.is_some() && !report.is_valid());
+        assert_eq!(report.errors().count(), 2, "{}", report);
+
+        let warning = report.warnings().next().unwrap();
+        assert_eq!((warning.line, warning.column), (Some(4), Some(5)));
+        assert!(warning.message.contains("did you mean port?"));
+        assert!(report
+            .to_string()
+            .contains("directive.ais: error at line 2, column 5: Invalid url"));
+    }
+
+    #[test]
+    fn test_combined_options_and_versions() {
+        // Apache in front of a node app is a valid setup
+        let both = VALID.replace("\"nodejs_bool\": false", "\"nodejs_bool\": true");
+        assert!(check_directive("directive.ais", &both)
+            .1
+            .findings
+            .is_empty());
+
+        let php_only = VALID.replace("\"apache\": true", "\"apache\": false");
+        assert!(!check_directive("directive.ais", &php_only).1.is_valid());
+
+        let newer = VALID.replacen('{', "{\n    \"version\": 2,", 1);
+        let (_, report) = check_directive("directive.ais", &newer);
+        assert!(report
+            .errors()
+            .next()
+            .unwrap()
+            .message
+            .contains("version 2"));
+    }
+}
@@ -1,450 +1,332 @@
-// Please ignore this trash
-
-use {
-    dusa_collection_utils::{
-        errors::{
-            ErrorArray, ErrorArrayItem, OkWarning, UnifiedResult as uf, WarningArray,
-            WarningArrayItem, Warnings,
-        },
-        types::PathType,
-    },
-    dusa_common::{
-        get_id,
-        prefix::{receive_message, send_message},
-        set_file_ownership, DecryptResponseData, Message, MessageType, RequestPayload,
-        RequestRecsPlainText, RequestRecsSimple, RequestRecsWrite, SOCKET_PATH, VERSION,
-    },
-    nix::unistd::geteuid,
-    std::{fs, os::unix::net::UnixStream, path::PathBuf, time::Duration},
+// Client for dusad, which encrypts text with keys only it can read. Requests
+// go over its unix socket in dusa_common's framing, which works on std streams,
+// so they run with socket timeouts on tokio's blocking pool and never stall a
+// runtime thread. dusad answers a single request per connection and closes it
+// after its acknowledge, so every request connects anew. dusad being down is an
+// error for the caller, never a panic.
+
+use std::{
+    fmt,
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    sync::{Arc, OnceLock},
+    thread,
+    time::{Duration, Instant},
 };
 
-pub enum ProgramMode {
-    StoreFile,
-    RetrieveFile,
-    EncryptText,
-    DecryptText,
-    RemoveFile,
+use dusa_collection_utils::errors::{ErrorArray, ErrorArrayItem, Errors, WarningArray};
+use dusa_common::{
+    prefix::{receive_message, send_message},
+    Commands, Message, MessageType, RequestPayload, RequestRecsPlainText, SOCKET_PATH, VERSION,
+};
+use nix::unistd::geteuid;
+
+/// How long dusad has to answer a single request.
+pub const DUSA_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Attempts after the first when dusad can't be reached or doesn't answer.
+const RETRIES: u32 = 2;
+const RETRY_DELAY: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone, Copy)]
+enum Operation {
+    Encrypt,
+    Decrypt,
 }
 
-#[allow(unused_variables)]
-pub fn run(
-    mode: ProgramMode,
-    path: Option<String>,
-    owner: Option<String>,
-    name: Option<String>,
-    data: Option<String>,
-) -> uf<Option<String>> {
-    let mut e1: ErrorArray = ErrorArray::new_container();
-    let w1: WarningArray = WarningArray::new_container();
-
-    let socket_path: PathType = match SOCKET_PATH(false, e1.clone(), w1.clone()).uf_unwrap() {
-        Ok(d) => {
-            d.warning.display();
-            d.data
-        }
-        Err(e) => {
-            e.display(true);
-            unreachable!();
-        }
-    };
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DusaError {
+    /// dusad's socket can't be reached.
+    Unavailable(String),
+    /// dusad didn't answer in time.
+    Timeout,
+    /// dusad answered with an error.
+    Rejected(String),
+    /// The answer couldn't be understood.
+    Protocol(String),
+}
 
-    let stream: UnixStream = match UnixStream::connect(socket_path.clone()) {
-        Ok(d) => d,
-        Err(e) => {
-            e1.push(ErrorArrayItem::from(e));
-            return uf::new(Err(e1));
-        }
-    };
-
-    let result: uf<OkWarning<Option<String>>> = match mode {
-        ProgramMode::StoreFile => encrypt_file(path, stream, w1.clone(), e1.clone()),
-        ProgramMode::RetrieveFile => decrypt_file(path, stream, w1.clone(), e1.clone()),
-        ProgramMode::EncryptText => encrypt_text(data, stream, w1.clone(), e1.clone()),
-        ProgramMode::DecryptText => decrypt_text(data, stream, w1.clone(), e1.clone()),
-        ProgramMode::RemoveFile => remove_file(path, stream, w1.clone(), e1.clone()),
-    };
-
-    match result.uf_unwrap() {
-        Ok(d) => {
-            d.warning.display();
-            return uf::new(Ok(d.data));
-        }
-        Err(e) => return uf::new(Err(e)),
+impl DusaError {
+    fn is_retryable(&self) -> bool {
+        matches!(self, DusaError::Unavailable(_) | DusaError::Timeout)
     }
 }
 
-fn encrypt_file(
-    path: Option<String>,
-    mut stream: UnixStream,
-    mut warnings: WarningArray,
-    errors: ErrorArray,
-) -> uf<OkWarning<Option<String>>> {
-    let file_path: PathType = match path {
-        Some(p) => {
-            match get_file_path(errors.clone(), warnings.clone(), &PathBuf::from(p)).uf_unwrap() {
-                Ok(d) => {
-                    d.warning.display();
-                    d.data
-                }
-                Err(e) => return uf::new(Err(e)),
-            }
+impl fmt::Display for DusaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DusaError::Unavailable(reason) => write!(f, "dusad is unavailable: {}", reason),
+            DusaError::Timeout => write!(f, "dusad didn't answer in time"),
+            DusaError::Rejected(reason) => write!(f, "dusad refused the request: {}", reason),
+            DusaError::Protocol(reason) => write!(f, "unexpected answer from dusad: {}", reason),
         }
-        None => return uf::new(Err(errors)),
-    };
-
-    // Changing ownership of the file
-    let (uid, gid) = get_id();
-    if let Err(err) =
-        set_file_ownership(&file_path.to_path_buf(), uid, gid, errors.clone()).uf_unwrap()
-    {
-        return uf::new(Err(err));
     }
+}
 
-    // Creating the command to send
-    let request_data = RequestRecsWrite {
-        path: file_path,
-        owner: String::from("system"),
-        name: String::from("lost"),
-        uid: u32::from(geteuid()),
-    };
+impl From<DusaError> for ErrorArrayItem {
+    fn from(err: DusaError) -> Self {
+        ErrorArrayItem::new(Errors::GeneralError, err.to_string())
+    }
+}
 
-    let msg = Message {
-        version: VERSION.to_owned(),
-        msg_type: MessageType::Request,
-        payload: serde_json::to_value(RequestPayload::Write(request_data)).unwrap(),
-        error: None,
-    };
+pub struct DusaClient {
+    socket_path: PathBuf,
+    timeout: Duration,
+}
 
-    // Communicating with server
-    if let Err(err) = send_message(&mut stream, &msg, errors.clone()).uf_unwrap() {
-        return uf::new(Err(err));
-    }
-    std::thread::sleep(Duration::from_nanos(100));
-    let response = receive_message(&mut stream, errors.clone()).unwrap();
-
-    match response.msg_type {
-        MessageType::Response => {
-            let response_data = response.payload;
-            let msg = response_data
-                .get("Ok")
-                .and_then(|v| v.as_str().map(|s| s.to_string()));
-
-            return uf::new(Ok(OkWarning {
-                data: Some(msg.unwrap()),
-                warning: warnings,
-            }));
+static CLIENT: OnceLock<Arc<DusaClient>> = OnceLock::new();
+
+impl DusaClient {
+    pub fn new(socket_path: impl Into<PathBuf>, timeout: Duration) -> Self {
+        Self {
+            socket_path: socket_path.into(),
+            timeout,
         }
-        MessageType::ErrorResponse => return uf::new(Err(errors)),
-        _ => {
-            let msg = String::from("Server responded in an unexpected way, ignoring ...");
-            warnings.push(WarningArrayItem::new_details(Warnings::Warning, msg))
+    }
+
+    /// The client shared by this process, talking to dusad's default socket.
+    pub fn shared() -> Result<Arc<Self>, DusaError> {
+        if let Some(client) = CLIENT.get() {
+            return Ok(client.clone());
         }
+
+        let socket_path = SOCKET_PATH(
+            false,
+            ErrorArray::new_container(),
+            WarningArray::new_container(),
+        )
+        .uf_unwrap()
+        .map_err(|errors| DusaError::Unavailable(describe(errors)))?
+        .data
+        .to_path_buf();
+        Ok(CLIENT
+            .get_or_init(|| Arc::new(Self::new(socket_path, DUSA_TIMEOUT)))
+            .clone())
     }
 
-    uf::new(Ok(OkWarning {
-        data: None,
-        warning: warnings,
-    }))
-}
+    pub async fn encrypt_text(self: &Arc<Self>, data: &str) -> Result<String, DusaError> {
+        self.request_async(Operation::Encrypt, data).await
+    }
 
-#[allow(unused_variables)]
-fn decrypt_file(
-    path: Option<String>,
-    mut stream: UnixStream,
-    mut warnings: WarningArray,
-    mut errors: ErrorArray,
-) -> uf<OkWarning<Option<String>>> {
-    let request_data = RequestRecsSimple {
-        command: dusa_common::Commands::DecryptFile,
-        owner: String::from("system"),
-        name: String::from("lost"),
-        uid: u32::from(geteuid()),
-    };
-
-    let msg = Message {
-        version: VERSION.to_owned(),
-        msg_type: MessageType::Request,
-        payload: serde_json::to_value(RequestPayload::Simple(request_data)).unwrap(),
-        error: None,
-    };
+    pub async fn decrypt_text(self: &Arc<Self>, data: &str) -> Result<String, DusaError> {
+        self.request_async(Operation::Decrypt, data).await
+    }
 
-    // Communicating with server
-    if let Err(err) = send_message(&mut stream, &msg, errors.clone()).uf_unwrap() {
-        err.display(false);
-        return uf::new(Err(errors));
+    /// Like `encrypt_text`, for callers that aren't async.
+    pub fn encrypt_text_blocking(&self, data: &str) -> Result<String, DusaError> {
+        self.request(Operation::Encrypt, data)
     }
-    let response = receive_message(&mut stream, errors.clone()).unwrap();
-
-    match response.msg_type {
-        MessageType::Response => {
-            let response_data = response.payload;
-            let data = DecryptResponseData {
-                temp_p: response_data
-                    .get("temp_p")
-                    .and_then(|v| v.get("Content"))
-                    .and_then(|v| v.as_str())
-                    .map(|s| PathType::Content(s.to_string()))
-                    .unwrap_or_else(|| PathType::Content("/tmp/null".to_string())),
-                orig_p: response_data
-                    .get("orig_p")
-                    .and_then(|v| v.get("PathBuf"))
-                    .and_then(|v| v.as_str())
-                    .map(|s| PathType::Content(s.to_string()))
-                    .unwrap_or_else(|| PathType::Content("/tmp/null".to_string())),
-                ttl: response_data
-                    .get("ttl")
-                    .and_then(|v| v.get("secs"))
-                    .and_then(|v| v.as_u64())
-                    .map(|t| Duration::from_secs(t))
-                    .unwrap_or(Duration::from_secs(5)), // keep the timing tight
-            };
-
-            // Send an ACK message
-            let ack = Message {
-                version: VERSION.to_owned(),
-                msg_type: MessageType::Acknowledge,
-                payload: serde_json::json!({}),
-                error: None,
-            };
-            send_message(&mut stream, &ack, errors.clone());
-            let _ = receive_message(&mut stream, errors.clone()).unwrap();
-
-            // copy the file to the original path
-            match fs::copy(data.temp_p, data.orig_p) {
-                Ok(d) => {
-                    if d != 0 {
-                        // log(format!("{:#?}", data));
-                        return uf::new(Ok(OkWarning {
-                            data: Some("done".to_string()),
-                            warning: warnings,
-                        }));
-                    }
-                }
-                Err(e) => {
-                    errors.push(ErrorArrayItem::from(e));
-                    errors.display(true);
+
+    /// Like `decrypt_text`, for callers that aren't async.
+    pub fn decrypt_text_blocking(&self, data: &str) -> Result<String, DusaError> {
+        self.request(Operation::Decrypt, data)
+    }
+
+    async fn request_async(
+        self: &Arc<Self>,
+        operation: Operation,
+        data: &str,
+    ) -> Result<String, DusaError> {
+        let client = self.clone();
+        let data = data.to_owned();
+        let request = tokio::task::spawn_blocking(move || client.request(operation, &data));
+
+        // The socket timeouts already bound every attempt, this covers the rest
+        let deadline = (self.timeout + RETRY_DELAY * RETRIES) * (RETRIES + 1);
+        match tokio::time::timeout(deadline, request).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(e)) => Err(DusaError::Protocol(e.to_string())),
+            Err(_) => Err(DusaError::Timeout),
+        }
+    }
+
+    fn request(&self, operation: Operation, data: &str) -> Result<String, DusaError> {
+        let mut attempt = 0;
+        loop {
+            match self
+                .connect()
+                .and_then(|mut stream| self.send_request(&mut stream, operation, data))
+            {
+                Err(e) if e.is_retryable() && attempt < RETRIES => {
+                    attempt += 1;
+                    thread::sleep(RETRY_DELAY * attempt);
                 }
+                result => return result,
             }
         }
-        MessageType::ErrorResponse => {
-            return uf::new(Err(errors));
-        }
-        _ => {
-            let msg = String::from("Server responded in an unexpected way, ignoring ...");
-            warnings.push(WarningArrayItem::new_details(Warnings::Warning, msg))
-        }
     }
 
-    uf::new(Ok(OkWarning {
-        data: None,
-        warning: warnings,
-    }))
-}
+    fn connect(&self) -> Result<UnixStream, DusaError> {
+        let unavailable = |e: std::io::Error| {
+            DusaError::Unavailable(format!("{}: {}", self.socket_path.display(), e))
+        };
+
+        let stream = UnixStream::connect(&self.socket_path).map_err(unavailable)?;
+        stream
+            .set_read_timeout(Some(self.timeout))
+            .map_err(unavailable)?;
+        stream
+            .set_write_timeout(Some(self.timeout))
+            .map_err(unavailable)?;
+        Ok(stream)
+    }
 
-fn encrypt_text(
-    data: Option<String>,
-    mut stream: UnixStream,
-    mut warnings: WarningArray,
-    errors: ErrorArray,
-) -> uf<OkWarning<Option<String>>> {
-    let data = data.unwrap_or("hello world".to_string());
-
-    let request_data = RequestRecsPlainText {
-        command: dusa_common::Commands::EncryptRawText,
-        data,
-        uid: u32::from(geteuid()),
-    };
-
-    let msg = Message {
-        version: VERSION.to_owned(),
-        msg_type: MessageType::Request,
-        payload: serde_json::to_value(RequestPayload::PlainText(request_data)).unwrap(),
-        error: None,
-    };
-
-    // Communicating with server
-    let _ = send_message(&mut stream, &msg, errors.clone());
-    std::thread::sleep(Duration::from_nanos(100));
-    let response = receive_message(&mut stream, errors.clone()).unwrap();
-
-    match response.msg_type {
-        MessageType::Response => {
-            let response_data = response.payload;
-
-            // Send an ACK message
-            let ack = Message {
-                version: VERSION.to_owned(),
-                msg_type: MessageType::Acknowledge,
-                payload: serde_json::json!({}),
-                error: None,
-            };
-            send_message(&mut stream, &ack, errors.clone());
-            let _ = receive_message(&mut stream, errors.clone()).unwrap();
-
-            return uf::new(Ok(OkWarning {
-                data: response_data
+    fn send_request(
+        &self,
+        stream: &mut UnixStream,
+        operation: Operation,
+        data: &str,
+    ) -> Result<String, DusaError> {
+        let request = RequestRecsPlainText {
+            command: match operation {
+                Operation::Encrypt => Commands::EncryptRawText,
+                Operation::Decrypt => Commands::DecryptRawText,
+            },
+            data: data.to_owned(),
+            uid: u32::from(geteuid()),
+        };
+        let payload = serde_json::to_value(RequestPayload::PlainText(request))
+            .map_err(|e| DusaError::Protocol(e.to_string()))?;
+        let started = Instant::now();
+        // Reads and writes fail with an io error once the socket timeout passed
+        let failed = |errors: ErrorArray| match started.elapsed() >= self.timeout {
+            true => DusaError::Timeout,
+            false => DusaError::Unavailable(describe(errors)),
+        };
+
+        send_message(
+            stream,
+            &message(MessageType::Request, payload),
+            ErrorArray::new_container(),
+        )
+        .uf_unwrap()
+        .map_err(failed)?;
+        let response = receive_message(stream, ErrorArray::new_container())
+            .uf_unwrap()
+            .map_err(failed)?;
+
+        match response.msg_type {
+            MessageType::Response => {
+                let value = response
+                    .payload
                     .get("value")
-                    .and_then(|v| v.as_str())
-                    .map(|s| s.to_string()),
-                warning: warnings,
-            }));
-        }
-        MessageType::ErrorResponse => {
-            return uf::new(Err(errors));
-        }
-        _ => {
-            let msg = String::from("Server responded in an unexpected way, ignoring ...");
-            warnings.push(WarningArrayItem::new_details(Warnings::Warning, msg))
+                    .and_then(|value| value.as_str())
+                    .map(|value| value.to_owned())
+                    .ok_or_else(|| DusaError::Protocol(String::from("the answer has no value")))?;
+
+                // Acknowledged like dusa's own client does, the value is good
+                // either way and dusad closes the connection after this
+                let ack = message(MessageType::Acknowledge, serde_json::json!({}));
+                if send_message(stream, &ack, ErrorArray::new_container())
+                    .uf_unwrap()
+                    .is_ok()
+                {
+                    let _ = receive_message(stream, ErrorArray::new_container()).uf_unwrap();
+                }
+                Ok(value)
+            }
+            MessageType::ErrorResponse => Err(DusaError::Rejected(
+                response
+                    .error
+                    .map(|error| format!("{:?}", error))
+                    .unwrap_or_else(|| response.payload.to_string()),
+            )),
+            other => Err(DusaError::Protocol(format!("a {:?} message", other))),
         }
-    };
-
-    uf::new(Ok(OkWarning {
-        warning: warnings,
-        data: None,
-    }))
+    }
 }
 
-fn decrypt_text(
-    data: Option<String>,
-    mut stream: UnixStream,
-    mut warnings: WarningArray,
-    errors: ErrorArray,
-) -> uf<OkWarning<Option<String>>> {
-    let data = data.unwrap_or("hello world".to_string());
-
-    let request_data = RequestRecsPlainText {
-        command: dusa_common::Commands::DecryptRawText,
-        data,
-        uid: u32::from(geteuid()),
-    };
-
-    let msg = Message {
+fn message(msg_type: MessageType, payload: serde_json::Value) -> Message<serde_json::Value> {
+    Message {
         version: VERSION.to_owned(),
-        msg_type: MessageType::Request,
-        payload: serde_json::to_value(RequestPayload::PlainText(request_data)).unwrap(),
+        msg_type,
+        payload,
         error: None,
-    };
-
-    // Communicating with server
-    let _ = send_message(&mut stream, &msg, errors.clone());
-    std::thread::sleep(Duration::from_nanos(100));
-    let response = receive_message(&mut stream, errors.clone()).unwrap();
-
-    match response.msg_type {
-        MessageType::Response => {
-            let response_data = response.payload;
-
-            // Send an ACK message
-            let ack = Message {
-                version: VERSION.to_owned(),
-                msg_type: MessageType::Acknowledge,
-                payload: serde_json::json!({}),
-                error: None,
-            };
-            send_message(&mut stream, &ack, errors.clone());
-            let _ = receive_message(&mut stream, errors.clone()).unwrap();
-
-            return uf::new(Ok(OkWarning {
-                data: response_data
-                    .get("value")
-                    .and_then(|v| v.as_str())
-                    .map(|s| s.to_string()),
-                warning: warnings,
-            }));
-        }
-        MessageType::ErrorResponse => {
-            return uf::new(Err(errors));
-        }
-        _ => {
-            let msg = String::from("Server responded in an unexpected way, ignoring ...");
-            warnings.push(WarningArrayItem::new_details(Warnings::Warning, msg))
-        }
-    };
+    }
+}
 
-    uf::new(Ok(OkWarning {
-        warning: warnings,
-        data: None,
-    }))
+fn describe(mut errors: ErrorArray) -> String {
+    match errors.len() {
+        0 => String::from("no details"),
+        _ => errors.pop().to_string(),
+    }
 }
 
-#[allow(unused_variables)]
-fn remove_file(
-    path: Option<String>,
-    mut stream: UnixStream,
-    mut warnings: WarningArray,
-    errors: ErrorArray,
-) -> uf<OkWarning<Option<String>>> {
-    let request_data = RequestRecsSimple {
-        command: dusa_common::Commands::RemoveFile,
-        owner: String::from("system"),
-        name: String::from("lost"),
-        uid: u32::from(geteuid()),
-    };
-
-    let msg = Message {
-        version: VERSION.to_owned(),
-        msg_type: MessageType::Request,
-        payload: serde_json::to_value(RequestPayload::Simple(request_data)).unwrap(),
-        error: None,
-    };
-
-    // Communicating with server
-    let _ = send_message(&mut stream, &msg, errors.clone());
-    std::thread::sleep(Duration::from_nanos(100));
-    let response = receive_message(&mut stream, errors.clone()).unwrap();
-
-    match response.msg_type {
-        MessageType::Response => {
-            let response_data = response.payload;
-
-            // Send an ACK message
-            let ack = Message {
-                version: VERSION.to_owned(),
-                msg_type: MessageType::Acknowledge,
-                payload: serde_json::json!({}),
-                error: None,
-            };
-            send_message(&mut stream, &ack, errors.clone());
-            let _ = receive_message(&mut stream, errors.clone()).unwrap();
-
-            return uf::new(Ok(OkWarning {
-                data: response_data
-                    .get("value")
-                    .and_then(|v| v.as_str())
-                    .map(|s| s.to_string()),
-                warning: warnings,
-            }));
-        }
-        MessageType::ErrorResponse => {
-            return uf::new(Err(errors));
-        }
-        _ => {
-            let msg = String::from("Server responded in an unexpected way, ignoring ...");
-            warnings.push(WarningArrayItem::new_details(Warnings::Warning, msg))
-        }
-    };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+    use tempfile::TempDir;
+
+    // Answers like dusad: one request per connection, then its own acknowledge
+    fn serve(
+        listener: UnixListener,
+        answers: Vec<Message<serde_json::Value>>,
+    ) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            for answer in answers {
+                let (mut stream, _) = listener.accept().unwrap();
+                let request = receive_message(&mut stream, ErrorArray::new_container())
+                    .uf_unwrap()
+                    .unwrap();
+                assert_eq!(request.msg_type, MessageType::Request);
+                send_message(&mut stream, &answer, ErrorArray::new_container())
+                    .uf_unwrap()
+                    .unwrap();
+                if answer.msg_type == MessageType::Response {
+                    let ack = receive_message(&mut stream, ErrorArray::new_container())
+                        .uf_unwrap()
+                        .unwrap();
+                    assert_eq!(ack.msg_type, MessageType::Acknowledge);
+                }
+                let ack = message(MessageType::Acknowledge, serde_json::json!({}));
+                send_message(&mut stream, &ack, ErrorArray::new_container())
+                    .uf_unwrap()
+                    .unwrap();
+            }
+        })
+    }
 
-    uf::new(Ok(OkWarning {
-        warning: warnings,
-        data: None,
-    }))
-}
+    #[test]
+    fn test_each_request_gets_its_own_connection() {
+        let root = TempDir::new().unwrap();
+        let socket = root.path().join("dusa.sock");
+        let server = serve(
+            UnixListener::bind(&socket).unwrap(),
+            vec![
+                message(
+                    MessageType::Response,
+                    serde_json::json!({ "value": "sealed" }),
+                ),
+                message(
+                    MessageType::Response,
+                    serde_json::json!({ "value": "opened" }),
+                ),
+                message(MessageType::ErrorResponse, serde_json::json!({})),
+            ],
+        );
+        let client = DusaClient::new(&socket, Duration::from_secs(2));
+
+        assert_eq!(client.encrypt_text_blocking("secret").unwrap(), "sealed");
+        assert_eq!(client.decrypt_text_blocking("sealed").unwrap(), "opened");
+        let err = client.encrypt_text_blocking("secret").unwrap_err();
+        assert!(matches!(err, DusaError::Rejected(_)), "{}", err);
+        server.join().unwrap();
+    }
 
-fn get_file_path(
-    mut errors: ErrorArray,
-    _warnings: WarningArray,
-    option_path_ref: &PathBuf,
-) -> uf<OkWarning<PathType>> {
-    let err = match option_path_ref.canonicalize() {
-        Ok(d) => {
-            let result = OkWarning {
-                data: PathType::PathBuf(d),
-                warning: _warnings,
-            };
-            return uf::new(Ok(result));
-        }
-        Err(err) => ErrorArrayItem::from(err),
-    };
-    errors.push(err);
-    uf::new(Err(errors))
+    #[tokio::test]
+    async fn test_unavailable_daemon_is_an_error() {
+        let root = TempDir::new().unwrap();
+        let client = Arc::new(DusaClient::new(
+            root.path().join("dusa.sock"),
+            Duration::from_millis(100),
+        ));
+
+        let started = Instant::now();
+        let err = client.encrypt_text("secret").await.unwrap_err();
+        assert!(matches!(err, DusaError::Unavailable(_)), "{}", err);
+        // Retried with a growing delay, then given up
+        assert!(started.elapsed() >= RETRY_DELAY * 3);
+        assert!(client.decrypt_text_blocking("secret").is_err());
+    }
 }
@@ -1,49 +1,29 @@
+// Blocking access to dusad for code that isn't async, see `dusa::DusaClient`.
+
 use dusa_collection_utils::{errors::ErrorArrayItem, stringy::Stringy};
+use tokio::runtime::{Handle, RuntimeFlavor};
 
-use crate::dusa::run;
+use crate::dusa::DusaClient;
 
 pub fn encrypt_text(data: Stringy) -> Result<Stringy, ErrorArrayItem> {
-    match run(
-        crate::dusa::ProgramMode::EncryptText,
-        None,
-        None,
-        None,
-        Some(data.to_string()),
-    )
-    .uf_unwrap()
-    {
-        Ok(d) => match d {
-            Some(d) => Ok(Stringy::new(&d)),
-            None => {
-                return Err(ErrorArrayItem::new(
-                    dusa_collection_utils::errors::Errors::GeneralError,
-                    String::from("No data received from dusa"),
-                ))
-            }
-        },
-        Err(mut e) => return Err(e.pop()),
-    }
+    let client = DusaClient::shared()?;
+    let encrypted = off_runtime(|| client.encrypt_text_blocking(&data))?;
+    Ok(Stringy::from(encrypted))
 }
 
 pub fn decrypt_text(data: Stringy) -> Result<Stringy, ErrorArrayItem> {
-    match run(
-        crate::dusa::ProgramMode::DecryptText,
-        None,
-        None,
-        None,
-        Some(data.to_string()),
-    )
-    .uf_unwrap()
-    {
-        Ok(d) => match d {
-            Some(d) => Ok(Stringy::new(&d)),
-            None => {
-                return Err(ErrorArrayItem::new(
-                    dusa_collection_utils::errors::Errors::GeneralError,
-                    String::from("No data received from dusa"),
-                ))
-            }
-        },
-        Err(mut e) => return Err(e.pop()),
+    let client = DusaClient::shared()?;
+    let decrypted = off_runtime(|| client.decrypt_text_blocking(&data))?;
+    Ok(Stringy::from(decrypted))
+}
+
+// Called from a multi threaded runtime, the worker hands its other tasks off
+// while this one waits on dusad. Single threaded runtimes can't do that.
+fn off_runtime<T>(request: impl FnOnce() -> T) -> T {
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(request)
+        }
+        _ => request(),
     }
 }
@@ -1,9 +1,23 @@
-use std::{env, future::Future, pin::Pin, process::Output};
+use std::{
+    fs::{self, OpenOptions},
+    future::Future,
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::PathBuf,
+    pin::Pin,
+    process::Output,
+};
 
 use dusa_collection_utils::{
     errors::{ErrorArrayItem, Errors}, stringy::Stringy, types::{ClonePath, PathType}
 };
 use tokio::process::Command;
+use uuid::Uuid;
+
+use crate::{
+    constants::{ALLOWED_SIGNERS, SIGNING_KEYRING},
+    git_data::{RemoteAuth, AUTH_FAILURE},
+};
 
 /// Function to check if Git is installed.
 async fn check_git_installed() -> Result<(), ErrorArrayItem> {
@@ -28,14 +42,15 @@ async fn check_git_installed() -> Result<(), ErrorArrayItem> {
 #[derive(Debug)]
 pub enum GitAction {
     Clone {
-        repo_name: Stringy,
-        repo_owner: Stringy,
+        repo_url: Stringy,
         destination: PathType,
         repo_branch: Stringy,
+        auth: RemoteAuth,
     },
     Pull {
         target_branch: Stringy,
         destination: PathType,
+        auth: RemoteAuth,
     },
     Push {
         directory: PathType,
@@ -55,10 +70,28 @@ pub enum GitAction {
     },
     // git config --global --add safe.directory /var/www/current/path
     SetSafe(PathType),
-    SetTrack(PathType),
+    SetTrack(PathType, RemoteAuth),
     Branch(PathType),
     Fetch {
         destination: PathType,
+        auth: RemoteAuth,
+    },
+    // git lfs pull, replacing the checkout's pointer files with their objects
+    LfsPull {
+        destination: PathType,
+        auth: RemoteAuth,
+    },
+    // git lfs ls-files --name-only
+    LfsFiles(PathType),
+    // git verify-commit, against the signers trusted by this host
+    VerifyCommit {
+        destination: PathType,
+        commit: Stringy,
+    },
+    // git verify-tag, against the signers trusted by this host
+    VerifyTag {
+        destination: PathType,
+        tag: Stringy,
     },
 }
 
@@ -66,7 +99,7 @@ impl GitAction {
     /// Execute the Git action.
     pub fn execute<'a>(
         &'a self,
-    ) -> Pin<Box<dyn Future<Output = Result<Option<Output>, ErrorArrayItem>> + 'a>> {
+    ) -> Pin<Box<dyn Future<Output = Result<Option<Output>, ErrorArrayItem>> + Send + 'a>> {
         Box::pin(async move {
             if let Err(errs) = check_git_installed().await {
                 return Err(errs);
@@ -75,18 +108,14 @@ impl GitAction {
             match self {
                 GitAction::Clone {
                     destination,
-                    repo_name,
-                    repo_owner,
+                    repo_url,
                     repo_branch,
+                    auth,
                 } => {
-                    let url = format!("https://github.com/{}/{}.git", repo_owner, repo_name);
-                    execute_git_command(&[
-                        "clone",
-                        "-b",
-                        repo_branch,
-                        &url,
-                        &destination.to_string(),
-                    ])
+                    execute_authenticated_git_command(
+                        &["clone", "-b", repo_branch, repo_url, &destination.to_string()],
+                        auth,
+                    )
                     .await
                     .map(|op| Some(op))
                 }
@@ -94,9 +123,16 @@ impl GitAction {
                 GitAction::Pull {
                     target_branch,
                     destination,
+                    auth,
                 } => match destination.exists() {
                     true => {
-                        execute_git_command(&["-C", &destination.to_string(), "pull"]).await?;
+                        // Never merges, diverged or locally edited checkouts
+                        // are left to the conflict policy
+                        execute_authenticated_git_command(
+                            &["-C", &destination.to_string(), "pull", "--ff-only"],
+                            auth,
+                        )
+                        .await?;
                         execute_git_command(&[
                             "-C",
                             &destination.to_string(),
@@ -170,9 +206,10 @@ impl GitAction {
                     }
                 }
 
-                GitAction::Fetch { destination } => {
+                GitAction::Fetch { destination, auth } => {
                     // Define the `git fetch` command
-                    let git_fetch = tokio::process::Command::new("git")
+                    let (mut command, _key_file) = git_command(auth)?;
+                    let git_fetch = command
                         .arg("fetch")
                         .arg("--all")
                         .current_dir(destination.clone_path()) // Ensure the command runs inside the correct directory
@@ -184,6 +221,9 @@ impl GitAction {
                         Ok(output) => {
                             if !output.status.success() {
                                 let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+                                if is_auth_stderr(&stderr) {
+                                    return Err(auth_error(&stderr));
+                                }
                                 return Err(ErrorArrayItem::new(
                                     Errors::GeneralError,
                                     format!("Failed to fetch from remote: {}", stderr),
@@ -199,6 +239,80 @@ impl GitAction {
                     }
                 }
 
+                GitAction::LfsPull { destination, auth } => match destination.exists() {
+                    true => {
+                        // Installs the lfs filters in the repository's own config,
+                        // without them git sees every pulled object as a modification
+                        execute_git_command(&[
+                            "-C",
+                            &destination.to_string(),
+                            "lfs",
+                            "install",
+                            "--local",
+                        ])
+                        .await?;
+                        execute_authenticated_git_command(
+                            &["-C", &destination.to_string(), "lfs", "pull", "origin"],
+                            auth,
+                        )
+                        .await
+                        .map(Some)
+                    }
+                    false => Err(ErrorArrayItem::new(
+                        Errors::InvalidFile,
+                        String::from("Repo path not found"),
+                    )),
+                },
+
+                GitAction::LfsFiles(directory) => match directory.exists() {
+                    true => execute_git_command(&[
+                        "-C",
+                        &directory.to_string(),
+                        "lfs",
+                        "ls-files",
+                        "--name-only",
+                    ])
+                    .await
+                    .map(Some),
+                    false => Err(ErrorArrayItem::new(
+                        Errors::InvalidFile,
+                        String::from("Repo path not found"),
+                    )),
+                },
+
+                GitAction::VerifyCommit {
+                    destination,
+                    commit,
+                } => match destination.exists() {
+                    true => execute_verify_command(&[
+                        "-C",
+                        &destination.to_string(),
+                        "verify-commit",
+                        commit,
+                    ])
+                    .await
+                    .map(Some),
+                    false => Err(ErrorArrayItem::new(
+                        Errors::InvalidFile,
+                        String::from("Repo path not found"),
+                    )),
+                },
+
+                GitAction::VerifyTag { destination, tag } => match destination.exists() {
+                    true => execute_verify_command(&[
+                        "-C",
+                        &destination.to_string(),
+                        "verify-tag",
+                        tag,
+                    ])
+                    .await
+                    .map(Some),
+                    false => Err(ErrorArrayItem::new(
+                        Errors::InvalidFile,
+                        String::from("Repo path not found"),
+                    )),
+                },
+
                 GitAction::Switch {
                     branch,
                     destination,
@@ -219,8 +333,9 @@ impl GitAction {
                     .await
                     .map(|op| Some(op))
                 }
-                GitAction::SetTrack(directory) => {
-                    execute_git_command(&["fetch"]).await?;
+                GitAction::SetTrack(directory, auth) => {
+                    execute_authenticated_git_command(&["-C", &directory.to_string(), "fetch"], auth)
+                        .await?;
                     let branch = Self::Branch(directory.clone()).execute().await?;
                     match branch {
                         Some(d) => {
@@ -241,6 +356,8 @@ impl GitAction {
 
                                 if !branch_name.is_empty() {
                                     execute_git_command(&[
+                                        "-C",
+                                        &directory.to_string(),
                                         "branch",
                                         "--track",
                                         &branch_name,
@@ -258,9 +375,7 @@ impl GitAction {
                     }
                 }
                 GitAction::Branch(directory) => {
-                    // let original_dir = env::current_dir()?;
-                    env::set_current_dir(directory)?;
-                    execute_git_command(&["branch", "-r"])
+                    execute_git_command(&["-C", &directory.to_string(), "branch", "-r"])
                         .await
                         .map(|op| Some(op))
                 }
@@ -271,7 +386,17 @@ impl GitAction {
 
 /// Execute a Git command.
 async fn execute_git_command(args: &[&str]) -> Result<Output, ErrorArrayItem> {
-    let output: std::process::Output = match Command::new("git").args(args).output().await {
+    execute_authenticated_git_command(args, &RemoteAuth::Anonymous).await
+}
+
+/// Execute a Git command that talks to a remote using `auth`.
+async fn execute_authenticated_git_command(
+    args: &[&str],
+    auth: &RemoteAuth,
+) -> Result<Output, ErrorArrayItem> {
+    // The key file has to outlive the command, it's removed when dropped
+    let (mut command, _key_file) = git_command(auth)?;
+    let output: std::process::Output = match command.args(args).output().await {
         Ok(output) => output,
         Err(io_err) => {
             return Err(ErrorArrayItem::from(io_err));
@@ -281,13 +406,108 @@ async fn execute_git_command(args: &[&str]) -> Result<Output, ErrorArrayItem> {
     if output.status.success() {
         Ok(output)
     } else {
-        return Err(ErrorArrayItem::new(
-            Errors::GeneralError,
-            String::from_utf8(output.stderr).unwrap(),
-        ));
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        if is_auth_stderr(&stderr) {
+            return Err(auth_error(&stderr));
+        }
+        Err(ErrorArrayItem::new(Errors::GeneralError, stderr))
     }
 }
 
+/// Execute a signature check. Only the host's trusted signers are consulted,
+/// never the keyring or signers file of whoever runs the command, and a
+/// missing, bad or unknown signature all fail with git's explanation.
+async fn execute_verify_command(args: &[&str]) -> Result<Output, ErrorArrayItem> {
+    let output = Command::new("git")
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .env("GNUPGHOME", SIGNING_KEYRING)
+        .env("GIT_CONFIG_COUNT", "1")
+        .env("GIT_CONFIG_KEY_0", "gpg.ssh.allowedSignersFile")
+        .env("GIT_CONFIG_VALUE_0", ALLOWED_SIGNERS)
+        .args(args)
+        .output()
+        .await?;
+
+    match output.status.success() {
+        true => Ok(output),
+        false => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let reason = match stderr.trim() {
+                "" => "no signature found",
+                reason => reason,
+            };
+            Err(ErrorArrayItem::new(Errors::Git, reason.to_owned()))
+        }
+    }
+}
+
+/// Creates a `git` command that never prompts and, for token auth, sends the
+/// token as an http header set through the environment. Config passed with
+/// GIT_CONFIG_COUNT isn't persisted or visible in the process arguments.
+/// Deploy keys are written to a root-only temporary file for ssh to read.
+fn git_command(auth: &RemoteAuth) -> Result<(Command, Option<KeyFile>), ErrorArrayItem> {
+    let mut command = Command::new("git");
+    command.env("GIT_TERMINAL_PROMPT", "0");
+
+    match auth {
+        RemoteAuth::Anonymous => Ok((command, None)),
+        RemoteAuth::Token { username, token } => {
+            let basic = base64::encode(format!("{}:{}", username, token));
+            command
+                .env("GIT_CONFIG_COUNT", "1")
+                .env("GIT_CONFIG_KEY_0", "http.extraHeader")
+                .env("GIT_CONFIG_VALUE_0", format!("Authorization: Basic {}", basic));
+            Ok((command, None))
+        }
+        RemoteAuth::SshKey { private_key, .. } => {
+            let key_file = KeyFile::new(private_key)?;
+            command.env(
+                "GIT_SSH_COMMAND",
+                format!(
+                    "ssh -i {} -o IdentitiesOnly=yes -o BatchMode=yes -o StrictHostKeyChecking=accept-new",
+                    key_file.0.display()
+                ),
+            );
+            Ok((command, Some(key_file)))
+        }
+    }
+}
+
+/// A private key written to a 0600 temporary file, deleted on drop.
+struct KeyFile(PathBuf);
+
+impl KeyFile {
+    fn new(private_key: &str) -> Result<Self, ErrorArrayItem> {
+        let path = std::env::temp_dir().join(format!("ais-ssh-{}", Uuid::new_v4()));
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?;
+        file.write_all(private_key.as_bytes())?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for KeyFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+fn is_auth_stderr(stderr: &str) -> bool {
+    stderr.contains("Authentication failed")
+        || stderr.contains("Permission denied (publickey)")
+        || stderr.contains("could not read Username")
+        || stderr.contains("terminal prompts disabled")
+        || stderr.contains("The requested URL returned error: 401")
+        || stderr.contains("The requested URL returned error: 403")
+}
+
+fn auth_error(stderr: &str) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::Git, format!("{}: {}", AUTH_FAILURE, stderr.trim()))
+}
+
 /// Check if the remote repository is ahead of the local repository.
 async fn check_remote_ahead(directory: &PathType) -> Result<bool, ErrorArrayItem> {
     execute_git_command(&["-C", directory.to_str().unwrap(), "fetch"]).await?;
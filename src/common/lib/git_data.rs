@@ -1,11 +1,21 @@
 use crate::{
     constants::ARTISANCF,
-    dusa_wrapper::{decrypt_text, encrypt_text},
+    credential_schema::{self, CREDENTIALS_VERSION},
+    deploy_keys::DeployKey,
+    secrets::{decrypt_text, encrypt_text},
+    system::{prompt_input, prompt_yes_no},
+};
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    functions::{create_hash, truncate},
+    stringy::Stringy,
 };
-use dusa_collection_utils::{errors::ErrorArrayItem, stringy::Stringy};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use simple_pretty::{notice, warn};
 use std::{
-    fs::File,
+    fmt,
+    fs::{self, File},
     io::{Read, Write},
 };
 
@@ -19,21 +29,718 @@ pub struct GitAuth {
     pub user: Stringy,
     pub repo: Stringy,
     pub branch: Stringy,
-    pub token: Stringy, // TODO REMOVE LATER
+    /// Access token for private repositories, `TOKEN_PLACEHOLDER` when unset.
+    pub token: Stringy,
+    #[serde(default)]
+    pub provider: GitProvider,
+    /// Deploy a fixed revision instead of following the head of `branch`.
+    #[serde(default)]
+    pub pin: Option<GitPin>,
+    /// Secret push webhooks for this repository are signed with, `None` to only poll.
+    #[serde(default)]
+    pub webhook_secret: Option<Stringy>,
+    /// Seconds between polls of this repository, the monitor's default when unset.
+    #[serde(default)]
+    pub poll_interval: Option<u64>,
+    /// How a checkout with local modifications or a diverged branch is handled.
+    #[serde(default)]
+    pub conflict_policy: ConflictPolicy,
+    /// Check out submodules, recursively, and include them in releases.
+    #[serde(default)]
+    pub submodules: bool,
+    /// Fetch Git LFS objects so releases hold the files instead of pointers.
+    #[serde(default)]
+    pub lfs: bool,
+    /// Signatures a revision needs before it is checked out and deployed.
+    #[serde(default)]
+    pub signature_policy: SignaturePolicy,
+}
+
+/// Stored in place of a token when a repository doesn't need one.
+pub const TOKEN_PLACEHOLDER: &str = "******";
+
+/// Prefix of every error caused by a remote rejecting our credentials.
+pub const AUTH_FAILURE: &str = "Authentication failed";
+
+/// Returns true if `err` was caused by a rejected, expired or missing credential.
+pub fn is_auth_failure(err: &ErrorArrayItem) -> bool {
+    err.to_string().contains(AUTH_FAILURE)
+}
+
+/// Prefix of every error raised when a checkout was left alone because of
+/// local modifications or a diverged branch.
+pub const CHECKOUT_CONFLICT: &str = "Checkout conflict";
+
+/// Returns true if `err` was raised by `ConflictPolicy::Refuse`.
+pub fn is_checkout_conflict(err: &ErrorArrayItem) -> bool {
+    err.to_string().contains(CHECKOUT_CONFLICT)
+}
+
+/// Prefix of every error raised when a revision was refused because it isn't
+/// signed by a trusted signer.
+pub const UNTRUSTED_REVISION: &str = "Untrusted revision";
+
+/// Returns true if `err` was raised by a `SignaturePolicy` check.
+pub fn is_untrusted_revision(err: &ErrorArrayItem) -> bool {
+    err.to_string().contains(UNTRUSTED_REVISION)
+}
+
+/// Credentials presented to a remote. These are handed to libgit2 or the git
+/// cli in memory and are never written to .git/config or a command line.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub enum RemoteAuth {
+    #[default]
+    Anonymous,
+    Token {
+        username: Stringy,
+        token: Stringy,
+    },
+    /// A deploy key, the private key is held in memory in OpenSSH format.
+    SshKey {
+        username: Stringy,
+        private_key: Stringy,
+    },
+}
+
+impl fmt::Debug for RemoteAuth {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RemoteAuth::Anonymous => write!(f, "Anonymous"),
+            RemoteAuth::Token { username, .. } => {
+                write!(f, "Token({}, {})", username, TOKEN_PLACEHOLDER)
+            }
+            RemoteAuth::SshKey { username, .. } => {
+                write!(f, "SshKey({}, {})", username, TOKEN_PLACEHOLDER)
+            }
+        }
+    }
+}
+
+/// Where a repository is hosted. Credentials written before this existed
+/// deserialize as `GitHub`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub enum GitProvider {
+    #[default]
+    GitHub,
+    GitLab,
+    /// A self-hosted Gitea instance, `host` may include a port.
+    Gitea {
+        host: Stringy,
+    },
+    /// Any other server reachable over https.
+    Https {
+        host: Stringy,
+    },
+    /// Any server reachable over ssh as `user`.
+    Ssh {
+        host: Stringy,
+        user: Stringy,
+    },
+    /// Bare repositories on the local filesystem under `root`.
+    File {
+        root: Stringy,
+    },
+    /// A complete remote url, `user` and `repo` are only used for naming.
+    Url(Stringy),
+}
+
+impl GitProvider {
+    /// Builds a provider from a kind name and, when the kind needs one, a
+    /// location. `ssh` takes the location as `user@host`.
+    pub fn from_parts(kind: &str, location: &str) -> Result<Self, ErrorArrayItem> {
+        let location = location.trim();
+        let needs_location = |provider: GitProvider| match location.is_empty() {
+            true => Err(ErrorArrayItem::new(
+                Errors::GeneralError,
+                format!("A location is required for {} remotes", kind),
+            )),
+            false => Ok(provider),
+        };
+
+        match kind.trim().to_lowercase().as_str() {
+            "" | "github" => Ok(GitProvider::GitHub),
+            "gitlab" => Ok(GitProvider::GitLab),
+            "gitea" => needs_location(GitProvider::Gitea {
+                host: Stringy::new(location),
+            }),
+            "https" => needs_location(GitProvider::Https {
+                host: Stringy::new(location),
+            }),
+            "ssh" => match location.split_once('@') {
+                Some((user, host)) if !user.is_empty() && !host.is_empty() => {
+                    Ok(GitProvider::Ssh {
+                        host: Stringy::new(host),
+                        user: Stringy::new(user),
+                    })
+                }
+                _ => Err(ErrorArrayItem::new(
+                    Errors::GeneralError,
+                    String::from("ssh remotes must be given as user@host"),
+                )),
+            },
+            "file" => needs_location(GitProvider::File {
+                root: Stringy::new(location.trim_end_matches('/')),
+            }),
+            "url" => needs_location(GitProvider::Url(Stringy::new(location))),
+            other => Err(ErrorArrayItem::new(
+                Errors::GeneralError,
+                format!("Unknown git provider: {}", other),
+            )),
+        }
+    }
+
+    /// Interactively asks for a provider on stdin until a valid one is given.
+    pub fn prompt() -> Self {
+        loop {
+            let kind =
+                prompt_input("Provider (github, gitlab, gitea, https, ssh, file, url) [github]: ");
+            let location = match kind.to_lowercase().as_str() {
+                "" | "github" | "gitlab" => Stringy::new(""),
+                "ssh" => prompt_input("Remote (user@host): "),
+                "file" => prompt_input("Directory containing <user>/<repo>.git: "),
+                "url" => prompt_input("Remote url: "),
+                _ => prompt_input("Host: "),
+            };
+
+            match GitProvider::from_parts(&kind, &location) {
+                Ok(provider) => return provider,
+                Err(e) => println!("{}", e),
+            }
+        }
+    }
+
+    /// The username paired with an access token over https. Gitea and generic
+    /// servers accept the account name, the hosted providers want a fixed one.
+    fn token_username<'a>(&self, user: &'a str) -> &'a str {
+        match self {
+            GitProvider::GitHub => "x-access-token",
+            GitProvider::GitLab => "oauth2",
+            _ => user,
+        }
+    }
+
+    /// Returns the clone url for `owner/repo` on this provider.
+    pub fn url(&self, owner: &str, repo: &str) -> Stringy {
+        let url = match self {
+            GitProvider::GitHub => format!("https://github.com/{}/{}.git", owner, repo),
+            GitProvider::GitLab => format!("https://gitlab.com/{}/{}.git", owner, repo),
+            GitProvider::Gitea { host } | GitProvider::Https { host } => {
+                format!("https://{}/{}/{}.git", host, owner, repo)
+            }
+            GitProvider::Ssh { host, user } => {
+                format!("ssh://{}@{}/{}/{}.git", user, host, owner, repo)
+            }
+            GitProvider::File { root } => format!("file://{}/{}/{}.git", root, owner, repo),
+            GitProvider::Url(url) => url.to_string(),
+        };
+
+        Stringy::from(url)
+    }
+}
+
+impl fmt::Display for GitProvider {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GitProvider::GitHub => write!(f, "GitHub"),
+            GitProvider::GitLab => write!(f, "GitLab"),
+            GitProvider::Gitea { host } => write!(f, "Gitea ({})", host),
+            GitProvider::Https { host } => write!(f, "Https ({})", host),
+            GitProvider::Ssh { host, user } => write!(f, "Ssh ({}@{})", user, host),
+            GitProvider::File { root } => write!(f, "File ({})", root),
+            GitProvider::Url(url) => write!(f, "Url ({})", url),
+        }
+    }
+}
+
+/// A revision a repository is pinned to. Pinned hosts only move when the tag
+/// or commit they name changes, `branch` is still fetched to find it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum GitPin {
+    Tag(Stringy),
+    /// A full or abbreviated commit sha.
+    Commit(Stringy),
+    /// The highest semver tag matching a glob such as `v*`.
+    TagPattern(Stringy),
+}
+
+impl GitPin {
+    /// Builds a pin from a kind name and value, `None` for an empty kind.
+    pub fn from_parts(kind: &str, value: &str) -> Result<Option<Self>, ErrorArrayItem> {
+        let value = value.trim();
+        let kind = kind.trim().to_lowercase();
+        if !matches!(kind.as_str(), "" | "none") && value.is_empty() {
+            return Err(ErrorArrayItem::new(
+                Errors::GeneralError,
+                format!("A value is required for {} pins", kind),
+            ));
+        }
+
+        match kind.as_str() {
+            "" | "none" => Ok(None),
+            "tag" => Ok(Some(GitPin::Tag(Stringy::new(value)))),
+            "commit" => match value.len() >= 7 && value.chars().all(|c| c.is_ascii_hexdigit()) {
+                true => Ok(Some(GitPin::Commit(Stringy::new(value)))),
+                false => Err(ErrorArrayItem::new(
+                    Errors::GeneralError,
+                    format!("{} is not a commit sha", value),
+                )),
+            },
+            "pattern" => Ok(Some(GitPin::TagPattern(Stringy::new(value)))),
+            other => Err(ErrorArrayItem::new(
+                Errors::GeneralError,
+                format!("Unknown pin kind: {}", other),
+            )),
+        }
+    }
+
+    /// Interactively asks for an optional pin on stdin until a valid one is given.
+    pub fn prompt() -> Option<Self> {
+        loop {
+            let kind = prompt_input("Pin (none, tag, commit, pattern) [none]: ");
+            let value = match kind.to_lowercase().as_str() {
+                "" | "none" => Stringy::new(""),
+                "pattern" => prompt_input("Tag pattern (e.g. v*): "),
+                _ => prompt_input("Value: "),
+            };
+
+            match GitPin::from_parts(&kind, &value) {
+                Ok(pin) => return pin,
+                Err(e) => println!("{}", e),
+            }
+        }
+    }
+}
+
+impl fmt::Display for GitPin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GitPin::Tag(tag) => write!(f, "tag {}", tag),
+            GitPin::Commit(commit) => write!(f, "commit {}", commit),
+            GitPin::TagPattern(pattern) => write!(f, "highest tag matching {}", pattern),
+        }
+    }
+}
+
+/// What the monitor does when a checkout has local modifications, or its branch
+/// has commits the remote doesn't, as happens after a force push.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Leave the checkout as it is and report the conflict.
+    #[default]
+    Refuse,
+    /// Stash local modifications and keep diverged commits on a backup branch,
+    /// then follow the remote.
+    Stash,
+    /// Discard local modifications and commits and follow the remote.
+    Reset,
+}
+
+impl ConflictPolicy {
+    pub fn from_name(name: &str) -> Result<Self, ErrorArrayItem> {
+        match name.trim().to_lowercase().as_str() {
+            "" | "refuse" => Ok(ConflictPolicy::Refuse),
+            "stash" => Ok(ConflictPolicy::Stash),
+            "reset" => Ok(ConflictPolicy::Reset),
+            other => Err(ErrorArrayItem::new(
+                Errors::GeneralError,
+                format!("Unknown conflict policy: {}", other),
+            )),
+        }
+    }
+
+    /// Interactively asks for a policy on stdin until a valid one is given.
+    pub fn prompt() -> Self {
+        loop {
+            let name = prompt_input("On local changes (refuse, stash, reset) [refuse]: ");
+            match ConflictPolicy::from_name(&name) {
+                Ok(policy) => return policy,
+                Err(e) => println!("{}", e),
+            }
+        }
+    }
+}
+
+impl fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConflictPolicy::Refuse => write!(f, "refuse"),
+            ConflictPolicy::Stash => write!(f, "stash"),
+            ConflictPolicy::Reset => write!(f, "reset"),
+        }
+    }
+}
+
+/// Signatures the monitor requires before deploying a revision. Trusted ssh
+/// keys are listed in `ALLOWED_SIGNERS`, trusted gpg keys are imported into the
+/// `SIGNING_KEYRING`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SignaturePolicy {
+    /// Deploy revisions whether they are signed or not.
+    #[default]
+    Off,
+    /// The commit itself must carry a trusted signature.
+    Commit,
+    /// A tag pointing at the commit must carry a trusted signature.
+    Tag,
+}
+
+impl SignaturePolicy {
+    pub fn from_name(name: &str) -> Result<Self, ErrorArrayItem> {
+        match name.trim().to_lowercase().as_str() {
+            "" | "off" => Ok(SignaturePolicy::Off),
+            "commit" => Ok(SignaturePolicy::Commit),
+            "tag" => Ok(SignaturePolicy::Tag),
+            other => Err(ErrorArrayItem::new(
+                Errors::GeneralError,
+                format!("Unknown signature policy: {}", other),
+            )),
+        }
+    }
+
+    /// Interactively asks for a policy on stdin until a valid one is given.
+    pub fn prompt() -> Self {
+        loop {
+            let name = prompt_input("Require signatures (off, commit, tag) [off]: ");
+            match SignaturePolicy::from_name(&name) {
+                Ok(policy) => return policy,
+                Err(e) => println!("{}", e),
+            }
+        }
+    }
+}
+
+impl fmt::Display for SignaturePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SignaturePolicy::Off => write!(f, "off"),
+            SignaturePolicy::Commit => write!(f, "signed commits"),
+            SignaturePolicy::Tag => write!(f, "signed tags"),
+        }
+    }
+}
+
+/// A tag's version for picking the highest of a `TagPattern`. Leading text
+/// such as `v` or `release-` is ignored and missing components count as 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagVersion {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+    pub pre: Option<String>,
+}
+
+impl TagVersion {
+    pub fn parse(tag: &str) -> Option<Self> {
+        let start = tag.find(|c: char| c.is_ascii_digit())?;
+        let version = &tag[start..];
+        let version = version.split('+').next().unwrap_or(version);
+        let (core, pre) = match version.split_once('-') {
+            Some((core, pre)) => (core, Some(pre.to_owned())),
+            None => (version, None),
+        };
+
+        let mut parts = core.split('.').map(|part| part.parse::<u64>());
+        let major = parts.next()?.ok()?;
+        let minor = parts.next().unwrap_or(Ok(0)).ok()?;
+        let patch = parts.next().unwrap_or(Ok(0)).ok()?;
+        if parts.next().is_some() {
+            return None;
+        }
+
+        Some(Self {
+            major,
+            minor,
+            patch,
+            pre,
+        })
+    }
+}
+
+impl Ord for TagVersion {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.major, self.minor, self.patch)
+            .cmp(&(other.major, other.minor, other.patch))
+            // A pre-release sorts before the release it leads up to
+            .then_with(|| match (&self.pre, &other.pre) {
+                (None, None) => std::cmp::Ordering::Equal,
+                (None, Some(_)) => std::cmp::Ordering::Greater,
+                (Some(_), None) => std::cmp::Ordering::Less,
+                (Some(a), Some(b)) => a.cmp(b),
+            })
+    }
+}
+
+impl PartialOrd for TagVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl GitAuth {
+    /// A repository on GitHub without a token, every other setting at its default.
+    pub fn new(user: &str, repo: &str, branch: &str) -> Self {
+        Self {
+            user: Stringy::new(user),
+            repo: Stringy::new(repo),
+            branch: Stringy::new(branch),
+            token: Stringy::new(TOKEN_PLACEHOLDER),
+            provider: GitProvider::default(),
+            pin: None,
+            webhook_secret: None,
+            poll_interval: None,
+            conflict_policy: ConflictPolicy::default(),
+            submodules: false,
+            lfs: false,
+            signature_policy: SignaturePolicy::default(),
+        }
+    }
+
+    /// The url this repository is cloned and fetched from.
+    pub fn remote_url(&self) -> Stringy {
+        self.provider.url(&self.user, &self.repo)
+    }
+
+    /// The configured token, `None` if it is empty or the placeholder.
+    pub fn access_token(&self) -> Option<&Stringy> {
+        match self.token.trim() {
+            "" | TOKEN_PLACEHOLDER => None,
+            _ => Some(&self.token),
+        }
+    }
+
+    /// The configured webhook secret, `None` if it is unset or masked.
+    pub fn webhook_secret(&self) -> Option<&Stringy> {
+        self.webhook_secret
+            .as_ref()
+            .filter(|secret| !matches!(secret.trim(), "" | TOKEN_PLACEHOLDER))
+    }
+
+    /// Short hash identifying this repository's deploy key. Projects were named
+    /// by it before the project registry, see `ProjectRegistry::slug`. The
+    /// provider isn't part of it, so a repository and branch can only be
+    /// configured once whichever host it is on, see `GitCredentials::add_auth`.
+    pub fn id(&self) -> Stringy {
+        let hash = create_hash(format!("{}-{}-{}", self.branch, self.repo, self.user));
+        Stringy::new(truncate(&hash, 8))
+    }
+
+    /// Credentials to present when cloning or fetching this repository. Ssh
+    /// remotes use the repository's deploy key, generating it on first use.
+    pub fn remote_auth(&self) -> Result<RemoteAuth, ErrorArrayItem> {
+        match (&self.provider, self.access_token()) {
+            (GitProvider::Ssh { user, .. }, _) => {
+                let key = DeployKey::ensure(self)?;
+                Ok(RemoteAuth::SshKey {
+                    username: user.clone(),
+                    private_key: key.private_key().clone(),
+                })
+            }
+            // Tokens are only meaningful over https
+            (GitProvider::File { .. }, _) | (_, None) => Ok(RemoteAuth::Anonymous),
+            (provider, Some(token)) => Ok(RemoteAuth::Token {
+                username: Stringy::new(provider.token_username(&self.user)),
+                token: token.clone(),
+            }),
+        }
+    }
+
+    /// Asks for an access token on stdin, an empty answer stores the placeholder.
+    pub fn prompt_token() -> Stringy {
+        let token = prompt_input("Access token (leave empty for public repositories): ");
+        match token.is_empty() {
+            true => Stringy::new(TOKEN_PLACEHOLDER),
+            false => token,
+        }
+    }
+
+    /// Asks for a webhook secret on stdin, an empty answer disables webhooks.
+    pub fn prompt_webhook_secret() -> Option<Stringy> {
+        let secret = prompt_input("Webhook secret (leave empty to only poll): ");
+        match secret.is_empty() {
+            true => None,
+            false => Some(secret),
+        }
+    }
+
+    /// Asks for a poll interval on stdin until a number or nothing is given.
+    pub fn prompt_poll_interval() -> Option<u64> {
+        loop {
+            let interval = prompt_input("Poll interval in seconds (leave empty for the default): ");
+            if interval.is_empty() {
+                return None;
+            }
+
+            match interval.parse::<u64>() {
+                Ok(seconds) if seconds > 0 => return Some(seconds),
+                _ => println!("{} is not a number of seconds", interval),
+            }
+        }
+    }
+
+    /// Asks whether submodules and LFS objects are fetched, both default to no.
+    pub fn prompt_checkout_extras() -> (bool, bool) {
+        let submodules = prompt_yes_no("Check out submodules? [y/N]: ");
+        let lfs = prompt_yes_no("Fetch Git LFS objects? [y/N]: ");
+        (submodules, lfs)
+    }
+
+    /// Sets a field from its command line name and a string value, as used by
+    /// `ais_credentials add` and `update`. Providers and pins take their kind
+    /// and value separated by a colon, such as `gitea:git.example.com` or
+    /// `tag:v1.2.0`, and `default` clears the poll interval.
+    pub fn set_field(&mut self, field: &str, value: &str) -> Result<(), ErrorArrayItem> {
+        let value = value.trim();
+        let invalid = |reason: &str| {
+            Err(ErrorArrayItem::new(
+                Errors::GeneralError,
+                format!("Invalid {} {}: {}", field, value, reason),
+            ))
+        };
+
+        match field {
+            "user" | "repo" | "branch" if value.is_empty() => return invalid("must not be empty"),
+            "user" => self.user = Stringy::new(value),
+            "repo" => self.repo = Stringy::new(value),
+            "branch" => self.branch = Stringy::new(value),
+            "token" => {
+                self.token = match value {
+                    "" => Stringy::new(TOKEN_PLACEHOLDER),
+                    token => Stringy::new(token),
+                }
+            }
+            "provider" => {
+                let (kind, location) = value.split_once(':').unwrap_or((value, ""));
+                self.provider = GitProvider::from_parts(kind, location)?;
+            }
+            "pin" => {
+                let (kind, pinned) = value.split_once(':').unwrap_or((value, ""));
+                self.pin = GitPin::from_parts(kind, pinned)?;
+            }
+            "webhook-secret" => {
+                self.webhook_secret = match value {
+                    "" => None,
+                    secret => Some(Stringy::new(secret)),
+                }
+            }
+            "poll-interval" => {
+                self.poll_interval = match value {
+                    "" | "default" => None,
+                    seconds => match seconds.parse::<u64>() {
+                        Ok(seconds) if seconds > 0 => Some(seconds),
+                        _ => return invalid("not a number of seconds"),
+                    },
+                }
+            }
+            "conflict-policy" => self.conflict_policy = ConflictPolicy::from_name(value)?,
+            "signatures" => self.signature_policy = SignaturePolicy::from_name(value)?,
+            "submodules" | "lfs" => {
+                let enabled = match value.to_lowercase().as_str() {
+                    "yes" | "true" | "on" => true,
+                    "no" | "false" | "off" => false,
+                    _ => return invalid("expected yes or no"),
+                };
+                match field {
+                    "submodules" => self.submodules = enabled,
+                    _ => self.lfs = enabled,
+                }
+            }
+            _ => {
+                return Err(ErrorArrayItem::new(
+                    Errors::GeneralError,
+                    format!("Unknown field: {}", field),
+                ))
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Settings that are accepted but can't work as configured.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (name, value) in [
+            ("user", &self.user),
+            ("repo", &self.repo),
+            ("branch", &self.branch),
+        ] {
+            if value.trim().is_empty() {
+                problems.push(format!("{} is empty", name));
+            }
+        }
+
+        match (&self.provider, self.access_token()) {
+            (GitProvider::Ssh { .. }, Some(_)) => problems.push(String::from(
+                "ssh remotes use the deploy key, the token is ignored",
+            )),
+            (GitProvider::File { .. }, Some(_)) => problems.push(String::from(
+                "file remotes are read directly, the token is ignored",
+            )),
+            _ => (),
+        }
+
+        if self.poll_interval == Some(0) {
+            problems.push(String::from("poll interval is 0 seconds"));
+        }
+        if self.webhook_secret.is_some() && self.webhook_secret().is_none() {
+            problems.push(String::from(
+                "webhook secret is masked, webhooks can't be verified",
+            ));
+        }
+
+        problems
+    }
+
+    /// A copy safe to display or send over the network, with the token and
+    /// webhook secret masked.
+    pub fn masked(&self) -> Self {
+        let mut masked = self.clone();
+        masked.token = Stringy::new(TOKEN_PLACEHOLDER);
+        masked.webhook_secret = masked
+            .webhook_secret
+            .map(|_| Stringy::new(TOKEN_PLACEHOLDER));
+        masked
+    }
 }
 
 // TODO ensure we are creating an Array of GitAuth items to parse in loops
 impl GitCredentials {
+    /// Loads `ARTISANCF`. A file in an older format is backed up, migrated
+    /// and written back in the current one.
     pub fn new() -> Result<Self, ErrorArrayItem> {
-        let encrypted_credentials: Stringy = Stringy::new(&Self::read_file(ARTISANCF)?);
+        let encrypted_contents = Self::read_file(ARTISANCF)?;
+        let encrypted_credentials: Stringy = Stringy::new(&encrypted_contents);
 
         let decrypted_string = decrypt_text(encrypted_credentials)?.replace("\n", "");
 
-        let data: GitCredentials = serde_json::from_str(&decrypted_string)?;
+        let (data, migrated_from) = Self::parse(&decrypted_string)?;
+
+        if let Some(version) = migrated_from {
+            let backup = credential_schema::backup(ARTISANCF, version, &encrypted_contents)?;
+            // The migrated credentials are usable even if they can't be stored
+            match data.save(ARTISANCF) {
+                Ok(()) => notice(&format!(
+                    "Migrated {} from version {} to {}, the original is kept as {}",
+                    ARTISANCF, version, CREDENTIALS_VERSION, backup
+                )),
+                Err(e) => warn(&format!(
+                    "Failed to store the migrated {}: {}",
+                    ARTISANCF, e
+                )),
+            }
+        }
 
         Ok(data)
     }
 
+    /// Parses decrypted credentials of any version. Also returns the version
+    /// they were migrated from, `None` if they were current.
+    pub fn parse(json: &str) -> Result<(Self, Option<u64>), ErrorArrayItem> {
+        let mut document: Value = serde_json::from_str(json)?;
+        let migrated_from = credential_schema::migrate(&mut document)?;
+        let data: GitCredentials = serde_json::from_value(document)?;
+        Ok((data, migrated_from))
+    }
+
     pub fn new_vec() -> Result<Vec<GitAuth>, ErrorArrayItem> {
         let git_credential = Self::new()?;
         let git_vec = git_credential.auth_items.clone();
@@ -45,15 +752,20 @@ impl GitCredentials {
     }
 
     pub fn save(&self, file_path: &str) -> Result<(), ErrorArrayItem> {
-        // Serialize GitCredentials to JSON
-        let json_data = Stringy::new(&serde_json::to_string(self)?);
+        // Serialize GitCredentials to JSON, stamped with the schema version
+        let mut document = serde_json::to_value(self)?;
+        document["version"] = Value::from(CREDENTIALS_VERSION);
+        let json_data = Stringy::new(&serde_json::to_string(&document)?);
 
         // Encrypt the JSON data
         let encrypted_data = encrypt_text(json_data)?;
 
-        // Write the encrypted data to the file
-        let mut file = File::create(file_path)?;
+        // Write the encrypted data next to the file and move it into place,
+        // every daemon reads this file and must never see half of it
+        let staging = format!("{}.tmp", file_path);
+        let mut file = File::create(&staging)?;
         file.write_all(encrypted_data.as_bytes())?;
+        fs::rename(&staging, file_path)?;
 
         Ok(())
     }
@@ -65,13 +777,107 @@ impl GitCredentials {
         Ok(file_contents.replace("\n", ""))
     }
 
-    pub fn add_auth(&mut self, auth: GitAuth) {
+    /// Adds `auth`, refusing it if its repository and branch are configured
+    /// already, on any provider.
+    pub fn add_auth(&mut self, auth: GitAuth) -> Result<(), ErrorArrayItem> {
+        if let Some(other) = self
+            .auth_items
+            .iter()
+            .find(|other| *other.id() == *auth.id())
+        {
+            return Err(ErrorArrayItem::new(
+                Errors::GeneralError,
+                format!(
+                    "{}/{}@{} is already configured from {}",
+                    auth.user, auth.repo, auth.branch, other.provider
+                ),
+            ));
+        }
+
         self.auth_items.push(auth);
+        Ok(())
+    }
+
+    /// Removes every entry whose repository and branch an earlier one already
+    /// configures, returning them. Only files edited by hand have any.
+    pub fn deduplicate(&mut self) -> Vec<GitAuth> {
+        let mut kept: Vec<GitAuth> = Vec::new();
+        let mut removed = Vec::new();
+        for auth in self.auth_items.drain(..) {
+            match kept.iter().any(|other| *other.id() == *auth.id()) {
+                true => removed.push(auth),
+                false => kept.push(auth),
+            }
+        }
+        self.auth_items = kept;
+        removed
+    }
+
+    /// Finds the entry `selector` names: its id, `owner/repo` when only one
+    /// branch of the repository is configured, or `owner/repo@branch`.
+    pub fn position(&self, selector: &str) -> Result<usize, ErrorArrayItem> {
+        let selector = selector.trim();
+        let (name, branch) = match selector.split_once('@') {
+            Some((name, branch)) => (name, Some(branch)),
+            None => (selector, None),
+        };
+
+        let matches: Vec<usize> = self
+            .auth_items
+            .iter()
+            .enumerate()
+            .filter(|(_, auth)| {
+                *auth.id() == *selector
+                    || (name.eq_ignore_ascii_case(&format!("{}/{}", auth.user, auth.repo))
+                        && branch.iter().all(|branch| *auth.branch == **branch))
+            })
+            .map(|(index, _)| index)
+            .collect();
+
+        match matches.as_slice() {
+            [index] => Ok(*index),
+            [] => Err(ErrorArrayItem::new(
+                Errors::GeneralError,
+                format!("No repository matches {}", selector),
+            )),
+            _ => Err(ErrorArrayItem::new(
+                Errors::GeneralError,
+                format!(
+                    "{} matches {} branches, use owner/repo@branch",
+                    selector,
+                    matches.len()
+                ),
+            )),
+        }
+    }
+
+    /// Problems with the stored entries, paired with the `owner/repo@branch`
+    /// they were found in. Nothing here contacts a remote.
+    pub fn validate(&self) -> Vec<(String, String)> {
+        let mut problems = Vec::new();
+        for (index, auth) in self.auth_items.iter().enumerate() {
+            let name = format!("{}/{}@{}", auth.user, auth.repo, auth.branch);
+            if let Some(other) = self.auth_items[..index]
+                .iter()
+                .find(|other| *other.id() == *auth.id())
+            {
+                problems.push((
+                    name.clone(),
+                    format!("configured more than once, also from {}", other.provider),
+                ));
+            }
+            for problem in auth.problems() {
+                problems.push((name.clone(), problem));
+            }
+        }
+        problems
     }
 
     pub fn bootstrap_git_credentials() -> Result<GitCredentials, ErrorArrayItem> {
         match GitCredentials::new() {
             Ok(creds) => Ok(creds),
+            // An unreadable file, such as one from a newer version, is never replaced
+            Err(e) if std::path::Path::new(ARTISANCF).exists() => Err(e),
             Err(_) => {
                 let default_creds = GitCredentials {
                     auth_items: Vec::new(),
@@ -82,3 +888,148 @@ impl GitCredentials {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_provider_urls() {
+        let cases = [
+            (GitProvider::GitHub, "https://github.com/owner/site.git"),
+            (GitProvider::GitLab, "https://gitlab.com/owner/site.git"),
+            (
+                GitProvider::from_parts("gitea", "git.example.com:3000").unwrap(),
+                "https://git.example.com:3000/owner/site.git",
+            ),
+            (
+                GitProvider::from_parts("ssh", "git@git.example.com").unwrap(),
+                "ssh://git@git.example.com/owner/site.git",
+            ),
+            (
+                GitProvider::from_parts("file", "/srv/git/").unwrap(),
+                "file:///srv/git/owner/site.git",
+            ),
+            (
+                GitProvider::from_parts("url", "https://example.com/x.git").unwrap(),
+                "https://example.com/x.git",
+            ),
+        ];
+
+        for (provider, expected) in cases {
+            assert_eq!(provider.url("owner", "site").to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn test_provider_defaults_to_github() {
+        let auth: GitAuth = serde_json::from_str(
+            r#"{"user":"owner","repo":"site","branch":"main","token":"******"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(auth.provider, GitProvider::GitHub);
+        assert_eq!(
+            auth.remote_url().to_string(),
+            "https://github.com/owner/site.git"
+        );
+        assert!(GitProvider::from_parts("ssh", "git.example.com").is_err());
+        assert!(GitProvider::from_parts("gitea", "").is_err());
+    }
+
+    #[test]
+    fn test_remote_auth() {
+        let mut auth = GitAuth::new("owner", "site", "main");
+        auth.webhook_secret = Some(Stringy::new("hook"));
+        assert_eq!(auth.remote_auth().unwrap(), RemoteAuth::Anonymous);
+
+        auth.token = Stringy::new("secret");
+        assert_eq!(
+            auth.remote_auth().unwrap(),
+            RemoteAuth::Token {
+                username: Stringy::new("x-access-token"),
+                token: Stringy::new("secret"),
+            }
+        );
+        assert!(!format!("{:?}", auth.remote_auth().unwrap()).contains("secret"));
+        assert_eq!(auth.masked().access_token(), None);
+        assert_eq!(auth.masked().webhook_secret(), None);
+        assert!(auth.masked().webhook_secret.is_some());
+
+        auth.provider = GitProvider::from_parts("file", "/srv/git").unwrap();
+        assert_eq!(auth.remote_auth().unwrap(), RemoteAuth::Anonymous);
+    }
+
+    #[test]
+    fn test_edit_and_select_credentials() {
+        let parse = |branch: &str| -> GitAuth {
+            serde_json::from_str(&format!(
+                r#"{{"user":"owner","repo":"site","branch":"{}","token":"******"}}"#,
+                branch
+            ))
+            .unwrap()
+        };
+        let mut auth = parse("main");
+
+        auth.set_field("provider", "gitea:git.example.com:3000")
+            .unwrap();
+        assert_eq!(
+            auth.remote_url().to_string(),
+            "https://git.example.com:3000/owner/site.git"
+        );
+        auth.set_field("pin", "tag:v1.2.0").unwrap();
+        assert_eq!(auth.pin, Some(GitPin::Tag(Stringy::new("v1.2.0"))));
+        auth.set_field("pin", "none").unwrap();
+        assert_eq!(auth.pin, None);
+        auth.set_field("poll-interval", "90").unwrap();
+        assert_eq!(auth.poll_interval, Some(90));
+        auth.set_field("lfs", "yes").unwrap();
+        assert!(auth.lfs);
+        assert!(auth.set_field("poll-interval", "soon").is_err());
+        assert!(auth.set_field("branch", "").is_err());
+        assert!(auth.set_field("owner", "someone").is_err());
+
+        let mut credentials = GitCredentials {
+            auth_items: vec![parse("main"), parse("staging")],
+        };
+        assert!(credentials.position("owner/site").is_err());
+        assert_eq!(credentials.position("Owner/site@staging").unwrap(), 1);
+        let id = credentials.auth_items[0].id();
+        assert_eq!(credentials.position(&id).unwrap(), 0);
+        assert!(credentials.position("owner/other").is_err());
+        assert!(credentials.validate().is_empty());
+
+        // Another provider is still the same project
+        let mut gitlab = parse("main");
+        gitlab.set_field("provider", "gitlab").unwrap();
+        assert!(credentials.add_auth(gitlab.clone()).is_err());
+        credentials.auth_items.push(gitlab);
+        credentials.auth_items[1]
+            .set_field("provider", "ssh:git@example.com")
+            .unwrap();
+        credentials.auth_items[1]
+            .set_field("token", "secret")
+            .unwrap();
+        assert_eq!(credentials.validate().len(), 2);
+        assert_eq!(credentials.deduplicate().len(), 1);
+        assert_eq!(credentials.auth_items.len(), 2);
+    }
+
+    #[test]
+    fn test_pins_and_tag_versions() {
+        assert_eq!(GitPin::from_parts("", "").unwrap(), None);
+        assert!(GitPin::from_parts("commit", "main").is_err());
+        assert!(GitPin::from_parts("tag", "").is_err());
+        assert!(matches!(
+            GitPin::from_parts("pattern", "v*").unwrap(),
+            Some(GitPin::TagPattern(_))
+        ));
+
+        let version = |tag: &str| TagVersion::parse(tag).unwrap();
+        assert!(version("v1.10.0") > version("v1.9.3"));
+        assert!(version("v2.0.0") > version("v2.0.0-rc.1"));
+        assert_eq!(version("release-3"), version("3.0.0"));
+        assert_eq!(TagVersion::parse("latest"), None);
+        assert_eq!(TagVersion::parse("v1.2.3.4"), None);
+    }
+}
@@ -0,0 +1,1302 @@
+// Native git operations backed by libgit2. These run on tokio's blocking pool so
+// a slow remote never stalls the runtime, and they never touch the process cwd.
+// The `GitAction` cli implementation in git.rs is only used as a fallback.
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    stringy::Stringy,
+    types::PathType,
+};
+use git2::{
+    build::{CheckoutBuilder, RepoBuilder},
+    AutotagOption, BranchType, Cred, CredentialType, Direction, ErrorClass, ErrorCode,
+    FetchOptions, ObjectType, Oid, Remote, RemoteCallbacks, Repository, ResetType, Signature,
+    StashFlags, Status, StatusOptions, SubmoduleUpdateOptions, TreeWalkMode, TreeWalkResult,
+};
+
+use crate::git_data::{GitPin, RemoteAuth, TagVersion, AUTH_FAILURE};
+
+/// The result of a clone or fast-forward.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GitUpdate {
+    /// The commit checked out before the operation, `None` for a fresh clone.
+    pub old_commit: Option<Stringy>,
+    /// The commit checked out after the operation.
+    pub new_commit: Stringy,
+    /// Paths that differ between the old and new commit.
+    pub changed_files: Vec<Stringy>,
+}
+
+impl GitUpdate {
+    /// True if the operation moved the checkout to a different commit.
+    pub fn changed(&self) -> bool {
+        match &self.old_commit {
+            Some(old) => **old != *self.new_commit,
+            None => true,
+        }
+    }
+}
+
+/// Commit counts between a local branch and its upstream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AheadBehind {
+    pub ahead: usize,
+    pub behind: usize,
+}
+
+/// Local state of a checkout that stops it from simply following its remote.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CheckoutConflict {
+    /// Tracked paths modified, added or deleted in the working tree or index.
+    pub modified: Vec<Stringy>,
+    /// Commits on the local branch that origin doesn't have.
+    pub local_commits: usize,
+}
+
+impl CheckoutConflict {
+    pub fn is_empty(&self) -> bool {
+        self.modified.is_empty() && self.local_commits == 0
+    }
+}
+
+impl fmt::Display for CheckoutConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+        if !self.modified.is_empty() {
+            let mut listed: Vec<String> = self
+                .modified
+                .iter()
+                .take(5)
+                .map(|path| path.to_string())
+                .collect();
+            if self.modified.len() > listed.len() {
+                listed.push(String::from("..."));
+            }
+            parts.push(format!(
+                "{} modified files ({})",
+                self.modified.len(),
+                listed.join(", ")
+            ));
+        }
+        if self.local_commits > 0 {
+            parts.push(format!(
+                "{} commits missing from the remote",
+                self.local_commits
+            ));
+        }
+        write!(f, "{}", parts.join(" and "))
+    }
+}
+
+/// Clones `url` into `destination` checking out `branch`.
+pub async fn clone_repo(
+    url: Stringy,
+    branch: Stringy,
+    destination: PathType,
+    auth: RemoteAuth,
+) -> Result<GitUpdate, ErrorArrayItem> {
+    let destination: PathBuf = destination.to_path_buf();
+    run_blocking(move || {
+        let mut builder = RepoBuilder::new();
+        builder.branch(&branch);
+        builder.fetch_options(fetch_options(&auth));
+
+        let repo = builder.clone(&url, &destination).map_err(git_error)?;
+        let head = repo
+            .head()
+            .and_then(|head| head.peel_to_commit())
+            .map_err(git_error)?;
+
+        Ok(GitUpdate {
+            old_commit: None,
+            new_commit: Stringy::from(head.id().to_string()),
+            changed_files: Vec::new(),
+        })
+    })
+    .await
+}
+
+/// Fetches every configured refspec from `origin`.
+pub async fn fetch(destination: PathType, auth: RemoteAuth) -> Result<(), ErrorArrayItem> {
+    let destination: PathBuf = destination.to_path_buf();
+    run_blocking(move || {
+        let repo = open_repo(&destination)?;
+        let mut remote = repo.find_remote("origin").map_err(git_error)?;
+        remote
+            .fetch::<&str>(&[], Some(&mut fetch_options(&auth)), None)
+            .map_err(git_error)
+    })
+    .await
+}
+
+/// Lists the refs `url` advertises without cloning it, like `git ls-remote`.
+pub async fn list_remote(url: Stringy, auth: RemoteAuth) -> Result<Vec<Stringy>, ErrorArrayItem> {
+    run_blocking(move || {
+        let mut remote = Remote::create_detached(url.to_string()).map_err(git_error)?;
+        let connection = remote
+            .connect_auth(Direction::Fetch, Some(remote_callbacks(&auth)), None)
+            .map_err(git_error)?;
+        let refs = connection
+            .list()
+            .map_err(git_error)?
+            .iter()
+            .map(|head| Stringy::from(head.name().to_owned()))
+            .collect();
+        Ok(refs)
+    })
+    .await
+}
+
+/// Fast-forwards `branch` to `origin/<branch>` and checks it out.
+///
+/// Fails without touching the checkout if the local branch has diverged.
+pub async fn fast_forward(
+    destination: PathType,
+    branch: Stringy,
+) -> Result<GitUpdate, ErrorArrayItem> {
+    let destination: PathBuf = destination.to_path_buf();
+    run_blocking(move || {
+        let repo = open_repo(&destination)?;
+        let upstream = upstream_commit(&repo, &branch)?;
+
+        let local_name = format!("refs/heads/{}", branch);
+        let old_commit = match repo.find_reference(&local_name) {
+            Ok(reference) => Some(reference.peel_to_commit().map_err(git_error)?),
+            Err(e) if e.code() == ErrorCode::NotFound => None,
+            Err(e) => return Err(git_error(e)),
+        };
+
+        if let Some(old) = &old_commit {
+            if old.id() != upstream
+                && !repo
+                    .graph_descendant_of(upstream, old.id())
+                    .map_err(git_error)?
+            {
+                return Err(ErrorArrayItem::new(
+                    Errors::Git,
+                    format!("{} cannot be fast-forwarded to origin/{}", branch, branch),
+                ));
+            }
+        }
+
+        checkout_commit(&repo, &branch, upstream)?;
+
+        let changed_files = match &old_commit {
+            Some(old) => changed_files(&repo, old.id(), upstream)?,
+            None => Vec::new(),
+        };
+
+        Ok(GitUpdate {
+            old_commit: old_commit.map(|commit| Stringy::from(commit.id().to_string())),
+            new_commit: Stringy::from(upstream.to_string()),
+            changed_files,
+        })
+    })
+    .await
+}
+
+/// Checks out `branch`, creating it from `origin/<branch>` if it doesn't exist locally.
+pub async fn switch_branch(destination: PathType, branch: Stringy) -> Result<(), ErrorArrayItem> {
+    let destination: PathBuf = destination.to_path_buf();
+    run_blocking(move || {
+        let repo = open_repo(&destination)?;
+        let target = match repo.find_branch(&branch, BranchType::Local) {
+            Ok(local) => local.get().peel_to_commit().map_err(git_error)?.id(),
+            Err(e) if e.code() == ErrorCode::NotFound => upstream_commit(&repo, &branch)?,
+            Err(e) => return Err(git_error(e)),
+        };
+
+        checkout_commit(&repo, &branch, target)
+    })
+    .await
+}
+
+/// Counts the commits `branch` is ahead of and behind `origin/<branch>`.
+pub async fn ahead_behind(
+    destination: PathType,
+    branch: Stringy,
+) -> Result<AheadBehind, ErrorArrayItem> {
+    let destination: PathBuf = destination.to_path_buf();
+    run_blocking(move || {
+        let repo = open_repo(&destination)?;
+        let local = repo
+            .find_branch(&branch, BranchType::Local)
+            .and_then(|local| local.get().peel_to_commit())
+            .map_err(git_error)?;
+        let upstream = upstream_commit(&repo, &branch)?;
+
+        let (ahead, behind) = repo
+            .graph_ahead_behind(local.id(), upstream)
+            .map_err(git_error)?;
+
+        Ok(AheadBehind { ahead, behind })
+    })
+    .await
+}
+
+/// Creates a local tracking branch for every branch on `origin` that doesn't have one.
+pub async fn set_tracking(destination: PathType) -> Result<(), ErrorArrayItem> {
+    let destination: PathBuf = destination.to_path_buf();
+    run_blocking(move || {
+        let repo = open_repo(&destination)?;
+        let remote_branches = repo.branches(Some(BranchType::Remote)).map_err(git_error)?;
+
+        for remote_branch in remote_branches {
+            let (remote_branch, _) = remote_branch.map_err(git_error)?;
+            let remote_name = match remote_branch.name().map_err(git_error)? {
+                Some(name) => name.to_owned(),
+                None => continue,
+            };
+
+            // Skip origin/HEAD and anything not on origin
+            let local_name = match remote_name.strip_prefix("origin/") {
+                Some("HEAD") | None => continue,
+                Some(name) => name.to_owned(),
+            };
+
+            if repo.find_branch(&local_name, BranchType::Local).is_ok() {
+                continue;
+            }
+
+            let commit = remote_branch.get().peel_to_commit().map_err(git_error)?;
+            let mut local = repo
+                .branch(&local_name, &commit, false)
+                .map_err(git_error)?;
+            local.set_upstream(Some(&remote_name)).map_err(git_error)?;
+        }
+
+        Ok(())
+    })
+    .await
+}
+
+/// Returns the commit currently checked out.
+pub async fn head_commit(destination: PathType) -> Result<Stringy, ErrorArrayItem> {
+    let destination: PathBuf = destination.to_path_buf();
+    run_blocking(move || {
+        let repo = open_repo(&destination)?;
+        let head = repo
+            .head()
+            .and_then(|head| head.peel_to_commit())
+            .map_err(git_error)?;
+        Ok(Stringy::from(head.id().to_string()))
+    })
+    .await
+}
+
+/// Returns the commit `origin/<branch>` pointed at when last fetched.
+pub async fn upstream_head(
+    destination: PathType,
+    branch: Stringy,
+) -> Result<Stringy, ErrorArrayItem> {
+    let destination: PathBuf = destination.to_path_buf();
+    run_blocking(move || {
+        let repo = open_repo(&destination)?;
+        let upstream = upstream_commit(&repo, &branch)?;
+        Ok(Stringy::from(upstream.to_string()))
+    })
+    .await
+}
+
+/// Names of the tags that point at `commit`, annotated or lightweight.
+pub async fn tags_at(
+    destination: PathType,
+    commit: Stringy,
+) -> Result<Vec<Stringy>, ErrorArrayItem> {
+    let destination: PathBuf = destination.to_path_buf();
+    run_blocking(move || {
+        let repo = open_repo(&destination)?;
+        let target = Oid::from_str(&commit).map_err(git_error)?;
+        let names = repo.tag_names(None).map_err(git_error)?;
+
+        let mut tags = Vec::new();
+        for name in names.iter().flatten() {
+            // Tags of trees or blobs can't point at a commit
+            let tagged = repo
+                .revparse_single(&format!("refs/tags/{}", name))
+                .and_then(|object| object.peel_to_commit());
+            if matches!(tagged, Ok(tagged) if tagged.id() == target) {
+                tags.push(Stringy::from(name));
+            }
+        }
+        Ok(tags)
+    })
+    .await
+}
+
+/// Resolves `pin` to the commit it names from the refs fetched so far.
+pub async fn resolve_pin(destination: PathType, pin: GitPin) -> Result<Stringy, ErrorArrayItem> {
+    let destination: PathBuf = destination.to_path_buf();
+    run_blocking(move || {
+        let repo = open_repo(&destination)?;
+        let revision = match &pin {
+            GitPin::Tag(tag) => format!("refs/tags/{}", tag),
+            GitPin::Commit(commit) => commit.to_string(),
+            GitPin::TagPattern(pattern) => {
+                let tags = repo.tag_names(Some(pattern)).map_err(git_error)?;
+                let highest = tags
+                    .iter()
+                    .flatten()
+                    .filter_map(|tag| TagVersion::parse(tag).map(|version| (version, tag)))
+                    .max_by(|a, b| a.0.cmp(&b.0))
+                    .ok_or_else(|| {
+                        ErrorArrayItem::new(
+                            Errors::Git,
+                            format!("No version tag matches {}", pattern),
+                        )
+                    })?;
+                format!("refs/tags/{}", highest.1)
+            }
+        };
+
+        let commit = repo
+            .revparse_single(&revision)
+            .and_then(|object| object.peel_to_commit())
+            .map_err(|err| {
+                ErrorArrayItem::new(
+                    Errors::Git,
+                    format!("Failed to resolve {}: {}", pin, err.message()),
+                )
+            })?;
+        Ok(Stringy::from(commit.id().to_string()))
+    })
+    .await
+}
+
+/// Checks out `commit` with a detached HEAD, as pinned checkouts follow no branch.
+///
+/// Like `fast_forward`, local modifications abort the checkout.
+pub async fn checkout_detached(
+    destination: PathType,
+    commit: Stringy,
+) -> Result<GitUpdate, ErrorArrayItem> {
+    let destination: PathBuf = destination.to_path_buf();
+    run_blocking(move || {
+        let repo = open_repo(&destination)?;
+        let target = Oid::from_str(&commit).map_err(git_error)?;
+        let old_commit = repo
+            .head()
+            .and_then(|head| head.peel_to_commit())
+            .map(|commit| commit.id())
+            .ok();
+
+        let new_commit = repo.find_commit(target).map_err(git_error)?;
+        repo.checkout_tree(new_commit.as_object(), Some(CheckoutBuilder::new().safe()))
+            .map_err(git_error)?;
+        repo.set_head_detached(target).map_err(git_error)?;
+
+        let changed_files = match old_commit {
+            Some(old) => changed_files(&repo, old, target)?,
+            None => Vec::new(),
+        };
+
+        Ok(GitUpdate {
+            old_commit: old_commit.map(|commit| Stringy::from(commit.to_string())),
+            new_commit: Stringy::from(target.to_string()),
+            changed_files,
+        })
+    })
+    .await
+}
+
+/// Looks for local modifications to tracked files and, for a checkout that
+/// follows `branch`, commits origin doesn't have. Run after a fetch. Untracked
+/// files are ignored, releases are exported from commits and never see them.
+pub async fn inspect_checkout(
+    destination: PathType,
+    branch: Option<Stringy>,
+) -> Result<CheckoutConflict, ErrorArrayItem> {
+    let destination: PathBuf = destination.to_path_buf();
+    run_blocking(move || {
+        let repo = open_repo(&destination)?;
+
+        // Submodules are always forced to their recorded commit by `update_submodules`
+        let mut options = StatusOptions::new();
+        options
+            .include_untracked(false)
+            .include_ignored(false)
+            .exclude_submodules(true);
+        let modified = repo
+            .statuses(Some(&mut options))
+            .map_err(git_error)?
+            .iter()
+            .filter(|entry| entry.status() != Status::CURRENT)
+            .filter_map(|entry| entry.path().map(|path| Stringy::from(path.to_owned())))
+            .collect();
+
+        let local_commits = match &branch {
+            Some(branch) => match repo.find_branch(branch, BranchType::Local) {
+                Ok(local) => {
+                    let local = local.get().peel_to_commit().map_err(git_error)?;
+                    let upstream = upstream_commit(&repo, branch)?;
+                    repo.graph_ahead_behind(local.id(), upstream)
+                        .map_err(git_error)?
+                        .0
+                }
+                Err(e) if e.code() == ErrorCode::NotFound => 0,
+                Err(e) => return Err(git_error(e)),
+            },
+            None => 0,
+        };
+
+        Ok(CheckoutConflict {
+            modified,
+            local_commits,
+        })
+    })
+    .await
+}
+
+/// Stashes local modifications to tracked files, returning the stash commit.
+pub async fn stash_changes(
+    destination: PathType,
+    message: String,
+) -> Result<Stringy, ErrorArrayItem> {
+    let destination: PathBuf = destination.to_path_buf();
+    run_blocking(move || {
+        let mut repo = open_repo(&destination)?;
+        // Deployed checkouts rarely have an identity configured
+        let signature = repo
+            .signature()
+            .or_else(|_| Signature::now("ais", "ais@localhost"))
+            .map_err(git_error)?;
+
+        repo.stash_save(&signature, &message, Some(StashFlags::DEFAULT))
+            .map(|oid| Stringy::from(oid.to_string()))
+            .map_err(git_error)
+    })
+    .await
+}
+
+/// Discards local modifications to tracked files, like `git reset --hard`.
+pub async fn discard_changes(destination: PathType) -> Result<(), ErrorArrayItem> {
+    let destination: PathBuf = destination.to_path_buf();
+    run_blocking(move || {
+        let repo = open_repo(&destination)?;
+        let head = repo
+            .head()
+            .and_then(|head| head.peel(ObjectType::Commit))
+            .map_err(git_error)?;
+        repo.reset(&head, ResetType::Hard, None).map_err(git_error)
+    })
+    .await
+}
+
+/// Moves `branch` to `origin/<branch>` and checks it out, dropping its local
+/// commits and any modifications. With `backup` the old head is first kept on
+/// a branch of that name.
+pub async fn reset_branch(
+    destination: PathType,
+    branch: Stringy,
+    backup: Option<String>,
+) -> Result<GitUpdate, ErrorArrayItem> {
+    let destination: PathBuf = destination.to_path_buf();
+    run_blocking(move || {
+        let repo = open_repo(&destination)?;
+        let upstream = upstream_commit(&repo, &branch)?;
+        let mut local = repo
+            .find_branch(&branch, BranchType::Local)
+            .map_err(git_error)?
+            .into_reference();
+        let old = local.peel_to_commit().map_err(git_error)?;
+
+        if let Some(backup) = backup {
+            repo.branch(&backup, &old, false).map_err(git_error)?;
+        }
+
+        let target = repo.find_commit(upstream).map_err(git_error)?;
+        repo.checkout_tree(target.as_object(), Some(CheckoutBuilder::new().force()))
+            .map_err(git_error)?;
+        local
+            .set_target(upstream, "ais: reset to origin")
+            .map_err(git_error)?;
+        repo.set_head(&format!("refs/heads/{}", branch))
+            .map_err(git_error)?;
+
+        Ok(GitUpdate {
+            old_commit: Some(Stringy::from(old.id().to_string())),
+            new_commit: Stringy::from(upstream.to_string()),
+            changed_files: changed_files(&repo, old.id(), upstream)?,
+        })
+    })
+    .await
+}
+
+/// Writes the tree of `commit` into `target` without touching the checkout's
+/// working tree, index or HEAD.
+pub async fn export_commit(
+    destination: PathType,
+    commit: Stringy,
+    target: PathType,
+) -> Result<(), ErrorArrayItem> {
+    let destination: PathBuf = destination.to_path_buf();
+    let target: PathBuf = target.to_path_buf();
+    run_blocking(move || {
+        let repo = open_repo(&destination)?;
+        let oid = Oid::from_str(&commit).map_err(git_error)?;
+        export_tree(&repo, oid, &target)
+    })
+    .await
+}
+
+/// Initializes every submodule and checks out the commit recorded for it,
+/// recursing into nested submodules. Submodules are fetched with the same
+/// credentials as the repository itself.
+pub async fn update_submodules(
+    destination: PathType,
+    auth: RemoteAuth,
+) -> Result<(), ErrorArrayItem> {
+    let destination: PathBuf = destination.to_path_buf();
+    run_blocking(move || {
+        let repo = open_repo(&destination)?;
+        update_submodules_of(&repo, &auth)
+    })
+    .await
+}
+
+/// Exports the recorded commit of every submodule in `commit` into its path
+/// under `target`, recursing into nested submodules. The submodules have to be
+/// checked out by `update_submodules` first.
+pub async fn export_submodules(
+    destination: PathType,
+    commit: Stringy,
+    target: PathType,
+) -> Result<(), ErrorArrayItem> {
+    let destination: PathBuf = destination.to_path_buf();
+    let target: PathBuf = target.to_path_buf();
+    run_blocking(move || {
+        let repo = open_repo(&destination)?;
+        let oid = Oid::from_str(&commit).map_err(git_error)?;
+        export_submodules_of(&repo, oid, &target)
+    })
+    .await
+}
+
+/// Points `origin` at `url`, leaving the config untouched if it already matches.
+pub async fn set_origin(destination: PathType, url: Stringy) -> Result<(), ErrorArrayItem> {
+    let destination: PathBuf = destination.to_path_buf();
+    run_blocking(move || {
+        let repo = open_repo(&destination)?;
+        let remote = repo.find_remote("origin").map_err(git_error)?;
+        if remote.url() == Some(&*url) {
+            return Ok(());
+        }
+
+        repo.remote_set_url("origin", &url).map_err(git_error)
+    })
+    .await
+}
+
+/// Runs a blocking libgit2 operation on tokio's blocking pool.
+async fn run_blocking<T, F>(operation: F) -> Result<T, ErrorArrayItem>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, ErrorArrayItem> + Send + 'static,
+{
+    tokio::task::spawn_blocking(operation)
+        .await
+        .map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))?
+}
+
+/// Builds fetch options that answer credential requests from `auth`.
+///
+/// libgit2 keeps asking while the callback returns credentials, so a rejected
+/// token or key is only offered once before the fetch fails as an auth failure.
+fn fetch_options(auth: &RemoteAuth) -> FetchOptions<'_> {
+    let mut options = FetchOptions::new();
+    options.remote_callbacks(remote_callbacks(auth));
+    // Pinned tags may point at commits no fetched branch contains
+    options.download_tags(AutotagOption::All);
+    options
+}
+
+fn remote_callbacks(auth: &RemoteAuth) -> RemoteCallbacks<'_> {
+    let mut callbacks = RemoteCallbacks::new();
+    let mut attempted = false;
+    callbacks.credentials(move |_url, username_from_url, allowed| match auth {
+        RemoteAuth::Anonymous => Err(git2::Error::from_str("the remote requires credentials")),
+        RemoteAuth::Token { username, token } => {
+            if attempted || !allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+                return Err(git2::Error::from_str("the remote rejected the token"));
+            }
+            attempted = true;
+            Cred::userpass_plaintext(username, token)
+        }
+        RemoteAuth::SshKey {
+            username,
+            private_key,
+        } => {
+            let username = username_from_url.unwrap_or(username);
+            // Ssh asks for the username on its own before the key
+            if allowed.contains(CredentialType::USERNAME) {
+                return Cred::username(username);
+            }
+            if attempted || !allowed.contains(CredentialType::SSH_KEY) {
+                return Err(git2::Error::from_str("the remote rejected the deploy key"));
+            }
+            attempted = true;
+            Cred::ssh_key_from_memory(username, None, private_key, None)
+        }
+    });
+    callbacks
+}
+
+fn open_repo(path: &Path) -> Result<Repository, ErrorArrayItem> {
+    Repository::open(path).map_err(git_error)
+}
+
+fn upstream_commit(repo: &Repository, branch: &str) -> Result<Oid, ErrorArrayItem> {
+    repo.find_reference(&format!("refs/remotes/origin/{}", branch))
+        .and_then(|reference| reference.peel_to_commit())
+        .map(|commit| commit.id())
+        .map_err(git_error)
+}
+
+/// Updates the working tree to `target`, then points `branch` and HEAD at it.
+///
+/// The checkout happens first with the safe strategy so local modifications
+/// that would be overwritten abort the operation before any ref moves.
+fn checkout_commit(repo: &Repository, branch: &str, target: Oid) -> Result<(), ErrorArrayItem> {
+    let commit = repo.find_commit(target).map_err(git_error)?;
+    repo.checkout_tree(commit.as_object(), Some(CheckoutBuilder::new().safe()))
+        .map_err(git_error)?;
+
+    match repo.find_branch(branch, BranchType::Local) {
+        Ok(local) => {
+            local
+                .into_reference()
+                .set_target(target, "ais: fast-forward")
+                .map_err(git_error)?;
+        }
+        Err(e) if e.code() == ErrorCode::NotFound => {
+            let mut local = repo.branch(branch, &commit, false).map_err(git_error)?;
+            local
+                .set_upstream(Some(&format!("origin/{}", branch)))
+                .map_err(git_error)?;
+        }
+        Err(e) => return Err(git_error(e)),
+    }
+
+    repo.set_head(&format!("refs/heads/{}", branch))
+        .map_err(git_error)
+}
+
+/// Writes the tree of `commit` into `target` without touching the index.
+fn export_tree(repo: &Repository, commit: Oid, target: &Path) -> Result<(), ErrorArrayItem> {
+    let commit = repo.find_commit(commit).map_err(git_error)?;
+
+    let mut checkout = CheckoutBuilder::new();
+    checkout
+        .target_dir(target)
+        .force()
+        .recreate_missing(true)
+        .update_index(false);
+
+    repo.checkout_tree(commit.as_object(), Some(&mut checkout))
+        .map_err(git_error)
+}
+
+fn update_submodules_of(repo: &Repository, auth: &RemoteAuth) -> Result<(), ErrorArrayItem> {
+    for mut submodule in repo.submodules().map_err(git_error)? {
+        let mut options = SubmoduleUpdateOptions::new();
+        options.fetch(fetch_options(auth));
+        submodule
+            .update(true, Some(&mut options))
+            .map_err(|err| submodule_error(submodule.path(), err))?;
+
+        let nested = submodule
+            .open()
+            .map_err(|err| submodule_error(submodule.path(), err))?;
+        update_submodules_of(&nested, auth)?;
+    }
+
+    Ok(())
+}
+
+fn export_submodules_of(
+    repo: &Repository,
+    commit: Oid,
+    target: &Path,
+) -> Result<(), ErrorArrayItem> {
+    let tree = repo
+        .find_commit(commit)
+        .and_then(|commit| commit.tree())
+        .map_err(git_error)?;
+
+    // Submodules are recorded in the tree as entries pointing at a commit
+    let mut gitlinks = Vec::new();
+    tree.walk(TreeWalkMode::PreOrder, |root, entry| {
+        if entry.kind() == Some(ObjectType::Commit) {
+            if let Some(name) = entry.name() {
+                gitlinks.push((format!("{}{}", root, name), entry.id()));
+            }
+        }
+        TreeWalkResult::Ok
+    })
+    .map_err(git_error)?;
+
+    for (path, oid) in gitlinks {
+        let submodule = repo
+            .find_submodule(&path)
+            .and_then(|submodule| submodule.open())
+            .map_err(|err| submodule_error(Path::new(&path), err))?;
+
+        let destination = target.join(&path);
+        std::fs::create_dir_all(&destination)?;
+        export_tree(&submodule, oid, &destination)?;
+        export_submodules_of(&submodule, oid, &destination)?;
+    }
+
+    Ok(())
+}
+
+fn submodule_error(path: &Path, err: git2::Error) -> ErrorArrayItem {
+    ErrorArrayItem::new(
+        Errors::Git,
+        format!("Submodule {}: {}", path.display(), git_message(&err)),
+    )
+}
+
+fn changed_files(repo: &Repository, old: Oid, new: Oid) -> Result<Vec<Stringy>, ErrorArrayItem> {
+    let old_tree = repo
+        .find_commit(old)
+        .and_then(|commit| commit.tree())
+        .map_err(git_error)?;
+    let new_tree = repo
+        .find_commit(new)
+        .and_then(|commit| commit.tree())
+        .map_err(git_error)?;
+
+    let diff = repo
+        .diff_tree_to_tree(Some(&old_tree), Some(&new_tree), None)
+        .map_err(git_error)?;
+
+    Ok(diff
+        .deltas()
+        .filter_map(|delta| delta.new_file().path().or(delta.old_file().path()))
+        .map(|path| Stringy::from(path.to_string_lossy().to_string()))
+        .collect())
+}
+
+fn git_error(err: git2::Error) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::Git, git_message(&err))
+}
+
+fn git_message(err: &git2::Error) -> String {
+    // Credential callback errors surface with the callback's message and no
+    // useful code, servers rejecting a token surface as http errors
+    let auth_failed = err.code() == ErrorCode::Auth
+        || err.message().contains("credentials")
+        || err.message().contains("rejected the token")
+        || err.message().contains("rejected the deploy key")
+        || (err.class() == ErrorClass::Http
+            && (err.message().contains("401") || err.message().contains("403")));
+
+    match auth_failed {
+        true => format!("{}: {}", AUTH_FAILURE, err.message()),
+        false => err.message().to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::git_data::{is_auth_failure, GitProvider};
+    use git2::{Signature, Time};
+    use std::fs;
+    use tempfile::TempDir;
+
+    /// Creates a bare "remote" at `<root>/owner/site.git` and a seed
+    /// repository that pushes to it.
+    fn setup_remote() -> (TempDir, PathBuf, Repository) {
+        let root = TempDir::new().unwrap();
+        let bare_path = root.path().join("owner").join("site.git");
+        Repository::init_bare(&bare_path).unwrap();
+
+        let seed = Repository::init(root.path().join("seed")).unwrap();
+        seed.remote("origin", bare_path.to_str().unwrap()).unwrap();
+
+        (root, bare_path, seed)
+    }
+
+    fn commit_file(repo: &Repository, name: &str, content: &str) -> Oid {
+        let workdir = repo.workdir().unwrap().to_path_buf();
+        fs::write(workdir.join(name), content).unwrap();
+
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new(name)).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+
+        let signature = Signature::new("ais", "ais@localhost", &Time::new(0, 0)).unwrap();
+        let parent = repo
+            .find_reference("refs/heads/main")
+            .and_then(|reference| reference.peel_to_commit())
+            .ok();
+        let parents: Vec<&git2::Commit> = parent.iter().collect();
+
+        let oid = repo
+            .commit(
+                Some("refs/heads/main"),
+                &signature,
+                &signature,
+                name,
+                &tree,
+                &parents,
+            )
+            .unwrap();
+
+        repo.find_remote("origin")
+            .unwrap()
+            .push(&["refs/heads/main:refs/heads/main"], None)
+            .unwrap();
+
+        oid
+    }
+
+    fn push_tag(repo: &Repository, name: &str, target: Oid) {
+        let object = repo.find_object(target, None).unwrap();
+        let signature = Signature::new("ais", "ais@localhost", &Time::new(0, 0)).unwrap();
+        repo.tag(name, &object, &signature, name, false).unwrap();
+        repo.find_remote("origin")
+            .unwrap()
+            .push(&[format!("refs/tags/{}:refs/tags/{}", name, name)], None)
+            .unwrap();
+    }
+
+    fn path_type(path: &Path) -> PathType {
+        PathType::PathBuf(path.to_path_buf())
+    }
+
+    #[tokio::test]
+    async fn test_clone_checks_out_branch() {
+        let (root, bare_path, seed) = setup_remote();
+        let first = commit_file(&seed, "index.html", "hello");
+        let checkout = root.path().join("checkout");
+
+        let update = clone_repo(
+            Stringy::from(bare_path.to_string_lossy().to_string()),
+            Stringy::new("main"),
+            path_type(&checkout),
+            RemoteAuth::Anonymous,
+        )
+        .await
+        .unwrap();
+
+        assert!(update.changed());
+        assert_eq!(update.new_commit.to_string(), first.to_string());
+        assert!(checkout.join("index.html").exists());
+    }
+
+    #[tokio::test]
+    async fn test_clone_from_file_provider() {
+        let (root, _, seed) = setup_remote();
+        let first = commit_file(&seed, "index.html", "hello");
+        let checkout = root.path().join("checkout");
+
+        let provider = GitProvider::File {
+            root: Stringy::from(root.path().to_string_lossy().to_string()),
+        };
+        let url = provider.url("owner", "site");
+
+        let update = clone_repo(
+            url.clone(),
+            Stringy::new("main"),
+            path_type(&checkout),
+            RemoteAuth::Anonymous,
+        )
+        .await
+        .unwrap();
+        assert_eq!(update.new_commit.to_string(), first.to_string());
+
+        let moved = Stringy::new("file:///srv/git/owner/site.git");
+        set_origin(path_type(&checkout), moved.clone())
+            .await
+            .unwrap();
+        let repo = Repository::open(&checkout).unwrap();
+        assert_eq!(
+            repo.find_remote("origin").unwrap().url(),
+            Some(moved.to_string().as_str())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fetch_and_fast_forward() {
+        let (root, bare_path, seed) = setup_remote();
+        let first = commit_file(&seed, "index.html", "hello");
+        let checkout = root.path().join("checkout");
+
+        clone_repo(
+            Stringy::from(bare_path.to_string_lossy().to_string()),
+            Stringy::new("main"),
+            path_type(&checkout),
+            RemoteAuth::Anonymous,
+        )
+        .await
+        .unwrap();
+
+        let second = commit_file(&seed, "about.html", "about");
+        fetch(path_type(&checkout), RemoteAuth::Anonymous)
+            .await
+            .unwrap();
+
+        let counts = ahead_behind(path_type(&checkout), Stringy::new("main"))
+            .await
+            .unwrap();
+        assert_eq!(
+            counts,
+            AheadBehind {
+                ahead: 0,
+                behind: 1
+            }
+        );
+
+        let update = fast_forward(path_type(&checkout), Stringy::new("main"))
+            .await
+            .unwrap();
+        assert!(update.changed());
+        assert_eq!(
+            update.old_commit.map(|c| c.to_string()),
+            Some(first.to_string())
+        );
+        assert_eq!(update.new_commit.to_string(), second.to_string());
+        assert_eq!(
+            update
+                .changed_files
+                .iter()
+                .map(|file| file.to_string())
+                .collect::<Vec<String>>(),
+            vec![String::from("about.html")]
+        );
+        assert!(checkout.join("about.html").exists());
+
+        // A second pass has nothing to do
+        let update = fast_forward(path_type(&checkout), Stringy::new("main"))
+            .await
+            .unwrap();
+        assert!(!update.changed());
+        assert!(update.changed_files.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_conflicts_are_detected_and_resolved() {
+        let (root, bare_path, seed) = setup_remote();
+        let first = commit_file(&seed, "index.html", "hello");
+        let checkout = root.path().join("checkout");
+
+        clone_repo(
+            Stringy::from(bare_path.to_string_lossy().to_string()),
+            Stringy::new("main"),
+            path_type(&checkout),
+            RemoteAuth::Anonymous,
+        )
+        .await
+        .unwrap();
+        let clean = inspect_checkout(path_type(&checkout), Some(Stringy::new("main")))
+            .await
+            .unwrap();
+        assert!(clean.is_empty());
+
+        // Rewrite the remote's history as a force push would
+        seed.find_reference("refs/heads/main")
+            .unwrap()
+            .delete()
+            .unwrap();
+        fs::remove_file(root.path().join("seed").join("index.html")).unwrap();
+        let mut index = seed.index().unwrap();
+        index.remove_path(Path::new("index.html")).unwrap();
+        index.write().unwrap();
+        let rewritten = commit_file_forced(&seed, "about.html", "about");
+
+        fs::write(checkout.join("index.html"), "edited on the server").unwrap();
+        fetch(path_type(&checkout), RemoteAuth::Anonymous)
+            .await
+            .unwrap();
+
+        let conflict = inspect_checkout(path_type(&checkout), Some(Stringy::new("main")))
+            .await
+            .unwrap();
+        assert_eq!(conflict.modified.len(), 1);
+        assert_eq!(conflict.local_commits, 1);
+        assert!(conflict.to_string().contains("index.html"));
+        assert!(fast_forward(path_type(&checkout), Stringy::new("main"))
+            .await
+            .is_err());
+
+        stash_changes(path_type(&checkout), String::from("ais"))
+            .await
+            .unwrap();
+        let update = reset_branch(
+            path_type(&checkout),
+            Stringy::new("main"),
+            Some(String::from("ais/backup")),
+        )
+        .await
+        .unwrap();
+        assert_eq!(update.new_commit.to_string(), rewritten.to_string());
+
+        let repo = Repository::open(&checkout).unwrap();
+        let backup = repo
+            .find_branch("ais/backup", BranchType::Local)
+            .unwrap()
+            .get()
+            .peel_to_commit()
+            .unwrap();
+        assert_eq!(backup.id(), first);
+        assert!(checkout.join("about.html").exists());
+        assert!(
+            inspect_checkout(path_type(&checkout), Some(Stringy::new("main")))
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        fs::write(checkout.join("about.html"), "edited again").unwrap();
+        discard_changes(path_type(&checkout)).await.unwrap();
+        assert_eq!(
+            fs::read_to_string(checkout.join("about.html")).unwrap(),
+            "about"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_submodules_are_checked_out_and_exported() {
+        let (root, bare_path, seed) = setup_remote();
+
+        let theme_path = root.path().join("owner").join("theme.git");
+        Repository::init_bare(&theme_path)
+            .unwrap()
+            .set_head("refs/heads/main")
+            .unwrap();
+        let theme = Repository::init(root.path().join("theme")).unwrap();
+        theme
+            .remote("origin", theme_path.to_str().unwrap())
+            .unwrap();
+        commit_file(&theme, "style.css", "body {}");
+
+        let mut submodule = seed
+            .submodule(theme_path.to_str().unwrap(), Path::new("theme"), true)
+            .unwrap();
+        submodule.clone(None).unwrap();
+        submodule.add_finalize().unwrap();
+        let commit = commit_file(&seed, "index.html", "hello");
+
+        let checkout = root.path().join("checkout");
+        clone_repo(
+            Stringy::from(bare_path.to_string_lossy().to_string()),
+            Stringy::new("main"),
+            path_type(&checkout),
+            RemoteAuth::Anonymous,
+        )
+        .await
+        .unwrap();
+        assert!(!checkout.join("theme").join("style.css").exists());
+
+        update_submodules(path_type(&checkout), RemoteAuth::Anonymous)
+            .await
+            .unwrap();
+        assert!(checkout.join("theme").join("style.css").exists());
+
+        let target = root.path().join("release");
+        let commit = Stringy::from(commit.to_string());
+        export_commit(path_type(&checkout), commit.clone(), path_type(&target))
+            .await
+            .unwrap();
+        export_submodules(path_type(&checkout), commit, path_type(&target))
+            .await
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(target.join("theme").join("style.css")).unwrap(),
+            "body {}"
+        );
+        assert!(target.join("index.html").exists());
+    }
+
+    /// Commits `name` on main and force pushes it.
+    fn commit_file_forced(repo: &Repository, name: &str, content: &str) -> Oid {
+        let workdir = repo.workdir().unwrap().to_path_buf();
+        fs::write(workdir.join(name), content).unwrap();
+
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new(name)).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+
+        let signature = Signature::new("ais", "ais@localhost", &Time::new(0, 0)).unwrap();
+        let oid = repo
+            .commit(
+                Some("refs/heads/main"),
+                &signature,
+                &signature,
+                name,
+                &tree,
+                &[],
+            )
+            .unwrap();
+
+        repo.find_remote("origin")
+            .unwrap()
+            .push(&["+refs/heads/main:refs/heads/main"], None)
+            .unwrap();
+
+        oid
+    }
+
+    #[tokio::test]
+    async fn test_set_tracking_and_switch() {
+        let (root, bare_path, seed) = setup_remote();
+        let first = commit_file(&seed, "index.html", "hello");
+        let commit = seed.find_commit(first).unwrap();
+        seed.branch("staging", &commit, false).unwrap();
+        seed.find_remote("origin")
+            .unwrap()
+            .push(&["refs/heads/staging:refs/heads/staging"], None)
+            .unwrap();
+
+        let checkout = root.path().join("checkout");
+        clone_repo(
+            Stringy::from(bare_path.to_string_lossy().to_string()),
+            Stringy::new("main"),
+            path_type(&checkout),
+            RemoteAuth::Anonymous,
+        )
+        .await
+        .unwrap();
+
+        set_tracking(path_type(&checkout)).await.unwrap();
+        switch_branch(path_type(&checkout), Stringy::new("staging"))
+            .await
+            .unwrap();
+
+        let repo = Repository::open(&checkout).unwrap();
+        assert_eq!(repo.head().unwrap().shorthand(), Some("staging"));
+        assert_eq!(
+            head_commit(path_type(&checkout)).await.unwrap().to_string(),
+            first.to_string()
+        );
+    }
+
+    #[test]
+    fn test_auth_errors_are_flagged() {
+        let rejected = git2::Error::new(
+            ErrorCode::GenericError,
+            ErrorClass::Http,
+            "unexpected http status code: 401",
+        );
+        let missing = git2::Error::new(ErrorCode::Auth, ErrorClass::Http, "auth required");
+        let other = git2::Error::new(ErrorCode::NotFound, ErrorClass::Reference, "no ref");
+
+        assert!(is_auth_failure(&git_error(rejected)));
+        assert!(is_auth_failure(&git_error(missing)));
+        assert!(!is_auth_failure(&git_error(other)));
+    }
+
+    #[tokio::test]
+    async fn test_export_commit() {
+        let (root, bare_path, seed) = setup_remote();
+        let first = commit_file(&seed, "index.html", "hello");
+        commit_file(&seed, "about.html", "about");
+        let checkout = root.path().join("checkout");
+        let export = root.path().join("export");
+
+        clone_repo(
+            Stringy::from(bare_path.to_string_lossy().to_string()),
+            Stringy::new("main"),
+            path_type(&checkout),
+            RemoteAuth::Anonymous,
+        )
+        .await
+        .unwrap();
+
+        fs::create_dir_all(&export).unwrap();
+        export_commit(
+            path_type(&checkout),
+            Stringy::from(first.to_string()),
+            path_type(&export),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(export.join("index.html")).unwrap(),
+            "hello"
+        );
+        assert!(!export.join("about.html").exists());
+        assert!(!export.join(".git").exists());
+        // The checkout itself is left on the newest commit
+        assert!(checkout.join("about.html").exists());
+    }
+
+    #[tokio::test]
+    async fn test_pinned_checkout() {
+        let (root, bare_path, seed) = setup_remote();
+        let first = commit_file(&seed, "index.html", "hello");
+        push_tag(&seed, "v1.9.0", first);
+        let checkout = root.path().join("checkout");
+
+        clone_repo(
+            Stringy::from(bare_path.to_string_lossy().to_string()),
+            Stringy::new("main"),
+            path_type(&checkout),
+            RemoteAuth::Anonymous,
+        )
+        .await
+        .unwrap();
+
+        let second = commit_file(&seed, "about.html", "about");
+        push_tag(&seed, "v1.10.0", second);
+        commit_file(&seed, "draft.html", "draft");
+        fetch(path_type(&checkout), RemoteAuth::Anonymous)
+            .await
+            .unwrap();
+
+        let resolve = |pin: GitPin| resolve_pin(path_type(&checkout), pin);
+        let highest = resolve(GitPin::TagPattern(Stringy::new("v1.*")))
+            .await
+            .unwrap();
+        assert_eq!(highest.to_string(), second.to_string());
+        let tagged = resolve(GitPin::Tag(Stringy::new("v1.9.0"))).await.unwrap();
+        assert_eq!(tagged.to_string(), first.to_string());
+        let short = resolve(GitPin::Commit(Stringy::from(
+            first.to_string()[..8].to_owned(),
+        )))
+        .await
+        .unwrap();
+        assert_eq!(short.to_string(), first.to_string());
+        assert!(resolve(GitPin::TagPattern(Stringy::new("release-*")))
+            .await
+            .is_err());
+
+        // Signed tag policies look up the tags of the commit being deployed
+        let tags = tags_at(path_type(&checkout), highest.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            tags.iter().map(|tag| tag.to_string()).collect::<Vec<_>>(),
+            vec![String::from("v1.10.0")]
+        );
+        let upstream = upstream_head(path_type(&checkout), Stringy::new("main"))
+            .await
+            .unwrap();
+        assert!(tags_at(path_type(&checkout), upstream)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let update = checkout_detached(path_type(&checkout), highest)
+            .await
+            .unwrap();
+        assert!(update.changed());
+        assert!(checkout.join("about.html").exists());
+        assert!(!checkout.join("draft.html").exists());
+        assert_eq!(
+            head_commit(path_type(&checkout)).await.unwrap().to_string(),
+            second.to_string()
+        );
+    }
+}
@@ -0,0 +1,277 @@
+// Deploy hooks declared in a repository's directive. Pre-deploy hooks run in the
+// new release before it goes live, post-deploy hooks run once it is live. Every
+// run is appended to the deploy audit log whatever its outcome.
+
+use std::{
+    fmt,
+    fs::OpenOptions,
+    io::Write,
+    path::Path,
+    process::Stdio,
+    time::{Duration, Instant},
+};
+
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    stringy::Stringy,
+    types::PathType,
+};
+use nix::unistd::{Gid, Uid};
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+use crate::system::current_timestamp;
+
+/// Hooks are killed after this many seconds unless they set their own timeout.
+pub const DEFAULT_HOOK_TIMEOUT: u64 = 300;
+
+/// Only the end of a hook's output is kept, that is where errors usually are.
+const OUTPUT_LIMIT: usize = 4096;
+
+/// A shell command run as part of a deploy.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DeployHook {
+    /// Run with `sh -c` from the release directory.
+    pub command: String,
+    /// Seconds before the hook is killed and the deploy aborted.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+fn default_timeout() -> u64 {
+    DEFAULT_HOOK_TIMEOUT
+}
+
+/// The hooks of a repository, each list runs in order.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeployHooks {
+    #[serde(default)]
+    pub pre_deploy: Vec<DeployHook>,
+    #[serde(default)]
+    pub post_deploy: Vec<DeployHook>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookStage {
+    PreDeploy,
+    PostDeploy,
+}
+
+impl fmt::Display for HookStage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HookStage::PreDeploy => write!(f, "pre-deploy"),
+            HookStage::PostDeploy => write!(f, "post-deploy"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum HookOutcome {
+    Succeeded,
+    Failed { code: Option<i32> },
+    TimedOut,
+}
+
+/// One line of the deploy audit log.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HookAuditEntry {
+    pub timestamp: u64,
+    pub project: Stringy,
+    pub commit: Stringy,
+    pub stage: HookStage,
+    pub command: String,
+    pub outcome: HookOutcome,
+    pub duration_ms: u128,
+    /// The tail of the hook's combined stdout and stderr.
+    pub output: String,
+}
+
+/// Runs `hooks` in order from `working_dir` as `user`, stopping at the first
+/// failure. The error carries the failing hook's output so it can be reported.
+pub async fn run_hooks(
+    project: &str,
+    commit: &str,
+    stage: HookStage,
+    hooks: &[DeployHook],
+    working_dir: &PathType,
+    user: (Uid, Gid),
+    audit_log: &Path,
+) -> Result<(), ErrorArrayItem> {
+    for hook in hooks {
+        let started = Instant::now();
+        let (outcome, output) = run_hook(hook, working_dir, user).await?;
+
+        let entry = HookAuditEntry {
+            timestamp: current_timestamp(),
+            project: Stringy::new(project),
+            commit: Stringy::new(commit),
+            stage,
+            command: hook.command.clone(),
+            outcome: outcome.clone(),
+            duration_ms: started.elapsed().as_millis(),
+            output: output.clone(),
+        };
+        append_audit_entry(audit_log, &entry)?;
+
+        let reason = match outcome {
+            HookOutcome::Succeeded => continue,
+            HookOutcome::Failed { code: Some(code) } => format!("exited with {}", code),
+            HookOutcome::Failed { code: None } => String::from("was killed by a signal"),
+            HookOutcome::TimedOut => format!("timed out after {}s", hook.timeout),
+        };
+
+        return Err(ErrorArrayItem::new(
+            Errors::GeneralError,
+            format!(
+                "The {} hook `{}` of {} {}:\n{}",
+                stage, hook.command, project, reason, output
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+async fn run_hook(
+    hook: &DeployHook,
+    working_dir: &PathType,
+    user: (Uid, Gid),
+) -> Result<(HookOutcome, String), ErrorArrayItem> {
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(&hook.command)
+        .current_dir(working_dir)
+        .uid(user.0.as_raw())
+        .gid(user.1.as_raw())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // Dropping the output future on timeout kills the hook
+        .kill_on_drop(true)
+        .spawn()?;
+
+    match tokio::time::timeout(Duration::from_secs(hook.timeout), child.wait_with_output()).await {
+        Ok(output) => {
+            let output = output?;
+            let mut combined = String::from_utf8_lossy(&output.stdout).to_string();
+            combined.push_str(&String::from_utf8_lossy(&output.stderr));
+
+            let outcome = match output.status.success() {
+                true => HookOutcome::Succeeded,
+                false => HookOutcome::Failed {
+                    code: output.status.code(),
+                },
+            };
+            Ok((outcome, tail(&combined)))
+        }
+        Err(_) => Ok((HookOutcome::TimedOut, String::new())),
+    }
+}
+
+fn tail(output: &str) -> String {
+    let output = output.trim_end();
+    if output.len() <= OUTPUT_LIMIT {
+        return output.to_owned();
+    }
+
+    let mut start = output.len() - OUTPUT_LIMIT;
+    while !output.is_char_boundary(start) {
+        start += 1;
+    }
+    format!("...{}", &output[start..])
+}
+
+fn append_audit_entry(audit_log: &Path, entry: &HookAuditEntry) -> Result<(), ErrorArrayItem> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(audit_log)?;
+    writeln!(file, "{}", serde_json::to_string(entry)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, os::unix::fs::MetadataExt};
+    use tempfile::TempDir;
+
+    fn hook(command: &str, timeout: u64) -> DeployHook {
+        DeployHook {
+            command: command.to_owned(),
+            timeout,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hooks_run_in_order_and_are_audited() {
+        let dir = TempDir::new().unwrap();
+        let metadata = fs::metadata(dir.path()).unwrap();
+        let user = (Uid::from_raw(metadata.uid()), Gid::from_raw(metadata.gid()));
+        let working_dir = PathType::PathBuf(dir.path().to_path_buf());
+        let audit_log = dir.path().join("audit.log");
+
+        let hooks = vec![
+            hook("echo first > order", 5),
+            hook("echo second >> order", 5),
+        ];
+        run_hooks(
+            "site",
+            "abc",
+            HookStage::PreDeploy,
+            &hooks,
+            &working_dir,
+            user,
+            &audit_log,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.path().join("order")).unwrap(),
+            "first\nsecond\n"
+        );
+
+        // A failing hook stops the run and reports its output
+        let hooks = vec![
+            hook("echo migration broke >&2; exit 3", 5),
+            hook("touch never", 5),
+        ];
+        let err = run_hooks(
+            "site",
+            "abc",
+            HookStage::PostDeploy,
+            &hooks,
+            &working_dir,
+            user,
+            &audit_log,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("exited with 3"));
+        assert!(err.to_string().contains("migration broke"));
+        assert!(!dir.path().join("never").exists());
+
+        let err = run_hooks(
+            "site",
+            "abc",
+            HookStage::PreDeploy,
+            &[hook("sleep 5", 1)],
+            &working_dir,
+            user,
+            &audit_log,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("timed out"));
+
+        let entries: Vec<HookAuditEntry> = fs::read_to_string(&audit_log)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[2].outcome, HookOutcome::Failed { code: Some(3) });
+        assert_eq!(entries[3].outcome, HookOutcome::TimedOut);
+    }
+}
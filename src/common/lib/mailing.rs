@@ -1,4 +1,4 @@
-use crate::dusa_wrapper::encrypt_text;
+use crate::secrets::encrypt_text;
 use dusa_collection_utils::{errors::{ErrorArrayItem, Errors}, stringy::Stringy};
 use serde::{Deserialize, Serialize};
 use std::{fmt, io::Write, net::TcpStream};
@@ -1,20 +1,33 @@
 pub mod apache;
 pub mod common;
+pub mod config_bundle;
 pub mod constants;
+pub mod credential_schema;
+pub mod deploy_keys;
 pub mod directive;
+pub mod directive_plan;
+pub mod directive_schema;
 pub mod dusa;
 pub mod dusa_wrapper;
 pub mod git;
 pub mod git_data;
+pub mod git_native;
+pub mod hooks;
 pub mod log;
 pub mod mailing;
 pub mod manager;
 pub mod messages;
 pub mod network;
 pub mod node;
+pub mod project_env;
+pub mod projects;
+pub mod release;
+pub mod remote_check;
+pub mod secrets;
 pub mod setcap;
 pub mod socket;
 pub mod system;
 pub mod systemd;
+pub mod teardown;
 pub mod version;
 pub mod monitor;
\ No newline at end of file
@@ -15,6 +15,15 @@ pub enum NetworkRequestType {
     QUERYSTATUS,
     QUERYGITREPO,
     UPDATEGITREPO,
+    VALIDATEGITREPO,
+    QUERYSERVICES,
+    RESTARTSERVICE,
+    QUERYDEPLOYKEYS,
+    QUERYRELEASES,
+    ROLLBACK,
+    QUERYPROJECTENV,
+    UPDATEPROJECTENV,
+    QUERYDIRECTIVES,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
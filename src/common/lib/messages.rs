@@ -12,6 +12,7 @@ use tokio::{
 use crate::{
     common::{GeneralMessage, MessageType, Status},
     socket::get_socket_path,
+    systemd::ProcessInfo,
     version::Version,
 };
 
@@ -76,6 +77,34 @@ pub async fn send_acknowledge(stream: &mut UnixStream) {
 
 /// Report status to the aggregator
 pub async fn report_status(status: Status) -> Result<(), ErrorArrayItem> {
+    let mut stream: UnixStream = connect_aggregator().await?;
+
+    let general_message = GeneralMessage {
+        version: Version::get(),
+        msg_type: MessageType::StatusUpdate,
+        payload: serde_json::to_value(&status).map_err(ErrorArrayItem::from)?,
+        error: None,
+    };
+
+    send_message(&mut stream, &general_message).await
+}
+
+/// Report the detailed state of the monitored services to the aggregator
+pub async fn report_services(services: Vec<ProcessInfo>) -> Result<(), ErrorArrayItem> {
+    let mut stream: UnixStream = connect_aggregator().await?;
+
+    let general_message = GeneralMessage {
+        version: Version::get(),
+        msg_type: MessageType::ServicesUpdate,
+        payload: serde_json::to_value(&services).map_err(ErrorArrayItem::from)?,
+        error: None,
+    };
+
+    send_message(&mut stream, &general_message).await
+}
+
+/// Opens a connection to the aggregator socket
+async fn connect_aggregator() -> Result<UnixStream, ErrorArrayItem> {
     let throw_away_array_warning: WarningArray = WarningArray::new_container();
     let throw_away_array_error: ErrorArray = ErrorArray::new_container();
     let socket_path_result: Result<dusa_collection_utils::errors::OkWarning<PathType>, ErrorArray> =
@@ -85,16 +114,7 @@ pub async fn report_status(status: Status) -> Result<(), ErrorArrayItem> {
         Err(mut e) => return Err(e.pop()),
     };
 
-    let mut stream: UnixStream = UnixStream::connect(socket_path)
+    UnixStream::connect(socket_path)
         .await
-        .map_err(|e| ErrorArrayItem::from(e))?;
-
-    let general_message = GeneralMessage {
-        version: Version::get(),
-        msg_type: MessageType::StatusUpdate,
-        payload: serde_json::to_value(&status).map_err(|e| ErrorArrayItem::from(e))?,
-        error: None,
-    };
-
-    send_message(&mut stream, &general_message).await
+        .map_err(ErrorArrayItem::from)
 }
@@ -3,11 +3,28 @@ use std::io::Write;
 
 use dusa_collection_utils::errors::ErrorArrayItem;
 
+use crate::constants::SYSTEMD_UNIT_DIR;
+
 pub const MONITOR_DIR: &str = "/opt/monitors/"; 
 
+// Path of the monitoring script of a service
+pub fn monitoring_script_path(service_id: &str) -> String {
+    format!("{}{}.monitor", MONITOR_DIR, service_id)
+}
+
 // Function to create a monitoring script file
 pub fn create_monitoring_script(directory_to_watch: &str, service_id: &str) -> Result<(), ErrorArrayItem> {
-    let script_content = format!(
+    let script_content = render_monitoring_script(directory_to_watch, service_id);
+
+    create_dir_all(MONITOR_DIR)?;
+    let mut script_file = File::create(monitoring_script_path(service_id))?;
+    script_file.write_all(script_content.as_bytes())?;
+    Ok(())
+}
+
+// Contents of the monitoring script, restarting the service on changes
+pub fn render_monitoring_script(directory_to_watch: &str, service_id: &str) -> String {
+    format!(
         r#"#!/bin/bash
 
 DIRECTORY_TO_WATCH="{}"
@@ -34,17 +51,21 @@ inotifywait -m -r -e modify,create,delete "$DIRECTORY_TO_WATCH" | grep -vE '\.gi
 done
 "#,
         directory_to_watch, service_id
-    );
-
-    create_dir_all(MONITOR_DIR)?;
-    let mut script_file = File::create(format!("{}{}.monitor", MONITOR_DIR, service_id))?;
-    script_file.write_all(script_content.as_bytes())?;
-    Ok(())
+    )
 }
 
 // Function to create a systemd service file for the monitoring script
 pub fn create_monitoring_service(service_id: &str, script_path: &str) -> Result<(), ErrorArrayItem> {
-    let service_file_content = format!(
+    let service_file_content = render_monitoring_service(service_id, script_path);
+
+    let mut service_file = File::create(format!("{}/{}_monitor.service", SYSTEMD_UNIT_DIR, service_id))?;
+    service_file.write_all(service_file_content.as_bytes())?;
+    Ok(())
+}
+
+// Contents of the systemd service running the monitoring script
+pub fn render_monitoring_service(service_id: &str, script_path: &str) -> String {
+    format!(
         r#"[Unit]
 Description=Recursive File Monitor for {}
 
@@ -59,11 +80,7 @@ WantedBy=multi-user.target
 "#,
         service_id,
         script_path
-    );
-
-    let mut service_file = File::create(format!("/etc/systemd/system/{}_monitor.service", service_id))?;
-    service_file.write_all(service_file_content.as_bytes())?;
-    Ok(())
+    )
 }
 
 // Function to reload systemd and enable the new service
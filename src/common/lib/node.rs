@@ -1,15 +1,18 @@
 use std::{
     io,
+    path::Path,
     process::{Command, ExitStatus},
 };
 
 use dusa_collection_utils::{errors::ErrorArrayItem, stringy::Stringy, types::PathType};
 
-/// Function to create a systemd service file dynamically
+/// Function to create a systemd service file dynamically. The variables in
+/// `environment_file` are set when it exists, see `project_env`.
 pub fn create_node_systemd_service(
     exec_start: &str,
     working_dir: &PathType,
     description: &str,
+    environment_file: &Path,
 ) -> Result<Stringy, ErrorArrayItem> {
     // Setting environmental variables depending on the directive file
     let service_file_content = format!(
@@ -27,12 +30,17 @@ User=www-data
 Group=www-data
 Environment=PATH=/usr/bin:/usr/local/bin
 #Environment=NODE_ENV=production
+# The project's own variables, optional so projects without any still start
+EnvironmentFile=-{}
 WorkingDirectory={}
 
 [Install]
 WantedBy=multi-user.target
 "#,
-        description, exec_start, working_dir
+        description,
+        exec_start,
+        environment_file.display(),
+        working_dir
     );
 
     Ok(Stringy::new(&service_file_content))
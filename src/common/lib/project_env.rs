@@ -0,0 +1,370 @@
+// Environment variables of deployed projects, such as api keys and database
+// urls, so they never have to be committed to the repository. Each project has
+// its own set, kept encrypted with the configured secret store and rendered
+// into root-only files the services read on start:
+//
+//   /etc/ais/env/<id>.env.enc       the variables, encrypted
+//   /etc/ais/env/<id>.env           EnvironmentFile of the node service
+//   /etc/ais/env/<id>.apache.conf   SetEnv lines included by the vhost
+//
+// systemd and apache read these as root before dropping privileges. php-fpm
+// gets the SetEnv variables from mod_proxy_fcgi with every request, so PHP
+// sites see them in getenv() and $_SERVER.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    stringy::Stringy,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    apache::reload_apache,
+    constants::{PROJECT_BASE_DIR, PROJECT_ENV_DIR},
+    directive::parse_directive,
+    git_data::TOKEN_PLACEHOLDER,
+    projects::restart_project,
+    secrets::{decrypt_text, encrypt_text},
+    teardown::find_directive,
+};
+
+/// Changes to a project's variables sent to the manager. Names in `unset` are
+/// removed after `set` is applied.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ProjectEnvUpdate {
+    pub project: String,
+    #[serde(default)]
+    pub set: BTreeMap<String, String>,
+    #[serde(default)]
+    pub unset: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProjectEnv {
+    dir: PathBuf,
+    project: String,
+    vars: BTreeMap<String, String>,
+}
+
+impl ProjectEnv {
+    /// The variables of `project`, none if it has never had any.
+    pub fn load(project: &str) -> Result<Self, ErrorArrayItem> {
+        Self::load_from(Path::new(PROJECT_ENV_DIR), project)
+    }
+
+    pub fn load_from(dir: &Path, project: &str) -> Result<Self, ErrorArrayItem> {
+        // The id ends up in file names and unit names
+        if project.is_empty() || project.contains('/') || project.starts_with('.') {
+            return Err(ErrorArrayItem::new(
+                Errors::GeneralError,
+                format!("{} is not a valid project id", project),
+            ));
+        }
+
+        let mut env = Self {
+            dir: dir.to_path_buf(),
+            project: project.to_owned(),
+            vars: BTreeMap::new(),
+        };
+
+        let store = env.path("env.enc");
+        if store.exists() {
+            let encrypted = Stringy::from(fs::read_to_string(&store)?);
+            env.vars = serde_json::from_str(&decrypt_text(encrypted)?).map_err(|e| {
+                ErrorArrayItem::new(
+                    Errors::InvalidFile,
+                    format!("Failed to parse {}: {}", store.display(), e),
+                )
+            })?;
+        }
+        Ok(env)
+    }
+
+    pub fn project(&self) -> &str {
+        &self.project
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vars.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.vars.get(name).map(String::as_str)
+    }
+
+    /// Names with their values masked, safe to display or send over the network.
+    pub fn masked(&self) -> BTreeMap<String, String> {
+        self.vars
+            .keys()
+            .map(|name| (name.clone(), TOKEN_PLACEHOLDER.to_owned()))
+            .collect()
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), ErrorArrayItem> {
+        validate_name(name)?;
+        validate_value(name, value)?;
+        self.vars.insert(name.to_owned(), value.to_owned());
+        Ok(())
+    }
+
+    /// Returns whether the variable existed.
+    pub fn unset(&mut self, name: &str) -> bool {
+        self.vars.remove(name).is_some()
+    }
+
+    pub fn apply(&mut self, update: &ProjectEnvUpdate) -> Result<(), ErrorArrayItem> {
+        for (name, value) in &update.set {
+            self.set(name, value)?;
+        }
+        for name in &update.unset {
+            self.unset(name);
+        }
+        Ok(())
+    }
+
+    /// Encrypts the variables to disk and renders the files the services read.
+    pub fn save(&self) -> Result<(), ErrorArrayItem> {
+        fs::create_dir_all(&self.dir)?;
+
+        let json = Stringy::from(serde_json::to_string(&self.vars)?);
+        write_private(&self.path("env.enc"), &encrypt_text(json)?)?;
+        self.render()
+    }
+
+    /// Writes the EnvironmentFile and the apache include, or removes them when
+    /// the project has no variables.
+    pub fn render(&self) -> Result<(), ErrorArrayItem> {
+        let environment_file = self.environment_file();
+        let apache_include = self.apache_include();
+
+        if self.is_empty() {
+            for path in [&environment_file, &apache_include] {
+                if path.exists() {
+                    fs::remove_file(path)?;
+                }
+            }
+            return Ok(());
+        }
+
+        fs::create_dir_all(&self.dir)?;
+        write_private(&environment_file, &self.render_environment_file())?;
+        write_private(&apache_include, &self.render_apache_include())?;
+        Ok(())
+    }
+
+    pub fn environment_file(&self) -> PathBuf {
+        self.path("env")
+    }
+
+    pub fn apache_include(&self) -> PathBuf {
+        self.path("apache.conf")
+    }
+
+    fn path(&self, extension: &str) -> PathBuf {
+        env_path(&self.dir, &self.project, extension)
+    }
+
+    // Double quoted, systemd would otherwise expand $ and unescape \
+    fn render_environment_file(&self) -> String {
+        let mut rendered = String::new();
+        for (name, value) in &self.vars {
+            let mut quoted = String::with_capacity(value.len());
+            for c in value.chars() {
+                if matches!(c, '"' | '\\' | '`' | '$') {
+                    quoted.push('\\');
+                }
+                quoted.push(c);
+            }
+            rendered.push_str(&format!("{}=\"{}\"\n", name, quoted));
+        }
+        rendered
+    }
+
+    // Apache only unescapes \" inside quotes, validate_value keeps out what it
+    // can't represent
+    fn render_apache_include(&self) -> String {
+        let mut rendered = String::new();
+        for (name, value) in &self.vars {
+            rendered.push_str(&format!(
+                "SetEnv {} \"{}\"\n",
+                name,
+                value.replace('"', "\\\"")
+            ));
+        }
+        rendered
+    }
+}
+
+/// The EnvironmentFile of `project`'s node service.
+pub fn environment_file(project: &str) -> PathBuf {
+    env_path(Path::new(PROJECT_ENV_DIR), project, "env")
+}
+
+/// The file `project`'s vhost includes for its variables.
+pub fn apache_include(project: &str) -> PathBuf {
+    env_path(Path::new(PROJECT_ENV_DIR), project, "apache.conf")
+}
+
+/// Removes the variables of `project` and the files rendered from them,
+/// without decrypting anything. Returns the removed files.
+pub fn remove_project_env(project: &str) -> Result<Vec<PathBuf>, ErrorArrayItem> {
+    let mut removed = Vec::new();
+    for extension in ["env.enc", "env", "apache.conf"] {
+        let path = env_path(Path::new(PROJECT_ENV_DIR), project, extension);
+        if path.exists() {
+            fs::remove_file(&path)?;
+            removed.push(path);
+        }
+    }
+    Ok(removed)
+}
+
+fn env_path(dir: &Path, project: &str, extension: &str) -> PathBuf {
+    dir.join(format!("{}.{}", project, extension))
+}
+
+/// Restarts the project's node service and reloads apache if the project is
+/// served by it, so changed variables take effect. Returns what was done.
+pub async fn reload_project(project: &str) -> Result<Vec<String>, ErrorArrayItem> {
+    let mut steps = Vec::new();
+
+    if restart_project(project)? {
+        steps.push(format!("Restarted {}.service", project));
+    }
+
+    let served_by_apache = match find_directive(&Path::new(PROJECT_BASE_DIR).join(project)) {
+        Some(path) => parse_directive(&path).await?.apache,
+        None => false,
+    };
+    if served_by_apache {
+        reload_apache().await?;
+        steps.push(String::from("Reloaded apache"));
+    }
+
+    Ok(steps)
+}
+
+fn validate_name(name: &str) -> Result<(), ErrorArrayItem> {
+    let mut chars = name.chars();
+    let valid = matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+
+    match valid {
+        true => Ok(()),
+        false => Err(ErrorArrayItem::new(
+            Errors::GeneralError,
+            format!(
+                "{} is not a valid variable name, use letters, digits and underscores",
+                name
+            ),
+        )),
+    }
+}
+
+// Both files hold one variable per line, and apache expands ${...} in its
+// configuration and reads a trailing \ as escaping the closing quote
+fn validate_value(name: &str, value: &str) -> Result<(), ErrorArrayItem> {
+    let problem = if value.chars().any(|c| c.is_control()) {
+        Some("line breaks or other control characters")
+    } else if value.contains("${") {
+        Some("${")
+    } else if value.ends_with('\\') {
+        Some("a trailing backslash")
+    } else {
+        None
+    };
+
+    match problem {
+        None => Ok(()),
+        Some(problem) => Err(ErrorArrayItem::new(
+            Errors::GeneralError,
+            format!("The value of {} can't contain {}", name, problem),
+        )),
+    }
+}
+
+fn write_private(path: &Path, contents: &str) -> Result<(), ErrorArrayItem> {
+    // Written next to the file and moved over it, so a service starting now
+    // never reads half of it
+    let staging = PathBuf::from(format!("{}.tmp", path.display()));
+    let _ = fs::remove_file(&staging);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&staging)?;
+    file.write_all(contents.as_bytes())?;
+    fs::rename(&staging, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secrets::{use_store, MemoryStore};
+    use std::{os::unix::fs::PermissionsExt, sync::Arc};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_save_and_render() {
+        use_store(Arc::new(MemoryStore::new()));
+        let root = TempDir::new().unwrap();
+
+        let mut env = ProjectEnv::load_from(root.path(), "shop").unwrap();
+        assert!(env.is_empty());
+        env.set("DATABASE_URL", "postgres://shop:p\"w$1@db/shop")
+            .unwrap();
+        env.set("API_KEY", "abc").unwrap();
+        env.save().unwrap();
+
+        let store = fs::read_to_string(root.path().join("shop.env.enc")).unwrap();
+        assert!(!store.contains("abc"));
+
+        let mut env = ProjectEnv::load_from(root.path(), "shop").unwrap();
+        assert_eq!(env.get("API_KEY"), Some("abc"));
+        assert_eq!(env.masked()["API_KEY"], TOKEN_PLACEHOLDER);
+
+        let environment_file = fs::read_to_string(env.environment_file()).unwrap();
+        assert_eq!(
+            environment_file,
+            "API_KEY=\"abc\"\nDATABASE_URL=\"postgres://shop:p\\\"w\\$1@db/shop\"\n"
+        );
+        let apache = fs::read_to_string(env.apache_include()).unwrap();
+        assert!(apache.contains("SetEnv DATABASE_URL \"postgres://shop:p\\\"w$1@db/shop\"\n"));
+        assert_eq!(
+            fs::metadata(env.environment_file())
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777,
+            0o600
+        );
+
+        env.unset("API_KEY");
+        env.unset("DATABASE_URL");
+        env.save().unwrap();
+        assert!(!env.environment_file().exists() && !env.apache_include().exists());
+    }
+
+    #[test]
+    fn test_rejected_variables() {
+        let root = TempDir::new().unwrap();
+        let mut env = ProjectEnv::load_from(root.path(), "shop").unwrap();
+
+        assert!(env.set("1PASSWORD", "x").is_err());
+        assert!(env.set("API-KEY", "x").is_err());
+        assert!(env.set("KEY", "two\nlines").is_err());
+        assert!(env.set("KEY", "${HOME}").is_err());
+        assert!(env.set("KEY", "C:\\").is_err());
+        assert!(env.set("_KEY2", "$HOME\\n").is_ok());
+
+        assert!(ProjectEnv::load_from(root.path(), "../etc").is_err());
+    }
+}
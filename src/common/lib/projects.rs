@@ -0,0 +1,450 @@
+// Registry of deployed projects. Every repository is given a short readable
+// slug, derived from its name and never changed once assigned, which names its
+// directory under PROJECT_BASE_DIR, its systemd units and its log lines.
+//
+// Projects deployed before the registry existed were named by the hash id of
+// their repository, they are moved to their slug on their next sync. Entries
+// outlive their credentials and are marked orphaned until they are torn down.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    stringy::Stringy,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    constants::{PROJECT_BASE_DIR, PROJECT_REGISTRY, SYSTEMD_UNIT_DIR},
+    git_data::GitAuth,
+    monitor::MONITOR_DIR,
+    systemd::{disable_now, reload_systemd_daemon, restart_if_exists},
+};
+
+/// Slugs are cut to this many characters, unit names get a suffix on top.
+const MAX_SLUG_LENGTH: usize = 40;
+
+/// Where systemd finds unit files. A slug is never given to a project when it
+/// would name a unit of the system, such as a repository called `mysql`.
+const UNIT_SEARCH_PATH: &[&str] = &[
+    SYSTEMD_UNIT_DIR,
+    "/run/systemd/system",
+    "/lib/systemd/system",
+    "/usr/lib/systemd/system",
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ProjectEntry {
+    pub slug: Stringy,
+    /// Free form name shown next to the slug, `None` to show the repository.
+    #[serde(default)]
+    pub display_name: Option<Stringy>,
+    pub user: Stringy,
+    pub repo: Stringy,
+    pub branch: Stringy,
+    /// The hash id the project was named by before it had a slug.
+    pub legacy_id: Stringy,
+    /// Unix timestamp the repository was found missing from the credentials.
+    #[serde(default)]
+    pub orphaned_since: Option<u64>,
+}
+
+impl ProjectEntry {
+    fn matches(&self, auth: &GitAuth) -> bool {
+        *self.user == *auth.user && *self.repo == *auth.repo && *self.branch == *auth.branch
+    }
+
+    /// The display name, or `user/repo (branch)` when none was set.
+    pub fn name(&self) -> String {
+        match &self.display_name {
+            Some(name) => name.to_string(),
+            None => format!("{}/{} ({})", self.user, self.repo, self.branch),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ProjectRegistry {
+    pub projects: Vec<ProjectEntry>,
+}
+
+impl ProjectRegistry {
+    /// Loads the registry, an empty one if nothing has been registered yet.
+    pub fn load() -> Result<Self, ErrorArrayItem> {
+        Self::load_from(Path::new(PROJECT_REGISTRY))
+    }
+
+    pub fn load_from(path: &Path) -> Result<Self, ErrorArrayItem> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let data = fs::read_to_string(path)?;
+        serde_json::from_str(&data).map_err(|e| {
+            ErrorArrayItem::new(
+                Errors::InvalidFile,
+                format!("Failed to parse {}: {}", path.display(), e),
+            )
+        })
+    }
+
+    pub fn save(&self) -> Result<(), ErrorArrayItem> {
+        self.save_to(Path::new(PROJECT_REGISTRY))
+    }
+
+    /// Writes the registry through a temporary file so readers never see half of it.
+    pub fn save_to(&self, path: &Path) -> Result<(), ErrorArrayItem> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let staging: PathBuf = path.with_extension("tmp");
+        fs::write(&staging, serde_json::to_string_pretty(self)?)?;
+        fs::rename(&staging, path)?;
+        Ok(())
+    }
+
+    pub fn get(&self, auth: &GitAuth) -> Option<&ProjectEntry> {
+        self.projects.iter().find(|entry| entry.matches(auth))
+    }
+
+    /// Looks a project up by its slug or its legacy hash id.
+    pub fn find(&self, name: &str) -> Option<&ProjectEntry> {
+        self.projects
+            .iter()
+            .find(|entry| *entry.slug == *name)
+            .or_else(|| self.projects.iter().find(|entry| *entry.legacy_id == *name))
+    }
+
+    /// The slug of `name` if it is a registered project's legacy id, `name` otherwise.
+    pub fn resolve(&self, name: &str) -> String {
+        match self.find(name) {
+            Some(entry) => entry.slug.to_string(),
+            None => name.to_owned(),
+        }
+    }
+
+    /// The slug of a repository, its hash id until it has been registered.
+    pub fn slug(&self, auth: &GitAuth) -> Stringy {
+        match self.get(auth) {
+            Some(entry) => entry.slug.clone(),
+            None => auth.id(),
+        }
+    }
+
+    /// Registers `auth` if it is new, or adopts it again if it was orphaned.
+    /// Returns true if the registry changed.
+    pub fn ensure(&mut self, auth: &GitAuth) -> bool {
+        if let Some(entry) = self.projects.iter_mut().find(|entry| entry.matches(auth)) {
+            return entry.orphaned_since.take().is_some();
+        }
+
+        let slug = self.unique_slug(&auth.user, &auth.repo, &auth.branch);
+        self.projects.push(ProjectEntry {
+            slug,
+            display_name: None,
+            user: auth.user.clone(),
+            repo: auth.repo.clone(),
+            branch: auth.branch.clone(),
+            legacy_id: auth.id(),
+            orphaned_since: None,
+        });
+        true
+    }
+
+    /// Registers a project exported from another host, keeping its slug when
+    /// it is free here. A repository that is already registered keeps its
+    /// slug, and its display name unless `replace`. Returns the slug it has here.
+    pub fn import(&mut self, entry: &ProjectEntry, replace: bool) -> Stringy {
+        let same_repository = |local: &ProjectEntry| {
+            *local.user == *entry.user
+                && *local.repo == *entry.repo
+                && *local.branch == *entry.branch
+        };
+        if let Some(local) = self
+            .projects
+            .iter_mut()
+            .find(|local| same_repository(local))
+        {
+            if replace {
+                local.display_name = entry.display_name.clone();
+            }
+            local.orphaned_since = None;
+            return local.slug.clone();
+        }
+
+        let slug = match self.is_free(&entry.slug) {
+            true => entry.slug.clone(),
+            false => self.unique_slug(&entry.user, &entry.repo, &entry.branch),
+        };
+        self.projects.push(ProjectEntry {
+            slug: slug.clone(),
+            orphaned_since: None,
+            ..entry.clone()
+        });
+        slug
+    }
+
+    /// Marks every project without a repository in `auths` as orphaned at
+    /// `now`, returning the projects that weren't orphaned before.
+    pub fn mark_orphans(&mut self, auths: &[GitAuth], now: u64) -> Vec<ProjectEntry> {
+        self.projects
+            .iter_mut()
+            .filter(|entry| entry.orphaned_since.is_none())
+            .filter(|entry| !auths.iter().any(|auth| entry.matches(auth)))
+            .map(|entry| {
+                entry.orphaned_since = Some(now);
+                entry.clone()
+            })
+            .collect()
+    }
+
+    /// Projects that have been orphaned for at least `grace_period` seconds.
+    pub fn expired_orphans(&self, now: u64, grace_period: u64) -> Vec<ProjectEntry> {
+        self.projects
+            .iter()
+            .filter(|entry| {
+                entry
+                    .orphaned_since
+                    .is_some_and(|since| now.saturating_sub(since) >= grace_period)
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Forgets a torn down project, its slug may be handed out again.
+    pub fn remove(&mut self, slug: &str) {
+        self.projects.retain(|entry| *entry.slug != *slug);
+    }
+
+    /// Sets or clears the display name of a registered project.
+    pub fn set_display_name(
+        &mut self,
+        name: &str,
+        display_name: Option<Stringy>,
+    ) -> Result<(), ErrorArrayItem> {
+        let slug = self.resolve(name);
+        match self.projects.iter_mut().find(|entry| *entry.slug == *slug) {
+            Some(entry) => {
+                entry.display_name = display_name;
+                Ok(())
+            }
+            None => Err(ErrorArrayItem::new(
+                Errors::GeneralError,
+                format!("Unknown project {}", name),
+            )),
+        }
+    }
+
+    // Prefers the bare repository name, then adds the branch and the owner
+    // until the slug is free, and numbers it as a last resort
+    fn unique_slug(&self, user: &str, repo: &str, branch: &str) -> Stringy {
+        let candidates = [
+            slugify(repo),
+            slugify(&format!("{}-{}", repo, branch)),
+            slugify(&format!("{}-{}-{}", user, repo, branch)),
+        ];
+
+        if let Some(slug) = candidates.iter().find(|slug| self.is_free(slug)) {
+            return Stringy::from(slug.clone());
+        }
+
+        let base = &candidates[2];
+        let slug = (2..)
+            .map(|n| format!("{}-{}", base, n))
+            .find(|slug| self.is_free(slug))
+            .unwrap_or_else(|| base.clone());
+        Stringy::from(slug)
+    }
+
+    fn is_free(&self, slug: &str) -> bool {
+        !self
+            .projects
+            .iter()
+            .any(|entry| *entry.slug == *slug || *entry.legacy_id == *slug)
+            && !names_system_unit(slug, UNIT_SEARCH_PATH)
+    }
+}
+
+// True if the service or monitor unit of `slug` would replace a unit the
+// platform didn't write.
+fn names_system_unit(slug: &str, unit_dirs: &[&str]) -> bool {
+    [slug.to_owned(), format!("{}_monitor", slug)]
+        .iter()
+        .flat_map(|unit| {
+            unit_dirs
+                .iter()
+                .map(move |dir| Path::new(dir).join(format!("{}.service", unit)))
+        })
+        .filter(|path| path.symlink_metadata().is_ok())
+        .any(|path| !is_platform_unit(&path))
+}
+
+/// Restarts the service of `project` if it has one, never a system service it
+/// shares its name with. Returns true if it was restarted.
+pub fn restart_project(project: &str) -> Result<bool, ErrorArrayItem> {
+    let unit = Path::new(SYSTEMD_UNIT_DIR).join(format!("{}.service", project));
+    match is_platform_unit(&unit) {
+        true => restart_if_exists(project.to_owned()),
+        false => Ok(false),
+    }
+}
+
+/// True if the unit file at `path` was written by the platform, those run a
+/// project from PROJECT_BASE_DIR or its monitor from MONITOR_DIR. Masked units
+/// link to /dev/null and read as empty.
+pub fn is_platform_unit(path: &Path) -> bool {
+    match fs::read_to_string(path) {
+        Ok(unit) => unit.contains(PROJECT_BASE_DIR) || unit.contains(MONITOR_DIR),
+        Err(_) => false,
+    }
+}
+
+/// Resolves a project name given by an operator, accepting the hash ids
+/// projects had before they were given a slug.
+pub fn resolve_project(name: &str) -> String {
+    match ProjectRegistry::load() {
+        Ok(registry) => registry.resolve(name),
+        Err(_) => name.to_owned(),
+    }
+}
+
+/// Lowercases `text` and turns every run of other characters than letters and
+/// digits into a single dash, so it is safe as a directory and unit name.
+pub fn slugify(text: &str) -> String {
+    let mut slug = String::new();
+    for c in text.chars() {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c.to_ascii_lowercase());
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+
+    slug.truncate(MAX_SLUG_LENGTH);
+    let slug = slug.trim_end_matches('-');
+    match slug.is_empty() {
+        true => String::from("project"),
+        false => slug.to_owned(),
+    }
+}
+
+/// Moves a project deployed under its hash id to its slug. The old service and
+/// monitor units are stopped and removed, the directive recreates them under
+/// the slug once it finds the moved directive. Returns true if anything moved.
+pub fn migrate_legacy_project(entry: &ProjectEntry) -> Result<bool, ErrorArrayItem> {
+    let base = Path::new(PROJECT_BASE_DIR);
+    let old = base.join(&*entry.legacy_id);
+    let new = base.join(&*entry.slug);
+    if *entry.legacy_id == *entry.slug || !old.exists() || new.exists() {
+        return Ok(false);
+    }
+
+    // The monitor restarts the service on changes, so it goes first
+    for unit in [
+        format!("{}_monitor", entry.legacy_id),
+        entry.legacy_id.to_string(),
+    ] {
+        let unit_file = Path::new(SYSTEMD_UNIT_DIR).join(format!("{}.service", unit));
+        if unit_file.exists() {
+            disable_now(unit)?;
+            fs::remove_file(unit_file)?;
+        }
+    }
+
+    let monitor_script = Path::new(MONITOR_DIR).join(format!("{}.monitor", entry.legacy_id));
+    if monitor_script.exists() {
+        fs::remove_file(monitor_script)?;
+    }
+
+    fs::rename(old, new)?;
+    reload_systemd_daemon()?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_system_units_are_reserved() {
+        let root = TempDir::new().unwrap();
+        let dir = root.path().to_str().unwrap();
+        fs::write(
+            root.path().join("mysql.service"),
+            "[Service]\nExecStart=/usr/sbin/mysqld\n",
+        )
+        .unwrap();
+        fs::write(
+            root.path().join("site.service"),
+            format!(
+                "[Service]\nWorkingDirectory={}/site/current\n",
+                PROJECT_BASE_DIR
+            ),
+        )
+        .unwrap();
+
+        assert!(names_system_unit("mysql", &[dir]));
+        assert!(!names_system_unit("site", &[dir]));
+        assert!(!names_system_unit("shop", &[dir]));
+    }
+
+    #[test]
+    fn test_slugify() {
+        assert_eq!(slugify("My_Site.com"), "my-site-com");
+        assert_eq!(slugify("--docs--"), "docs");
+        assert_eq!(slugify("***"), "project");
+        assert!(slugify(&"a".repeat(80)).len() <= MAX_SLUG_LENGTH);
+    }
+
+    #[test]
+    fn test_slugs_are_unique_and_stable() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("projects.json");
+        let main = GitAuth::new("owner", "site", "main");
+        let staging = GitAuth::new("owner", "site", "staging");
+        let fork = GitAuth::new("other", "site", "staging");
+
+        let mut registry = ProjectRegistry::load_from(&path).unwrap();
+        assert!(registry.ensure(&main));
+        assert!(registry.ensure(&staging));
+        assert!(registry.ensure(&fork));
+        assert!(!registry.ensure(&main));
+        assert_eq!(&*registry.slug(&main), "site");
+        assert_eq!(&*registry.slug(&staging), "site-staging");
+        assert_eq!(&*registry.slug(&fork), "other-site-staging");
+
+        registry
+            .set_display_name(&main.id(), Some(Stringy::new("Marketing site")))
+            .unwrap();
+        registry.save_to(&path).unwrap();
+
+        // Slugs survive a reload whatever order the repositories come in
+        let mut registry = ProjectRegistry::load_from(&path).unwrap();
+        assert!(!registry.ensure(&fork));
+        assert_eq!(&*registry.slug(&staging), "site-staging");
+        assert_eq!(registry.resolve(&main.id()), "site");
+        assert_eq!(registry.find("site").unwrap().name(), "Marketing site");
+        assert_eq!(
+            registry.find("site-staging").unwrap().name(),
+            "owner/site (staging)"
+        );
+
+        // Removed repositories are orphaned once, and adopted again when re-added
+        let orphans = registry.mark_orphans(&[main.clone(), fork.clone()], 100);
+        assert_eq!(orphans.len(), 1);
+        assert_eq!(&*orphans[0].slug, "site-staging");
+        assert_eq!(registry.mark_orphans(&[main], 200).len(), 1);
+        assert_eq!(registry.expired_orphans(150, 50).len(), 1);
+        assert_eq!(registry.expired_orphans(250, 50).len(), 2);
+        assert!(registry.ensure(&fork));
+        assert_eq!(registry.expired_orphans(250, 50).len(), 1);
+
+        registry.remove("site-staging");
+        assert!(registry.find("site-staging").is_none());
+    }
+}
@@ -0,0 +1,415 @@
+// Release layout for deployed projects. Every project directory holds the git
+// checkout, one directory per deployed commit and a symlink to the live one:
+//
+//   /var/www/ais/<id>/repo
+//   /var/www/ais/<id>/releases/<commit>
+//   /var/www/ais/<id>/releases/manifest.json
+//   /var/www/ais/<id>/current -> releases/<commit>
+//
+// Apache and the node services only ever see `current`, which is swapped with a
+// rename so a request never observes a half-updated tree. The manifest records
+// the order releases were deployed in, which is what listing, rollback and
+// pruning go by. Directory times change with anything touching the tree.
+// Rolling back leaves the manifest alone, its newest entry is the release the
+// monitor last deployed in full.
+
+use std::{
+    fs,
+    os::unix::fs::symlink,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    stringy::Stringy,
+    types::PathType,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    constants::{PROJECT_BASE_DIR, RELEASES_TO_KEEP},
+    git_native,
+    projects::{resolve_project, restart_project},
+    system::current_timestamp,
+};
+
+pub const REPO_DIR: &str = "repo";
+pub const RELEASES_DIR: &str = "releases";
+pub const CURRENT_LINK: &str = "current";
+const MANIFEST: &str = "manifest.json";
+
+/// A release retained on disk.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Release {
+    pub commit: Stringy,
+    /// Unix timestamp the release was last deployed at.
+    pub created: u64,
+    pub current: bool,
+}
+
+/// Releases in the order they were deployed, oldest first.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct ReleaseManifest {
+    releases: Vec<ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ManifestEntry {
+    commit: Stringy,
+    created: u64,
+}
+
+/// Request body for the manager's rollback request.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RollbackRequest {
+    pub project: Stringy,
+    /// The release to switch to, `None` for the one before the current release.
+    pub commit: Option<Stringy>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReleaseLayout {
+    root: PathBuf,
+}
+
+impl ReleaseLayout {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    /// The layout of the project `id` under the platform's project directory.
+    pub fn for_project(id: &str) -> Self {
+        Self::new(Path::new(PROJECT_BASE_DIR).join(id))
+    }
+
+    pub fn root(&self) -> PathType {
+        PathType::PathBuf(self.root.clone())
+    }
+
+    pub fn repo_dir(&self) -> PathType {
+        PathType::PathBuf(self.root.join(REPO_DIR))
+    }
+
+    pub fn release_dir(&self, commit: &str) -> PathType {
+        PathType::PathBuf(self.root.join(RELEASES_DIR).join(commit))
+    }
+
+    pub fn current_dir(&self) -> PathType {
+        PathType::PathBuf(self.root.join(CURRENT_LINK))
+    }
+
+    /// True for projects deployed before releases existed, where the project
+    /// directory itself is the git checkout.
+    pub fn is_legacy(&self) -> bool {
+        self.root.join(".git").exists()
+    }
+
+    /// Moves a legacy checkout into `repo/`. The project is briefly absent
+    /// between the two renames, callers deploy a release straight after.
+    pub fn migrate_legacy(&self) -> Result<(), ErrorArrayItem> {
+        if !self.is_legacy() {
+            return Ok(());
+        }
+
+        let staging = self.root.with_extension("migrating");
+        fs::rename(&self.root, &staging)?;
+        fs::create_dir_all(&self.root)?;
+        fs::rename(&staging, self.root.join(REPO_DIR))?;
+        Ok(())
+    }
+
+    /// The commit `current` points at, if any.
+    pub fn current_release(&self) -> Option<Stringy> {
+        let target = fs::read_link(self.root.join(CURRENT_LINK)).ok()?;
+        target
+            .file_name()
+            .map(|name| Stringy::from(name.to_string_lossy().to_string()))
+    }
+
+    /// Retained releases, newest first.
+    pub fn releases(&self) -> Result<Vec<Release>, ErrorArrayItem> {
+        let releases_dir = self.root.join(RELEASES_DIR);
+        if !releases_dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let manifest = self.read_manifest()?;
+        let current = self.current_release();
+        let mut releases = Vec::new();
+
+        for entry in fs::read_dir(releases_dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            // Skip half-prepared releases
+            if !entry.file_type()?.is_dir() || name.ends_with(".tmp") {
+                continue;
+            }
+
+            // Releases prepared before the manifest existed only have their
+            // directory's time, and are older than every recorded one
+            let position = manifest
+                .releases
+                .iter()
+                .position(|recorded| *recorded.commit == *name);
+            let created = match position {
+                Some(index) => manifest.releases[index].created,
+                None => entry
+                    .metadata()?
+                    .modified()?
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map(|duration| duration.as_secs())
+                    .unwrap_or(0),
+            };
+
+            let release = Release {
+                current: current.as_deref() == Some(name.as_str()),
+                commit: Stringy::from(name),
+                created,
+            };
+            releases.push((position, release));
+        }
+
+        releases.sort_by(|(a_position, a), (b_position, b)| {
+            b_position
+                .cmp(a_position)
+                .then(b.created.cmp(&a.created))
+                .then(b.commit.cmp(&a.commit))
+        });
+        Ok(releases.into_iter().map(|(_, release)| release).collect())
+    }
+
+    fn read_manifest(&self) -> Result<ReleaseManifest, ErrorArrayItem> {
+        let path = self.root.join(RELEASES_DIR).join(MANIFEST);
+        if !path.exists() {
+            return Ok(ReleaseManifest::default());
+        }
+
+        serde_json::from_str(&fs::read_to_string(&path)?).map_err(|e| {
+            ErrorArrayItem::new(
+                Errors::InvalidFile,
+                format!("Failed to parse {}: {}", path.display(), e),
+            )
+        })
+    }
+
+    // Written through a temporary file, a torn manifest would reorder releases
+    fn write_manifest(&self, manifest: &ReleaseManifest) -> Result<(), ErrorArrayItem> {
+        let path = self.root.join(RELEASES_DIR).join(MANIFEST);
+        let staging = path.with_extension("json.tmp");
+        fs::write(&staging, serde_json::to_string_pretty(manifest)?)?;
+        fs::rename(&staging, &path)?;
+        Ok(())
+    }
+
+    /// The release deployed last, whether or not it is still live.
+    pub fn deployed_release(&self) -> Result<Option<Stringy>, ErrorArrayItem> {
+        Ok(self
+            .read_manifest()?
+            .releases
+            .pop()
+            .map(|recorded| recorded.commit))
+    }
+
+    /// Records `commit` as the newest release, once it has been deployed.
+    pub fn record(&self, commit: &str) -> Result<(), ErrorArrayItem> {
+        let mut manifest = self.read_manifest()?;
+        manifest
+            .releases
+            .retain(|recorded| *recorded.commit != *commit);
+        manifest.releases.push(ManifestEntry {
+            commit: Stringy::new(commit),
+            created: current_timestamp(),
+        });
+        self.write_manifest(&manifest)
+    }
+
+    /// Exports `commit` from the checkout into its release directory. Also
+    /// returns whether this call created it, a retained release is returned as
+    /// it is and must be neither built in again nor removed.
+    ///
+    /// The tree is written to a temporary directory first so an interrupted
+    /// export is never mistaken for a finished release.
+    pub async fn prepare(&self, commit: &str) -> Result<(PathType, bool), ErrorArrayItem> {
+        let release_dir = self.release_dir(commit);
+        if release_dir.exists() {
+            return Ok((release_dir, false));
+        }
+
+        let staging = self.root.join(RELEASES_DIR).join(format!("{}.tmp", commit));
+        if staging.exists() {
+            fs::remove_dir_all(&staging)?;
+        }
+        fs::create_dir_all(&staging)?;
+
+        git_native::export_commit(
+            self.repo_dir(),
+            Stringy::new(commit),
+            PathType::PathBuf(staging.clone()),
+        )
+        .await?;
+
+        fs::rename(&staging, &release_dir)?;
+        Ok((release_dir, true))
+    }
+
+    /// Atomically points `current` at the release for `commit`.
+    pub fn activate(&self, commit: &str) -> Result<(), ErrorArrayItem> {
+        if !self.release_dir(commit).exists() {
+            return Err(ErrorArrayItem::new(
+                Errors::InvalidFile,
+                format!("No release {} for {}", commit, self.root.display()),
+            ));
+        }
+
+        // rename(2) replaces the old link in one step, unlike removing it first
+        let staging = self.root.join(format!("{}.tmp", CURRENT_LINK));
+        if fs::symlink_metadata(&staging).is_ok() {
+            fs::remove_file(&staging)?;
+        }
+        symlink(Path::new(RELEASES_DIR).join(commit), &staging)?;
+        fs::rename(&staging, self.root.join(CURRENT_LINK))?;
+        Ok(())
+    }
+
+    /// Switches back to `commit`, or to the release before the current one.
+    /// Returns the commit now live.
+    pub fn rollback(&self, commit: Option<&str>) -> Result<Stringy, ErrorArrayItem> {
+        let releases = self.releases()?;
+
+        let target = match commit {
+            Some(commit) => releases
+                .iter()
+                .find(|release| &*release.commit == commit)
+                .ok_or_else(|| {
+                    ErrorArrayItem::new(
+                        Errors::InvalidFile,
+                        format!("Release {} is not retained", commit),
+                    )
+                })?,
+            None => {
+                let current = releases.iter().position(|release| release.current);
+                let previous = match current {
+                    Some(index) => releases.get(index + 1),
+                    None => releases.first(),
+                };
+                previous.ok_or_else(|| {
+                    ErrorArrayItem::new(
+                        Errors::InvalidFile,
+                        String::from("There is no earlier release to roll back to"),
+                    )
+                })?
+            }
+        };
+
+        self.activate(&target.commit)?;
+        Ok(target.commit.clone())
+    }
+
+    /// Deletes all but the `keep` newest releases, never removing the live one.
+    pub fn prune(&self, keep: usize) -> Result<Vec<Stringy>, ErrorArrayItem> {
+        let mut removed = Vec::new();
+
+        for release in self.releases()?.into_iter().skip(keep) {
+            if release.current {
+                continue;
+            }
+            fs::remove_dir_all(self.release_dir(&release.commit))?;
+            removed.push(release.commit);
+        }
+
+        if !removed.is_empty() {
+            let mut manifest = self.read_manifest()?;
+            manifest
+                .releases
+                .retain(|recorded| !removed.contains(&recorded.commit));
+            self.write_manifest(&manifest)?;
+        }
+        Ok(removed)
+    }
+
+    /// Prunes down to the platform's default number of retained releases.
+    pub fn prune_default(&self) -> Result<Vec<Stringy>, ErrorArrayItem> {
+        self.prune(RELEASES_TO_KEEP)
+    }
+}
+
+/// Rolls `project` back to `commit`, or the previous release, and restarts its
+/// service if it has one. Returns the commit now live.
+pub fn rollback_project(project: &str, commit: Option<&str>) -> Result<Stringy, ErrorArrayItem> {
+    // Projects may still be referred to by the hash id they had before their slug
+    let project = &resolve_project(project);
+    let layout = ReleaseLayout::for_project(project);
+    if !layout.root().exists() {
+        return Err(ErrorArrayItem::new(
+            Errors::InvalidFile,
+            format!("Unknown project {}", project),
+        ));
+    }
+
+    let live = layout.rollback(commit)?;
+    restart_project(project)?;
+    Ok(live)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    // A release whose directory was last modified `age` seconds ago, recorded
+    // in the manifest unless it predates it
+    fn fake_release(layout: &ReleaseLayout, commit: &str, age: u64, recorded: bool) {
+        let dir = layout.release_dir(commit);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("index.html"), commit).unwrap();
+        if recorded {
+            layout.record(commit).unwrap();
+        }
+
+        let modified = SystemTime::now() - std::time::Duration::from_secs(age);
+        fs::File::open(&dir)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_activate_rollback_and_prune() {
+        let root = TempDir::new().unwrap();
+        let layout = ReleaseLayout::new(root.path().to_path_buf());
+
+        // Recorded order wins over directory times
+        fake_release(&layout, "old", 0, false);
+        fake_release(&layout, "aaa", 100, true);
+        fake_release(&layout, "bbb", 300, true);
+        fake_release(&layout, "ccc", 200, true);
+        let order: Vec<Stringy> = layout
+            .releases()
+            .unwrap()
+            .into_iter()
+            .map(|release| release.commit)
+            .collect();
+        assert_eq!(order, ["ccc", "bbb", "aaa", "old"].map(Stringy::new));
+
+        layout.activate("ccc").unwrap();
+        assert_eq!(layout.current_release().as_deref(), Some("ccc"));
+        assert_eq!(
+            fs::read_to_string(layout.current_dir().join("index.html")).unwrap(),
+            "ccc"
+        );
+
+        assert_eq!(&*layout.rollback(None).unwrap(), "bbb");
+        assert_eq!(&*layout.rollback(Some("aaa")).unwrap(), "aaa");
+        // Rolling back never changes what was deployed last
+        assert_eq!(layout.deployed_release().unwrap().as_deref(), Some("ccc"));
+        assert!(layout.rollback(Some("zzz")).is_err());
+
+        // The live release survives pruning even when it is older
+        let removed = layout.prune(1).unwrap();
+        assert_eq!(removed, ["bbb", "old"].map(Stringy::new));
+        assert!(layout.release_dir("aaa").exists());
+        assert!(layout.release_dir("ccc").exists());
+        assert_eq!(layout.read_manifest().unwrap().releases.len(), 2);
+    }
+}
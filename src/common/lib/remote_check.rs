@@ -0,0 +1,224 @@
+// Checks of credential entries against their remotes, run before credentials
+// are saved so a typo in the owner, repository or branch, or a token without
+// read access, is reported straight away instead of by a failing git monitor.
+// Remotes are only listed, like `git ls-remote`, nothing is cloned.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    git_data::{is_auth_failure, GitAuth, GitPin, TagVersion},
+    git_native,
+};
+
+/// The outcome of checking one credential entry.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RemoteCheck {
+    /// The entry as `owner/repo@branch`.
+    pub repository: String,
+    /// Why the entry can't be deployed from, empty if every check passed.
+    pub problems: Vec<String>,
+}
+
+impl RemoteCheck {
+    pub fn passed(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl fmt::Display for RemoteCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.passed() {
+            true => write!(f, "{}: ok", self.repository),
+            false => write!(f, "{}: {}", self.repository, self.problems.join(", ")),
+        }
+    }
+}
+
+/// Checks that the remote of `auth` exists and its credentials can read it,
+/// and that its branch and any pinned tag are there.
+pub async fn check_remote(auth: &GitAuth) -> RemoteCheck {
+    let mut check = RemoteCheck {
+        repository: format!("{}/{}@{}", auth.user, auth.repo, auth.branch),
+        problems: Vec::new(),
+    };
+
+    let remote_auth = match auth.remote_auth() {
+        Ok(remote_auth) => remote_auth,
+        Err(e) => {
+            check
+                .problems
+                .push(format!("its credentials can't be loaded: {}", e));
+            return check;
+        }
+    };
+
+    let refs = match git_native::list_remote(auth.remote_url(), remote_auth).await {
+        Ok(refs) => refs,
+        Err(e) => {
+            check.problems.push(match is_auth_failure(&e) {
+                true => format!(
+                    "{} can't be read with its credentials: {}",
+                    auth.remote_url(),
+                    e
+                ),
+                false => format!("{} can't be reached: {}", auth.remote_url(), e),
+            });
+            return check;
+        }
+    };
+    let has_ref = |name: &str| refs.iter().any(|advertised| **advertised == *name);
+
+    if !has_ref(&format!("refs/heads/{}", auth.branch)) {
+        check
+            .problems
+            .push(format!("branch {} doesn't exist", auth.branch));
+    }
+
+    match &auth.pin {
+        Some(GitPin::Tag(tag)) if !has_ref(&format!("refs/tags/{}", tag)) => {
+            check.problems.push(format!("tag {} doesn't exist", tag));
+        }
+        Some(GitPin::TagPattern(pattern)) => {
+            let matched = refs
+                .iter()
+                .filter_map(|name| name.strip_prefix("refs/tags/"))
+                // Annotated tags are advertised a second time, peeled
+                .filter(|tag| !tag.ends_with("^{}"))
+                .any(|tag| glob_match(pattern, tag) && TagVersion::parse(tag).is_some());
+            if !matched {
+                check
+                    .problems
+                    .push(format!("no version tag matches {}", pattern));
+            }
+        }
+        // Remotes only advertise commits that are the tip of a ref
+        _ => (),
+    }
+
+    check
+}
+
+/// Checks every entry, see `check_remote`.
+pub async fn check_remotes(auths: &[GitAuth]) -> Vec<RemoteCheck> {
+    let mut checks = Vec::with_capacity(auths.len());
+    for auth in auths {
+        checks.push(check_remote(auth).await);
+    }
+    checks
+}
+
+// Matches `*` and `?` the way git matches tag patterns
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was and the text position it is retried from
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, from)) => {
+                    p = star + 1;
+                    t = from + 1;
+                    backtrack = Some((star, from + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::{Repository, Signature, Time};
+    use std::{fs, path::Path};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("v*", "v1.2.0"));
+        assert!(glob_match("release-?.*", "release-3.1"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("v*", "1.2.0"));
+        assert!(!glob_match("v?", "v10"));
+    }
+
+    #[tokio::test]
+    async fn test_check_remote() {
+        let root = TempDir::new().unwrap();
+        let bare_path = root.path().join("owner").join("site.git");
+        Repository::init_bare(&bare_path).unwrap();
+        let seed_path = root.path().join("seed");
+        let seed = Repository::init(&seed_path).unwrap();
+        seed.remote("origin", bare_path.to_str().unwrap()).unwrap();
+
+        fs::write(seed_path.join("index.html"), "hello").unwrap();
+        let mut index = seed.index().unwrap();
+        index.add_path(Path::new("index.html")).unwrap();
+        let tree = seed.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = Signature::new("ais", "ais@localhost", &Time::new(0, 0)).unwrap();
+        let commit = seed
+            .commit(
+                Some("refs/heads/main"),
+                &signature,
+                &signature,
+                "index",
+                &tree,
+                &[],
+            )
+            .unwrap();
+        let commit = seed.find_object(commit, None).unwrap();
+        seed.tag("v1.0.0", &commit, &signature, "v1.0.0", false)
+            .unwrap();
+        seed.find_remote("origin")
+            .unwrap()
+            .push(
+                &[
+                    "refs/heads/main:refs/heads/main",
+                    "refs/tags/v1.0.0:refs/tags/v1.0.0",
+                ],
+                None,
+            )
+            .unwrap();
+
+        let auth = |branch: &str, pin: Option<GitPin>| -> GitAuth {
+            let mut auth = GitAuth::new("owner", "site", branch);
+            auth.set_field("provider", &format!("file:{}", root.path().display()))
+                .unwrap();
+            auth.pin = pin;
+            auth
+        };
+
+        let check = check_remote(&auth("main", None)).await;
+        assert!(check.passed(), "{}", check);
+        let check = check_remote(&auth(
+            "main",
+            GitPin::from_parts("pattern", "v1.*").unwrap(),
+        ))
+        .await;
+        assert!(check.passed(), "{}", check);
+
+        let check = check_remote(&auth("mian", GitPin::from_parts("tag", "v2.0.0").unwrap())).await;
+        assert_eq!(check.problems.len(), 2, "{}", check);
+
+        let mut missing = auth("main", None);
+        missing.set_field("repo", "sight").unwrap();
+        let check = check_remote(&missing).await;
+        assert_eq!(check.problems.len(), 1);
+        assert!(check.problems[0].contains("can't be reached"), "{}", check);
+    }
+}
@@ -0,0 +1,345 @@
+// Encryption of secrets stored on disk or passed between services, such as
+// the credential file, deploy keys and queued emails. The backend is chosen in
+// SECRETS_CONFIG:
+//
+//   {"backend": "dusa"}                                   the default
+//   {"backend": "key-file", "path": "/etc/ais/secret.key"}
+//
+// The dusa backend asks dusad over its socket and fails whenever dusad is
+// down. The key file backend encrypts locally with a root-only key, generated
+// on first use. Data written by one backend can't be read by another, except
+// that the key file backend still hands data it didn't write to dusa, so an
+// existing credential file keeps working and is moved over on its next save.
+// Services exchanging secrets, like the mailer, must use the same backend.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use dusa_collection_utils::{
+    errors::{ErrorArrayItem, Errors},
+    stringy::Stringy,
+};
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    constants::{SECRETS_CONFIG, SECRET_KEY_FILE},
+    dusa_wrapper,
+};
+
+// Marks data sealed by the local backends, followed by base64 of nonce and ciphertext
+const SEALED_PREFIX: &str = "ais1:";
+
+/// Encrypts and decrypts text. Ciphertexts are single lines of text.
+pub trait SecretStore: Send + Sync {
+    fn encrypt(&self, data: &Stringy) -> Result<Stringy, ErrorArrayItem>;
+    fn decrypt(&self, data: &Stringy) -> Result<Stringy, ErrorArrayItem>;
+}
+
+/// The backend settings in SECRETS_CONFIG.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(tag = "backend", rename_all = "kebab-case")]
+pub enum SecretBackend {
+    #[default]
+    Dusa,
+    KeyFile {
+        #[serde(default = "default_key_file")]
+        path: String,
+    },
+}
+
+fn default_key_file() -> String {
+    SECRET_KEY_FILE.to_owned()
+}
+
+impl SecretBackend {
+    /// Reads SECRETS_CONFIG, the dusa backend if it doesn't exist.
+    pub fn load() -> Result<Self, ErrorArrayItem> {
+        Self::load_from(Path::new(SECRETS_CONFIG))
+    }
+
+    pub fn load_from(path: &Path) -> Result<Self, ErrorArrayItem> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        serde_json::from_str(&fs::read_to_string(path)?).map_err(|e| {
+            ErrorArrayItem::new(
+                Errors::InvalidFile,
+                format!("Failed to parse {}: {}", path.display(), e),
+            )
+        })
+    }
+
+    pub fn open(&self) -> Arc<dyn SecretStore> {
+        match self {
+            SecretBackend::Dusa => Arc::new(DusaStore),
+            SecretBackend::KeyFile { path } => Arc::new(KeyFileStore::new(path)),
+        }
+    }
+}
+
+static STORE: RwLock<Option<Arc<dyn SecretStore>>> = RwLock::new(None);
+
+/// The configured store, opened on first use.
+pub fn store() -> Result<Arc<dyn SecretStore>, ErrorArrayItem> {
+    if let Some(store) = STORE.read().unwrap().as_ref() {
+        return Ok(store.clone());
+    }
+
+    let store = SecretBackend::load()?.open();
+    *STORE.write().unwrap() = Some(store.clone());
+    Ok(store)
+}
+
+/// Replaces the store for the rest of the process, regardless of the config.
+pub fn use_store(store: Arc<dyn SecretStore>) {
+    *STORE.write().unwrap() = Some(store);
+}
+
+pub fn encrypt_text(data: Stringy) -> Result<Stringy, ErrorArrayItem> {
+    store()?.encrypt(&data)
+}
+
+pub fn decrypt_text(data: Stringy) -> Result<Stringy, ErrorArrayItem> {
+    store()?.decrypt(&data)
+}
+
+/// Encryption by dusad, see `dusa_wrapper`.
+pub struct DusaStore;
+
+impl SecretStore for DusaStore {
+    fn encrypt(&self, data: &Stringy) -> Result<Stringy, ErrorArrayItem> {
+        dusa_wrapper::encrypt_text(data.clone())
+    }
+
+    fn decrypt(&self, data: &Stringy) -> Result<Stringy, ErrorArrayItem> {
+        dusa_wrapper::decrypt_text(data.clone())
+    }
+}
+
+/// ChaCha20-Poly1305 with a key read from a root-only file.
+pub struct KeyFileStore {
+    path: PathBuf,
+}
+
+impl KeyFileStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    // Generates the key on first use. A key other users can read is refused,
+    // it would no longer protect anything.
+    fn key(&self) -> Result<[u8; 32], ErrorArrayItem> {
+        if !self.path.exists() {
+            self.generate()?;
+        }
+
+        let mode = fs::metadata(&self.path)?.permissions().mode();
+        if mode & 0o077 != 0 {
+            return Err(ErrorArrayItem::new(
+                Errors::InvalidFile,
+                format!(
+                    "{} is readable by other users, restrict it to its owner",
+                    self.path.display()
+                ),
+            ));
+        }
+
+        fs::read(&self.path)?.try_into().map_err(|_| {
+            ErrorArrayItem::new(
+                Errors::InvalidFile,
+                format!("{} is not a 32 byte key", self.path.display()),
+            )
+        })
+    }
+
+    fn generate(&self) -> Result<(), ErrorArrayItem> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        let staging = self
+            .path
+            .with_extension(format!("{:016x}.tmp", OsRng.next_u64()));
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&staging)?;
+
+        // Linked into place once complete, so the key is never seen half
+        // written. Another service may generate it at the same moment, the
+        // first link wins and the other key was never used.
+        let linked = file
+            .write_all(&key)
+            .and_then(|_| file.sync_all())
+            .and_then(|_| fs::hard_link(&staging, &self.path));
+        let _ = fs::remove_file(&staging);
+        match linked {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(()),
+            Err(e) => Err(ErrorArrayItem::from(e)),
+        }
+    }
+}
+
+impl SecretStore for KeyFileStore {
+    fn encrypt(&self, data: &Stringy) -> Result<Stringy, ErrorArrayItem> {
+        seal(&self.key()?, data)
+    }
+
+    fn decrypt(&self, data: &Stringy) -> Result<Stringy, ErrorArrayItem> {
+        match data.trim().starts_with(SEALED_PREFIX) {
+            true => open(&self.key()?, data),
+            // Written before the key file backend was configured
+            false => DusaStore.decrypt(data),
+        }
+    }
+}
+
+/// A key that only lives as long as the process, for tests. Never configurable,
+/// everything it encrypted is lost when the process exits.
+#[cfg(test)]
+pub struct MemoryStore {
+    key: [u8; 32],
+}
+
+#[cfg(test)]
+impl MemoryStore {
+    pub fn new() -> Self {
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        Self { key }
+    }
+}
+
+#[cfg(test)]
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+impl SecretStore for MemoryStore {
+    fn encrypt(&self, data: &Stringy) -> Result<Stringy, ErrorArrayItem> {
+        seal(&self.key, data)
+    }
+
+    fn decrypt(&self, data: &Stringy) -> Result<Stringy, ErrorArrayItem> {
+        open(&self.key, data)
+    }
+}
+
+fn seal(key: &[u8; 32], data: &str) -> Result<Stringy, ErrorArrayItem> {
+    let mut nonce = [0u8; 12];
+    OsRng.fill_bytes(&mut nonce);
+
+    let ciphertext = ChaCha20Poly1305::new(Key::from_slice(key))
+        .encrypt(Nonce::from_slice(&nonce), data.as_bytes())
+        .map_err(|_| {
+            ErrorArrayItem::new(Errors::GeneralError, String::from("Failed to encrypt"))
+        })?;
+
+    let mut sealed = nonce.to_vec();
+    sealed.extend_from_slice(&ciphertext);
+    Ok(Stringy::from(format!(
+        "{}{}",
+        SEALED_PREFIX,
+        base64::encode(sealed)
+    )))
+}
+
+fn open(key: &[u8; 32], data: &str) -> Result<Stringy, ErrorArrayItem> {
+    let damaged = || {
+        ErrorArrayItem::new(
+            Errors::GeneralError,
+            String::from(
+                "Failed to decrypt, the data is damaged or was encrypted with another key",
+            ),
+        )
+    };
+
+    let encoded = data
+        .trim()
+        .strip_prefix(SEALED_PREFIX)
+        .ok_or_else(damaged)?;
+    let sealed = base64::decode(encoded).map_err(|_| damaged())?;
+    if sealed.len() < 12 {
+        return Err(damaged());
+    }
+
+    let (nonce, ciphertext) = sealed.split_at(12);
+    let plaintext = ChaCha20Poly1305::new(Key::from_slice(key))
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| damaged())?;
+    Ok(Stringy::from(String::from_utf8(plaintext)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_key_file_store() {
+        let root = TempDir::new().unwrap();
+        let path = root.path().join("ais").join("secret.key");
+        let store = KeyFileStore::new(&path);
+
+        let sealed = store.encrypt(&Stringy::new("ghp_example")).unwrap();
+        // Only the key is left behind
+        assert_eq!(fs::read_dir(path.parent().unwrap()).unwrap().count(), 1);
+        assert!(sealed.starts_with(SEALED_PREFIX) && !sealed.contains("ghp_example"));
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        // Callers strip the newlines files end with
+        let read_back = Stringy::from(format!("{}\n", sealed));
+        assert_eq!(
+            &*KeyFileStore::new(&path).decrypt(&read_back).unwrap(),
+            "ghp_example"
+        );
+
+        assert!(MemoryStore::new().decrypt(&sealed).is_err());
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(store.decrypt(&sealed).is_err());
+    }
+
+    #[test]
+    fn test_backend_config() {
+        let root = TempDir::new().unwrap();
+        let path = root.path().join("secrets.json");
+        assert_eq!(
+            SecretBackend::load_from(&path).unwrap(),
+            SecretBackend::Dusa
+        );
+
+        fs::write(&path, r#"{"backend": "key-file"}"#).unwrap();
+        assert_eq!(
+            SecretBackend::load_from(&path).unwrap(),
+            SecretBackend::KeyFile {
+                path: SECRET_KEY_FILE.to_owned()
+            }
+        );
+
+        for unknown in ["vault", "memory"] {
+            fs::write(&path, format!(r#"{{"backend": "{}"}}"#, unknown)).unwrap();
+            assert!(SecretBackend::load_from(&path).is_err());
+        }
+    }
+}
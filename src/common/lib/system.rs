@@ -96,6 +96,11 @@ pub fn format_unix_timestamp(timestamp: u64) -> Stringy {
     return Stringy::from_string(data)
 }
 
+/// Asks a yes or no question on stdin, anything but `y` or `yes` is a no.
+pub fn prompt_yes_no(prompt: &str) -> bool {
+    matches!(prompt_input(prompt).to_lowercase().as_str(), "y" | "yes")
+}
+
 pub fn prompt_input(prompt: &str) -> Stringy {
     print!("{}", prompt);
     io::stdout().flush().unwrap();
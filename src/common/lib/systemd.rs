@@ -1,5 +1,6 @@
 use chrono::{DateTime, Utc};
 use dusa_collection_utils::{errors::{ErrorArrayItem, Errors}, stringy::Stringy};
+use serde::{Deserialize, Serialize};
 use std::{
     fmt, io,
     process::{Command, ExitStatus},
@@ -7,7 +8,7 @@ use std::{
 use systemctl::Unit;
 
 /// Enum representing different services.
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum Services {
     PhpProcessor,
     WebServer,
@@ -20,7 +21,7 @@ pub enum Services {
 }
 
 /// Enum representing the status of a service.
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Status {
     Running,
     Stopped,
@@ -28,20 +29,20 @@ pub enum Status {
 }
 
 /// Enum representing memory information.
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Memory {
     MemoryConsumed(Stringy),
 }
 
 /// Enum representing subprocesses information.
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum SubProcesses {
     Pid(u64),
     Tasks(u64),
 }
 
 /// Struct representing information about a process.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProcessInfo {
     pub service: Stringy,
     pub refered: Services,
@@ -86,6 +87,20 @@ impl Processes {
 }
 
 impl Services {
+    /// The services watched by ais_services and exposed through the aggregator.
+    pub fn monitored() -> Vec<Services> {
+        vec![
+            Services::PhpProcessor,
+            Services::WebServer,
+            Services::SshServer,
+            // Services::Monitor, Removing netdata for now
+            Services::Firewall,
+            Services::Locker,
+            Services::Database,
+            // Services::Docker, Removing till we change snap to service
+        ]
+    }
+
     /// Restarts the service and returns a bool based on the running status after the restart.
     pub fn restart(&self) -> Result<bool, ErrorArrayItem> {
         let unit_name: String = format!("{}", self);
@@ -110,6 +125,30 @@ impl Services {
     }
 }
 
+impl Memory {
+    /// Converts the systemd memory reading (e.g. `12.3M`) into bytes.
+    pub fn bytes(&self) -> u64 {
+        let Memory::MemoryConsumed(data) = self;
+
+        // Newer systemd versions append the peak usage: `5.1M (peak: 6.2M)`
+        let reading: &str = data.split_whitespace().next().unwrap_or("0");
+        let (number, unit) = match reading.find(|c: char| c.is_ascii_alphabetic()) {
+            Some(pos) => reading.split_at(pos),
+            None => (reading, "B"),
+        };
+
+        let multiplier: f64 = match unit {
+            "K" => 1024.0,
+            "M" => 1024.0 * 1024.0,
+            "G" => 1024.0 * 1024.0 * 1024.0,
+            "T" => 1024.0 * 1024.0 * 1024.0 * 1024.0,
+            _ => 1.0,
+        };
+
+        (number.parse::<f64>().unwrap_or(0.0) * multiplier) as u64
+    }
+}
+
 impl ProcessInfo {
     /// Retrieves information about a specific service.
     pub fn get_info(service: Services) -> Result<Self, ErrorArrayItem> {
@@ -166,6 +205,16 @@ pub fn enable_now(service_name: String) -> io::Result<ExitStatus> {
     Ok(status)
 }
 
+pub fn disable_now(service_name: String) -> io::Result<ExitStatus> {
+    let status = Command::new("systemctl")
+        .arg("disable")
+        .arg(&service_name)
+        .arg("--now")
+        .status()?;
+
+    Ok(status)
+}
+
 pub fn is_service(service_name: String) -> Result<bool, ErrorArrayItem> {
     systemctl::exists(&service_name).map_err(|err| ErrorArrayItem::new(Errors::GeneralError, err.to_string()))
 }
@@ -270,6 +319,20 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_memory_bytes() {
+        assert_eq!(Memory::MemoryConsumed(Stringy::new("0B")).bytes(), 0);
+        assert_eq!(Memory::MemoryConsumed(Stringy::new("512K")).bytes(), 512 * 1024);
+        assert_eq!(
+            Memory::MemoryConsumed(Stringy::new("1.5M")).bytes(),
+            (1.5 * 1024.0 * 1024.0) as u64
+        );
+        assert_eq!(
+            Memory::MemoryConsumed(Stringy::new("2G (peak: 3G)")).bytes(),
+            2 * 1024 * 1024 * 1024
+        );
+    }
+
     #[test]
     fn test_subprocesses_display() {
         assert_eq!(format!("{}", SubProcesses::Pid(123)), "123");
@@ -0,0 +1,332 @@
+// Removal of projects whose repository was taken out of the credentials. The
+// git monitor marks such projects as orphaned in the project registry and tears
+// them down once they have been orphaned for the grace period, operators can
+// tear one down straight away with `ais_releases teardown`.
+
+use std::{fs, path::Path, process::Command};
+
+use dusa_collection_utils::errors::{ErrorArrayItem, Errors};
+use serde_json::Value;
+
+use crate::{
+    apache::reload_apache,
+    constants::{PROJECT_ARCHIVE_DIR, PROJECT_BASE_DIR, SYSTEMD_UNIT_DIR, WEBSERVER_CONFIG_DIR},
+    directive::validate_directive,
+    directive_schema::url_problem,
+    monitor::MONITOR_DIR,
+    project_env::remove_project_env,
+    projects::{is_platform_unit, ProjectRegistry},
+    release::{CURRENT_LINK, REPO_DIR},
+    system::current_timestamp,
+    systemd::{disable_now, reload_systemd_daemon},
+};
+
+/// Apache only serves the vhosts linked in here.
+const WEBSERVER_ENABLED_DIR: &str = "/etc/apache2/sites-enabled";
+
+/// What happens to a torn down project's directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Disposal {
+    /// Packed into a tarball under `PROJECT_ARCHIVE_DIR` before it is removed.
+    Archive,
+    Delete,
+}
+
+/// Stops and removes everything the platform set up for the project `name`:
+/// its service and monitor units, monitor script, Apache vhost, environment
+/// variables and directory.
+/// Every finished step is pushed to `steps`, so a failure part way through can
+/// still be reported accurately.
+pub async fn teardown_project(
+    name: &str,
+    disposal: Disposal,
+    steps: &mut Vec<String>,
+) -> Result<(), ErrorArrayItem> {
+    let project_dir = Path::new(PROJECT_BASE_DIR).join(name);
+    if !is_project_name(name) || !project_dir.is_dir() {
+        return Err(unknown_project(name));
+    }
+
+    // Read before anything is removed, the vhost is named after its url. A
+    // directive failing validation still names the vhost it was deployed with,
+    // one that can't be read at all only costs the vhost step.
+    let vhost = match find_directive(&project_dir) {
+        Some(path) => match deployed_vhost(&path).await {
+            Ok(vhost) => vhost,
+            Err(e) => {
+                steps.push(format!("Skipped the apache vhost: {}", e));
+                None
+            }
+        },
+        None => None,
+    };
+
+    // The monitor restarts the service on changes, so it goes first
+    let mut units_removed = false;
+    for unit in [format!("{}_monitor", name), name.to_owned()] {
+        let unit_file = Path::new(SYSTEMD_UNIT_DIR).join(format!("{}.service", unit));
+        if unit_file.exists() {
+            // A project named like a system service must never take it down
+            if !is_platform_unit(&unit_file) {
+                steps.push(format!(
+                    "Left {}.service alone, it isn't the project's",
+                    unit
+                ));
+                continue;
+            }
+            disable_now(unit.clone())?;
+            fs::remove_file(&unit_file)?;
+            steps.push(format!("Disabled and removed {}.service", unit));
+            units_removed = true;
+        }
+    }
+    if units_removed {
+        reload_systemd_daemon()?;
+    }
+
+    let monitor_script = Path::new(MONITOR_DIR).join(format!("{}.monitor", name));
+    if monitor_script.exists() {
+        fs::remove_file(&monitor_script)?;
+        steps.push(format!("Removed {}", monitor_script.display()));
+    }
+
+    if let Some(url) = vhost {
+        let mut vhost_removed = false;
+        for dir in [WEBSERVER_ENABLED_DIR, WEBSERVER_CONFIG_DIR] {
+            let config = Path::new(dir).join(format!("{}.conf", url));
+            // Enabled vhosts are usually symlinks, which exists() sees through
+            if config.symlink_metadata().is_ok() {
+                fs::remove_file(&config)?;
+                steps.push(format!("Removed {}", config.display()));
+                vhost_removed = true;
+            }
+        }
+        if vhost_removed {
+            reload_apache().await?;
+            steps.push(String::from("Reloaded apache"));
+        }
+    }
+
+    for path in remove_project_env(name)? {
+        steps.push(format!("Removed {}", path.display()));
+    }
+
+    if disposal == Disposal::Archive {
+        let archive = archive_project(name)?;
+        steps.push(format!("Archived {} to {}", name, archive));
+    }
+    fs::remove_dir_all(&project_dir)?;
+    steps.push(format!("Removed {}", project_dir.display()));
+
+    Ok(())
+}
+
+/// The directory name of the project an operator asked to tear down: the slug
+/// of a registered project, found by slug or legacy id, or one of the
+/// `unregistered_projects`. Anything else is refused.
+pub fn resolve_teardown(
+    registry: &ProjectRegistry,
+    project: &str,
+) -> Result<String, ErrorArrayItem> {
+    resolve_teardown_in(Path::new(PROJECT_BASE_DIR), registry, project)
+}
+
+fn resolve_teardown_in(
+    base: &Path,
+    registry: &ProjectRegistry,
+    project: &str,
+) -> Result<String, ErrorArrayItem> {
+    let name = match registry.find(project) {
+        Some(entry) => entry.slug.to_string(),
+        None if unregistered_projects_in(base, registry)?
+            .iter()
+            .any(|name| name == project) =>
+        {
+            project.to_owned()
+        }
+        None => return Err(unknown_project(project)),
+    };
+
+    match is_project_name(&name) {
+        true => Ok(name),
+        false => Err(unknown_project(project)),
+    }
+}
+
+/// Directories under `PROJECT_BASE_DIR` no registered project is named after,
+/// such as projects removed from the credentials before the registry existed.
+pub fn unregistered_projects(registry: &ProjectRegistry) -> Result<Vec<String>, ErrorArrayItem> {
+    unregistered_projects_in(Path::new(PROJECT_BASE_DIR), registry)
+}
+
+fn unregistered_projects_in(
+    base: &Path,
+    registry: &ProjectRegistry,
+) -> Result<Vec<String>, ErrorArrayItem> {
+    if !base.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut names = Vec::new();
+    for entry in fs::read_dir(base)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+
+        let name = entry.file_name().to_string_lossy().to_string();
+        if registry.find(&name).is_none() {
+            names.push(name);
+        }
+    }
+
+    names.sort();
+    Ok(names)
+}
+
+// The url of the vhost the directive at `path` set up, if it set one up. Falls
+// back to reading just the url and apache keys from a directive that no longer
+// deserializes. The url names the files removed, so it must be a host name.
+async fn deployed_vhost(path: &Path) -> Result<Option<String>, ErrorArrayItem> {
+    let (apache, url) = match validate_directive(path).await? {
+        (Some(directive), _) => (directive.apache, directive.url),
+        (None, report) => {
+            let content = fs::read_to_string(path)?;
+            let document: Value = content
+                .lines()
+                .filter(|line| !line.trim_start().starts_with('#'))
+                .collect::<Vec<_>>()
+                .join("\n")
+                .parse()
+                .map_err(|_| ErrorArrayItem::new(Errors::InvalidFile, report.to_string()))?;
+            match document.get("url").and_then(Value::as_str) {
+                Some(url) => (
+                    document.get("apache").and_then(Value::as_bool) != Some(false),
+                    url.to_owned(),
+                ),
+                None => return Err(ErrorArrayItem::new(Errors::InvalidFile, report.to_string())),
+            }
+        }
+    };
+
+    if !apache {
+        return Ok(None);
+    }
+    match url_problem(&url) {
+        None => Ok(Some(url)),
+        Some(problem) => Err(ErrorArrayItem::new(
+            Errors::InvalidFile,
+            format!("{} names the vhost {}, {}", path.display(), url, problem),
+        )),
+    }
+}
+
+// A single path component naming a directory under PROJECT_BASE_DIR, never
+// the base directory itself or its parent
+fn is_project_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains('/')
+}
+
+fn unknown_project(name: &str) -> ErrorArrayItem {
+    ErrorArrayItem::new(Errors::InvalidFile, format!("Unknown project {}", name))
+}
+
+// The live release's directive, or the one of a project still on the legacy
+// layout or never released
+pub(crate) fn find_directive(project_dir: &Path) -> Option<std::path::PathBuf> {
+    [
+        project_dir.join(CURRENT_LINK),
+        project_dir.to_path_buf(),
+        project_dir.join(REPO_DIR),
+    ]
+    .into_iter()
+    .map(|dir| dir.join("directive.ais"))
+    .find(|path| path.exists())
+}
+
+fn archive_project(name: &str) -> Result<String, ErrorArrayItem> {
+    fs::create_dir_all(PROJECT_ARCHIVE_DIR)?;
+    let archive = Path::new(PROJECT_ARCHIVE_DIR)
+        .join(format!("{}-{}.tar.gz", name, current_timestamp()))
+        .to_string_lossy()
+        .to_string();
+
+    let status = Command::new("tar")
+        .arg("-czf")
+        .arg(&archive)
+        .arg("-C")
+        .arg(PROJECT_BASE_DIR)
+        .arg(name)
+        .status()?;
+
+    if !status.success() {
+        // Never remove a project that couldn't be archived
+        let _ = fs::remove_file(&archive);
+        return Err(ErrorArrayItem::new(
+            Errors::GeneralError,
+            format!("Failed to archive {}, tar exited with {}", name, status),
+        ));
+    }
+
+    Ok(archive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::projects::ProjectEntry;
+    use dusa_collection_utils::stringy::Stringy;
+    use tempfile::TempDir;
+
+    #[tokio::test]
+    async fn test_deployed_vhost_of_broken_directives() {
+        let root = TempDir::new().unwrap();
+        let path = root.path().join("directive.ais");
+        let vhost = |content: &str| {
+            fs::write(&path, content).unwrap();
+            deployed_vhost(&path)
+        };
+
+        // Missing the port, with an unknown key and a comment
+        let broken = "# old\n{\"url\": \"shop.example.com\", \"apache\": true, \"ssl\": 1}";
+        assert_eq!(
+            vhost(broken).await.unwrap().as_deref(),
+            Some("shop.example.com")
+        );
+        assert_eq!(
+            vhost("{\"url\": \"a.com\", \"apache\": false}")
+                .await
+                .unwrap(),
+            None
+        );
+        assert!(vhost("{\"url\": \"../../etc/passwd\"}").await.is_err());
+        assert!(vhost("{\"url\": ").await.is_err());
+    }
+
+    #[test]
+    fn test_resolve_teardown_only_accepts_projects() {
+        let base = TempDir::new().unwrap();
+        for dir in ["site", "leftover"] {
+            fs::create_dir(base.path().join(dir)).unwrap();
+        }
+        let registry = ProjectRegistry {
+            projects: vec![ProjectEntry {
+                slug: Stringy::new("site"),
+                display_name: None,
+                user: Stringy::new("artisan"),
+                repo: Stringy::new("site"),
+                branch: Stringy::new("main"),
+                legacy_id: Stringy::new("1a2b3c"),
+                orphaned_since: None,
+            }],
+        };
+        let resolve = |project| resolve_teardown_in(base.path(), &registry, project);
+
+        assert_eq!(resolve("site").unwrap(), "site");
+        assert_eq!(resolve("1a2b3c").unwrap(), "site");
+        assert_eq!(resolve("leftover").unwrap(), "leftover");
+        for project in [".", "..", "", "missing", "site/..", "../site"] {
+            assert!(resolve(project).is_err(), "{:?} was accepted", project);
+        }
+    }
+}
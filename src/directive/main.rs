@@ -4,18 +4,19 @@
 // We save two hashes to ensure we aren't changing thing when they arent needed. We save a hash before copy. and we save a hash that we modify.
 
 use ais_common::{
-    apache::{create_apache_config, reload_apache}, common::{AppName, AppStatus, Status}, directive::{parse_directive, scan_directories}, messages::report_status, monitor::{create_monitoring_script, create_monitoring_service, MONITOR_DIR}, node::{create_node_systemd_service, run_npm_install}, system::current_timestamp, systemd::{enable_now, reload_systemd_daemon}, version::Version
+    common::{AppName, AppStatus, ComponentStatus, Status}, constants::PROJECT_BASE_DIR, directive::{project_id, scan_directories, validate_directive}, directive_plan::DirectivePlan, messages::report_status, system::current_timestamp, version::Version
 };
 use dusa_collection_utils::{
     errors::{ErrorArray, ErrorArrayItem},
     functions::{create_hash, make_file, open_file, truncate},
     types::{ClonePath, PathType},
 };
-use simple_pretty::{notice, warn};
+use simple_pretty::{halt, notice, pass, warn};
 use std::{
-    fs::{self, File},
-    io::{Read, Write},
-    path::Path,
+    collections::BTreeMap,
+    fs,
+    io::Read,
+    path::{Path, PathBuf},
     thread,
     time::Duration,
 };
@@ -25,10 +26,10 @@ pub const SYSTEM_DIRECTIVE_PATH: &str = "/tmp";
 fn generate_directive_hash(directive_path: PathType) -> Result<String, ErrorArrayItem> {
     let mut directive_file: std::fs::File = open_file(directive_path.clone(), false)?;
 
+    // The whole parent path is hashed, not just the project id, so a project
+    // moved to the release layout is configured again for its new location
     let directive_parent = get_parent_dir(&directive_path);
 
-    let service_id: String = directive_parent.to_string().replace("/var/www/ais/", "");
-
     let mut directive_buffer: Vec<u8> = Vec::new();
 
     directive_file
@@ -38,7 +39,7 @@ fn generate_directive_hash(directive_path: PathType) -> Result<String, ErrorArra
     let directive_hash: String =
         String::from_utf8(directive_buffer).map_err(|err| ErrorArrayItem::from(err))?;
 
-    Ok(create_hash(format!("{}_{}", directive_hash, service_id)))
+    Ok(create_hash(format!("{}_{}", directive_hash, directive_parent)))
 }
 
 fn store_directive(directive_path: PathType) -> Result<(), ErrorArrayItem> {
@@ -101,92 +102,13 @@ fn check_directive(directive_path: PathType) -> Result<bool, ErrorArrayItem> {
 
 /// This need the directive in the project folder
 async fn executing_directive(directive_path: PathType) -> Result<(), ErrorArrayItem> {
-    let directive: ais_common::directive::Directive = parse_directive(&directive_path).await?;
-    let directive_parent = get_parent_dir(&directive_path);
-    notice(&format!("Executing directive: {}", directive_parent));
-
-    // Checking if we need to reconfigure apache
-    if directive.apache {
-        let changed = create_apache_config(&directive, &directive_parent)?;
-        match changed {
-            true => {
-                match reload_apache().await {
-                    Ok(b) => {
-                        if !b {
-                            eprintln!("My god we killed apache, quick email the admin");
-                            eprintln!("The apache config we rolled out most likely killed apache");
-                        }
-                    }
-                    Err(e) => return Err(e),
-                }
-                print!("Apache config updated for {:#?}", directive_parent);
-            }
-            false => print!("The project {} needs no changes", directive_parent),
-        }
-    }
-
-    // Checking if the project is a node thing.
-    if directive.nodejs_bool {
-        let _version = match directive.nodejs_version {
-            Some(d) => d,
-            None => String::from("22"),
-        };
-
-        // TODO add check with nvm to ensure the correct version is installed.
-
-        // build application
-        if let Ok(_) = run_npm_install(&directive_parent) {
-            println!("Npm dependencies installed for {}", directive_path);
-        } else {
-            return Err(ErrorArrayItem::new(
-                dusa_collection_utils::errors::Errors::GeneralError,
-                String::from("An error occurred while installing npm dependencies"),
-            ));
-        };
-
-        // TODO MITOBYTE HAS THE WRONG VERSION of directive.ais
-        // // create system d service file
-        // let exec_start = match directive.nodejs_exec_command {
-        //     Some(d) => d,
-        //     None => format!("/usr/bin/npm dev run"),
-        // };
+    let plan = DirectivePlan::new(&directive_path).await?;
+    notice(&format!("Executing directive: {}", plan.project_dir.display()));
 
-        let exec_start = format!("/usr/bin/npm run dev");
-
-        let description: &str = &format!("Ais project id {}", &directive_parent);
-
-        // Create the systemd service file content
-        let service_file_data =
-            create_node_systemd_service(&exec_start, &directive_parent, description)?;
-
-        // Write the service file
-        let service_id: String = directive_parent.to_string().replace("/var/www/ais/", "");
-
-        let service_path: PathType =
-            PathType::Content(format!("/etc/systemd/system/{}.service", service_id));
-
-        if service_path.exists() {
-            fs::remove_file(service_path.clone())?;
-        }
-
-        let mut service_file = File::create(service_path.clone())?;
-        service_file
-            .write(service_file_data.as_bytes())
-            .map_err(|err| ErrorArrayItem::from(err))?;
-
-        // Setting up monitoring
-        create_monitoring_script(&directive_parent.to_string(), &service_id)?;
-        create_monitoring_service(
-            &service_id,
-            &format!("{}{}.monitor", MONITOR_DIR, &service_id),
-        )?;
-
-        // Reload systemd daemon
-        reload_systemd_daemon()?;
-
-        // Enable and start the service and the path unit
-        enable_now(format!("{}", service_id))?;
-        enable_now(format!("{}_monitor", service_id))?;
+    // TODO add check with nvm to ensure the correct node version is installed.
+    plan.execute().await?;
+    for step in &plan.steps {
+        println!("{}", step);
     }
 
     // report to the aggregator
@@ -195,6 +117,8 @@ async fn executing_directive(directive_path: PathType) -> Result<(), ErrorArrayI
         app_status: AppStatus::Running,
         timestamp: current_timestamp(),
         version: Version::get(),
+        message: None,
+        components: None,
     };
 
     if let Err(err) = report_status(status).await {
@@ -204,9 +128,105 @@ async fn executing_directive(directive_path: PathType) -> Result<(), ErrorArrayI
     }
 }
 
+fn usage() {
+    halt("Usage: ais_directive [lint [<directive>...] | plan [<directive>...]]");
+    notice("Without a mode directives are executed as they change");
+    notice("lint and plan take directives or project directories, every project by default");
+}
+
+// The directives named on the command line, a project directory names its
+// directive.ais, or every project's directive if none are named
+async fn directive_paths(args: &[String]) -> Result<Vec<PathBuf>, ErrorArrayItem> {
+    if args.is_empty() {
+        return scan_directories(PROJECT_BASE_DIR).await;
+    }
+
+    Ok(args
+        .iter()
+        .map(|arg| match Path::new(arg).is_dir() {
+            true => Path::new(arg).join("directive.ais"),
+            false => PathBuf::from(arg),
+        })
+        .collect())
+}
+
+// Prints every error and warning in the directives, failing if any has errors
+async fn lint(args: &[String]) {
+    let paths = match directive_paths(args).await {
+        Ok(paths) => paths,
+        Err(e) => {
+            halt(&format!("Failed to find directives: {}", e));
+            std::process::exit(1);
+        }
+    };
+
+    let mut failed = false;
+    for path in paths {
+        match validate_directive(&path).await {
+            Ok((_, report)) if report.findings.is_empty() => {
+                pass(&format!("{}: ok", path.display()))
+            }
+            Ok((_, report)) => {
+                for finding in &report.findings {
+                    println!("{}: {}", report.path, finding);
+                }
+                failed |= !report.is_valid();
+            }
+            Err(e) => {
+                halt(&format!("Failed to read {}: {}", path.display(), e));
+                failed = true;
+            }
+        }
+    }
+
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+// Prints what executing the directives would change, without changing anything
+async fn plan(args: &[String]) {
+    let paths = match directive_paths(args).await {
+        Ok(paths) => paths,
+        Err(e) => {
+            halt(&format!("Failed to find directives: {}", e));
+            std::process::exit(1);
+        }
+    };
+
+    let mut failed = false;
+    for path in paths {
+        match DirectivePlan::new(&path).await {
+            Ok(plan) => {
+                println!("{}", plan);
+                let path = PathType::PathBuf(path);
+                if let Ok(true) = check_directive(path) {
+                    notice("Already executed, it only runs again once the directive changes");
+                }
+            }
+            Err(e) => {
+                halt(&format!("Failed to plan {}: {}", path.display(), e));
+                failed = true;
+            }
+        }
+    }
+
+    if failed {
+        std::process::exit(1);
+    }
+}
+
 #[tokio::main]
 async fn main() {
-    let base_path = "/var/www/ais";
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None => (),
+        Some("lint") => return lint(&args[1..]).await,
+        Some("plan") => return plan(&args[1..]).await,
+        Some(_) => return usage(),
+    }
+
+    let base_path = PROJECT_BASE_DIR;
 
     loop {
         let directive_paths = match scan_directories(base_path).await {
@@ -218,6 +238,8 @@ async fn main() {
                     app_status: AppStatus::Warning,
                     timestamp: current_timestamp(),
                     version: Version::get(),
+                    message: None,
+                    components: None,
                 };
                 if let Err(err) = report_status(status).await {
                     ErrorArray::new(vec![e, err]).display(true)
@@ -226,7 +248,43 @@ async fn main() {
             }
         };
 
+        // Every directive is validated on each pass and reported per project
+        let mut components: BTreeMap<String, ComponentStatus> = BTreeMap::new();
+
         for directive_path_string in directive_paths {
+            let name = project_id(&directive_path_string)
+                .unwrap_or_else(|| directive_path_string.to_string_lossy().to_string());
+            let report = match validate_directive(&directive_path_string).await {
+                Ok((_, report)) => report,
+                Err(e) => {
+                    warn(&format!(
+                        "Failed to read {}: {}",
+                        directive_path_string.display(),
+                        e
+                    ));
+                    continue;
+                }
+            };
+            components.insert(
+                name,
+                ComponentStatus {
+                    app_status: match report.is_valid() {
+                        true => AppStatus::Running,
+                        false => AppStatus::Warning,
+                    },
+                    timestamp: current_timestamp(),
+                    message: match report.findings.is_empty() {
+                        true => None,
+                        false => Some(report.to_string().into()),
+                    },
+                },
+            );
+            // Invalid directives are left alone until they are fixed
+            if !report.is_valid() {
+                warn(&report.to_string());
+                continue;
+            }
+
             let directive_path: PathType = PathType::PathBuf(directive_path_string);
 
             // If we haven't already stored the directive data
@@ -241,6 +299,8 @@ async fn main() {
                             app_status: AppStatus::Warning,
                             timestamp: current_timestamp(),
                             version: Version::get(),
+                            message: None,
+                            components: None,
                         };
 
                         let e2 = report_status(status).await;
@@ -265,6 +325,8 @@ async fn main() {
                         app_status: AppStatus::Warning,
                         timestamp: current_timestamp(),
                         version: Version::get(),
+                        message: None,
+                        components: None,
                     };
                     if let Err(err) = report_status(status).await {
                         ErrorArray::new(vec![err]).display(false)
@@ -275,12 +337,15 @@ async fn main() {
         }
 
         // Send okay
-        let status: Status = Status {
+        let mut status: Status = Status {
             app_name: AppName::Directive,
             app_status: AppStatus::Running,
             timestamp: current_timestamp(),
             version: Version::get(),
+            message: None,
+            components: Some(components),
         };
+        status.derive_from_components();
 
         if let Err(err) = report_status(status).await {
             ErrorArray::new(vec![err]).display(false)
@@ -1,20 +1,43 @@
+use std::collections::BTreeMap;
+use std::path::Path;
 use std::pin::Pin;
+use std::sync::Arc;
 
-use ais_common::common::{AppName, AppStatus, Status};
+use ais_common::common::{AppName, AppStatus, ComponentStatus, Status};
+use ais_common::constants::{DEPLOY_AUDIT_LOG, ORPHAN_GRACE_PERIOD};
+use ais_common::directive::{load_hooks, run_build_steps};
 use ais_common::git::GitAction;
-use ais_common::git_data::{GitAuth, GitCredentials};
+use ais_common::git_data::{
+    is_auth_failure, is_checkout_conflict, is_untrusted_revision, ConflictPolicy, GitAuth,
+    GitCredentials, GitPin, SignaturePolicy, CHECKOUT_CONFLICT, UNTRUSTED_REVISION,
+};
+use ais_common::git_native::{self, GitUpdate};
+use ais_common::hooks::{run_hooks, DeployHooks, HookStage};
 use ais_common::messages::report_status;
+use ais_common::projects::{
+    migrate_legacy_project, restart_project, ProjectEntry, ProjectRegistry,
+};
+use ais_common::release::ReleaseLayout;
 use ais_common::setcap::{get_id, set_file_ownership, SystemUsers};
 use ais_common::system::current_timestamp;
-use ais_common::systemd::restart_if_exists;
+use ais_common::teardown::{teardown_project, Disposal};
 use ais_common::version::Version;
 use dusa_collection_utils::errors::{ErrorArray, ErrorArrayItem, Errors};
-use dusa_collection_utils::functions::{create_hash, truncate};
+use dusa_collection_utils::functions::truncate;
+use dusa_collection_utils::stringy::Stringy;
 use dusa_collection_utils::types::{ClonePath, PathType};
-use rand::seq::SliceRandom;
-use rand::{rngs::StdRng, SeedableRng};
-use simple_pretty::{notice, warn};
-use tokio::time::{self, Duration};
+use schedule::{Scheduler, MAX_CONCURRENT_SYNCS};
+use simple_pretty::{notice, pass, warn};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::time::{self, Duration, Instant};
+
+mod schedule;
+mod webhook;
+
+// How often the scheduler looks for repositories that are due
+const TICK: Duration = Duration::from_secs(1);
+// Credentials are reloaded this often to pick up added and removed repositories
+const CREDENTIAL_RELOAD: Duration = Duration::from_secs(30);
 
 #[tokio::main]
 async fn main() {
@@ -28,130 +51,765 @@ async fn main() {
         }
     });
 
+    let (trigger, mut triggered) = mpsc::unbounded_channel::<GitAuth>();
+    let (finished, mut finished_syncs) =
+        mpsc::unbounded_channel::<(String, Result<(), ErrorArrayItem>)>();
+    let permits = Arc::new(Semaphore::new(MAX_CONCURRENT_SYNCS));
+    let mut scheduler = Scheduler::new();
+    let mut registry = ProjectRegistry::default();
+    let mut webhooks_started = false;
+    let mut next_reload = Instant::now();
+    let mut tick = time::interval(TICK);
+
     loop {
-        let credentials = match GitCredentials::new().into() {
-            Some(Ok(cred_data)) => cred_data,
-            Some(Err(e)) => {
-                notice("No git credentials loaded");
-                ErrorArray::new(vec![e]).display(false);
-                time::sleep(Duration::from_secs(30)).await;
-                return;
+        tokio::select! {
+            _ = tick.tick() => {}
+            Some(auth) = triggered.recv() => {
+                notice(&format!("Webhook push received for {}", registry.slug(&auth)));
+                scheduler.trigger(&auth.id(), Instant::now());
             }
-            None => unreachable!(),
-        };
+            Some((id, result)) = finished_syncs.recv() => {
+                if let Err(e) = &result {
+                    warn(&format!("Failed to update {}: {}", registry.resolve(&id), e));
+                }
+                scheduler.finish(&id, result, Instant::now());
+                report_components(&registry, scheduler.components()).await;
+            }
+        }
 
-        match git_loop(credentials.clone()).await {
-            Ok(_) => {
-                // Send okay
-                let status: Status = Status {
-                    app_name: AppName::Github,
-                    app_status: AppStatus::Running,
-                    timestamp: current_timestamp(),
-                    version: Version::get(),
-                };
-                if let Err(err) = report_status(status).await {
-                    ErrorArray::new(vec![err]).display(false)
+        let now = Instant::now();
+        if now >= next_reload {
+            next_reload = now + CREDENTIAL_RELOAD;
+            match GitCredentials::new() {
+                Ok(mut credentials) => {
+                    // They would share a project, only the first is monitored
+                    for auth in credentials.deduplicate() {
+                        warn(&format!(
+                            "{}/{}@{} from {} is configured more than once, ignoring it",
+                            auth.user, auth.repo, auth.branch, auth.provider
+                        ));
+                    }
+                    // The listener only runs once a repository has been given a webhook secret
+                    if !webhooks_started
+                        && credentials
+                            .auth_items
+                            .iter()
+                            .any(|auth| auth.webhook_secret().is_some())
+                    {
+                        webhooks_started = start_webhooks(trigger.clone()).await;
+                        scheduler.set_webhooks(webhooks_started);
+                    }
+                    register_projects(&mut registry, &credentials.auth_items).await;
+                    scheduler.update(&credentials.auth_items, now);
+                }
+                Err(e) => {
+                    notice("No git credentials loaded");
+                    ErrorArray::new(vec![e]).display(false);
                 }
             }
-            Err(e) => {
-                ErrorArray::new(vec![e]).display(false);
-                // Set the application status to warning in the aggregator
-                let status: Status = Status {
-                    app_name: AppName::Github,
-                    app_status: AppStatus::Warning,
-                    timestamp: current_timestamp(),
-                    version: Version::get(),
+        }
+
+        for auth in scheduler.take_due(now) {
+            let permits = permits.clone();
+            let finished = finished.clone();
+            let project = registry.get(&auth).cloned();
+            tokio::spawn(async move {
+                // The semaphore is never closed
+                let _permit = permits.acquire_owned().await;
+                let id = auth.id().to_string();
+
+                // A panicking sync must still hand its repository back to the scheduler
+                let result = match tokio::spawn(async move {
+                    match project {
+                        Some(project) => sync_project(&auth, &project).await,
+                        None => Err(ErrorArrayItem::new(
+                            Errors::GeneralError,
+                            String::from("The project registry could not be updated"),
+                        )),
+                    }
+                })
+                .await
+                {
+                    Ok(result) => result,
+                    Err(e) => Err(ErrorArrayItem::new(
+                        Errors::GeneralError,
+                        format!("The sync task failed: {}", e),
+                    )),
                 };
-                if let Err(err) = report_status(status).await {
-                    ErrorArray::new(vec![err]).display(false)
+
+                let _ = finished.send((id, result));
+            });
+        }
+    }
+}
+
+// Start the webhook listener, returns false if it couldn't bind so polling
+// stays at full speed
+async fn start_webhooks(trigger: mpsc::UnboundedSender<GitAuth>) -> bool {
+    match webhook::bind().await {
+        Ok(listener) => {
+            tokio::spawn(async move {
+                if let Err(e) = webhook::serve(listener, trigger).await {
+                    ErrorArray::new(vec![e]).display(false);
                 }
+            });
+            true
+        }
+        Err(e) => {
+            ErrorArray::new(vec![e]).display(false);
+            false
+        }
+    }
+}
+
+// Register new repositories so they get a slug before their first sync, and
+// tear down the projects of repositories removed from the credentials once
+// their grace period is over. The registry is reloaded every time to pick up
+// display names set and teardowns done elsewhere.
+async fn register_projects(registry: &mut ProjectRegistry, auths: &[GitAuth]) {
+    match ProjectRegistry::load() {
+        Ok(loaded) => *registry = loaded,
+        Err(e) => {
+            ErrorArray::new(vec![e]).display(false);
+            return;
+        }
+    }
+
+    let mut changed = false;
+    for auth in auths {
+        changed |= registry.ensure(auth);
+    }
+
+    let now = current_timestamp();
+    for orphan in registry.mark_orphans(auths, now) {
+        warn(&format!(
+            "{} is no longer configured and will be torn down in {} hours unless it is added back.",
+            orphan.slug,
+            ORPHAN_GRACE_PERIOD / 3600
+        ));
+        changed = true;
+    }
+
+    for orphan in registry.expired_orphans(now, ORPHAN_GRACE_PERIOD) {
+        let mut steps = Vec::new();
+        let result = teardown_project(&orphan.slug, Disposal::Archive, &mut steps).await;
+        for step in steps {
+            notice(&format!("Teardown of {}: {}", orphan.slug, step));
+        }
+
+        match result {
+            Ok(()) => {
+                pass(&format!("Tore down {}.", orphan.slug));
+                registry.remove(&orphan.slug);
+                changed = true;
             }
+            // Retried on the next reload
+            Err(e) => warn(&format!("Failed to tear down {}: {}", orphan.slug, e)),
+        }
+    }
+
+    if changed {
+        if let Err(e) = registry.save() {
+            ErrorArray::new(vec![e]).display(false);
         }
+    }
+}
+
+// Report every repository as a component of the monitor's status, so a broken
+// repository shows up by name without hiding the healthy ones
+async fn report_components(
+    registry: &ProjectRegistry,
+    components: BTreeMap<String, ComponentStatus>,
+) {
+    let components = components
+        .into_iter()
+        .map(|(id, component)| (registry.resolve(&id), component))
+        .collect();
+    let mut status: Status = Status {
+        app_name: AppName::Github,
+        app_status: AppStatus::Running,
+        timestamp: current_timestamp(),
+        version: Version::get(),
+        message: None,
+        components: Some(components),
+    };
+    status.derive_from_components();
+
+    if let Err(err) = report_status(status).await {
+        ErrorArray::new(vec![err]).display(false)
+    }
+}
+
+// Update the project's checkout and deploy it as a new release until its head
+// has been deployed in full, so a failed build, hook or restart is retried. A
+// rolled back project stays on its release until the next commit arrives.
+async fn sync_project(auth: &GitAuth, project: &ProjectEntry) -> Result<(), ErrorArrayItem> {
+    let name = &*project.slug;
+    if migrate_legacy_project(project)? {
+        notice(&format!(
+            "Renamed project {} to {}.",
+            project.legacy_id, name
+        ));
+    }
+
+    let layout = &ReleaseLayout::for_project(name);
+    if layout.is_legacy() {
+        notice(&format!("Moving {} to the release layout.", name));
+        layout.migrate_legacy()?;
+    }
 
-        // Sleep between iterations
-        time::sleep(Duration::from_secs(20)).await;
+    let git_project_path = layout.repo_dir();
+
+    if git_project_path.exists() {
+        handle_existing_repo(auth, name, &git_project_path).await?;
+    } else {
+        std::fs::create_dir_all(layout.root())?;
+        handle_new_repo(auth, name, &git_project_path).await?;
+    }
+
+    let head = git_native::head_commit(git_project_path.clone()).await?;
+    if layout.deployed_release()?.as_deref() != Some(&*head) {
+        fetch_extras(auth, name, &git_project_path).await?;
+        deploy_release(auth, name, layout).await?;
     }
+
+    if auth.pin.is_some() {
+        report_pin(auth, name, layout).await?;
+    }
+
+    Ok(())
 }
 
-async fn git_loop(credentials: GitCredentials) -> Result<(), ErrorArrayItem> {
-    // Load Git credentials and shuffle them
-    let mut credentials_shuffled = credentials.clone();
-    let mut rng: StdRng = StdRng::from_entropy(); // Use a seedable RNG that is Send safe
-    credentials_shuffled.auth_items.shuffle(&mut rng);
+// Log the pinned target of a project and whether its checkout and live release match it
+async fn report_pin(
+    auth: &GitAuth,
+    project: &str,
+    layout: &ReleaseLayout,
+) -> Result<(), ErrorArrayItem> {
+    let pin = match &auth.pin {
+        Some(pin) => pin.clone(),
+        None => return Ok(()),
+    };
 
-    for auth in &credentials_shuffled.auth_items {
-        let ac = auth.clone();
-        let git_project_path = generate_git_project_path(&ac);
+    let target = git_native::resolve_pin(layout.repo_dir(), pin.clone()).await?;
+    let checked_out = git_native::head_commit(layout.repo_dir()).await?;
+    let live = layout.current_release();
 
-        if git_project_path.exists() {
-            handle_existing_repo(&ac, &git_project_path).await?;
-        } else {
-            handle_new_repo(&ac, &git_project_path).await?;
+    if *checked_out == *target && live.as_deref() == Some(&*target) {
+        notice(&format!(
+            "{} is pinned to {} ({}) and running it.",
+            project,
+            pin,
+            truncate(&target, 8)
+        ));
+    } else {
+        warn(&format!(
+            "{} is pinned to {} ({}) but has {} checked out and {} live.",
+            project,
+            pin,
+            truncate(&target, 8),
+            truncate(&checked_out, 8),
+            live.as_ref()
+                .map(|commit| truncate(commit, 8).to_owned())
+                .unwrap_or_else(|| String::from("nothing"))
+        ));
+    }
+
+    Ok(())
+}
+
+// Export the checkout's head as a release, build it and switch it live. A failing
+// pre-deploy hook keeps the live release, a failing post-deploy hook restores it.
+// A release still on disk, live or retained, was built when it was first
+// deployed and is switched to as it is.
+async fn deploy_release(
+    auth: &GitAuth,
+    project: &str,
+    layout: &ReleaseLayout,
+) -> Result<(), ErrorArrayItem> {
+    let commit = git_native::head_commit(layout.repo_dir()).await?;
+    // Only its restart failed last time
+    if layout.current_release().as_deref() == Some(&*commit) {
+        restart_service(project).await?;
+        return layout.record(&commit);
+    }
+
+    // Fresh clones and cli pulls reach the checkout unverified
+    verify_revision(auth, project, &layout.repo_dir(), &commit).await?;
+    let (release_dir, fresh) = layout.prepare(&commit).await?;
+    let webuser = get_id(SystemUsers::Www)?;
+    let audit_log = Path::new(DEPLOY_AUDIT_LOG);
+
+    let prepared = async {
+        if fresh {
+            export_extras(auth, layout, &commit, &release_dir).await?;
+            run_build_steps(&release_dir).await?;
+            // Set ownership to the web user, the hooks run as it
+            set_file_ownership(&release_dir, webuser.0, webuser.1)?;
         }
+
+        let hooks = load_hooks(&release_dir).await?;
+        if fresh {
+            run_hooks(
+                project,
+                &commit,
+                HookStage::PreDeploy,
+                &hooks.pre_deploy,
+                &release_dir,
+                webuser,
+                audit_log,
+            )
+            .await?;
+        }
+        Ok::<DeployHooks, ErrorArrayItem>(hooks)
+    }
+    .await;
+
+    let hooks = match prepared {
+        Ok(hooks) => hooks,
+        Err(e) => {
+            // The live release is untouched, a broken new one is rebuilt from
+            // scratch on the next sync
+            if fresh {
+                std::fs::remove_dir_all(&release_dir)?;
+            }
+            return Err(e);
+        }
+    };
+
+    let previous = layout.current_release();
+    layout.activate(&commit)?;
+    notice(&format!(
+        "Release {} is live for {}.",
+        truncate(&commit, 8),
+        project
+    ));
+    restart_service(project).await?;
+
+    if let Err(e) = run_hooks(
+        project,
+        &commit,
+        HookStage::PostDeploy,
+        &hooks.post_deploy,
+        &release_dir,
+        webuser,
+        audit_log,
+    )
+    .await
+    {
+        if let Some(previous) = previous.filter(|previous| **previous != *commit) {
+            layout.activate(&previous)?;
+            if fresh {
+                std::fs::remove_dir_all(&release_dir)?;
+            }
+            warn(&format!(
+                "Restored release {} of {}.",
+                truncate(&previous, 8),
+                project
+            ));
+            restart_service(project).await?;
+        }
+        return Err(e);
+    }
+
+    layout.record(&commit)?;
+    for removed in layout.prune_default()? {
+        notice(&format!(
+            "Removed release {} of {}.",
+            truncate(&removed, 8),
+            project
+        ));
     }
 
     Ok(())
 }
 
-// Generate the path for the git project based on branch, repo, and user
-fn generate_git_project_path(auth: &GitAuth) -> PathType {
-    PathType::Content(format!(
-        "/var/www/ais/{}",
-        truncate(
-            &create_hash(format!("{}-{}-{}", auth.branch, auth.repo, auth.user)),
-            8
-        )
-    ))
+// Check out submodules and fetch LFS objects for repositories that use them,
+// failures are reported like any other fetch failure
+async fn fetch_extras(
+    auth: &GitAuth,
+    project: &str,
+    git_project_path: &PathType,
+) -> Result<(), ErrorArrayItem> {
+    if auth.submodules {
+        git_native::update_submodules(git_project_path.clone_path(), auth.remote_auth()?).await?;
+        notice(&format!("Updated the submodules of {}.", project));
+    }
+
+    if auth.lfs {
+        let lfs_pull = GitAction::LfsPull {
+            destination: git_project_path.clone_path(),
+            auth: auth.remote_auth()?,
+        };
+        lfs_pull.execute().await?;
+        notice(&format!("Fetched the LFS objects of {}.", project));
+    }
+
+    Ok(())
 }
 
-// Handle an existing repo: fetch, pull, set tracking, restart if needed
+// Add what exporting the commit leaves out of a release: the trees of its
+// submodules, and the LFS objects it only has pointer files for
+async fn export_extras(
+    auth: &GitAuth,
+    layout: &ReleaseLayout,
+    commit: &Stringy,
+    release_dir: &PathType,
+) -> Result<(), ErrorArrayItem> {
+    if auth.submodules {
+        git_native::export_submodules(layout.repo_dir(), commit.clone(), release_dir.clone_path())
+            .await?;
+    }
+
+    if auth.lfs {
+        // The checkout is at the released commit, its working tree holds the objects
+        let lfs_files = GitAction::LfsFiles(layout.repo_dir()).execute().await?;
+        if let Some(output) = lfs_files {
+            for file in String::from_utf8_lossy(&output.stdout)
+                .lines()
+                .map(str::trim)
+                .filter(|file| !file.is_empty())
+            {
+                let target = release_dir.join(file);
+                if let Some(parent) = target.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::copy(layout.repo_dir().join(file), target)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Handle an existing repo: fetch, fast-forward and set tracking. Returns true
+// if the checkout moved to a new commit.
 async fn handle_existing_repo(
     auth: &GitAuth,
+    project: &str,
+    git_project_path: &PathType,
+) -> Result<bool, ErrorArrayItem> {
+    match native_update(auth, project, git_project_path).await {
+        Ok(update) => {
+            if update.changed() {
+                notice(&format!(
+                    "Updated {} from {} to {}, {} files changed.",
+                    project,
+                    update
+                        .old_commit
+                        .as_ref()
+                        .map(|commit| truncate(commit, 8).to_owned())
+                        .unwrap_or_else(|| String::from("none")),
+                    truncate(&update.new_commit, 8),
+                    update.changed_files.len()
+                ));
+                git_native::set_tracking(git_project_path.clone_path()).await?;
+            } else {
+                notice(&format!("No new data pulled for {}.", project));
+            }
+            Ok(update.changed())
+        }
+        // The cli would be rejected with the same credentials, only ever
+        // follows the branch head so it can't honour a pin, and would pull
+        // straight over a revision that failed verification. A conflict the
+        // policy refused stays refused, and the cli only fast-forwards so one
+        // that wasn't inspected is never merged either
+        Err(e)
+            if is_auth_failure(&e)
+                || is_checkout_conflict(&e)
+                || is_untrusted_revision(&e)
+                || auth.pin.is_some() =>
+        {
+            Err(e)
+        }
+        Err(e) => {
+            warn(&format!(
+                "Native update failed for {}, falling back to the git cli: {}",
+                project, e
+            ));
+            handle_existing_repo_cli(auth, project, git_project_path).await
+        }
+    }
+}
+
+// Fetch and move to the pinned revision, or fast-forward the configured branch, using libgit2
+async fn native_update(
+    auth: &GitAuth,
+    project: &str,
+    git_project_path: &PathType,
+) -> Result<GitUpdate, ErrorArrayItem> {
+    // The provider may have changed since this checkout was cloned
+    git_native::set_origin(git_project_path.clone_path(), auth.remote_url()).await?;
+    git_native::fetch(git_project_path.clone_path(), auth.remote_auth()?).await?;
+
+    // Checked before anything moves so a refused revision never reaches the checkout
+    if auth.signature_policy != SignaturePolicy::Off {
+        let target = match &auth.pin {
+            Some(pin) => {
+                git_native::resolve_pin(git_project_path.clone_path(), pin.clone()).await?
+            }
+            None => {
+                git_native::upstream_head(git_project_path.clone_path(), auth.branch.clone())
+                    .await?
+            }
+        };
+        verify_revision(auth, project, git_project_path, &target).await?;
+    }
+
+    let reset = resolve_conflicts(auth, project, git_project_path).await?;
+
+    let update = match &auth.pin {
+        Some(pin) => checkout_pin(git_project_path, pin).await?,
+        None => {
+            git_native::fast_forward(git_project_path.clone_path(), auth.branch.clone()).await?
+        }
+    };
+
+    // A reset already moved the branch, the fast-forward after it has nothing to do
+    Ok(match reset {
+        Some(reset) if !update.changed() => reset,
+        _ => update,
+    })
+}
+
+// Apply the repository's conflict policy to local modifications and, for
+// checkouts following a branch, commits the remote no longer has. Returns the
+// update when the branch had to be reset onto the remote.
+async fn resolve_conflicts(
+    auth: &GitAuth,
+    project: &str,
+    git_project_path: &PathType,
+) -> Result<Option<GitUpdate>, ErrorArrayItem> {
+    let branch = match auth.pin {
+        Some(_) => None,
+        None => Some(auth.branch.clone()),
+    };
+    let conflict = git_native::inspect_checkout(git_project_path.clone_path(), branch).await?;
+    if conflict.is_empty() {
+        return Ok(None);
+    }
+
+    if auth.conflict_policy == ConflictPolicy::Refuse {
+        return Err(ErrorArrayItem::new(
+            Errors::Git,
+            format!(
+                "{}: {} has {}, left untouched",
+                CHECKOUT_CONFLICT, project, conflict
+            ),
+        ));
+    }
+
+    if !conflict.modified.is_empty() {
+        match auth.conflict_policy {
+            ConflictPolicy::Stash => {
+                let stash = git_native::stash_changes(
+                    git_project_path.clone_path(),
+                    format!("ais: local changes found at {}", current_timestamp()),
+                )
+                .await?;
+                warn(&format!(
+                    "Stashed {} modified files of {} as {}.",
+                    conflict.modified.len(),
+                    project,
+                    truncate(&stash, 8)
+                ));
+            }
+            _ => {
+                git_native::discard_changes(git_project_path.clone_path()).await?;
+                warn(&format!(
+                    "Discarded {} modified files of {}.",
+                    conflict.modified.len(),
+                    project
+                ));
+            }
+        }
+    }
+
+    if conflict.local_commits == 0 {
+        return Ok(None);
+    }
+
+    let backup = match auth.conflict_policy {
+        ConflictPolicy::Stash => Some(format!("ais/diverged-{}", current_timestamp())),
+        _ => None,
+    };
+    let update = git_native::reset_branch(
+        git_project_path.clone_path(),
+        auth.branch.clone(),
+        backup.clone(),
+    )
+    .await?;
+
+    match backup {
+        Some(backup) => warn(&format!(
+            "Reset {} onto origin/{}, its {} local commits are kept on {}.",
+            project, auth.branch, conflict.local_commits, backup
+        )),
+        None => warn(&format!(
+            "Reset {} onto origin/{}, dropping {} local commits.",
+            project, auth.branch, conflict.local_commits
+        )),
+    }
+
+    Ok(Some(update))
+}
+
+// Refuse `commit` unless it carries the signature the repository's policy asks
+// for from a signer this host trusts
+async fn verify_revision(
+    auth: &GitAuth,
+    project: &str,
     git_project_path: &PathType,
+    commit: &Stringy,
 ) -> Result<(), ErrorArrayItem> {
+    let verified = match auth.signature_policy {
+        SignaturePolicy::Off => return Ok(()),
+        SignaturePolicy::Commit => GitAction::VerifyCommit {
+            destination: git_project_path.clone_path(),
+            commit: commit.clone(),
+        }
+        .execute()
+        .await
+        .map(|_| ()),
+        SignaturePolicy::Tag => verify_tags(git_project_path, commit).await,
+    };
+
+    verified.map_err(|e| {
+        ErrorArrayItem::new(
+            Errors::Git,
+            format!(
+                "{}: refused {} of {}, {} are required: {}",
+                UNTRUSTED_REVISION,
+                truncate(commit, 8),
+                project,
+                auth.signature_policy,
+                e
+            ),
+        )
+    })
+}
+
+// Succeeds if any tag pointing at `commit` has a trusted signature
+async fn verify_tags(git_project_path: &PathType, commit: &Stringy) -> Result<(), ErrorArrayItem> {
+    let tags = git_native::tags_at(git_project_path.clone_path(), commit.clone()).await?;
+    let mut last_error = ErrorArrayItem::new(Errors::Git, String::from("no tag points at it"));
+
+    for tag in tags {
+        let verify = GitAction::VerifyTag {
+            destination: git_project_path.clone_path(),
+            tag: tag.clone(),
+        };
+        match verify.execute().await {
+            Ok(_) => return Ok(()),
+            Err(e) => last_error = ErrorArrayItem::new(Errors::Git, format!("tag {}: {}", tag, e)),
+        }
+    }
+
+    Err(last_error)
+}
+
+// Check out the commit a pin currently resolves to
+async fn checkout_pin(
+    git_project_path: &PathType,
+    pin: &GitPin,
+) -> Result<GitUpdate, ErrorArrayItem> {
+    let target = git_native::resolve_pin(git_project_path.clone_path(), pin.clone()).await?;
+    git_native::checkout_detached(git_project_path.clone_path(), target).await
+}
+
+// Legacy update path that shells out to the git binary
+async fn handle_existing_repo_cli(
+    auth: &GitAuth,
+    project: &str,
+    git_project_path: &PathType,
+) -> Result<bool, ErrorArrayItem> {
     set_safe_directory(git_project_path).await?;
-    fetch_updates(git_project_path).await?;
+    fetch_updates(auth, git_project_path).await?;
 
     let new_data_downloaded = pull_updates(auth, git_project_path).await?;
 
     if new_data_downloaded {
         finalize_git_actions(auth, git_project_path).await?;
     } else {
-        notice(&format!(
-            "No new data pulled for {}.",
-            truncate(
-                &create_hash(format!("{}-{}-{}", auth.branch, auth.repo, auth.user)),
-                8
-            )
-        ));
+        notice(&format!("No new data pulled for {}.", project));
     }
 
-    Ok(())
+    Ok(new_data_downloaded)
 }
 
 // Handle a new repo by cloning and setting up safe directories
 async fn handle_new_repo(
     auth: &GitAuth,
+    project: &str,
     git_project_path: &PathType,
 ) -> Result<(), ErrorArrayItem> {
-    // Clone the repository
+    match git_native::clone_repo(
+        auth.remote_url(),
+        auth.branch.clone(),
+        git_project_path.clone_path(),
+        auth.remote_auth()?,
+    )
+    .await
+    {
+        Ok(update) => {
+            notice(&format!(
+                "Cloned {} at {}.",
+                project,
+                truncate(&update.new_commit, 8)
+            ));
+            git_native::set_tracking(git_project_path.clone_path()).await?;
+
+            if let Some(pin) = &auth.pin {
+                let update = checkout_pin(git_project_path, pin).await?;
+                notice(&format!(
+                    "Checked out {} ({}) for {}.",
+                    pin,
+                    truncate(&update.new_commit, 8),
+                    project
+                ));
+            }
+        }
+        Err(e) => {
+            // A failed clone can leave a partial checkout behind
+            if git_project_path.exists() {
+                std::fs::remove_dir_all(git_project_path)?;
+            }
+
+            // The cli would be rejected with the same credentials and can't
+            // check out a pin
+            if is_auth_failure(&e) || auth.pin.is_some() {
+                return Err(e);
+            }
+
+            warn(&format!(
+                "Native clone failed for {}, falling back to the git cli: {}",
+                project, e
+            ));
+            clone_repo_cli(auth, git_project_path).await?;
+        }
+    }
+
+    // Set safe directory so the cli fallback keeps working on this checkout
+    set_safe_directory(git_project_path).await?;
+
+    Ok(())
+}
+
+// Legacy clone path that shells out to the git binary
+async fn clone_repo_cli(auth: &GitAuth, git_project_path: &PathType) -> Result<(), ErrorArrayItem> {
     let git_clone = GitAction::Clone {
-        repo_name: auth.clone().repo,
-        repo_owner: auth.clone().user,
+        repo_url: auth.remote_url(),
         destination: git_project_path.clone_path(),
         repo_branch: auth.clone().branch,
+        auth: auth.remote_auth()?,
     };
     git_clone.execute().await?;
 
-    // Set ownership to the web user
-    let webuser = get_id(SystemUsers::Www)?;
-    set_file_ownership(&git_project_path, webuser.0, webuser.1)?;
-
     // Set safe directory
     set_safe_directory(git_project_path).await?;
 
     // Force switch to the correct branch after cloning
-    fetch_updates(git_project_path).await?;
+    fetch_updates(auth, git_project_path).await?;
 
     Ok(())
 }
@@ -165,30 +823,30 @@ async fn set_safe_directory(git_project_path: &PathType) -> Result<(), ErrorArra
 }
 
 // Fetch updates from the remote repository
-async fn fetch_updates(git_project_path: &PathType) -> Result<(), ErrorArrayItem> {
+async fn fetch_updates(auth: &GitAuth, git_project_path: &PathType) -> Result<(), ErrorArrayItem> {
     let fetch_update = GitAction::Fetch {
         destination: git_project_path.clone_path(),
+        auth: auth.remote_auth()?,
     };
     fetch_update.execute().await?;
 
     Ok(())
 }
 
-// Finalize git actions: set tracking, switch branch, restart service
+// Finalize git actions: set tracking, switch branch
 async fn finalize_git_actions(
     auth: &GitAuth,
     git_project_path: &PathType,
 ) -> Result<(), ErrorArrayItem> {
-    set_tracking(git_project_path).await?;
+    set_tracking(auth, git_project_path).await?;
     switch_branch(auth, git_project_path).await?;
-    restart_service(auth).await?;
 
     Ok(())
 }
 
 // Set the tracking branch
-async fn set_tracking(git_project_path: &PathType) -> Result<(), ErrorArrayItem> {
-    let git_set_tracking = GitAction::SetTrack(git_project_path.clone_path());
+async fn set_tracking(auth: &GitAuth, git_project_path: &PathType) -> Result<(), ErrorArrayItem> {
+    let git_set_tracking = GitAction::SetTrack(git_project_path.clone_path(), auth.remote_auth()?);
     git_set_tracking.execute().await?;
 
     Ok(())
@@ -205,11 +863,9 @@ async fn switch_branch(auth: &GitAuth, git_project_path: &PathType) -> Result<()
     Ok(())
 }
 
-// Restart the service using the hash
-async fn restart_service(auth: &GitAuth) -> Result<(), ErrorArrayItem> {
-    let data = create_hash(format!("{}-{}-{}", auth.branch, auth.repo, auth.user));
-    let service_name = truncate(&data, 8);
-    restart_if_exists(service_name.to_owned())?;
+// Restart the project's service, which the directive names after its slug
+async fn restart_service(service_name: &str) -> Result<(), ErrorArrayItem> {
+    restart_project(service_name)?;
     notice(&format!("Service restarted: {}.", service_name));
 
     Ok(())
@@ -220,6 +876,7 @@ async fn pull_updates(auth: &GitAuth, git_project_path: &PathType) -> Result<boo
     let pull_update = GitAction::Pull {
         target_branch: auth.branch.clone(),
         destination: git_project_path.clone_path(),
+        auth: auth.remote_auth()?,
     };
 
     match pull_update.execute().await {
@@ -237,13 +894,12 @@ async fn pull_updates(auth: &GitAuth, git_project_path: &PathType) -> Result<boo
             }
         }
         Err(e) => {
-            if e.err_type == Errors::GeneralError {
-                warn("non-critical errors occurred");
-                Ok(true) // Assume new data was pulled in case of non-critical error
-            } else if e.to_string().contains("safe directory") {
+            // A failed pull leaves the checkout in an unknown state, so it is
+            // reported rather than deployed
+            if e.to_string().contains("safe directory") {
                 // Handle "safe directory" error by boxing recursive calls
                 set_safe_directory(git_project_path).await?;
-                fetch_updates(git_project_path).await?;
+                fetch_updates(auth, git_project_path).await?;
 
                 // Recursively call pull_updates inside a Box to avoid infinite future size
                 Pin::from(Box::new(pull_updates(auth, git_project_path))).await
@@ -253,4 +909,3 @@ async fn pull_updates(auth: &GitAuth, git_project_path: &PathType) -> Result<boo
         }
     }
 }
-
@@ -0,0 +1,264 @@
+// Per repository scheduling for the git monitor. Every repository is polled on
+// its own interval with some jitter so hosts don't hit a forge in lockstep, and
+// a failing repository backs off on its own without delaying the others.
+
+use std::collections::{BTreeMap, HashMap};
+
+use ais_common::common::{AppStatus, ComponentStatus};
+use ais_common::git_data::{is_auth_failure, is_untrusted_revision, GitAuth};
+use ais_common::system::current_timestamp;
+use dusa_collection_utils::errors::ErrorArrayItem;
+use dusa_collection_utils::stringy::Stringy;
+use rand::Rng;
+use tokio::time::{Duration, Instant};
+
+/// Poll interval for repositories that don't set their own.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(20);
+/// Default interval for repositories that receive webhooks, polling only
+/// catches deliveries that went missing.
+pub const WEBHOOK_INTERVAL: Duration = Duration::from_secs(300);
+/// Failing repositories are retried at least this often.
+pub const MAX_BACKOFF: Duration = Duration::from_secs(3600);
+/// Repositories synced at the same time.
+pub const MAX_CONCURRENT_SYNCS: usize = 4;
+
+/// Runs are delayed by up to this share of their interval.
+const JITTER_DIVISOR: u32 = 10;
+
+struct Entry {
+    auth: GitAuth,
+    next_run: Instant,
+    failures: u32,
+    running: bool,
+    /// Set when a webhook arrives mid-run so the push isn't missed.
+    rerun: bool,
+    last_error: Option<ErrorArrayItem>,
+    /// Unix timestamp of the last finished run.
+    last_finished: Option<u64>,
+}
+
+pub struct Scheduler {
+    entries: HashMap<String, Entry>,
+    webhooks: bool,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            webhooks: false,
+        }
+    }
+
+    /// Tells the scheduler the webhook listener is running, which lengthens
+    /// the default interval of repositories with a webhook secret.
+    pub fn set_webhooks(&mut self, enabled: bool) {
+        self.webhooks = enabled;
+    }
+
+    /// Starts scheduling new repositories, due straight away, and forgets
+    /// removed ones. Changed settings apply from the next run.
+    pub fn update(&mut self, auths: &[GitAuth], now: Instant) {
+        self.entries
+            .retain(|id, _| auths.iter().any(|auth| *auth.id() == **id));
+
+        for auth in auths {
+            match self.entries.get_mut(&*auth.id()) {
+                Some(entry) => entry.auth = auth.clone(),
+                None => {
+                    self.entries.insert(
+                        auth.id().to_string(),
+                        Entry {
+                            auth: auth.clone(),
+                            next_run: now,
+                            failures: 0,
+                            running: false,
+                            rerun: false,
+                            last_error: None,
+                            last_finished: None,
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    /// Returns the repositories due at `now` and marks them as running.
+    pub fn take_due(&mut self, now: Instant) -> Vec<GitAuth> {
+        self.entries
+            .values_mut()
+            .filter(|entry| !entry.running && entry.next_run <= now)
+            .map(|entry| {
+                entry.running = true;
+                entry.auth.clone()
+            })
+            .collect()
+    }
+
+    /// Makes a repository due now, or right after its current run.
+    pub fn trigger(&mut self, id: &str, now: Instant) {
+        if let Some(entry) = self.entries.get_mut(id) {
+            match entry.running {
+                true => entry.rerun = true,
+                false => entry.next_run = now,
+            }
+        }
+    }
+
+    /// Records the outcome of a run and schedules the next one.
+    pub fn finish(&mut self, id: &str, result: Result<(), ErrorArrayItem>, now: Instant) {
+        let webhooks = self.webhooks;
+        let entry = match self.entries.get_mut(id) {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        let interval = interval_for(&entry.auth, webhooks);
+        let delay = match result {
+            Ok(()) => {
+                entry.failures = 0;
+                entry.last_error = None;
+                interval + jitter(interval)
+            }
+            Err(e) => {
+                entry.failures += 1;
+                entry.last_error = Some(e);
+                backoff(interval, entry.failures)
+            }
+        };
+
+        entry.running = false;
+        entry.last_finished = Some(current_timestamp());
+        entry.next_run = match std::mem::take(&mut entry.rerun) {
+            true => now,
+            false => now + delay,
+        };
+    }
+
+    /// The status of every repository that has finished a run, keyed by id.
+    pub fn components(&self) -> BTreeMap<String, ComponentStatus> {
+        self.entries
+            .iter()
+            .filter_map(|(id, entry)| {
+                let timestamp = entry.last_finished?;
+                let (app_status, message) = match &entry.last_error {
+                    None => (AppStatus::Running, None),
+                    // Rejected credentials need an operator, so they get their own status
+                    Some(e) if is_auth_failure(e) => {
+                        (AppStatus::AuthFailure, Some(Stringy::from(e.to_string())))
+                    }
+                    Some(e) if is_untrusted_revision(e) => {
+                        (AppStatus::Untrusted, Some(Stringy::from(e.to_string())))
+                    }
+                    Some(e) => (AppStatus::Warning, Some(Stringy::from(e.to_string()))),
+                };
+
+                Some((
+                    id.clone(),
+                    ComponentStatus {
+                        app_status,
+                        timestamp,
+                        message,
+                    },
+                ))
+            })
+            .collect()
+    }
+}
+
+/// The interval a repository is polled at when it is healthy.
+pub fn interval_for(auth: &GitAuth, webhooks: bool) -> Duration {
+    match auth.poll_interval {
+        Some(seconds) => Duration::from_secs(seconds),
+        None if webhooks && auth.webhook_secret().is_some() => WEBHOOK_INTERVAL,
+        None => DEFAULT_INTERVAL,
+    }
+}
+
+/// Doubles the interval for every consecutive failure, up to `MAX_BACKOFF`.
+pub fn backoff(interval: Duration, failures: u32) -> Duration {
+    let factor = 2u32.saturating_pow(failures.min(16));
+    interval
+        .saturating_mul(factor)
+        .min(MAX_BACKOFF.max(interval))
+}
+
+fn jitter(interval: Duration) -> Duration {
+    let max = interval / JITTER_DIVISOR;
+    if max.is_zero() {
+        return max;
+    }
+    rand::thread_rng().gen_range(Duration::ZERO..max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dusa_collection_utils::errors::Errors;
+
+    fn auth(repo: &str, poll_interval: Option<u64>) -> GitAuth {
+        let mut auth = GitAuth::new("owner", repo, "main");
+        auth.poll_interval = poll_interval;
+        auth
+    }
+
+    #[test]
+    fn test_backoff() {
+        let interval = Duration::from_secs(20);
+        assert_eq!(backoff(interval, 1), Duration::from_secs(40));
+        assert_eq!(backoff(interval, 3), Duration::from_secs(160));
+        assert_eq!(backoff(interval, 40), MAX_BACKOFF);
+        // Intervals longer than the cap are never shortened
+        assert_eq!(
+            backoff(Duration::from_secs(7200), 2),
+            Duration::from_secs(7200)
+        );
+    }
+
+    #[test]
+    fn test_failures_are_isolated() {
+        let now = Instant::now();
+        let mut scheduler = Scheduler::new();
+        let healthy = auth("healthy", Some(60));
+        let broken = auth("broken", Some(60));
+        scheduler.update(&[healthy.clone(), broken.clone()], now);
+
+        assert_eq!(scheduler.take_due(now).len(), 2);
+        assert!(scheduler.take_due(now).is_empty());
+
+        scheduler.finish(&healthy.id(), Ok(()), now);
+        scheduler.finish(
+            &broken.id(),
+            Err(ErrorArrayItem::new(Errors::Git, String::from("gone"))),
+            now,
+        );
+
+        // Healthy runs again within its interval plus jitter, broken backs off
+        let due = scheduler.take_due(now + Duration::from_secs(66));
+        assert_eq!(due.len(), 1);
+        assert_eq!(&*due[0].repo, "healthy");
+        assert_eq!(
+            scheduler
+                .take_due(now + Duration::from_secs(120))
+                .iter()
+                .map(|auth| auth.repo.to_string())
+                .collect::<Vec<_>>(),
+            vec![String::from("broken")]
+        );
+        let components = scheduler.components();
+        assert_eq!(components[&*healthy.id()].app_status, AppStatus::Running);
+        assert_eq!(components[&*broken.id()].app_status, AppStatus::Warning);
+
+        // A webhook during a run queues another one straight after it
+        scheduler.trigger(&broken.id(), now);
+        scheduler.finish(&broken.id(), Ok(()), now + Duration::from_secs(121));
+        assert_eq!(scheduler.take_due(now + Duration::from_secs(121)).len(), 1);
+        assert_eq!(
+            scheduler.components()[&*broken.id()].app_status,
+            AppStatus::Running
+        );
+
+        scheduler.update(&[broken], now);
+        assert_eq!(scheduler.entries.len(), 1);
+    }
+}
@@ -0,0 +1,426 @@
+// Receiver for GitHub and Gitea style push webhooks. A verified push for a
+// monitored repository is handed to the monitor loop to be synced right away,
+// polling stays on at a slower interval to catch deliveries that never arrive.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use ais_common::constants::{ARTISANCF, WEBHOOK_ADDRESS};
+use ais_common::git_data::{GitAuth, GitCredentials};
+use dusa_collection_utils::errors::{ErrorArray, ErrorArrayItem, Errors};
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+use simple_pretty::{notice, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc::UnboundedSender, Mutex};
+use tokio::time::{timeout, Duration};
+
+const MAX_HEADER_SIZE: usize = 16 * 1024;
+const MAX_BODY_SIZE: usize = 5 * 1024 * 1024;
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+struct Request {
+    method: String,
+    /// Header names are lowercased.
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+/// The decrypted credentials, loaded again only once their file changed so
+/// new secrets apply without a restart.
+struct CredentialCache {
+    path: PathBuf,
+    load: fn() -> Result<Vec<GitAuth>, ErrorArrayItem>,
+    /// The file's modification time and length when it was last loaded.
+    cached: Mutex<Option<((SystemTime, u64), Arc<Vec<GitAuth>>)>>,
+}
+
+impl CredentialCache {
+    fn new(path: impl Into<PathBuf>, load: fn() -> Result<Vec<GitAuth>, ErrorArrayItem>) -> Self {
+        Self {
+            path: path.into(),
+            load,
+            cached: Mutex::new(None),
+        }
+    }
+
+    async fn get(&self) -> Result<Arc<Vec<GitAuth>>, ErrorArrayItem> {
+        let metadata = fs::metadata(&self.path)?;
+        let stamp = (metadata.modified()?, metadata.len());
+
+        let mut cached = self.cached.lock().await;
+        match cached.as_ref() {
+            Some((loaded, credentials)) if *loaded == stamp => Ok(credentials.clone()),
+            _ => {
+                let credentials = Arc::new((self.load)()?);
+                *cached = Some((stamp, credentials.clone()));
+                Ok(credentials)
+            }
+        }
+    }
+}
+
+pub async fn bind() -> Result<TcpListener, ErrorArrayItem> {
+    let listener = TcpListener::bind(WEBHOOK_ADDRESS).await?;
+    notice(&format!("Listening for webhooks on {}", WEBHOOK_ADDRESS));
+    Ok(listener)
+}
+
+/// Accepts webhook deliveries and sends every repository a verified push
+/// applies to down `trigger`.
+pub async fn serve(
+    listener: TcpListener,
+    trigger: UnboundedSender<GitAuth>,
+) -> Result<(), ErrorArrayItem> {
+    let credentials = Arc::new(CredentialCache::new(ARTISANCF, GitCredentials::new_vec));
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let trigger = trigger.clone();
+        let credentials = credentials.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, trigger, &credentials).await {
+                ErrorArray::new(vec![e]).display(false);
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    trigger: UnboundedSender<GitAuth>,
+    credentials: &CredentialCache,
+) -> Result<(), ErrorArrayItem> {
+    let status = match timeout(READ_TIMEOUT, read_request(&mut stream)).await {
+        Ok(Ok(request)) => match precheck(&request) {
+            Some(status) => status,
+            None => {
+                let credentials = credentials.get().await?;
+                let (status, triggered) = handle_request(&request, &credentials);
+                for auth in triggered {
+                    if trigger.send(auth).is_err() {
+                        return Err(ErrorArrayItem::new(
+                            Errors::GeneralError,
+                            String::from("The git monitor stopped accepting webhooks"),
+                        ));
+                    }
+                }
+                status
+            }
+        },
+        Ok(Err(status)) => status,
+        Err(_) => 408,
+    };
+
+    if status == 401 {
+        warn("Rejected a webhook without a valid signature");
+    }
+
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status,
+        reason(status)
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+/// Reads a single request, failing with the status to answer with.
+async fn read_request(stream: &mut TcpStream) -> Result<Request, u16> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+
+    let header_end = loop {
+        if let Some(position) = find_header_end(&buffer) {
+            break position;
+        }
+        if buffer.len() > MAX_HEADER_SIZE {
+            return Err(431);
+        }
+
+        let read = stream.read(&mut chunk).await.map_err(|_| 400u16)?;
+        if read == 0 {
+            return Err(400);
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    };
+
+    let mut request = parse_head(&buffer[..header_end])?;
+    let length: usize = match request.headers.get("content-length") {
+        Some(length) => length.parse().map_err(|_| 400u16)?,
+        None => return Err(411),
+    };
+    if length > MAX_BODY_SIZE {
+        return Err(413);
+    }
+
+    let mut body = buffer.split_off(header_end + 4);
+    while body.len() < length {
+        let read = stream.read(&mut chunk).await.map_err(|_| 400u16)?;
+        if read == 0 {
+            return Err(400);
+        }
+        body.extend_from_slice(&chunk[..read]);
+    }
+    body.truncate(length);
+
+    request.body = body;
+    Ok(request)
+}
+
+fn find_header_end(buffer: &[u8]) -> Option<usize> {
+    buffer.windows(4).position(|window| window == b"\r\n\r\n")
+}
+
+fn parse_head(head: &[u8]) -> Result<Request, u16> {
+    let head = std::str::from_utf8(head).map_err(|_| 400u16)?;
+    let mut lines = head.split("\r\n");
+
+    let method = lines
+        .next()
+        .and_then(|line| line.split_whitespace().next())
+        .ok_or(400u16)?
+        .to_owned();
+
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_owned()))
+        .collect();
+
+    Ok(Request {
+        method,
+        headers,
+        body: Vec::new(),
+    })
+}
+
+/// The response to a delivery that can be refused without the credentials,
+/// so unsigned requests never cause a decryption.
+fn precheck(request: &Request) -> Option<u16> {
+    if request.method != "POST" {
+        return Some(405);
+    }
+    match signature(request) {
+        Some(_) => None,
+        None => Some(401),
+    }
+}
+
+fn signature(request: &Request) -> Option<&String> {
+    request
+        .headers
+        .get("x-hub-signature-256")
+        .or_else(|| request.headers.get("x-gitea-signature"))
+}
+
+/// Decides the response to a delivery and the repositories it should sync.
+fn handle_request(request: &Request, credentials: &[GitAuth]) -> (u16, Vec<GitAuth>) {
+    let signature = match (precheck(request), signature(request)) {
+        (None, Some(signature)) => signature,
+        (status, _) => return (status.unwrap_or(401), Vec::new()),
+    };
+
+    let payload: Value = match serde_json::from_slice(&request.body) {
+        Ok(payload) => payload,
+        Err(_) => return (400, Vec::new()),
+    };
+    let full_name = match payload["repository"]["full_name"].as_str() {
+        Some(full_name) => full_name,
+        None => return (400, Vec::new()),
+    };
+
+    let known: Vec<&GitAuth> = credentials
+        .iter()
+        .filter(|auth| auth.webhook_secret().is_some())
+        .filter(|auth| full_name.eq_ignore_ascii_case(&format!("{}/{}", auth.user, auth.repo)))
+        .collect();
+    if known.is_empty() {
+        return (404, Vec::new());
+    }
+
+    // Branches of one repository may be configured with different secrets
+    let verified: Vec<&GitAuth> = known
+        .into_iter()
+        .filter(|auth| match auth.webhook_secret() {
+            Some(secret) => verify_signature(secret, &request.body, signature),
+            None => false,
+        })
+        .collect();
+    if verified.is_empty() {
+        return (401, Vec::new());
+    }
+
+    let event = request
+        .headers
+        .get("x-github-event")
+        .or_else(|| request.headers.get("x-gitea-event"))
+        .map(String::as_str)
+        .unwrap_or("push");
+    if event != "push" {
+        return (200, Vec::new());
+    }
+
+    let git_ref = payload["ref"].as_str().unwrap_or_default();
+    let triggered: Vec<GitAuth> = verified
+        .into_iter()
+        .filter(|auth| push_applies(auth, git_ref))
+        .cloned()
+        .collect();
+
+    match triggered.is_empty() {
+        true => (200, triggered),
+        false => (202, triggered),
+    }
+}
+
+/// True if a push to `git_ref` can change what `auth` deploys. Pinned
+/// repositories follow tags, the rest their branch.
+fn push_applies(auth: &GitAuth, git_ref: &str) -> bool {
+    match &auth.pin {
+        Some(_) => {
+            git_ref.starts_with("refs/tags/") || git_ref == format!("refs/heads/{}", auth.branch)
+        }
+        None => git_ref == format!("refs/heads/{}", auth.branch),
+    }
+}
+
+/// Checks a hex encoded HMAC-SHA256 of `body`, with or without the `sha256=`
+/// prefix GitHub adds. The comparison is constant time.
+fn verify_signature(secret: &str, body: &[u8], signature: &str) -> bool {
+    let signature = signature.trim();
+    let signature = signature.strip_prefix("sha256=").unwrap_or(signature);
+    let signature = match hex::decode(signature) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+
+    let mut mac = match Hmac::<Sha256>::new_from_slice(secret.as_bytes()) {
+        Ok(mac) => mac,
+        Err(_) => return false,
+    };
+    mac.update(body);
+    mac.verify_slice(&signature).is_ok()
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ais_common::git_data::GitPin;
+    use dusa_collection_utils::stringy::Stringy;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tempfile::NamedTempFile;
+
+    fn auth(branch: &str, secret: &str) -> GitAuth {
+        let mut auth = GitAuth::new("owner", "site", branch);
+        auth.webhook_secret = Some(Stringy::new(secret));
+        auth
+    }
+
+    fn push(git_ref: &str, secret: &str) -> Request {
+        let body = format!(
+            r#"{{"ref":"{}","repository":{{"full_name":"Owner/site"}}}}"#,
+            git_ref
+        );
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body.as_bytes());
+
+        let mut request = parse_head(
+            format!(
+                "POST /webhook HTTP/1.1\r\nX-GitHub-Event: push\r\nX-Hub-Signature-256: sha256={}",
+                hex::encode(mac.finalize().into_bytes())
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        request.body = body.into_bytes();
+        request
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        // The example from GitHub's webhook documentation
+        let secret = "It's a Secret to Everybody";
+        let body = b"Hello, World!";
+        let signature = "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17";
+
+        assert!(verify_signature(secret, body, signature));
+        assert!(verify_signature(secret, body, &signature[7..]));
+        assert!(!verify_signature("wrong", body, signature));
+        assert!(!verify_signature(secret, b"Hello, World?", signature));
+        assert!(!verify_signature(secret, body, "sha256=zz"));
+    }
+
+    #[test]
+    fn test_handle_request() {
+        let credentials = vec![auth("main", "one"), auth("staging", "two")];
+
+        let (status, triggered) = handle_request(&push("refs/heads/main", "one"), &credentials);
+        assert_eq!(status, 202);
+        assert_eq!(triggered.len(), 1);
+        assert_eq!(&*triggered[0].branch, "main");
+
+        // Signed with the main branch's secret, so staging is not touched
+        let (status, triggered) = handle_request(&push("refs/heads/staging", "one"), &credentials);
+        assert_eq!(status, 200);
+        assert!(triggered.is_empty());
+
+        assert_eq!(
+            handle_request(&push("refs/heads/main", "wrong"), &credentials).0,
+            401
+        );
+
+        let mut unsigned = push("refs/heads/main", "one");
+        unsigned.headers.remove("x-hub-signature-256");
+        assert_eq!(precheck(&unsigned), Some(401));
+        assert_eq!(precheck(&push("refs/heads/main", "one")), None);
+
+        let mut pinned = auth("main", "one");
+        pinned.pin = Some(GitPin::TagPattern(Stringy::new("v*")));
+        assert!(push_applies(&pinned, "refs/tags/v1.0.0"));
+        assert!(!push_applies(&credentials[0], "refs/tags/v1.0.0"));
+    }
+
+    #[tokio::test]
+    async fn test_credentials_are_reloaded_when_changed() {
+        static LOADS: AtomicUsize = AtomicUsize::new(0);
+        fn load() -> Result<Vec<GitAuth>, ErrorArrayItem> {
+            LOADS.fetch_add(1, Ordering::SeqCst);
+            Ok(vec![auth("main", "one")])
+        }
+
+        let file = NamedTempFile::new().unwrap();
+        fs::write(file.path(), "sealed").unwrap();
+        let cache = CredentialCache::new(file.path(), load);
+
+        assert_eq!(cache.get().await.unwrap().len(), 1);
+        cache.get().await.unwrap();
+        assert_eq!(LOADS.load(Ordering::SeqCst), 1);
+
+        let changed = SystemTime::now() + Duration::from_secs(60);
+        file.as_file().set_modified(changed).unwrap();
+        cache.get().await.unwrap();
+        assert_eq!(LOADS.load(Ordering::SeqCst), 2);
+    }
+}
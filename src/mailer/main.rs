@@ -1,4 +1,4 @@
-use ais_common::dusa_wrapper::decrypt_text;
+use ais_common::secrets::decrypt_text;
 use ais_common::mailing::Email;
 use dusa_collection_utils::errors::{ErrorArray, ErrorArrayItem, Errors};
 use dusa_collection_utils::functions::{create_hash, truncate};
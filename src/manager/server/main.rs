@@ -2,12 +2,17 @@
 use ais_common::common::{
     AppName, GeneralMessage, MessageType, QueryMessage, QueryResponse, QueryType, Status,
 };
-use ais_common::constants::ARTISANCF;
+use ais_common::constants::{ARTISANCF, PROJECT_BASE_DIR};
+use ais_common::deploy_keys::DeployKey;
+use ais_common::directive::{project_id, scan_directories, validate_directive};
+use ais_common::directive_schema::DirectiveReport;
 use ais_common::git_data::{GitAuth, GitCredentials};
 use ais_common::mailing::{Email, EmailSecure};
 use ais_common::messages::{receive_message, send_message};
+use ais_common::remote_check::{check_remotes, RemoteCheck};
 use ais_common::socket::get_socket_path;
 use ais_common::system::{get_machine_id, get_system_stats};
+use ais_common::systemd::ProcessInfo;
 use ais_common::version::Version;
 use dusa_collection_utils::errors::{ErrorArray, ErrorArrayItem, WarningArray};
 use dusa_collection_utils::stringy::Stringy;
@@ -109,17 +114,95 @@ async fn query_aggregator() -> Result<HashMap<AppName, Status>, ErrorArrayItem>
     }
 }
 
+async fn query_services() -> Result<Vec<ProcessInfo>, ErrorArrayItem> {
+    let throw_away_array_warning = WarningArray::new_container();
+    let throw_away_array_error = ErrorArray::new_container();
+    let socket_path_result =
+        get_socket_path(false, throw_away_array_error, throw_away_array_warning).uf_unwrap();
+    let socket_path = match socket_path_result {
+        Ok(d) => d.strip(),
+        Err(mut e) => return Err(e.pop()),
+    };
+
+    let mut stream: UnixStream = UnixStream::connect(socket_path)
+        .await
+        .map_err(|e| ErrorArrayItem::from(e))?;
+
+    let query_message = QueryMessage {
+        query_type: QueryType::Services,
+        app_name: None,
+    };
+
+    let general_message = GeneralMessage {
+        version: Version::get(),
+        msg_type: MessageType::Query,
+        payload: serde_json::to_value(&query_message)?,
+        error: None,
+    };
+
+    send_message(&mut stream, &general_message).await?;
+    let response_message = receive_message(&mut stream).await?;
+
+    if response_message.msg_type == MessageType::Query {
+        let response: QueryResponse = serde_json::from_value(response_message.payload)?;
+        response.services.ok_or(ErrorArrayItem::new(
+            dusa_collection_utils::errors::Errors::GeneralError,
+            String::from("No services returned"),
+        ))
+    } else {
+        Err(ErrorArrayItem::new(
+            dusa_collection_utils::errors::Errors::GeneralError,
+            String::from("Unexpected message"),
+        ))
+    }
+}
+
 // TODO Implement a fall back function that will use systemd and logs.
 // TODO to determine the status of the system if the aggregator fails
 
-async fn update_git_config(new_auth: Vec<GitAuth>) -> Result<(), ErrorArrayItem> {
+/// Checks the new configuration against its remotes and saves it if every
+/// check passed. A dry run only checks.
+async fn update_git_config(
+    new_auth: Vec<GitAuth>,
+    dry_run: bool,
+) -> Result<Vec<RemoteCheck>, ErrorArrayItem> {
     let mut new_git_data = GitCredentials { auth_items: vec![] };
+    let current: Vec<GitAuth> = GitCredentials::new_vec().unwrap_or_default();
+
+    for mut git_item in new_auth {
+        // Clients only ever see masked secrets, keep the stored ones unless replaced
+        if let Some(existing) = current.iter().find(|existing| {
+            existing.user == git_item.user
+                && existing.repo == git_item.repo
+                && existing.branch == git_item.branch
+        }) {
+            if git_item.access_token().is_none() {
+                git_item.token = existing.token.clone();
+            }
+            if git_item.webhook_secret.is_some() && git_item.webhook_secret().is_none() {
+                git_item.webhook_secret = existing.webhook_secret.clone();
+            }
+        }
+        new_git_data.add_auth(git_item)?;
+    }
 
-    for git_item in new_auth {
-        new_git_data.add_auth(git_item);
+    let checks = check_remotes(&new_git_data.auth_items).await;
+    if !dry_run && checks.iter().all(|check| check.passed()) {
+        new_git_data.save(ARTISANCF)?;
     }
+    Ok(checks)
+}
 
-    new_git_data.save(ARTISANCF)
+async fn query_deploy_keys() -> Result<HashMap<Stringy, Stringy>, ErrorArrayItem> {
+    let git_credentials: Vec<GitAuth> = GitCredentials::new_vec()?;
+    let mut key_hashmap: HashMap<Stringy, Stringy> = HashMap::new();
+
+    for git_item in git_credentials {
+        let public_key = DeployKey::public_key(&git_item)?;
+        key_hashmap.insert(git_item.repo.clone(), public_key);
+    }
+
+    Ok(key_hashmap)
 }
 
 async fn query_git_config() -> Result<HashMap<Stringy, GitAuth>, ErrorArrayItem> {
@@ -128,8 +211,22 @@ async fn query_git_config() -> Result<HashMap<Stringy, GitAuth>, ErrorArrayItem>
 
     for git_item in git_credentials {
         let name = git_item.clone().repo;
-        git_hashmap.insert(name, git_item);
+        // Tokens never leave this machine
+        git_hashmap.insert(name, git_item.masked());
     }
 
     Ok(git_hashmap)
 }
+
+/// Validates the directive of every deployed project, keyed by project id.
+async fn query_directives() -> Result<HashMap<String, DirectiveReport>, ErrorArrayItem> {
+    let mut reports: HashMap<String, DirectiveReport> = HashMap::new();
+
+    for path in scan_directories(PROJECT_BASE_DIR).await? {
+        let (_, report) = validate_directive(&path).await?;
+        let name = project_id(&path).unwrap_or_else(|| report.path.clone());
+        reports.insert(name, report);
+    }
+
+    Ok(reports)
+}
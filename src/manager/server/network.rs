@@ -1,6 +1,10 @@
 use ais_common::constants::SERVERADDRESS;
 use ais_common::manager::{NetworkRequest, NetworkRequestType, NetworkResponse};
+use ais_common::project_env::{reload_project, ProjectEnv, ProjectEnvUpdate};
+use ais_common::projects::resolve_project;
+use ais_common::release::{rollback_project, ReleaseLayout, RollbackRequest};
 use ais_common::system::get_system_stats;
+use ais_common::systemd::Services;
 use dusa_collection_utils::errors::ErrorArrayItem;
 use dusa_collection_utils::stringy::Stringy;
 use systemctl::Unit;
@@ -8,7 +12,10 @@ use systemctl::Unit;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpListener;
 
-use crate::{query_aggregator, query_git_config, update_git_config};
+use crate::{
+    query_aggregator, query_deploy_keys, query_directives, query_git_config, query_services,
+    update_git_config,
+};
 
 #[allow(unreachable_patterns)]
 pub async fn start_server() -> Result<(), ErrorArrayItem> {
@@ -58,54 +65,52 @@ pub async fn start_server() -> Result<(), ErrorArrayItem> {
                                 let _ = socket.write_all(response.as_bytes()).await;
                             }
                         },
-                        NetworkRequestType::UPDATEGITREPO => {
-                            if let Some(data) = request.data {
-                                match serde_json::from_str(&data) {
-                                    Ok(new_auth) => {
-                                        if let Err(e) = update_git_config(new_auth).await {
-                                            eprintln!("Failed to update Git config: {}", e);
-                                            let response = NetworkResponse {
-                                                status: String::from("Error"),
-                                                data: Some(Stringy::new(
-                                                    "Failed to update Git config",
-                                                )),
-                                            };
-                                            let response =
-                                                serde_json::to_string(&response).unwrap();
-                                            let _ = socket.write_all(response.as_bytes()).await;
-                                        } else {
-                                            let response = NetworkResponse {
-                                                status: String::from("Success"),
-                                                data: None,
-                                            };
-                                            let response =
-                                                serde_json::to_string(&response).unwrap();
-                                            let _ = socket.write_all(response.as_bytes()).await;
+                        request_type @ (NetworkRequestType::UPDATEGITREPO
+                        | NetworkRequestType::VALIDATEGITREPO) => {
+                            // Validating runs the same remote checks without saving
+                            let dry_run = matches!(request_type, NetworkRequestType::VALIDATEGITREPO);
+                            let new_auth = request.data.map(|data| serde_json::from_str(&data));
+
+                            let response = match new_auth {
+                                Some(Ok(new_auth)) => match update_git_config(new_auth, dry_run).await {
+                                    Ok(checks) => {
+                                        let passed = checks.iter().all(|check| check.passed());
+                                        if passed && !dry_run {
                                             let unit: Unit =
                                                 systemctl::Unit::from_systemctl("git_monitor")
                                                     .unwrap();
                                             unit.restart().unwrap();
                                         }
+                                        NetworkResponse {
+                                            status: String::from(match passed {
+                                                true => "Success",
+                                                false => "Error",
+                                            }),
+                                            data: Some(Stringy::new(&serde_json::to_string(&checks).unwrap())),
+                                        }
                                     }
                                     Err(e) => {
-                                        eprintln!("Failed to parse GitAuth data: {}", e);
-                                        eprintln!("{:?}", data);
-                                        let response = NetworkResponse {
+                                        eprintln!("Failed to update Git config: {}", e);
+                                        NetworkResponse {
                                             status: String::from("Error"),
-                                            data: Some(Stringy::new("Invalid GitAuth data")),
-                                        };
-                                        let response = serde_json::to_string(&response).unwrap();
-                                        let _ = socket.write_all(response.as_bytes()).await;
+                                            data: Some(Stringy::new("Failed to update Git config")),
+                                        }
+                                    }
+                                },
+                                Some(Err(e)) => {
+                                    eprintln!("Failed to parse GitAuth data: {}", e);
+                                    NetworkResponse {
+                                        status: String::from("Error"),
+                                        data: Some(Stringy::new("Invalid GitAuth data")),
                                     }
                                 }
-                            } else {
-                                let response = NetworkResponse {
+                                None => NetworkResponse {
                                     status: String::from("Error"),
                                     data: Some(Stringy::new("No data provided")),
-                                };
-                                let response = serde_json::to_string(&response).unwrap();
-                                let _ = socket.write_all(response.as_bytes()).await;
-                            }
+                                },
+                            };
+                            let response = serde_json::to_string(&response).unwrap();
+                            let _ = socket.write_all(response.as_bytes()).await;
                         }
                         NetworkRequestType::QUERYGITREPO => match query_git_config().await {
                             Ok(git_statuses) => {
@@ -126,6 +131,230 @@ pub async fn start_server() -> Result<(), ErrorArrayItem> {
                                 let _ = socket.write_all(response.as_bytes()).await;
                             }
                         },
+                        NetworkRequestType::QUERYDEPLOYKEYS => match query_deploy_keys().await {
+                            Ok(deploy_keys) => {
+                                let response = NetworkResponse {
+                                    status: String::from("Success"),
+                                    data: Some(Stringy::new(&serde_json::to_string(&deploy_keys).unwrap())),
+                                };
+                                let response = serde_json::to_string(&response).unwrap();
+                                let _ = socket.write_all(response.as_bytes()).await;
+                            }
+                            Err(e) => {
+                                eprintln!("Failed to query deploy keys: {}", e);
+                                let response = NetworkResponse {
+                                    status: String::from("Error"),
+                                    data: Some(Stringy::new("Failed to query deploy keys")),
+                                };
+                                let response = serde_json::to_string(&response).unwrap();
+                                let _ = socket.write_all(response.as_bytes()).await;
+                            }
+                        },
+                        NetworkRequestType::QUERYSERVICES => match query_services().await {
+                            Ok(services) => {
+                                let response = NetworkResponse {
+                                    status: String::from("Success"),
+                                    data: Some(Stringy::new(&serde_json::to_string(&services).unwrap())),
+                                };
+                                let response = serde_json::to_string(&response).unwrap();
+                                let _ = socket.write_all(response.as_bytes()).await;
+                            }
+                            Err(e) => {
+                                eprintln!("Failed to query services: {}", e);
+                                let response = NetworkResponse {
+                                    status: String::from("Error"),
+                                    data: Some(Stringy::new("Failed to query services")),
+                                };
+                                let response = serde_json::to_string(&response).unwrap();
+                                let _ = socket.write_all(response.as_bytes()).await;
+                            }
+                        },
+                        NetworkRequestType::RESTARTSERVICE => {
+                            let service: Option<Services> = request
+                                .data
+                                .and_then(|data| serde_json::from_str(&data).ok())
+                                .filter(|service| Services::monitored().contains(service));
+
+                            let response = match service {
+                                Some(service) => match service.restart() {
+                                    Ok(true) => NetworkResponse {
+                                        status: String::from("Success"),
+                                        data: Some(Stringy::from(format!("{} restarted", service))),
+                                    },
+                                    Ok(false) => NetworkResponse {
+                                        status: String::from("Error"),
+                                        data: Some(Stringy::from(format!(
+                                            "{} is not active after restart",
+                                            service
+                                        ))),
+                                    },
+                                    Err(e) => {
+                                        eprintln!("Failed to restart {}: {}", service, e);
+                                        NetworkResponse {
+                                            status: String::from("Error"),
+                                            data: Some(Stringy::from(format!(
+                                                "Failed to restart {}",
+                                                service
+                                            ))),
+                                        }
+                                    }
+                                },
+                                None => NetworkResponse {
+                                    status: String::from("Error"),
+                                    data: Some(Stringy::new("Unknown or unmonitored service")),
+                                },
+                            };
+                            let response = serde_json::to_string(&response).unwrap();
+                            let _ = socket.write_all(response.as_bytes()).await;
+                        }
+                        NetworkRequestType::QUERYRELEASES => {
+                            // data is the project id
+                            let response = match request.data {
+                                Some(project) => {
+                                    match ReleaseLayout::for_project(&resolve_project(&project)).releases() {
+                                        Ok(releases) => NetworkResponse {
+                                            status: String::from("Success"),
+                                            data: Some(Stringy::new(&serde_json::to_string(&releases).unwrap())),
+                                        },
+                                        Err(e) => {
+                                            eprintln!("Failed to list releases of {}: {}", project, e);
+                                            NetworkResponse {
+                                                status: String::from("Error"),
+                                                data: Some(Stringy::new("Failed to list releases")),
+                                            }
+                                        }
+                                    }
+                                }
+                                None => NetworkResponse {
+                                    status: String::from("Error"),
+                                    data: Some(Stringy::new("No project given")),
+                                },
+                            };
+                            let response = serde_json::to_string(&response).unwrap();
+                            let _ = socket.write_all(response.as_bytes()).await;
+                        }
+                        NetworkRequestType::ROLLBACK => {
+                            let rollback: Option<RollbackRequest> = request
+                                .data
+                                .and_then(|data| serde_json::from_str(&data).ok());
+
+                            let response = match rollback {
+                                Some(rollback) => match rollback_project(
+                                    &rollback.project,
+                                    rollback.commit.as_deref(),
+                                ) {
+                                    Ok(live) => NetworkResponse {
+                                        status: String::from("Success"),
+                                        data: Some(Stringy::from(format!(
+                                            "{} is now running {}",
+                                            rollback.project, live
+                                        ))),
+                                    },
+                                    Err(e) => {
+                                        eprintln!("Failed to roll back {}: {}", rollback.project, e);
+                                        NetworkResponse {
+                                            status: String::from("Error"),
+                                            data: Some(Stringy::from(e.to_string())),
+                                        }
+                                    }
+                                },
+                                None => NetworkResponse {
+                                    status: String::from("Error"),
+                                    data: Some(Stringy::new("Invalid rollback request")),
+                                },
+                            };
+                            let response = serde_json::to_string(&response).unwrap();
+                            let _ = socket.write_all(response.as_bytes()).await;
+                        }
+                        NetworkRequestType::QUERYPROJECTENV => {
+                            // data is the project id, values are always masked
+                            let response = match request.data {
+                                Some(project) => match ProjectEnv::load(&resolve_project(&project)) {
+                                    Ok(env) => NetworkResponse {
+                                        status: String::from("Success"),
+                                        data: Some(Stringy::new(&serde_json::to_string(&env.masked()).unwrap())),
+                                    },
+                                    Err(e) => {
+                                        eprintln!("Failed to load the variables of {}: {}", project, e);
+                                        NetworkResponse {
+                                            status: String::from("Error"),
+                                            data: Some(Stringy::new("Failed to load the project's variables")),
+                                        }
+                                    }
+                                },
+                                None => NetworkResponse {
+                                    status: String::from("Error"),
+                                    data: Some(Stringy::new("No project given")),
+                                },
+                            };
+                            let response = serde_json::to_string(&response).unwrap();
+                            let _ = socket.write_all(response.as_bytes()).await;
+                        }
+                        NetworkRequestType::UPDATEPROJECTENV => {
+                            let update: Option<ProjectEnvUpdate> = request
+                                .data
+                                .and_then(|data| serde_json::from_str(&data).ok());
+
+                            let response = match update {
+                                Some(update) => {
+                                    let project = resolve_project(&update.project);
+                                    let saved = ProjectEnv::load(&project).and_then(|mut env| {
+                                        env.apply(&update)?;
+                                        env.save()
+                                    });
+                                    match saved {
+                                        Ok(()) => match reload_project(&project).await {
+                                            Ok(_) => NetworkResponse {
+                                                status: String::from("Success"),
+                                                data: Some(Stringy::from(format!("Updated the variables of {}", project))),
+                                            },
+                                            Err(e) => {
+                                                eprintln!("Failed to restart {}: {}", project, e);
+                                                NetworkResponse {
+                                                    status: String::from("Error"),
+                                                    data: Some(Stringy::from(format!(
+                                                        "Updated the variables of {}, but failed to restart it",
+                                                        project
+                                                    ))),
+                                                }
+                                            }
+                                        },
+                                        Err(e) => {
+                                            eprintln!("Failed to update the variables of {}: {}", project, e);
+                                            NetworkResponse {
+                                                status: String::from("Error"),
+                                                data: Some(Stringy::from(e.to_string())),
+                                            }
+                                        }
+                                    }
+                                }
+                                None => NetworkResponse {
+                                    status: String::from("Error"),
+                                    data: Some(Stringy::new("Invalid variables update")),
+                                },
+                            };
+                            let response = serde_json::to_string(&response).unwrap();
+                            let _ = socket.write_all(response.as_bytes()).await;
+                        }
+                        NetworkRequestType::QUERYDIRECTIVES => match query_directives().await {
+                            Ok(reports) => {
+                                let response = NetworkResponse {
+                                    status: String::from("Success"),
+                                    data: Some(Stringy::new(&serde_json::to_string(&reports).unwrap())),
+                                };
+                                let response = serde_json::to_string(&response).unwrap();
+                                let _ = socket.write_all(response.as_bytes()).await;
+                            }
+                            Err(e) => {
+                                eprintln!("Failed to validate directives: {}", e);
+                                let response = NetworkResponse {
+                                    status: String::from("Error"),
+                                    data: Some(Stringy::new("Failed to validate directives")),
+                                };
+                                let response = serde_json::to_string(&response).unwrap();
+                                let _ = socket.write_all(response.as_bytes()).await;
+                            }
+                        },
                         NetworkRequestType::QUERYSYSTEM => {
                             let data = get_system_stats();
                             let response = NetworkResponse {
@@ -12,8 +12,10 @@ mod ui;
 use ais_common::{
     common::{AppName, AppStatus, Status},
     constants::SERVERPORT,
-    git_data::{GitAuth, GitCredentials},
-    manager::{NetworkRequest, NetworkRequestType, NetworkResponse}, system::prompt_input,
+    git_data::{ConflictPolicy, GitAuth, GitCredentials, GitPin, GitProvider, SignaturePolicy},
+    manager::{NetworkRequest, NetworkRequestType, NetworkResponse}, remote_check::RemoteCheck,
+    system::prompt_input,
+    systemd::{ProcessInfo, Services},
 };
 use crossterm::event::{self, Event, KeyCode};
 use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
@@ -22,7 +24,7 @@ use tui::{
     backend::CrosstermBackend,
     layout::Alignment,
     style::{Color, Style},
-    widgets::Paragraph,
+    widgets::{Paragraph, TableState},
     Terminal,
 };
 use ui::draw_ui;
@@ -58,6 +60,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let system_stats = Arc::new(Mutex::new(HashMap::new()));
     let cpu_history = Arc::new(Mutex::new(VecDeque::with_capacity(100)));
     let ram_history = Arc::new(Mutex::new(VecDeque::with_capacity(100)));
+    let services = Arc::new(Mutex::new(Vec::new()));
+    let memory_history = Arc::new(Mutex::new(HashMap::new()));
+    let service_table = Arc::new(Mutex::new(TableState::default()));
     let redraw_ui = Arc::new(Mutex::new(true));
 
     // Spawn threads to update data
@@ -74,6 +79,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         flash_state.clone(),
         cpu_history.clone(),
         ram_history.clone(),
+        services.clone(),
+        memory_history.clone(),
+        service_table.clone(),
         redraw_ui.clone(),
     );
 
@@ -91,6 +99,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         system_stats.clone(),
         cpu_history.clone(),
         ram_history.clone(),
+        services.clone(),
+        memory_history.clone(),
+        service_table.clone(),
         redraw_ui.clone(),
     );
 
@@ -111,6 +122,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     &system_stats,
                     &cpu_history,
                     &ram_history,
+                    &services,
+                    &memory_history,
+                    &service_table,
                 )
             })?;
         }
@@ -121,6 +135,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 KeyCode::Char('q') => break,
                 KeyCode::Char('a') => handle_aggregator_query(&ip_address, &messages),
                 KeyCode::Char('g') => handle_git_repo_query(&ip_address, &messages),
+                KeyCode::Up => select_service(&services, &service_table, -1),
+                KeyCode::Down => select_service(&services, &service_table, 1),
+                KeyCode::Char('r') => {
+                    handle_service_restart(&ip_address, &messages, &services, &service_table)
+                }
                 KeyCode::Char('u') => {
                     *redraw_ui.lock().unwrap() = false;
                     handle_git_repo_update(&ip_address, &messages, &git_data, terminal.clone());
@@ -152,6 +171,9 @@ fn spawn_data_update_threads(
     flash_state: Arc<Mutex<bool>>,
     cpu_history: Arc<Mutex<VecDeque<(f64, f64)>>>,
     ram_history: Arc<Mutex<VecDeque<(f64, f64)>>>,
+    services: Arc<Mutex<Vec<ProcessInfo>>>,
+    memory_history: Arc<Mutex<HashMap<Services, VecDeque<u64>>>>,
+    service_table: Arc<Mutex<TableState>>,
     redraw_ui: Arc<Mutex<bool>>,
 ) {
     // Thread for periodic data updates
@@ -167,6 +189,9 @@ fn spawn_data_update_threads(
     let flash_state_clone = flash_state.clone();
     let cpu_history_clone = cpu_history.clone();
     let ram_history_clone = ram_history.clone();
+    let services_clone = services.clone();
+    let memory_history_clone = memory_history.clone();
+    let service_table_clone = service_table.clone();
     let redraw_ui_clone = redraw_ui.clone();
 
     thread::spawn(move || loop {
@@ -181,6 +206,8 @@ fn spawn_data_update_threads(
             &messages_clone,
             &cpu_history_clone,
             &ram_history_clone,
+            &services_clone,
+            &memory_history_clone,
         );
 
         let redraw = *redraw_ui_clone.lock().unwrap();
@@ -201,6 +228,9 @@ fn spawn_data_update_threads(
                         &system_stats_clone,
                         &cpu_history_clone,
                         &ram_history_clone,
+                        &services_clone,
+                        &memory_history_clone,
+                        &service_table_clone,
                     )
                 })
                 .unwrap();
@@ -222,6 +252,9 @@ fn spawn_flash_state_thread(
     system_stats: Arc<Mutex<HashMap<String, String>>>,
     cpu_history: Arc<Mutex<VecDeque<(f64, f64)>>>,
     ram_history: Arc<Mutex<VecDeque<(f64, f64)>>>,
+    services: Arc<Mutex<Vec<ProcessInfo>>>,
+    memory_history: Arc<Mutex<HashMap<Services, VecDeque<u64>>>>,
+    service_table: Arc<Mutex<TableState>>,
     redraw_ui: Arc<Mutex<bool>>,
 ) {
     thread::spawn(move || loop {
@@ -248,6 +281,9 @@ fn spawn_flash_state_thread(
                         &system_stats,
                         &cpu_history,
                         &ram_history,
+                        &services,
+                        &memory_history,
+                        &service_table,
                     )
                 })
                 .unwrap();
@@ -287,6 +323,18 @@ fn handle_aggregator_query(
                             (format!("Timed Out: {:?}", app_name), Color::Gray),
                         );
                     }
+                    AppStatus::AuthFailure => {
+                        messages_lock.insert(
+                            format!("{:?}", app_name),
+                            (format!("Credentials rejected: {:?}", app_name), Color::Red),
+                        );
+                    }
+                    AppStatus::Untrusted => {
+                        messages_lock.insert(
+                            format!("{:?}", app_name),
+                            (format!("Untrusted revision refused: {:?}", app_name), Color::Red),
+                        );
+                    }
                     _ => {}
                 }
             }
@@ -312,8 +360,15 @@ fn handle_git_repo_query(
                     repo.clone(),
                     (
                         format!(
-                            "User: {}\nRepo: {}\nBranch: {}\nToken: {}\n---",
-                            auth.user, auth.repo, auth.branch, auth.token
+                            "User: {}\nRepo: {}\nBranch: {}\nPin: {}\nOn conflict: {}\nSignatures: {}\nRemote: {}\nToken: {}\n---",
+                            auth.user,
+                            auth.repo,
+                            auth.branch,
+                            pin_state(&auth),
+                            auth.conflict_policy,
+                            auth.signature_policy,
+                            auth.remote_url(),
+                            token_state(&auth)
                         ),
                         Color::White,
                     ),
@@ -352,15 +407,33 @@ fn handle_git_repo_update(
         let user = prompt_input("User: ");
         let repo = prompt_input("Repo: ");
         let branch = prompt_input("Branch: ");
+        let provider = GitProvider::prompt();
+        let pin = GitPin::prompt();
+        let token = GitAuth::prompt_token();
+        let webhook_secret = GitAuth::prompt_webhook_secret();
+        let poll_interval = GitAuth::prompt_poll_interval();
+        let conflict_policy = ConflictPolicy::prompt();
+        let (submodules, lfs) = GitAuth::prompt_checkout_extras();
+        let signature_policy = SignaturePolicy::prompt();
 
         let auth = GitAuth {
             user,
             repo,
             branch,
-            token: Stringy::new("******"),
+            token,
+            provider,
+            pin,
+            webhook_secret,
+            poll_interval,
+            conflict_policy,
+            submodules,
+            lfs,
+            signature_policy,
         };
 
-        git_creds.add_auth(auth);
+        if let Err(e) = git_creds.add_auth(auth) {
+            println!("Skipped it: {}", e);
+        }
     }
 
     // Re-enable raw mode after input
@@ -376,7 +449,7 @@ fn handle_git_repo_update(
     };
 
     if let Ok(response) = send_request(ip_address, &request) {
-        if response.status == "OK" {
+        if response.status == "Success" {
             let mut messages_lock = messages.lock().unwrap();
             messages_lock.insert(
                 "GitUpdate".to_string(),
@@ -386,11 +459,21 @@ fn handle_git_repo_update(
                 ),
             );
         } else {
+            // The server answers with the remote checks when any of them failed
+            let failed: Vec<String> = response
+                .data
+                .and_then(|data| serde_json::from_str::<Vec<RemoteCheck>>(&data).ok())
+                .unwrap_or_default()
+                .iter()
+                .filter(|check| !check.passed())
+                .map(|check| check.to_string())
+                .collect();
+            let message = match failed.is_empty() {
+                true => "Failed to update Git repository".to_string(),
+                false => format!("Git repository not updated, {}", failed.join("; ")),
+            };
             let mut messages_lock = messages.lock().unwrap();
-            messages_lock.insert(
-                "GitUpdate".to_string(),
-                ("Failed to update Git repository".to_string(), Color::Red),
-            );
+            messages_lock.insert("GitUpdate".to_string(), (message, Color::Red));
         }
     }
 
@@ -409,6 +492,8 @@ fn update_data(
     messages: &Arc<Mutex<HashMap<String, (String, Color)>>>,
     cpu_history: &Arc<Mutex<VecDeque<(f64, f64)>>>,
     ram_history: &Arc<Mutex<VecDeque<(f64, f64)>>>,
+    services: &Arc<Mutex<Vec<ProcessInfo>>>,
+    memory_history: &Arc<Mutex<HashMap<Services, VecDeque<u64>>>>,
 ) {
     // Update aggregator status
     update_aggregator_status(ip_address, aggregator_data, aggregator_status, messages);
@@ -423,6 +508,8 @@ fn update_data(
         cpu_history,
         ram_history,
     );
+    // Update the monitored services
+    update_services_data(ip_address, services, memory_history);
 }
 
 fn update_aggregator_status(
@@ -445,11 +532,17 @@ fn update_aggregator_status(
                         .into_iter()
                         .map(|(_, status)| {
                             format!(
-                                "App: {:#?}\nStatus: {:#?}\nTimestamp: {}\nVersion: {}\n---",
+                                "App: {:#?}\nStatus: {:#?}\nTimestamp: {}\nVersion: {}\n{}{}---",
                                 status.app_name,
                                 status.app_status,
                                 status.timestamp,
-                                status.version
+                                status.version,
+                                status
+                                    .message
+                                    .as_ref()
+                                    .map(|message| format!("Message: {}\n", message))
+                                    .unwrap_or_default(),
+                                format_components(&status)
                             )
                         })
                         .collect::<Vec<_>>()
@@ -485,6 +578,21 @@ fn update_aggregator_status(
                                     (format!("Not Running: {:?}", app_name), Color::White),
                                 );
                             }
+                            AppStatus::AuthFailure => {
+                                messages_lock.insert(
+                                    format!("{:?}", app_name),
+                                    (format!("Credentials rejected: {:?}", app_name), Color::Red),
+                                );
+                            }
+                            AppStatus::Untrusted => {
+                                messages_lock.insert(
+                                    format!("{:?}", app_name),
+                                    (
+                                        format!("Untrusted revision refused: {:?}", app_name),
+                                        Color::Red,
+                                    ),
+                                );
+                            }
                         }
                     }
                 }
@@ -503,6 +611,56 @@ fn update_aggregator_status(
     }
 }
 
+// One line per component, with the error of unhealthy ones
+fn format_components(status: &Status) -> String {
+    status
+        .components
+        .iter()
+        .flatten()
+        .map(|(name, component)| match &component.message {
+            Some(message) => format!(
+                "  {:?}/{}: {:?} - {}\n",
+                status.app_name, name, component.app_status, message
+            ),
+            None => format!(
+                "  {:?}/{}: {:?}\n",
+                status.app_name, name, component.app_status
+            ),
+        })
+        .collect()
+}
+
+// Tokens are never shown, only whether one is configured
+fn token_state(auth: &GitAuth) -> &'static str {
+    match auth.access_token() {
+        Some(_) => "set",
+        None => "not set",
+    }
+}
+
+// Pinned repos show their target, the rest follow their branch
+fn pin_state(auth: &GitAuth) -> String {
+    match &auth.pin {
+        Some(pin) => pin.to_string(),
+        None => String::from("branch head"),
+    }
+}
+
+// Public deploy keys by repo, empty if the server couldn't provide them
+fn query_deploy_keys(ip_address: &str) -> HashMap<String, String> {
+    let request = NetworkRequest {
+        request_type: NetworkRequestType::QUERYDEPLOYKEYS,
+        data: None,
+    };
+    match send_request(ip_address, &request) {
+        Ok(response) if response.status == "Success" => response
+            .data
+            .and_then(|data| serde_json::from_str(&data).ok())
+            .unwrap_or_default(),
+        _ => HashMap::new(),
+    }
+}
+
 fn update_git_data(ip_address: &str, git_data: &Arc<Mutex<String>>) {
     let request = NetworkRequest {
         request_type: NetworkRequestType::QUERYGITREPO,
@@ -512,12 +670,24 @@ fn update_git_data(ip_address: &str, git_data: &Arc<Mutex<String>>) {
         if let Some(git_data_response) = response.data {
             let git_auths: HashMap<String, GitAuth> =
                 serde_json::from_str(&git_data_response).unwrap();
+            let deploy_keys = query_deploy_keys(ip_address);
             let git_str = git_auths
                 .into_iter()
-                .map(|(_, auth)| {
+                .map(|(repo, auth)| {
                     format!(
-                        "User: {}\nRepo: {}\nBranch: {}\nToken: {}\n---",
-                        auth.user, auth.repo, auth.branch, auth.token
+                        "User: {}\nRepo: {}\nBranch: {}\nPin: {}\nOn conflict: {}\nSignatures: {}\nRemote: {}\nToken: {}\nDeploy key: {}\n---",
+                        auth.user,
+                        auth.repo,
+                        auth.branch,
+                        pin_state(&auth),
+                        auth.conflict_policy,
+                        auth.signature_policy,
+                        auth.remote_url(),
+                        token_state(&auth),
+                        deploy_keys
+                            .get(&repo)
+                            .map(|key| key.as_str())
+                            .unwrap_or("unavailable")
                     )
                 })
                 .collect::<Vec<_>>()
@@ -583,6 +753,88 @@ fn update_system_data(
     }
 }
 
+fn update_services_data(
+    ip_address: &str,
+    services: &Arc<Mutex<Vec<ProcessInfo>>>,
+    memory_history: &Arc<Mutex<HashMap<Services, VecDeque<u64>>>>,
+) {
+    let request = NetworkRequest {
+        request_type: NetworkRequestType::QUERYSERVICES,
+        data: None,
+    };
+    if let Ok(response) = send_request(ip_address, &request) {
+        if let Some(services_response) = response.data {
+            if let Ok(service_info) = serde_json::from_str::<Vec<ProcessInfo>>(&services_response)
+            {
+                let mut memory_history_lock = memory_history.lock().unwrap();
+                for info in &service_info {
+                    let history = memory_history_lock
+                        .entry(info.refered.clone())
+                        .or_insert_with(|| VecDeque::with_capacity(100));
+                    if history.len() == 100 {
+                        history.pop_front();
+                    }
+                    history.push_back(info.memory.bytes());
+                }
+
+                let mut services_lock = services.lock().unwrap();
+                *services_lock = service_info;
+            }
+        }
+    }
+}
+
+fn select_service(
+    services: &Arc<Mutex<Vec<ProcessInfo>>>,
+    service_table: &Arc<Mutex<TableState>>,
+    offset: i64,
+) {
+    let count = services.lock().unwrap().len() as i64;
+    if count == 0 {
+        return;
+    }
+
+    let mut table_lock = service_table.lock().unwrap();
+    let current = table_lock.selected().map(|i| i as i64).unwrap_or(-1);
+    let next = (current + offset).rem_euclid(count);
+    table_lock.select(Some(next as usize));
+}
+
+fn handle_service_restart(
+    ip_address: &str,
+    messages: &Arc<Mutex<HashMap<String, (String, Color)>>>,
+    services: &Arc<Mutex<Vec<ProcessInfo>>>,
+    service_table: &Arc<Mutex<TableState>>,
+) {
+    let selected = service_table.lock().unwrap().selected();
+    let service = match selected.and_then(|i| services.lock().unwrap().get(i).cloned()) {
+        Some(info) => info.refered,
+        None => return,
+    };
+
+    let request = NetworkRequest {
+        request_type: NetworkRequestType::RESTARTSERVICE,
+        data: Some(serde_json::to_string(&service).unwrap()),
+    };
+
+    let message = match send_request(ip_address, &request) {
+        Ok(response) if response.status == "Success" => {
+            (format!("Restarted {}", service), Color::Green)
+        }
+        Ok(response) => (
+            format!(
+                "Restart failed: {}",
+                response.data.unwrap_or(Stringy::from(format!("{}", service)))
+            ),
+            Color::Red,
+        ),
+        Err(e) => (format!("Restart failed: {}", e), Color::Red),
+    };
+
+    let mut messages_lock = messages.lock().unwrap();
+    messages_lock.insert("ServiceRestart".to_string(), message);
+}
+
 fn send_request(ip_address: &str, request: &NetworkRequest) -> io::Result<NetworkResponse> {
     let server_address = format!("{}:{}", ip_address, SERVERPORT);
     let mut stream = TcpStream::connect(server_address)?;
@@ -591,10 +843,11 @@ fn send_request(ip_address: &str, request: &NetworkRequest) -> io::Result<Networ
     stream.write_all(request_json.as_bytes())?;
     stream.flush()?;
 
-    let mut buffer = vec![0; 1024];
-    let n = stream.read(&mut buffer)?;
+    // The server closes the connection once the response is written
+    let mut buffer = Vec::new();
+    stream.read_to_end(&mut buffer)?;
 
-    let response: NetworkResponse = serde_json::from_slice(&buffer[0..n])?;
+    let response: NetworkResponse = serde_json::from_slice(&buffer)?;
 
     stream.shutdown(Shutdown::Both)?;
 
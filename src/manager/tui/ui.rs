@@ -3,11 +3,15 @@ use std::{
     sync::{Arc, Mutex},
 };
 
+use ais_common::systemd::{self, ProcessInfo, Services};
 use tui::{
     layout::{Alignment, Constraint, Direction, Layout},
     style::{Color, Modifier, Style},
     symbols,
-    widgets::{Axis, Block, Borders, Chart, Dataset, List, ListItem, Paragraph},
+    widgets::{
+        Axis, Block, Borders, Cell, Chart, Dataset, List, ListItem, Paragraph, Row, Sparkline,
+        Table, TableState,
+    },
 };
 
 use crate::centered_paragraph;
@@ -24,6 +28,9 @@ pub fn draw_ui<B: tui::backend::Backend>(
     system_stats: &Arc<Mutex<HashMap<String, String>>>,
     cpu_history: &Arc<Mutex<VecDeque<(f64, f64)>>>,
     ram_history: &Arc<Mutex<VecDeque<(f64, f64)>>>,
+    services: &Arc<Mutex<Vec<ProcessInfo>>>,
+    memory_history: &Arc<Mutex<HashMap<Services, VecDeque<u64>>>>,
+    service_table: &Arc<Mutex<TableState>>,
 ) {
     // Layout
     let chunks = Layout::default()
@@ -31,11 +38,12 @@ pub fn draw_ui<B: tui::backend::Backend>(
         .margin(1)
         .constraints(
             [
-                Constraint::Percentage(35),
+                Constraint::Percentage(28),
                 Constraint::Percentage(10),
-                Constraint::Percentage(10),
-                Constraint::Percentage(20),
-                Constraint::Percentage(25),
+                Constraint::Percentage(8),
+                Constraint::Percentage(22),
+                Constraint::Percentage(18),
+                Constraint::Percentage(14),
             ]
             .as_ref(),
         )
@@ -53,10 +61,15 @@ pub fn draw_ui<B: tui::backend::Backend>(
         )
         .split(chunks[0]);
 
+    let service_chunks = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(70), Constraint::Percentage(30)].as_ref())
+        .split(chunks[3]);
+
     let middle_chunks = Layout::default()
         .direction(Direction::Horizontal)
         .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
-        .split(chunks[3]);
+        .split(chunks[4]);
 
     // let lower_chunks = Layout::default()
     //     .direction(Direction::Horizontal)
@@ -132,6 +145,67 @@ pub fn draw_ui<B: tui::backend::Backend>(
         chunks[2],
     );
 
+    // Service monitor table
+    let service_info: Vec<ProcessInfo> = services.lock().unwrap().clone();
+    let rows: Vec<Row> = service_info
+        .iter()
+        .map(|info| {
+            let status_color = match info.status {
+                systemd::Status::Running => Color::Green,
+                systemd::Status::Stopped => Color::Red,
+                systemd::Status::Error => Color::Yellow,
+            };
+            Row::new(vec![
+                Cell::from(info.service.to_string()),
+                Cell::from(info.status.to_string()).style(Style::default().fg(status_color)),
+                Cell::from(info.memory.to_string()),
+                Cell::from(info.children.to_string()),
+                Cell::from(info.timestamp.to_string()),
+            ])
+        })
+        .collect();
+    let service_table_widget = Table::new(rows)
+        .header(
+            Row::new(vec!["Service", "Status", "Memory", "Tasks", "Checked"])
+                .style(Style::default().add_modifier(Modifier::BOLD)),
+        )
+        .block(
+            Block::default()
+                .title("Services")
+                .borders(Borders::ALL)
+                .style(Style::default().fg(Color::Cyan)),
+        )
+        .widths(&[
+            Constraint::Percentage(28),
+            Constraint::Percentage(22),
+            Constraint::Percentage(12),
+            Constraint::Percentage(10),
+            Constraint::Percentage(28),
+        ])
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    let mut table_state = service_table.lock().unwrap();
+    f.render_stateful_widget(service_table_widget, service_chunks[0], &mut table_state);
+
+    // Memory sparkline for the selected service
+    let selected_info = table_state.selected().and_then(|i| service_info.get(i));
+    let (memory_title, memory_data): (String, Vec<u64>) = match selected_info {
+        Some(info) => (
+            format!("Memory: {}", info.service),
+            memory_history
+                .lock()
+                .unwrap()
+                .get(&info.refered)
+                .map(|history| history.iter().cloned().collect())
+                .unwrap_or_default(),
+        ),
+        None => (String::from("Memory: select a service"), Vec::new()),
+    };
+    let memory_sparkline = Sparkline::default()
+        .block(Block::default().title(memory_title).borders(Borders::ALL))
+        .data(&memory_data)
+        .style(Style::default().fg(Color::Blue));
+    f.render_widget(memory_sparkline, service_chunks[1]);
+
     // CPU and RAM usage charts
     let cpu_history: VecDeque<(f64, f64)> = cpu_history.lock().unwrap().clone();
     let ram_history: VecDeque<(f64, f64)> = ram_history.lock().unwrap().clone();
@@ -176,11 +250,12 @@ pub fn draw_ui<B: tui::backend::Backend>(
                        q: Quit\n\
                        a: Query Aggregator Status\n\
                        g: Query GitHub Repo Status\n\
-                       u: Update GitHub Repo";
+                       u: Update GitHub Repo\n\
+                       Up/Down: Select Service  r: Restart Service";
     let helper_block = Block::default().title("Helper").borders(Borders::ALL);
-    f.render_widget(helper_block, chunks[4]);
+    f.render_widget(helper_block, chunks[5]);
     f.render_widget(
         Paragraph::new(helper_text).alignment(Alignment::Center),
-        chunks[4],
+        chunks[5],
     );
 }
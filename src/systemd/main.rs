@@ -1,5 +1,5 @@
 use ais_common::common::{AppName, AppStatus, Status};
-use ais_common::messages::report_status;
+use ais_common::messages::{report_services, report_status};
 use ais_common::system::current_timestamp;
 use ais_common::systemd::{self, ProcessInfo, Services};
 use ais_common::version::Version;
@@ -27,16 +27,7 @@ async fn monitor_services() -> Result<(), Box<dyn Error>> {
     let mut previous_status: HashMap<Services, ProcessInfo> = HashMap::new();
 
     loop {
-        let mut services = vec![
-            Services::PhpProcessor,
-            Services::WebServer,
-            Services::SshServer,
-            // Services::Monitor, Removing netdata for now
-            Services::Firewall,
-            Services::Locker,
-            Services::Database,
-            // Services::Docker, Removing till we change snap to service
-        ];
+        let mut services = Services::monitored();
 
         // Shuffle services to avoid any bias in processing order
         let mut rng: StdRng = StdRng::from_entropy();
@@ -56,14 +47,8 @@ async fn monitor_services() -> Result<(), Box<dyn Error>> {
                                 service, prev_info.status, info.status
                             );
                         }
-                        #[allow(irrefutable_let_patterns)]
-                        if let ais_common::systemd::Memory::MemoryConsumed(mem) = info.memory {
-                            if mem.parse::<f64>().unwrap_or(0.0) > 1024.0 {
-                                println!(
-                                    "Service {} is consuming more than 1GB of memory",
-                                    service
-                                );
-                            }
+                        if info.memory.bytes() > 1024 * 1024 * 1024 {
+                            println!("Service {} is consuming more than 1GB of memory", service);
                         }
                     } else {
                         println!("Service {} is now being monitored", service);
@@ -103,10 +88,17 @@ async fn report_status_to_aggregator(status_state: Arc<Mutex<HashMap<Services, P
         app_status,
         timestamp: current_timestamp(),
         version: Version::get(),
+        message: None,
+        components: None,
     };
 
     // Send the status message to the aggregator
     _ = report_status(status).await;
+
+    // Send the per service details so the manager can display them
+    let mut service_info: Vec<ProcessInfo> = status_map.values().cloned().collect();
+    service_info.sort_by(|a, b| a.service.cmp(&b.service));
+    _ = report_services(service_info).await;
 }
 
 #[tokio::main]
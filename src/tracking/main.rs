@@ -1,5 +1,5 @@
 use ais_common::common::{AppName, AppStatus, Status};
-use ais_common::dusa_wrapper::encrypt_text;
+use ais_common::secrets::encrypt_text;
 use ais_common::messages::report_status;
 use ais_common::system::current_timestamp;
 use ais_common::version::Version;
@@ -305,7 +305,14 @@ async fn store_summary(summaries: HashMap<String, SessionSummary>) -> io::Result
     all_summary_data.push_str("--------------------------------\n");
 
     // Encrypt and write the summary data to the final file
-    let encrypted_data = encrypt_text(Stringy::new(&all_summary_data)).unwrap();
+    // Without dusad there is nothing to seal it with, this report is skipped
+    let encrypted_data = match encrypt_text(Stringy::new(&all_summary_data)) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("Not storing the summary, it couldn't be encrypted: {}", e);
+            return Ok(());
+        }
+    };
     let mut file = OpenOptions::new()
         .create(true)
         .append(true)
@@ -334,6 +341,8 @@ async fn monitor_system_logs() -> io::Result<()> {
             },
             timestamp: current_timestamp(),
             version: Version::get(),
+            message: None,
+            components: None,
         };
         _ = report_status(status).await;
 